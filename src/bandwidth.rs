@@ -0,0 +1,219 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limits the rate at which bytes are transferred. Tokens for one second's worth of bytes can
+/// build up, so short bursts pass without delay
+#[derive(Debug)]
+pub struct TokenBucket {
+    // bytes per second, where zero means unlimited
+    rate: u64,
+    // may go negative when a transfer takes more than is available, which later ones pay off
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Creates a bucket which never delays transfers
+    pub fn unlimited() -> Self {
+        TokenBucket::new(0)
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Changes the rate in bytes per second, where zero means unlimited
+    pub fn set_rate(&mut self, rate: u64) {
+        self.refill(Instant::now());
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.rate as f64);
+    }
+
+    /// Takes tokens for transferring the given number of bytes. Returns how long to wait before
+    /// transferring them to stay within the rate
+    pub fn take(&mut self, bytes: u64) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    /// Takes tokens like `take`, as if it was the given time
+    fn take_at(&mut self, bytes: u64, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::from_secs(0)
+        }
+
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// Download and upload limits for a session, a torrent or a peer. Clones share the same buckets,
+/// so changing the rates of one changes them for everything the limits apply to
+#[derive(Debug, Clone)]
+pub struct Limits {
+    download: Arc<Mutex<TokenBucket>>,
+    upload: Arc<Mutex<TokenBucket>>,
+}
+
+impl Limits {
+    /// Creates limits of the given rates in bytes per second, where zero means unlimited
+    pub fn new(download_rate: u64, upload_rate: u64) -> Self {
+        Limits {
+            download: Arc::new(Mutex::new(TokenBucket::new(download_rate))),
+            upload: Arc::new(Mutex::new(TokenBucket::new(upload_rate))),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Limits::new(0, 0)
+    }
+
+    pub fn download_rate(&self) -> u64 {
+        self.download.lock().unwrap().rate()
+    }
+
+    pub fn upload_rate(&self) -> u64 {
+        self.upload.lock().unwrap().rate()
+    }
+
+    pub fn set_download_rate(&self, rate: u64) {
+        self.download.lock().unwrap().set_rate(rate);
+    }
+
+    pub fn set_upload_rate(&self, rate: u64) {
+        self.upload.lock().unwrap().set_rate(rate);
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::unlimited()
+    }
+}
+
+/// Which way bytes are transferred
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+/// The limits a connection's transfers pass through, such as the session's, the torrent's and the
+/// peer's, so that a transfer waits for the strictest of them. Protocol overhead only counts
+/// against the limits when the shared flag says so
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    levels: Vec<Limits>,
+    count_overhead: Arc<AtomicBool>,
+}
+
+impl Throttle {
+    pub fn new(limits: Limits, count_overhead: Arc<AtomicBool>) -> Self {
+        Throttle {
+            levels: vec![limits],
+            count_overhead,
+        }
+    }
+
+    /// Creates a throttle which never delays transfers
+    pub fn unlimited() -> Self {
+        Throttle::new(Limits::unlimited(), Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Returns a throttle which passes transfers through the given limits as well
+    pub fn and(&self, limits: Limits) -> Throttle {
+        let mut throttle = self.clone();
+        throttle.levels.push(limits);
+        throttle
+    }
+
+    /// Takes tokens for transferring the given bytes of piece data and protocol overhead in the
+    /// given direction from every level. Returns how long to wait to stay within all of them
+    pub fn take(&self, direction: Direction, payload: u64, overhead: u64) -> Duration {
+        self.take_at(direction, payload, overhead, Instant::now())
+    }
+
+    /// Takes tokens like `take`, as if it was the given time
+    fn take_at(&self, direction: Direction, payload: u64, overhead: u64, now: Instant) -> Duration {
+        let bytes = if self.count_overhead.load(Ordering::SeqCst) { payload + overhead } else { payload };
+        if bytes == 0 {
+            return Duration::from_secs(0)
+        }
+        self.levels.iter().map(|limits| {
+            let bucket = match direction {
+                Direction::Download => &limits.download,
+                Direction::Upload => &limits.upload,
+            };
+            bucket.lock().unwrap().take_at(bytes, now)
+        }).max().unwrap_or_else(|| Duration::from_secs(0))
+    }
+}
+
+#[cfg(test)]
+mod bandwidth_tests {
+    use super::{Direction, Limits, Throttle, TokenBucket};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn token_bucket_test() {
+        let mut unlimited = TokenBucket::unlimited();
+        assert_eq!(unlimited.take(1 << 30), Duration::from_secs(0));
+
+        let mut bucket = TokenBucket::new(1000);
+        let now = Instant::now();
+        assert_eq!(bucket.take_at(600, now), Duration::from_secs(0));
+        // the burst is used up, so the rest has to wait for tokens to come in
+        assert_eq!(bucket.take_at(900, now), Duration::from_millis(500));
+        // which they do while time passes
+        assert_eq!(bucket.take_at(100, now + Duration::from_millis(700)), Duration::from_millis(0));
+
+        bucket.set_rate(0);
+        assert_eq!(bucket.rate(), 0);
+        assert_eq!(bucket.take(5000), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn throttle_test() {
+        let session = Limits::new(0, 1000);
+        let peer = Limits::new(1000, 100);
+        let count_overhead = Arc::new(AtomicBool::new(false));
+        let throttle = Throttle::new(session.clone(), count_overhead.clone()).and(peer.clone());
+
+        // the peer's limit is stricter than the session's, which is unlimited for downloads
+        let now = Instant::now();
+        assert_eq!(throttle.take_at(Direction::Download, 600, 400, now), Duration::from_secs(0));
+        assert_eq!(throttle.take_at(Direction::Download, 600, 0, now), Duration::from_millis(200));
+        assert_eq!(throttle.take_at(Direction::Upload, 200, 0, now), Duration::from_secs(1));
+
+        // overhead alone passes freely until it is counted
+        peer.set_download_rate(0);
+        peer.set_upload_rate(0);
+        session.set_upload_rate(0);
+        assert_eq!(throttle.take(Direction::Upload, 0, 1 << 20), Duration::from_secs(0));
+        session.set_upload_rate(100);
+        assert_eq!(session.upload_rate(), 100);
+        count_overhead.store(true, Ordering::SeqCst);
+        assert_eq!(throttle.take_at(Direction::Upload, 0, 300, now), Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::str;
+
+// nesting deeper than this is rejected so that malicious input can't overflow the stack
+const MAX_DEPTH: usize = 64;
+
+/// Describes why a buffer isn't valid, canonical bencode, along with the offset of the offending
+/// byte
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    UnexpectedByte(usize),
+    // an integer or string length which is empty, has leading zeros, is negative zero or overflows
+    InvalidInteger(usize),
+    UnsortedKey(usize),
+    DuplicateKey(usize),
+    TooDeep(usize),
+    TrailingData(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of data"),
+            DecodeError::UnexpectedByte(pos) => write!(f, "unexpected byte at offset {}", pos),
+            DecodeError::InvalidInteger(pos) => write!(f, "invalid integer at offset {}", pos),
+            DecodeError::UnsortedKey(pos) => write!(f, "dictionary key out of order at offset {}", pos),
+            DecodeError::DuplicateKey(pos) => write!(f, "duplicate dictionary key at offset {}", pos),
+            DecodeError::TooDeep(pos) => write!(f, "nesting too deep at offset {}", pos),
+            DecodeError::TrailingData(pos) => write!(f, "trailing data at offset {}", pos),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// A decoded bencode value which borrows its strings from the buffer it was decoded from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'a> {
+    Integer(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Value<'a>>),
+    Dict(Dict<'a>),
+}
+
+impl<'a> Value<'a> {
+    pub fn as_integer(&self) -> Option<i64> {
+        match *self {
+            Value::Integer(n) => Some(n),
+            _ => None
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None
+        }
+    }
+
+    /// Returns the value as a string if it is a byte string of valid UTF-8
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes().and_then(|bytes| str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value<'a>]> {
+        match *self {
+            Value::List(ref list) => Some(list),
+            _ => None
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict<'a>> {
+        match *self {
+            Value::Dict(ref dict) => Some(dict),
+            _ => None
+        }
+    }
+}
+
+/// A decoded dictionary, which also remembers the exact bytes it was decoded from so that it can
+/// be hashed, as for the info hash
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dict<'a> {
+    entries: BTreeMap<&'a [u8], Value<'a>>,
+    raw: &'a [u8],
+}
+
+impl<'a> Dict<'a> {
+    pub fn get(&self, key: &str) -> Option<&Value<'a>> {
+        self.entries.get(key.as_bytes())
+    }
+
+    pub fn get_integer(&self, key: &str) -> Option<i64> {
+        self.get(key).and_then(|v| v.as_integer())
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Option<&'a [u8]> {
+        self.get(key).and_then(|v| v.as_bytes())
+    }
+
+    pub fn get_list(&self, key: &str) -> Option<&[Value<'a>]> {
+        self.get(key).and_then(|v| v.as_list())
+    }
+
+    pub fn get_dict(&self, key: &str) -> Option<&Dict<'a>> {
+        self.get(key).and_then(|v| v.as_dict())
+    }
+
+    /// Iterates over the entries in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &Value<'a>)> {
+        self.entries.iter().map(|(k, v)| (*k, v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the bytes the dictionary was decoded from, including its `d` and `e` delimiters
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+}
+
+/// Decodes a buffer which must contain exactly one value in canonical form: integers and string
+/// lengths without leading zeros and dictionary keys in strictly ascending order. Canonical form
+/// guarantees that re-encoding the value yields the same bytes
+pub fn decode(buf: &[u8]) -> Result<Value<'_>, DecodeError> {
+    let (value, end) = decode_prefix(buf)?;
+    if end != buf.len() {
+        return Err(DecodeError::TrailingData(end))
+    }
+    Ok(value)
+}
+
+/// Decodes the value at the start of a buffer which may carry other data after it, as in the
+/// metadata messages of BEP 9. Returns the value along with the offset just past it
+pub fn decode_prefix(buf: &[u8]) -> Result<(Value<'_>, usize), DecodeError> {
+    let mut decoder = Decoder { buf, pos: 0 };
+    let value = decoder.value(0)?;
+    Ok((value, decoder.pos))
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.buf.get(self.pos).cloned().ok_or(DecodeError::UnexpectedEnd)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value<'a>, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::TooDeep(self.pos))
+        }
+
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let n = self.integer(b'e')?;
+                Ok(Value::Integer(n))
+            },
+            b'0'..=b'9' => self.bytes().map(Value::Bytes),
+            b'l' => {
+                self.pos += 1;
+                let mut list = vec![];
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            },
+            b'd' => {
+                let start = self.pos;
+                self.pos += 1;
+                let mut entries = BTreeMap::new();
+                let mut last: Option<&'a [u8]> = None;
+                while self.peek()? != b'e' {
+                    let key_pos = self.pos;
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(DecodeError::UnexpectedByte(key_pos))
+                    }
+                    let key = self.bytes()?;
+                    if let Some(last) = last {
+                        if key == last {
+                            return Err(DecodeError::DuplicateKey(key_pos))
+                        } else if key < last {
+                            return Err(DecodeError::UnsortedKey(key_pos))
+                        }
+                    }
+                    let value = self.value(depth + 1)?;
+                    entries.insert(key, value);
+                    last = Some(key);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(Dict { entries, raw: &self.buf[start..self.pos] }))
+            },
+            _ => Err(DecodeError::UnexpectedByte(self.pos))
+        }
+    }
+
+    /// Parses a byte string, `<length>:<bytes>`
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let length_pos = self.pos;
+        let length = self.integer(b':')?;
+        if length < 0 {
+            return Err(DecodeError::InvalidInteger(length_pos))
+        }
+        let length = length as usize;
+        if self.buf.len() - self.pos < length {
+            return Err(DecodeError::UnexpectedEnd)
+        }
+        let bytes = &self.buf[self.pos..self.pos + length];
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    /// Parses a decimal integer up to the given terminator, which is consumed
+    fn integer(&mut self, terminator: u8) -> Result<i64, DecodeError> {
+        let start = self.pos;
+        let end = match self.buf[start..].iter().position(|&b| b == terminator) {
+            Some(i) => start + i,
+            None => return Err(DecodeError::UnexpectedEnd)
+        };
+        let digits = &self.buf[start..end];
+        let unsigned = if digits.first() == Some(&b'-') { &digits[1..] } else { digits };
+
+        let canonical = !unsigned.is_empty() &&
+            unsigned.iter().all(|b| b.is_ascii_digit()) &&
+            (unsigned[0] != b'0' || digits == b"0");
+        if !canonical {
+            return Err(DecodeError::InvalidInteger(start))
+        }
+
+        // the digits are ASCII, so they are valid UTF-8
+        let n = str::from_utf8(digits).unwrap().parse::<i64>().map_err(|_| DecodeError::InvalidInteger(start))?;
+        self.pos = end + 1;
+        Ok(n)
+    }
+}
+
+/// An owned bencode value, used to build data for encoding
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bencode {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+    /// Encodes the value in canonical form
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match *self {
+            Bencode::Integer(n) => out.extend(format!("i{}e", n).as_bytes()),
+            Bencode::Bytes(ref bytes) => {
+                out.extend(format!("{}:", bytes.len()).as_bytes());
+                out.extend(bytes);
+            },
+            Bencode::List(ref list) => {
+                out.push(b'l');
+                for value in list.iter() {
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            },
+            Bencode::Dict(ref m) => {
+                out.push(b'd');
+                for (key, value) in m.iter() {
+                    out.extend(format!("{}:", key.len()).as_bytes());
+                    out.extend(key);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+/// Converts a type into a bencode value for encoding
+pub trait ToBencode {
+    fn to_bencode(&self) -> Bencode;
+}
+
+/// Builds a type from a decoded bencode value
+pub trait FromBencode: Sized {
+    type Err;
+
+    fn from_bencode(value: &Value) -> Result<Self, Self::Err>;
+}
+
+impl ToBencode for str {
+    fn to_bencode(&self) -> Bencode {
+        Bencode::Bytes(self.as_bytes().to_vec())
+    }
+}
+
+impl ToBencode for String {
+    fn to_bencode(&self) -> Bencode {
+        self.as_str().to_bencode()
+    }
+}
+
+impl ToBencode for i64 {
+    fn to_bencode(&self) -> Bencode {
+        Bencode::Integer(*self)
+    }
+}
+
+impl ToBencode for u64 {
+    fn to_bencode(&self) -> Bencode {
+        Bencode::Integer(*self as i64)
+    }
+}
+
+impl ToBencode for u32 {
+    fn to_bencode(&self) -> Bencode {
+        Bencode::Integer(*self as i64)
+    }
+}
+
+impl<T: ToBencode> ToBencode for Vec<T> {
+    fn to_bencode(&self) -> Bencode {
+        Bencode::List(self.iter().map(|v| v.to_bencode()).collect())
+    }
+}
+
+impl<'a> ToBencode for Value<'a> {
+    fn to_bencode(&self) -> Bencode {
+        match *self {
+            Value::Integer(n) => Bencode::Integer(n),
+            Value::Bytes(bytes) => Bencode::Bytes(bytes.to_vec()),
+            Value::List(ref list) => Bencode::List(list.iter().map(|v| v.to_bencode()).collect()),
+            Value::Dict(ref dict) => {
+                Bencode::Dict(dict.iter().map(|(k, v)| (k.to_vec(), v.to_bencode())).collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod bencode_tests {
+    use super::{decode, decode_prefix, Bencode, DecodeError, ToBencode, Value};
+
+    #[test]
+    fn decode_test() {
+        let buf = b"d3:bar4:spam3:fooi42e4:listli-1e0:ee";
+        let value = decode(buf).unwrap();
+        let dict = value.as_dict().unwrap();
+        assert_eq!(dict.get("bar").and_then(|v| v.as_str()), Some("spam"));
+        assert_eq!(dict.get_integer("foo"), Some(42));
+        assert_eq!(dict.get_list("list").unwrap(), &[Value::Integer(-1), Value::Bytes(b"")][..]);
+        assert_eq!(dict.get("missing"), None);
+        assert_eq!(dict.raw(), &buf[..]);
+
+        // data after the value is left for the caller
+        let (value, end) = decode_prefix(b"d5:piecei0eeDATA").unwrap();
+        assert_eq!(value.as_dict().unwrap().get_integer("piece"), Some(0));
+        assert_eq!(end, 12);
+    }
+
+    #[test]
+    fn strings_with_colons_and_binary_test() {
+        let buf = b"d4:name6:a:\"b\"c6:pieces3:\xff\x00:e";
+        let value = decode(buf).unwrap();
+        let dict = value.as_dict().unwrap();
+        assert_eq!(dict.get("name").and_then(|v| v.as_str()), Some("a:\"b\"c"));
+        assert_eq!(dict.get_bytes("pieces"), Some(&b"\xff\x00:"[..]));
+        assert_eq!(dict.get("pieces").and_then(|v| v.as_str()), None);
+    }
+
+    #[test]
+    fn span_capture_test() {
+        let buf = b"d8:announce3:url4:infod6:lengthi5e4:name1:xee";
+        let value = decode(buf).unwrap();
+        let info = value.as_dict().unwrap().get_dict("info").unwrap();
+        assert_eq!(info.raw(), &b"d6:lengthi5e4:name1:xe"[..]);
+    }
+
+    #[test]
+    fn rejects_non_canonical_test() {
+        assert_eq!(decode(b"i03e"), Err(DecodeError::InvalidInteger(1)));
+        assert_eq!(decode(b"i-0e"), Err(DecodeError::InvalidInteger(1)));
+        assert_eq!(decode(b"ie"), Err(DecodeError::InvalidInteger(1)));
+        assert_eq!(decode(b"i1x2e"), Err(DecodeError::InvalidInteger(1)));
+        assert_eq!(decode(b"03:abc"), Err(DecodeError::InvalidInteger(0)));
+        assert_eq!(decode(b"d1:bi1e1:ai2ee"), Err(DecodeError::UnsortedKey(7)));
+        assert_eq!(decode(b"d1:ai1e1:ai2ee"), Err(DecodeError::DuplicateKey(7)));
+        assert_eq!(decode(b"di1ei2ee"), Err(DecodeError::UnexpectedByte(1)));
+        assert_eq!(decode(b"i1ei2e"), Err(DecodeError::TrailingData(3)));
+        assert_eq!(decode(b"5:abc"), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(decode(b"l"), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(decode(b"i99999999999999999999e"), Err(DecodeError::InvalidInteger(1)));
+        assert_eq!(decode(&[b'l'; 100]), Err(DecodeError::TooDeep(65)));
+        assert_eq!(decode(b"i0e"), Ok(Value::Integer(0)));
+        assert_eq!(decode(b"i-12e"), Ok(Value::Integer(-12)));
+    }
+
+    #[test]
+    fn encode_round_trip_test() {
+        let buf = b"d4:infod6:lengthi5e4:name1:xe4:listli-1e0:e3:numi7ee";
+        let value = decode(buf).unwrap();
+        assert_eq!(value.to_bencode().encode(), buf.to_vec());
+
+        let mut m = ::std::collections::BTreeMap::new();
+        m.insert(b"z".to_vec(), "last".to_bencode());
+        m.insert(b"a".to_vec(), Bencode::List(vec![1u32.to_bencode(), Bencode::Bytes(vec![0xff])]));
+        assert_eq!(Bencode::Dict(m).encode(), b"d1:ali1e1:\xffe1:z4:laste".to_vec());
+    }
+}
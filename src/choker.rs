@@ -0,0 +1,59 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How many peers are unchoked at once, one of which is the optimistic unchoke
+pub const UNCHOKE_SLOTS: usize = 4;
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// the optimistic unchoke moves on to another peer every this many rechokes
+pub const OPTIMISTIC_ROUNDS: u32 = 3;
+
+/// A connected peer as the choker sees it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub addr: SocketAddr,
+    pub interested: bool,
+    // the rate the peer is ranked by: how fast it sends us data while we download, and how fast
+    // we send it data once we seed
+    pub rate: f64,
+}
+
+/// Picks the peers to unchoke: the interested peers with the highest rates, plus the optimistic
+/// unchoke, which gives a peer we know nothing about yet a chance to show its rate
+pub fn choose(candidates: &[Candidate], slots: usize, optimistic: Option<SocketAddr>) -> Vec<SocketAddr> {
+    let mut interested: Vec<&Candidate> = candidates.iter().filter(|c| c.interested).collect();
+    interested.sort_by(|a, b| b.rate.partial_cmp(&a.rate).unwrap_or(::std::cmp::Ordering::Equal));
+
+    let optimistic = optimistic.filter(|addr| interested.iter().any(|c| c.addr == *addr));
+    let by_rate = slots.saturating_sub(optimistic.map_or(0, |_| 1));
+    let mut unchoked: Vec<SocketAddr> = interested.iter()
+        .map(|c| c.addr)
+        .filter(|addr| Some(*addr) != optimistic)
+        .take(by_rate)
+        .collect();
+    unchoked.extend(optimistic);
+    unchoked
+}
+
+#[cfg(test)]
+mod choker_tests {
+    use super::{choose, Candidate};
+    use std::net::SocketAddr;
+
+    fn candidate(port: u16, interested: bool, rate: f64) -> Candidate {
+        Candidate { addr: SocketAddr::from(([10, 0, 0, 1], port)), interested, rate }
+    }
+
+    #[test]
+    fn choose_test() {
+        let candidates = vec![candidate(1, true, 100.0), candidate(2, true, 300.0), candidate(3, false, 900.0),
+                              candidate(4, true, 200.0), candidate(5, true, 0.0)];
+        let addrs = |ports: &[u16]| ports.iter().map(|&p| SocketAddr::from(([10, 0, 0, 1], p))).collect::<Vec<_>>();
+
+        assert_eq!(choose(&candidates, 2, None), addrs(&[2, 4]));
+        // the optimistic unchoke takes one of the slots, and uninterested peers are never unchoked
+        assert_eq!(choose(&candidates, 3, Some(addrs(&[5])[0])), addrs(&[2, 4, 5]));
+        assert_eq!(choose(&candidates, 2, Some(addrs(&[2])[0])), addrs(&[4, 2]));
+        assert_eq!(choose(&candidates, 2, Some(addrs(&[3])[0])), addrs(&[2, 4]));
+        assert!(choose(&[], 4, None).is_empty());
+    }
+}
@@ -1,27 +1,54 @@
+use bandwidth::{Direction, Throttle};
 use peer::Peer;
 use torrent::Torrent;
-use std::net::{TcpStream, SocketAddr};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::io::{Read, Write, Error, ErrorKind};
-use util::{bytes_to_u32};
+use std::io::{self, Read, Write, ErrorKind};
+use std::thread;
+use error::Error;
+use event::Event;
+use util::{self, bytes_to_u32};
 use message::Message;
 use ipc::IpcMessage;
 use std::sync::mpsc::{channel, Receiver};
+use stream::{PeerStream, TransportKind};
+use mse;
+use mse::EncryptionPolicy;
 
-const PROTOCOL: &'static str = "BitTorrent protocol";
+pub const PROTOCOL: &str = "BitTorrent protocol";
 const BLOCK_SIZE: u32 = 16384; // 2^14
+// reserved handshake bit announcing support for v2 torrents (BEP 52)
+const V2_FLAG: u8 = 0x10;
+// bit of the sixth reserved handshake byte announcing support for extension messages (BEP 10)
+pub const EXTENSION_FLAG: u8 = 0x10;
+// the length byte, the protocol string, the reserved bytes, the info hash and the peer id
+const HANDSHAKE_LENGTH: u64 = 1 + 19 + 8 + 20 + 20;
+// larger requests are ignored, as other clients do
+const MAX_REQUEST_LENGTH: u32 = 131072; // 2^17
 
 #[derive(Debug)]
 pub struct Connection {
-    stream: TcpStream,
+    stream: PeerStream,
     client: Arc<Mutex<Peer>>,
     peer: Peer,
     torrent: Arc<Mutex<Torrent>>,
-    channel: Receiver<IpcMessage>
+    channel: Receiver<IpcMessage>,
+    // the limits of the session, the torrent and the peer, which transfers wait for
+    throttle: Throttle,
+    // the context of the connection's log records: the hex info hash and the peer's address
+    info_hash: String,
+    addr: SocketAddr,
+    // whether we refuse the peer's requests, as the torrent's choker decides
+    choking: bool,
+    // the blocks we requested from the peer and haven't received yet, by piece index and offset
+    requested: Vec<(u32, u32)>,
 }
 
 impl Connection {
-    pub fn new(client_mutex: Arc<Mutex<Peer>>, mut peer: Peer, stream: TcpStream, torrent_mutex: Arc<Mutex<Torrent>>) -> Self {
+    /// Creates a connection whose transfers stay within the limits of the given throttle as well
+    /// as those of the torrent and the peer
+    pub fn new(client_mutex: Arc<Mutex<Peer>>, mut peer: Peer, stream: PeerStream, torrent_mutex: Arc<Mutex<Torrent>>,
+               throttle: Throttle) -> Self {
         let num_pieces = {
             let t = torrent_mutex.lock().unwrap();
             t.pieces.len()
@@ -34,45 +61,95 @@ impl Connection {
         }
 
         let (tx, rx) = channel::<IpcMessage>();
-        {
+        let addr = SocketAddr::new(peer.ip, peer.port);
+        let (info_hash, throttle) = {
             let mut torrent = torrent_mutex.lock().unwrap();
             torrent.register_peer(tx);
-        }
+            let throttle = throttle.and(torrent.limits()).and(torrent.add_peer(addr));
+            (util::to_hex(&torrent.metainfo.info_hash), throttle)
+        };
 
         Connection {
             stream: stream,
             client: client_mutex,
             peer: peer,
             torrent: torrent_mutex,
-            channel: rx
+            channel: rx,
+            throttle,
+            info_hash,
+            addr,
+            choking: true,
+            requested: vec![],
         }
     }
 
-    pub fn connect(client_mutex: Arc<Mutex<Peer>>, peer: Peer, torrent_mutex: Arc<Mutex<Torrent>>) {
-        println!("Connecting to {}:{}...", peer.ip, peer.port);
+    /// Opens a connection to the given peer over the given transport, encrypted as the policy
+    /// requires, and exchanges messages with it until the torrent is complete or stopped. Data is
+    /// transferred no faster than the throttle allows. Returns an error if the peer could not be
+    /// reached or the connection broke. The torrent reports when the peer connects and
+    /// disconnects
+    pub fn connect(client_mutex: Arc<Mutex<Peer>>, peer: Peer, torrent_mutex: Arc<Mutex<Torrent>>,
+                   transport: TransportKind, policy: EncryptionPolicy,
+                   throttle: Throttle) -> Result<(), Error> {
         let addr = SocketAddr::new(peer.ip, peer.port);
-        match TcpStream::connect(&addr) {
-            Ok(stream) => {
-                println!("Connected successfully to {}:{}", peer.ip, peer.port);
-                let mut c = Connection::new(client_mutex, peer, stream, torrent_mutex);
-                let _ = c.initiate_handshake();
-                println!("Sent handshake");
-                let _ = c.receive_handshake();
-                println!("Received handshake");
-
-                let mut done = false;
-                while !done {
-                    let _ = c.check_messages();
-                    let message = c.receive_message().unwrap();
-                    println!("Received: {:?}", message);
-                    done = c.handle_message(message).unwrap();
-                }
+        let info_hash = torrent_mutex.lock().unwrap().metainfo.info_hash.clone();
+        debug!(info_hash = util::to_hex(&info_hash), peer:% = addr; "Connecting over {:?}", transport);
+        let stream = mse::connect(&addr, transport, &info_hash, policy)?;
+        let encrypted = stream.is_encrypted();
+
+        let mut c = Connection::new(client_mutex, peer, stream, torrent_mutex, throttle);
+        c.initiate_handshake()?;
+        c.receive_handshake()?;
+        debug!(info_hash = c.info_hash.as_str(), peer:% = addr; "Connected (encrypted: {})", encrypted);
+        c.torrent.lock().unwrap().emit(Event::PeerConnected { info_hash: info_hash.clone(), addr, encrypted });
+
+        let result = c.run();
+        match result {
+            Ok(()) => debug!(info_hash = c.info_hash.as_str(), peer:% = addr; "Disconnected"),
+            Err(ref e) => debug!(info_hash = c.info_hash.as_str(), peer:% = addr; "Connection broke: {}", e)
+        }
+        let error = result.as_ref().err().map(|e| e.to_string());
+        c.torrent.lock().unwrap().emit(Event::PeerDisconnected { info_hash, addr, error });
+        result
+    }
+
+    /// Tells the peer which pieces we have, then exchanges messages with it until the torrent is
+    /// complete or the connection should close. Called once the handshakes are done
+    pub fn run(&mut self) -> Result<(), Error> {
+        let bitfield = self.torrent.lock().unwrap().bitfield();
+        if let Some(bytes) = bitfield {
+            self.send_message(Message::Bitfield(bytes))?;
+        }
+        self.exchange_messages()
+    }
+
+    /// Handles messages from the peer and the torrent until the torrent is complete or the
+    /// connection should close
+    fn exchange_messages(&mut self) -> Result<(), Error> {
+        let mut done = false;
+        while !done {
+            if self.check_messages()? {
+                break;
             }
-            _ => println!("Failed to connect")
+            let message = self.receive_message()?;
+            done = self.handle_message(message)?;
         }
+        Ok(())
     }
 
-    fn initiate_handshake(&mut self) -> Result<(), Error> {
+    /// Sends our handshake for the torrent's info hash, opening a connection we made
+    pub fn initiate_handshake(&mut self) -> Result<(), Error> {
+        let info_hash = self.torrent.lock().unwrap().metainfo.info_hash.clone();
+        self.send_handshake(&info_hash)
+    }
+
+    /// Answers the handshake of a peer which connected to us with the same info hash it asked
+    /// for, which is the truncated v2 hash when a v2 peer asks for a hybrid torrent
+    pub fn answer_handshake(&mut self, info_hash: &[u8]) -> Result<(), Error> {
+        self.send_handshake(info_hash)
+    }
+
+    fn send_handshake(&mut self, info_hash: &[u8]) -> Result<(), Error> {
         let mut message = vec![];
 
         {
@@ -80,20 +157,26 @@ impl Connection {
 
             message.push(PROTOCOL.len() as u8);
             message.extend(PROTOCOL.bytes());
-            message.extend(vec![0;8].into_iter());
-            message.extend(t.metainfo.info_hash.iter().cloned());
+            let mut reserved = vec![0; 8];
+            if t.metainfo.info_hash_v2.is_some() {
+                reserved[7] |= V2_FLAG;
+            }
+            message.extend(reserved);
+            message.extend(info_hash.iter().cloned());
             message.extend(t.peer_id.bytes());
-            try!(self.stream.write_all(&message));
-            Ok(())
         }
+        try!(self.stream.write_all(&message));
+        self.torrent.lock().unwrap().record_upload(&self.addr, 0, message.len() as u64);
+        Ok(())
     }
 
     fn receive_handshake(&mut self) -> Result<(), Error> {
-        let pstrlen = try!(self.read_n(1));
-        let _pstr = try!(self.read_n(pstrlen[0] as u32));
-        let _reserved = try!(self.read_n(8));
-        let _info_hash = try!(self.read_n(20));
-        let _peer_id = try!(self.read_n(20));
+        let info_hash = read_handshake(&mut self.stream)?;
+        let mut torrent = self.torrent.lock().unwrap();
+        torrent.record_download(&self.addr, 0, HANDSHAKE_LENGTH);
+        if !torrent.metainfo.info_hashes().contains(&info_hash) {
+            return Err(Error::Protocol(String::from("Handshake for a different torrent")))
+        }
         Ok(())
     }
 
@@ -101,8 +184,17 @@ impl Connection {
         let length = bytes_to_u32(&try!(self.read_n(4)));
         if length > 0 {
             let message = try!(self.read_n(length));
-            Ok(Message::new(&message[0], &message[1..]))
+            let message = Message::new(&message[0], &message[1..])?;
+            let payload = payload_length(&message);
+            let overhead = 4 + length as u64 - payload;
+            self.torrent.lock().unwrap().record_download(&self.addr, payload, overhead);
+            // waiting before the next read keeps the peer's sending within the limits
+            thread::sleep(self.throttle.take(Direction::Download, payload, overhead));
+            trace!(info_hash = self.info_hash.as_str(), peer:% = self.addr, message = message.name(); "Received {:?}", message);
+            Ok(message)
         } else {
+            self.torrent.lock().unwrap().record_download(&self.addr, 0, 4);
+            trace!(info_hash = self.info_hash.as_str(), peer:% = self.addr, message = "keep-alive"; "Received keep-alive");
             Ok(Message::KeepAlive)
         }
     }
@@ -117,11 +209,11 @@ impl Connection {
                 if (n as u32) == bytes_to_read {
                     Ok(buf)
                 } else {
-                    Err(Error::new(ErrorKind::Other, "Not enough bytes"))
+                    Err(Error::Io(io::Error::new(ErrorKind::UnexpectedEof, "Not enough bytes")))
                 }
             }
             Err(e) => {
-                Err(e)
+                Err(Error::Io(e))
             }
         }
     }
@@ -133,6 +225,9 @@ impl Connection {
                 {
                     let client = self.client.lock().unwrap().clone();
                     let num_pieces = client.have.unwrap().len();
+                    if bytes.len() * 8 < num_pieces {
+                        return Err(Error::Protocol(String::from("Bitfield is too short")))
+                    }
                     let mut peer_have = self.peer.have.take().unwrap();
                     for i in 0..num_pieces {
                         let bytes_index = i / 8;
@@ -146,12 +241,14 @@ impl Connection {
                 try!(self.send_interested());
             },
             Message::Have(have_index) => {
-                {
-                    let client = self.client.lock().unwrap().clone();
-                    client.have.unwrap()[have_index as usize] = true;
+                match self.peer.have {
+                    Some(ref mut have) if (have_index as usize) < have.len() => have[have_index as usize] = true,
+                    _ => return Err(Error::Protocol(format!("Have message for unknown piece {}", have_index)))
                 }
                 try!(self.send_interested());
             },
+            // a peer which chokes us throws away the requests it didn't answer
+            Message::Choke => self.requested.clear(),
             Message::Unchoke => {
                 {
                     let mut client = self.client.lock().unwrap();
@@ -160,6 +257,7 @@ impl Connection {
                 try!(self.request_next_block());
             },
             Message::Piece(piece_index, offset, data) => {
+                self.requested.retain(|&r| r != (piece_index, offset));
                 let is_complete = {
                     let mut t = self.torrent.lock().unwrap();
                     let block_index = offset / BLOCK_SIZE;
@@ -172,10 +270,27 @@ impl Connection {
                     try!(self.request_next_block());
                 }
             },
-            Message::Cancel(_) => {
+            Message::Cancel(..) => {
                 try!(self.request_next_block());
             },
-            _ => panic!("Need to process message: {:?}", message)
+            Message::Interested => self.torrent.lock().unwrap().set_interested(&self.addr, true),
+            Message::NotInterested => self.torrent.lock().unwrap().set_interested(&self.addr, false),
+            Message::Request(piece_index, offset, length) if !self.choking && length <= MAX_REQUEST_LENGTH => {
+                let block = self.torrent.lock().unwrap().read_block(piece_index, offset, length)?;
+                if let Some(data) = block {
+                    self.send_message(Message::Piece(piece_index, offset, data))?;
+                }
+            },
+            Message::HashRequest(request) => {
+                let hashes = self.torrent.lock().unwrap().hashes(&request);
+                match hashes {
+                    Some(hashes) => self.send_message(Message::Hashes(request, hashes))?,
+                    None => self.send_message(Message::HashReject(request))?
+                }
+            },
+            // the remaining messages don't affect downloading yet. Hashes are never requested, as
+            // v2 torrents have to carry the piece layers of their files
+            _ => {}
         };
         Ok(false)
     }
@@ -205,47 +320,111 @@ impl Connection {
         match next_block {
             Some((piece_index, block_index, block_length)) => {
                 let offset = block_index * BLOCK_SIZE;
+                self.requested.push((piece_index, offset));
                 self.send_message(Message::Request(piece_index, offset, block_length))
             },
             None => {
-                println!("We've downloaded all the pieces we can from this peer.");
+                trace!(info_hash = self.info_hash.as_str(), peer:% = self.addr; "Nothing left to request from this peer");
                 Ok(())
             }
         }
     }
 
     pub fn send_message(&mut self, message: Message) -> Result<(), Error> {
-        println!("Sending: {:?}", message);
-        try!(self.stream.write_all(&message.serialize()));
+        trace!(info_hash = self.info_hash.as_str(), peer:% = self.addr, message = message.name(); "Sending {:?}", message);
+        let payload = payload_length(&message);
+        let bytes = message.serialize();
+        thread::sleep(self.throttle.take(Direction::Upload, payload, bytes.len() as u64 - payload));
+        try!(self.stream.write_all(&bytes));
+        self.torrent.lock().unwrap().record_upload(&self.addr, payload, bytes.len() as u64 - payload);
         Ok(())
     }
 
-    fn check_messages(&mut self) -> Result<(), Error> {
+    /// Handles a message from the torrent, if there is one. Returns whether the connection
+    /// should close
+    fn check_messages(&mut self) -> Result<bool, Error> {
+        let unchoked = self.torrent.lock().unwrap().is_unchoked(&self.addr);
+        if unchoked == self.choking {
+            self.choking = !unchoked;
+            self.send_message(if unchoked { Message::Unchoke } else { Message::Choke })?;
+        }
+
         loop {
             match self.channel.try_recv() {
                 Ok(message) => return self.handle_ipc(message),
                 Err(_) => break
             }
         }
-        Ok(())
+        Ok(false)
     }
 
     #[allow(unused_variables)]
-    fn handle_ipc(&mut self, message: IpcMessage) -> Result<(), Error> {
+    fn handle_ipc(&mut self, message: IpcMessage) -> Result<bool, Error> {
         match message {
-            IpcMessage::CancelRequest(piece_index, _) => {
-                self.send_message(Message::Cancel(piece_index))
-            }
+            // only blocks requested on this connection are cancelled, as happens in the endgame
+            IpcMessage::CancelRequest(piece_index, block_index, length) => {
+                let offset = block_index * BLOCK_SIZE;
+                if let Some(i) = self.requested.iter().position(|&r| r == (piece_index, offset)) {
+                    self.requested.remove(i);
+                    self.send_message(Message::Cancel(piece_index, offset, length))?;
+                }
+                Ok(false)
+            },
+            IpcMessage::Have(piece_index) => {
+                self.send_message(Message::Have(piece_index))?;
+                Ok(false)
+            },
+            IpcMessage::Stop => Ok(true)
         }
     }
 }
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.torrent.lock().unwrap().remove_peer(&self.addr);
+    }
+}
+
+/// Returns how many bytes of a message are piece data, which counts as payload rather than
+/// protocol overhead
+fn payload_length(message: &Message) -> u64 {
+    match *message {
+        Message::Piece(_, _, ref data) => data.len() as u64,
+        _ => 0
+    }
+}
+
+/// Reads the handshake a peer opens a connection with and returns the info hash of the torrent
+/// it asks for
+pub fn read_handshake(stream: &mut PeerStream) -> Result<Vec<u8>, Error> {
+    read_handshake_with_reserved(stream).map(|(_, info_hash)| info_hash)
+}
+
+/// Reads a peer's handshake and returns its reserved bytes, which announce the protocol
+/// extensions it supports, along with the info hash
+pub fn read_handshake_with_reserved(stream: &mut PeerStream) -> Result<([u8; 8], Vec<u8>), Error> {
+    let mut pstrlen = [0; 1];
+    stream.read_exact(&mut pstrlen)?;
+    let mut pstr = vec![0; pstrlen[0] as usize];
+    stream.read_exact(&mut pstr)?;
+    // the reserved bytes, the info hash and the peer id
+    let mut rest = [0; 48];
+    stream.read_exact(&mut rest)?;
+
+    if pstr != PROTOCOL.as_bytes() {
+        return Err(Error::Protocol(String::from("Unknown protocol in handshake")))
+    }
+    let mut reserved = [0; 8];
+    reserved.copy_from_slice(&rest[..8]);
+    Ok((reserved, rest[8..28].to_vec()))
+}
+
 #[cfg(test)]
 mod connection_tests {
     #[test]
     fn create_connection_test() {
         use metainfo;
-        use tracker;
+        use tracker::{self, AnnounceEvent, Progress};
         use peer::Peer;
         use torrent::Torrent;
         use std::net::SocketAddr;
@@ -256,11 +435,50 @@ mod connection_tests {
         let m = metainfo::from_file(&f).unwrap();
         let peer_id: String = create_peer_id();
 
-        let peers = tracker::retrieve_peers(&m, &peer_id, "8080").unwrap();
+        let progress = Progress { uploaded: 0, downloaded: 0, left: m.info.length };
+        let peers = tracker::announce(m.announce.as_ref().unwrap(), &m.info_hash, &peer_id, "8080", progress, Some(AnnounceEvent::Started)).unwrap().peers;
         let ref peer = peers[0];
-        let torrent = Torrent::new(peer_id, m);
+        let torrent = Torrent::new(peer_id, m).unwrap();
         let _ = Arc::new(Mutex::new(torrent));
         let _ = Arc::new(Mutex::new(Peer::from_bytes(&[127, 0, 0, 1, 31, 144])));
         let _ = SocketAddr::new(peer.ip, peer.port);
     }
+
+    #[test]
+    fn cancel_requested_blocks_test() {
+        use super::Connection;
+        use bandwidth::Throttle;
+        use ipc::IpcMessage;
+        use metainfo::test_metainfo;
+        use peer::Peer;
+        use storage::MemoryStorage;
+        use stream::{PeerStream, Transport};
+        use torrent::Torrent;
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+        use std::sync::{Arc, Mutex};
+        use util::create_peer_id;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let m = test_metainfo("cancel", 16384, &[0; 16384], &[]);
+        let torrent = Arc::new(Mutex::new(Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new()))));
+        let mut c = Connection::new(Arc::new(Mutex::new(Peer::local(0))), Peer::from_addr(addr),
+                                    PeerStream::plain(Transport::Tcp(stream)), torrent, Throttle::unlimited());
+        c.peer.have = Some(vec![true]);
+
+        // a block this connection never asked for isn't cancelled
+        c.handle_ipc(IpcMessage::CancelRequest(0, 0, 16384)).unwrap();
+        c.request_next_block().unwrap();
+        c.handle_ipc(IpcMessage::CancelRequest(0, 0, 16384)).unwrap();
+        c.handle_ipc(IpcMessage::CancelRequest(0, 0, 16384)).unwrap();
+        c.handle_ipc(IpcMessage::Have(0)).unwrap();
+
+        // a request, its cancel and the have, each after its length
+        let mut sent = vec![0; 17 + 17 + 9];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!((sent[4], sent[21], sent[38]), (6, 8, 4));
+        assert_eq!(c.requested, vec![]);
+    }
 }
@@ -0,0 +1,343 @@
+use bencode::{Bencode, ToBencode};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use hash;
+
+const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+// the automatic piece length is the smallest one which keeps the number of pieces below this
+const TARGET_PIECES: u64 = 1500;
+
+/// Builds a `.torrent` file from a file or a directory on disk
+///
+/// # Example
+///
+/// ```no_run
+/// # use bittorrent::create::TorrentBuilder;
+/// # fn main() -> Result<(), bittorrent::Error> {
+/// let bytes = TorrentBuilder::new("data/flag.jpg")
+///     .announce("http://tracker.example.com/announce")
+///     .comment("A flag")
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<u32>,
+    announce: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<u64>,
+    private: bool,
+    web_seeds: Vec<String>,
+}
+
+impl TorrentBuilder {
+    /// Starts building a torrent of the file or directory at the given path
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        TorrentBuilder {
+            path: path.as_ref().to_path_buf(),
+            piece_length: None,
+            announce: vec![],
+            comment: None,
+            created_by: Some(format!("bittorrent {}", env!("CARGO_PKG_VERSION"))),
+            creation_date: None,
+            private: false,
+            web_seeds: vec![],
+        }
+    }
+
+    /// Sets the piece length, which must be a power of two of at least 16 KiB. By default it is
+    /// chosen from the total size of the data
+    pub fn piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tracker in a tier of its own. The first tracker added becomes the `announce` URL
+    pub fn announce(mut self, url: &str) -> Self {
+        self.announce.push(vec![url.to_string()]);
+        self
+    }
+
+    /// Adds a tier of trackers to the `announce-list`
+    pub fn announce_tier(mut self, urls: Vec<String>) -> Self {
+        if !urls.is_empty() {
+            self.announce.push(urls);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: &str) -> Self {
+        self.created_by = Some(created_by.to_string());
+        self
+    }
+
+    /// Sets the creation date as seconds since the Unix epoch. Defaults to the time of building
+    pub fn creation_date(mut self, timestamp: u64) -> Self {
+        self.creation_date = Some(timestamp);
+        self
+    }
+
+    /// Marks the torrent as private, so that clients only get peers from its trackers
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Adds an HTTP URL from which the torrent's data can be downloaded
+    pub fn web_seed(mut self, url: &str) -> Self {
+        self.web_seeds.push(url.to_string());
+        self
+    }
+
+    /// Hashes the data and returns the bencoded `.torrent` file
+    pub fn build(&self) -> Result<Vec<u8>, Error> {
+        let name = match self.path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(Error::invalid("name", "path has no file name"))
+        };
+
+        let metadata = fs::metadata(&self.path)?;
+        let files = if metadata.is_dir() {
+            let mut files = vec![];
+            collect_files(&self.path, &mut vec![], &mut files)?;
+            if files.is_empty() {
+                return Err(Error::invalid("files", "directory contains no files"))
+            }
+            files
+        } else {
+            vec![(vec![], metadata.len())]
+        };
+
+        let total_length: u64 = files.iter().map(|&(_, length)| length).sum();
+        let piece_length = match self.piece_length {
+            Some(length) if length < MIN_PIECE_LENGTH || !length.is_power_of_two() => {
+                return Err(Error::invalid("piece length", "must be a power of two of at least 16 KiB"))
+            },
+            Some(length) => length,
+            None => auto_piece_length(total_length)
+        };
+
+        let paths: Vec<PathBuf> = files.iter().map(|(components, _)| {
+            let mut path = self.path.clone();
+            path.extend(components.iter());
+            path
+        }).collect();
+        let pieces = hash_pieces(&paths, piece_length)?;
+
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), name.to_bencode());
+        info.insert(b"piece length".to_vec(), Bencode::Integer(piece_length as i64));
+        info.insert(b"pieces".to_vec(), Bencode::Bytes(pieces));
+        if self.private {
+            info.insert(b"private".to_vec(), Bencode::Integer(1));
+        }
+        if metadata.is_dir() {
+            let list = files.iter().map(|&(ref components, length)| {
+                let mut m = BTreeMap::new();
+                m.insert(b"length".to_vec(), Bencode::Integer(length as i64));
+                m.insert(b"path".to_vec(), Bencode::List(components.iter().map(|c| c.to_bencode()).collect()));
+                Bencode::Dict(m)
+            }).collect();
+            info.insert(b"files".to_vec(), Bencode::List(list));
+        } else {
+            info.insert(b"length".to_vec(), Bencode::Integer(total_length as i64));
+        }
+
+        let mut m = BTreeMap::new();
+        m.insert(b"info".to_vec(), Bencode::Dict(info));
+        if let Some(url) = self.announce.first().and_then(|tier| tier.first()) {
+            m.insert(b"announce".to_vec(), url.to_bencode());
+        }
+        if self.announce.len() > 1 || self.announce.first().is_some_and(|tier| tier.len() > 1) {
+            let tiers = self.announce.iter()
+                .map(|tier| Bencode::List(tier.iter().map(|url| url.to_bencode()).collect()))
+                .collect();
+            m.insert(b"announce-list".to_vec(), Bencode::List(tiers));
+        }
+        if let Some(ref comment) = self.comment {
+            m.insert(b"comment".to_vec(), comment.to_bencode());
+        }
+        if let Some(ref created_by) = self.created_by {
+            m.insert(b"created by".to_vec(), created_by.to_bencode());
+        }
+        let creation_date = match self.creation_date {
+            Some(date) => date,
+            None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+        };
+        m.insert(b"creation date".to_vec(), Bencode::Integer(creation_date as i64));
+        if !self.web_seeds.is_empty() {
+            let urls = self.web_seeds.iter().map(|url| url.to_bencode()).collect();
+            m.insert(b"url-list".to_vec(), Bencode::List(urls));
+        }
+
+        Ok(Bencode::Dict(m).encode())
+    }
+
+    /// Hashes the data and writes the `.torrent` file to the given path
+    pub fn write_to(&self, path: &Path) -> Result<(), Error> {
+        let bytes = self.build()?;
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+}
+
+/// Picks the smallest power of two piece length which keeps the number of pieces of data with the
+/// given length near the target
+fn auto_piece_length(total_length: u64) -> u32 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && total_length / piece_length as u64 > TARGET_PIECES {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+/// Walks a directory in sorted order and collects the path components, relative to the torrent's
+/// directory, and the length of every file in it
+fn collect_files(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<(Vec<String>, u64)>) -> Result<(), Error> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, io::Error>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let metadata = fs::metadata(entry.path())?;
+        prefix.push(entry.file_name().to_string_lossy().into_owned());
+        if metadata.is_dir() {
+            collect_files(&entry.path(), prefix, files)?;
+        } else if metadata.is_file() {
+            files.push((prefix.clone(), metadata.len()));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Reads the files one after another as a single stream of data and returns the concatenated SHA1
+/// hashes of its pieces
+fn hash_pieces(paths: &[PathBuf], piece_length: u32) -> Result<Vec<u8>, Error> {
+    let mut pieces = vec![];
+    let mut buf = Vec::with_capacity(piece_length as usize);
+
+    for path in paths {
+        let mut f = File::open(path)?;
+        loop {
+            let start = buf.len();
+            buf.resize(piece_length as usize, 0);
+            let n = f.read(&mut buf[start..])?;
+            buf.truncate(start + n);
+            if buf.len() == piece_length as usize {
+                pieces.extend(hash::sha(&buf));
+                buf.clear();
+            } else if n == 0 {
+                break;
+            }
+        }
+    }
+
+    if !buf.is_empty() {
+        pieces.extend(hash::sha(&buf));
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod create_tests {
+    use super::{TorrentBuilder, auto_piece_length};
+    use bencode;
+    use bencode::{FromBencode, ToBencode};
+    use metainfo;
+    use metainfo::MetaInfo;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use hash;
+
+    #[test]
+    fn single_file_round_trip_test() {
+        let dir = env::temp_dir().join("bittorrent_create_file_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("file.bin"), &data).unwrap();
+
+        let out = dir.join("file.torrent");
+        TorrentBuilder::new(dir.join("file.bin"))
+            .announce("http://tracker.example.com/announce")
+            .comment("test")
+            .piece_length(16384)
+            .creation_date(1500000000)
+            .write_to(&out)
+            .unwrap();
+
+        let m = metainfo::from_file(&out.to_string_lossy().into_owned()).unwrap();
+        assert_eq!(m.announce.unwrap(), "http://tracker.example.com/announce");
+        assert_eq!(m.info.name, "file.bin");
+        assert_eq!(m.info.length, 40000);
+        assert_eq!(m.info.pieces, vec![
+            hash::sha(&data[0..16384]),
+            hash::sha(&data[16384..32768]),
+            hash::sha(&data[32768..]),
+        ]);
+
+        let bytes = fs::read(&out).unwrap();
+        let b = bencode::decode(&bytes).unwrap();
+        let info = b.as_dict().unwrap().get_dict("info").unwrap().raw();
+        let decoded: MetaInfo = FromBencode::from_bencode(&b).unwrap();
+        assert_eq!(decoded.info_hash, hash::sha(info));
+        assert_eq!(b.to_bencode().encode(), bytes);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn directory_round_trip_test() {
+        let dir = env::temp_dir().join("bittorrent_create_dir_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("album/b")).unwrap();
+        fs::write(dir.join("album/a.txt"), vec![1; 20000]).unwrap();
+        fs::write(dir.join("album/b/c.txt"), vec![2; 5000]).unwrap();
+
+        let out = dir.join("album.torrent");
+        TorrentBuilder::new(dir.join("album"))
+            .announce("http://a.example.com/announce")
+            .announce("http://b.example.com/announce")
+            .private(true)
+            .web_seed("http://seed.example.com/")
+            .write_to(&out)
+            .unwrap();
+
+        let m = metainfo::from_file(&out.to_string_lossy().into_owned()).unwrap();
+        assert_eq!(m.info.length, 25000);
+        assert!(m.info.private);
+        assert_eq!(m.info.pieces.len(), 2);
+        assert_eq!(m.info.files(), vec![
+            (PathBuf::from("album/a.txt"), 20000),
+            (PathBuf::from("album/b/c.txt"), 5000),
+        ]);
+
+        let mut data = vec![1; 20000];
+        data.extend(vec![2; 5000]);
+        assert_eq!(m.info.pieces[1], hash::sha(&data[16384..]));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn auto_piece_length_test() {
+        assert_eq!(auto_piece_length(0), 16384);
+        assert_eq!(auto_piece_length(1500 * 16384), 16384);
+        assert_eq!(auto_piece_length(4 * 1024 * 1024 * 1024), 4 * 1024 * 1024);
+        assert_eq!(auto_piece_length(1 << 50), 16 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,79 @@
+use bencode::DecodeError;
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while reading torrents, talking to trackers and peers, or
+/// storing data
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file or socket failed
+    Io(io::Error),
+    /// Data which should be bencoded isn't valid, canonical bencode
+    Bencode(DecodeError),
+    /// A required field of a metainfo file, tracker response or resume file is missing
+    MissingField(String),
+    /// A field is present but has the wrong type or an unusable value. Holds the field's name and
+    /// what is wrong with it
+    InvalidField(String, String),
+    /// The tracker couldn't be reached or rejected the announce
+    Tracker(String),
+    /// A peer broke the wire protocol
+    Protocol(String),
+    /// The storage backend failed to read or write torrent data
+    Storage(io::Error),
+}
+
+impl Error {
+    pub fn missing(field: &str) -> Error {
+        Error::MissingField(field.to_string())
+    }
+
+    pub fn invalid(field: &str, reason: &str) -> Error {
+        Error::InvalidField(field.to_string(), reason.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Bencode(ref e) => write!(f, "invalid bencode: {}", e),
+            Error::MissingField(ref field) => write!(f, "missing field `{}`", field),
+            Error::InvalidField(ref field, ref reason) => write!(f, "invalid field `{}`: {}", field, reason),
+            Error::Tracker(ref reason) => write!(f, "tracker error: {}", reason),
+            Error::Protocol(ref reason) => write!(f, "protocol error: {}", reason),
+            Error::Storage(ref e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) | Error::Storage(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) | Error::Storage(e) => e,
+            e => io::Error::other(e.to_string())
+        }
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Error {
+        Error::Bencode(e)
+    }
+}
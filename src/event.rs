@@ -0,0 +1,75 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Something which happened to a torrent of a session. Torrents are named by the info hash the
+/// session knows them by
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    TorrentAdded(Vec<u8>),
+    TorrentRemoved(Vec<u8>),
+    // the torrent's connections were closed until it's resumed
+    TorrentPaused(Vec<u8>),
+    TorrentResumed(Vec<u8>),
+    // every wanted piece of the torrent is downloaded and verified
+    TorrentFinished(Vec<u8>),
+    // a piece was downloaded, matched its hash and was stored
+    PieceVerified { info_hash: Vec<u8>, piece: u32 },
+    // a piece was downloaded but didn't match its hash, so it will be downloaded again
+    PieceFailed { info_hash: Vec<u8>, piece: u32 },
+    // the handshake with a peer succeeded, over an encrypted stream or not
+    PeerConnected { info_hash: Vec<u8>, addr: SocketAddr, encrypted: bool },
+    // the connection to a peer closed, because of the given error if it broke
+    PeerDisconnected { info_hash: Vec<u8>, addr: SocketAddr, error: Option<String> },
+    // a tracker answered an announce with the given number of peers
+    TrackerReply { info_hash: Vec<u8>, url: String, peers: usize },
+    TrackerError { info_hash: Vec<u8>, url: String, error: String },
+    // reading or writing the torrent's data failed
+    StorageError { info_hash: Vec<u8>, error: String },
+}
+
+/// Hands every event to all subscribers. Subscribers whose receiver was dropped are forgotten
+#[derive(Debug, Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Events::default()
+    }
+
+    /// Returns a receiver for all events emitted from now on
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn emit(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod event_tests {
+    use super::{Event, Events};
+
+    #[test]
+    fn subscribe_test() {
+        let events = Events::new();
+        events.emit(Event::TorrentAdded(vec![0; 20]));
+
+        let first = events.subscribe();
+        let second = events.subscribe();
+        events.emit(Event::TorrentAdded(vec![1; 20]));
+        assert_eq!(first.try_recv(), Ok(Event::TorrentAdded(vec![1; 20])));
+        assert_eq!(second.try_recv(), Ok(Event::TorrentAdded(vec![1; 20])));
+        assert!(first.try_recv().is_err());
+
+        drop(second);
+        events.emit(Event::TorrentRemoved(vec![1; 20]));
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(first.try_recv(), Ok(Event::TorrentRemoved(vec![1; 20])));
+    }
+}
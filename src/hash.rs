@@ -1,6 +1,8 @@
 extern crate sha1;
+extern crate sha2;
 
 use self::sha1::Sha1;
+use self::sha2::{Digest, Sha256};
 
 /// Takes an array of bytes a performs a Sha1 hash on it,
 /// returning the generated hash as a vector of bytes
@@ -8,6 +10,7 @@ use self::sha1::Sha1;
 /// # Example
 ///
 /// ```
+/// # use bittorrent::hash::sha;
 /// let hash: Vec<u8> = sha("hello world".as_bytes());
 /// assert_eq!(hash, vec![42, 174, 108, 53, 201, 79, 207, 180, 21, 219, 233, 95, 64, 139, 156, 233, 30, 232, 70, 237]);
 /// ```
@@ -17,9 +20,14 @@ pub fn sha(b: &[u8]) -> Vec<u8> {
     m.digest().bytes().to_vec()
 }
 
+/// Takes an array of bytes and performs a SHA-256 hash on it, as used by v2 torrents (BEP 52)
+pub fn sha256(b: &[u8]) -> Vec<u8> {
+    Sha256::digest(b).to_vec()
+}
+
 #[cfg(test)]
 mod sha_tests {
-    use super::sha;
+    use super::{sha, sha256};
 
     #[test]
     fn make_sha_test() {
@@ -29,4 +37,11 @@ mod sha_tests {
             vec![91, 169, 60, 157, 176, 207, 249, 63, 82, 181, 33, 215, 66, 14, 67, 246, 237, 162, 120, 79]
         );
     }
+
+    #[test]
+    fn make_sha256_test() {
+        let hash: Vec<u8> = sha256(b"abc");
+        assert_eq!(hash[..4], [0xba, 0x78, 0x16, 0xbf]);
+        assert_eq!(hash.len(), 32);
+    }
 }
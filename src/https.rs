@@ -0,0 +1,69 @@
+use hyper;
+use hyper::Client;
+use hyper::net::{HttpStream, HttpsConnector, NetworkStream, SslClient};
+use native_tls::{TlsConnector, TlsStream};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use error::Error;
+
+/// Wraps hyper's connections in TLS, so that its client can fetch `https://` URLs as well as
+/// `http://` ones
+struct Tls(TlsConnector);
+
+/// A TLS connection which hyper can clone, as it does with plain connections
+#[derive(Clone)]
+struct Stream(Arc<Mutex<TlsStream<HttpStream>>>);
+
+impl SslClient for Tls {
+    type Stream = Stream;
+
+    fn wrap_client(&self, stream: HttpStream, host: &str) -> hyper::Result<Stream> {
+        match self.0.connect(host, stream) {
+            Ok(stream) => Ok(Stream(Arc::new(Mutex::new(stream)))),
+            Err(e) => Err(hyper::Error::Ssl(Box::new(io::Error::other(e.to_string()))))
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl NetworkStream for Stream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.lock().unwrap().get_mut().peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().get_ref().set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().get_ref().set_write_timeout(dur)
+    }
+
+    fn close(&mut self, _how: Shutdown) -> io::Result<()> {
+        self.0.lock().unwrap().shutdown()
+    }
+}
+
+/// Returns an HTTP client which also speaks HTTPS, checking certificates against the system's
+/// trusted roots
+pub fn client() -> Result<Client, Error> {
+    let connector = TlsConnector::new().map_err(|e| Error::Io(io::Error::other(e.to_string())))?;
+    Ok(Client::with_connector(HttpsConnector::new(Tls(connector))))
+}
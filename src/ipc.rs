@@ -1,3 +1,8 @@
 pub enum IpcMessage {
-    CancelRequest(u32, u32)
+    // another connection received the block with the given piece index, block index and length
+    CancelRequest(u32, u32, u32),
+    // the piece with the given index was verified, which peers should hear about
+    Have(u32),
+    // the torrent was removed, so its connections should close
+    Stop,
 }
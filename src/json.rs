@@ -0,0 +1,390 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A JSON value. Numbers are kept as floats, which hold every integer up to 2^53 exactly
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+/// Text which isn't valid JSON. Holds the offset of the first byte which doesn't fit
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError(pub usize);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid JSON at offset {}", self.0)
+    }
+}
+
+impl Json {
+    /// Returns the member of an object with the given key
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => members.get(key),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None
+        }
+    }
+
+    /// Returns the value of a number which is a non-negative integer
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 && n < 2f64.powi(64) => Some(n as u64),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref values) => Some(values),
+            _ => None
+        }
+    }
+}
+
+/// Builds an object from its members
+pub fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+impl<'a> From<&'a str> for Json {
+    fn from(s: &'a str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(n: f64) -> Json {
+        Json::Number(n)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(values: Vec<T>) -> Json {
+        Json::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Writes the value as compact JSON
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            // JSON has no infinities or NaN
+            Json::Number(n) if !n.is_finite() => write!(f, "null"),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(ref members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Parses a JSON text, which must hold exactly one value
+pub fn parse(text: &str) -> Result<Json, ParseError> {
+    let mut parser = Parser { bytes: text.as_bytes(), pos: 0 };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos < parser.bytes.len() {
+        return Err(ParseError(parser.pos))
+    }
+    Ok(value)
+}
+
+// deeper nesting is refused rather than risking the stack
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.bytes.len() && b" \t\r\n".contains(&self.bytes[self.pos]) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), ParseError> {
+        if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(())
+        } else {
+            Err(ParseError(self.pos))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, ParseError> {
+        self.skip_whitespace();
+        if depth > MAX_DEPTH {
+            return Err(ParseError(self.pos))
+        }
+        match self.peek() {
+            Some(b'n') => self.expect("null").map(|_| Json::Null),
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = vec![];
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(values))
+                }
+                loop {
+                    values.push(self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(values))
+                        },
+                        _ => return Err(ParseError(self.pos))
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = BTreeMap::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members))
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() != Some(b'"') {
+                        return Err(ParseError(self.pos))
+                    }
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    members.insert(key, self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(members))
+                        },
+                        _ => return Err(ParseError(self.pos))
+                    }
+                }
+            },
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ => Err(ParseError(self.pos))
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, ParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b"+-.eE".contains(&b) || b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        // the bytes are ASCII, so they are valid UTF-8
+        let text = String::from_utf8_lossy(&self.bytes[start..self.pos]);
+        text.parse::<f64>().map(Json::Number).map_err(|_| ParseError(start))
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        // skips the opening quote
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let start = self.pos;
+            while self.peek().is_some_and(|b| b != b'"' && b != b'\\' && b >= 0x20) {
+                self.pos += 1;
+            }
+            // the text came from a str and the run ends at an ASCII byte, so it is valid UTF-8
+            s.push_str(&String::from_utf8_lossy(&self.bytes[start..self.pos]));
+
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(s)
+                },
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = self.peek().ok_or(ParseError(self.pos))?;
+                    self.pos += 1;
+                    match escaped {
+                        b'"' => s.push('"'),
+                        b'\\' => s.push('\\'),
+                        b'/' => s.push('/'),
+                        b'b' => s.push('\u{8}'),
+                        b'f' => s.push('\u{c}'),
+                        b'n' => s.push('\n'),
+                        b'r' => s.push('\r'),
+                        b't' => s.push('\t'),
+                        b'u' => s.push(self.unicode_escape()?),
+                        _ => return Err(ParseError(self.pos - 1))
+                    }
+                },
+                _ => return Err(ParseError(self.pos))
+            }
+        }
+    }
+
+    /// Reads the digits of a `\u` escape, along with the second half of a surrogate pair
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let first = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&first) {
+            self.expect("\\u")?;
+            let second = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&second) {
+                return Err(ParseError(self.pos - 4))
+            }
+            0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+        } else {
+            first
+        };
+        ::std::char::from_u32(code).ok_or(ParseError(self.pos - 4))
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self.bytes.get(self.pos..self.pos + 4).ok_or(ParseError(self.pos))?;
+        let digits = ::std::str::from_utf8(digits).map_err(|_| ParseError(self.pos))?;
+        let value = u32::from_str_radix(digits, 16).map_err(|_| ParseError(self.pos))?;
+        self.pos += 4;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod json_tests {
+    use super::{object, parse, Json, ParseError};
+
+    #[test]
+    fn parse_test() {
+        let value = parse(r#" {"id": 1, "params": {"paths": ["a\"b", "é😀"], "rate": -2.5e3},
+                              "ok": true, "none": null, "empty": [] } "#).unwrap();
+        assert_eq!(value.get("id").and_then(Json::as_u64), Some(1));
+        assert_eq!(value.get("ok").and_then(Json::as_bool), Some(true));
+        assert_eq!(value.get("none"), Some(&Json::Null));
+        assert_eq!(value.get("empty").and_then(Json::as_array), Some(&[][..]));
+        let params = value.get("params").unwrap();
+        assert_eq!(params.get("rate"), Some(&Json::Number(-2500.0)));
+        assert_eq!(params.get("rate").unwrap().as_u64(), None);
+        let paths: Vec<&str> = params.get("paths").unwrap().as_array().unwrap().iter().filter_map(Json::as_str).collect();
+        assert_eq!(paths, vec!["a\"b", "é😀"]);
+
+        assert_eq!(parse("[1,]"), Err(ParseError(3)));
+        assert_eq!(parse("{\"a\" 1}"), Err(ParseError(5)));
+        assert_eq!(parse("\"open"), Err(ParseError(5)));
+        assert_eq!(parse("1 2"), Err(ParseError(2)));
+        assert!(parse(&"[".repeat(100)).is_err());
+    }
+
+    #[test]
+    fn serialize_test() {
+        let value = object(vec![
+            ("name", Json::from("a \"quoted\"\nline")),
+            ("size", Json::from(16384u64)),
+            ("ratio", Json::from(0.5)),
+            ("eta", Json::from(None::<u64>)),
+            ("files", Json::from(vec!["a", "b"])),
+        ]);
+        let text = value.to_string();
+        assert_eq!(text, r#"{"eta":null,"files":["a","b"],"name":"a \"quoted\"\nline","ratio":0.5,"size":16384}"#);
+        assert_eq!(parse(&text), Ok(value));
+        assert_eq!(Json::Number(f64::INFINITY).to_string(), "null");
+    }
+}
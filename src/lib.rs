@@ -0,0 +1,91 @@
+//! A BitTorrent client library. A `Session` downloads any number of torrents, added from
+//! metainfo files or magnet links, and reports on them through progress queries and an event
+//! stream.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use bittorrent::{Event, Session, SessionConfig};
+//!
+//! # fn main() -> Result<(), bittorrent::Error> {
+//! let mut session = Session::new(SessionConfig::default());
+//! let events = session.subscribe();
+//! session.start()?;
+//! let info_hash = session.add_torrent_file("flag.torrent", vec![])?;
+//!
+//! for event in events.iter() {
+//!     if event == Event::TorrentFinished(info_hash.clone()) {
+//!         break;
+//!     }
+//! }
+//! println!("{:?}", session.status(&info_hash));
+//! session.shutdown()?;
+//! # Ok(())
+//! # }
+//! ```
+
+extern crate hyper;
+extern crate urlencoding;
+extern crate url;
+extern crate byteorder;
+extern crate rand;
+extern crate mio;
+extern crate num_bigint;
+extern crate native_tls;
+#[macro_use]
+extern crate log;
+
+pub mod bandwidth;
+mod bencode;
+mod choker;
+pub mod create;
+pub mod error;
+pub mod event;
+mod https;
+pub mod magnet;
+pub mod metainfo;
+pub mod tracker;
+mod tracker_response;
+pub mod hash;
+pub mod util;
+mod peer;
+mod block;
+mod piece;
+pub mod torrent;
+pub mod transmission;
+mod connection;
+mod message;
+mod ipc;
+mod json;
+mod listener;
+pub mod logging;
+mod manager;
+mod merkle;
+mod mse;
+pub mod reader;
+pub mod resume;
+pub mod rpc;
+pub mod session;
+pub mod storage;
+pub mod streaming;
+pub mod stats;
+mod stream;
+mod utp;
+pub mod verify;
+mod webseed;
+
+pub use bandwidth::Limits;
+pub use error::Error;
+pub use event::Event;
+pub use magnet::Magnet;
+pub use manager::PeerSource;
+pub use metainfo::MetaInfo;
+pub use mse::EncryptionPolicy;
+pub use peer::Peer;
+pub use piece::Priority;
+pub use reader::FileReader;
+pub use session::{Session, SessionConfig, TorrentStatus};
+pub use stats::Stats;
+pub use storage::{FileStorage, MemoryStorage, Storage};
+pub use stream::TransportKind;
+pub use torrent::{PeerStatus, PickMode};
@@ -1,39 +1,143 @@
 use std::net::TcpListener;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use bandwidth::Throttle;
 use util;
+use error::Error;
+use event::Event;
 
-use connection::Connection;
+use connection::{self, Connection};
 use torrent::Torrent;
 use peer::Peer;
-use message::Message;
+use manager::ConnectionManager;
+use mse;
+use mse::EncryptionPolicy;
+use session::{self, Torrents};
+use stream::{PeerStream, Transport};
+use utp::UtpListener;
 
-pub fn start(host: &str, port: u16, client_mutex: Arc<Mutex<Peer>>, torrent_mutex: Arc<Mutex<Torrent>>) {
-	let listener = TcpListener::bind((host, port)).unwrap();
+/// Accepts incoming peer connections over both TCP and uTP on the given port, and hands each to
+/// the torrent its handshake asks for. Their transfers stay within the throttle's limits. Returns
+/// the address the TCP listener is bound to
+pub fn start(host: &str, port: u16, torrents: Torrents, manager_mutex: Arc<Mutex<ConnectionManager>>,
+			 policy: EncryptionPolicy, throttle: Throttle) -> Result<SocketAddr, Error> {
+	let listener = TcpListener::bind((host, port))?;
+	let local_addr = listener.local_addr()?;
+	// uTP takes the same port, which the TCP listener picked if any port was asked for
+	let utp_listener = UtpListener::bind((host, local_addr.port()))?;
+	info!("Listening on {} over TCP and {} over uTP", local_addr, utp_listener.local_addr());
+	let context = Arc::new(Context {
+		torrents,
+		manager_mutex,
+		port: local_addr.port(),
+		policy,
+		throttle,
+	});
+
+	let tcp_context = context.clone();
 	thread::spawn(move || {
 		for stream in listener.incoming() {
 			match stream {
 				Ok(s) => {
-					let peer_addr = s.peer_addr().expect("Could not retrieve peer address");
-					let ip = peer_addr.ip();
-
-					match ip {
-						IpAddr::V4(ipv4_addr) => {
-							let mut bytes = vec![];
-							bytes.extend(ipv4_addr.octets().iter());
-							bytes.extend(util::u32_to_bytes(peer_addr.port() as u32));
-							let peer = Peer::from_bytes(bytes.as_slice());
-							let mut c = Connection::new(client_mutex.clone(), peer, s, torrent_mutex.clone());
-							let _ = c.send_message(Message::Choke);
-
-						}
-						_ => println!("Unsupported IP format") 
+					if let Ok(peer_addr) = s.peer_addr() {
+						tcp_context.clone().handle(Transport::Tcp(s), peer_addr);
 					}
-				}	
-				Err(e) => println!("{:?}", e)
+				}
+				Err(e) => warn!("Accepting a TCP connection failed: {}", e)
+			}
+		}
+	});
+
+	thread::spawn(move || {
+		loop {
+			match utp_listener.accept() {
+				Ok(s) => {
+					let peer_addr = s.peer_addr();
+					context.clone().handle(Transport::Utp(s), peer_addr);
+				}
+				Err(e) => {
+					warn!("Accepting a uTP connection failed: {}", e);
+					break;
+				}
 			}
 		}
 	});
+	Ok(local_addr)
+}
+
+// an incoming stream along with the info hash the session knows its torrent by, the info hash
+// its handshake asked for, which differs for the v2 hash of a hybrid torrent, and the torrent
+type Routed = (PeerStream, Vec<u8>, Vec<u8>, Arc<Mutex<Torrent>>);
+
+struct Context {
+	torrents: Torrents,
+	manager_mutex: Arc<Mutex<ConnectionManager>>,
+	// the port we listen on, which identifies us to peers
+	port: u16,
+	policy: EncryptionPolicy,
+	throttle: Throttle,
 }
 
+impl Context {
+	/// Answers the handshake of an incoming connection on its own thread, if it asks for one of
+	/// the session's torrents and the connection manager has a free slot for it, and then serves
+	/// the peer until it disconnects
+	fn handle(self: Arc<Self>, stream: Transport, peer_addr: SocketAddr) {
+		let ip = peer_addr.ip();
+
+		match ip {
+			IpAddr::V4(ipv4_addr) => {
+				let mut bytes = vec![];
+				bytes.extend(ipv4_addr.octets().iter());
+				bytes.extend(util::u32_to_bytes(peer_addr.port() as u32)[2..].iter());
+				let peer = Peer::from_bytes(bytes.as_slice());
+				let addr = SocketAddr::new(peer.ip, peer.port);
+
+				thread::spawn(move || {
+					let (stream, info_hash, asked_for, torrent_mutex) = match self.route(stream) {
+						Ok(routed) => routed,
+						Err(e) => {
+							debug!(peer:% = addr; "Rejected incoming connection: {}", e);
+							return;
+						}
+					};
+					if !self.manager_mutex.lock().unwrap().accept_incoming(&info_hash, peer.clone()) {
+						debug!(info_hash = util::to_hex(&info_hash), peer:% = addr; "Connection limit reached, dropping");
+						return;
+					}
+
+					let client_mutex = Arc::new(Mutex::new(Peer::local(self.port)));
+					let encrypted = stream.is_encrypted();
+					torrent_mutex.lock().unwrap().emit(Event::PeerConnected { info_hash: info_hash.clone(), addr, encrypted });
+					let mut c = Connection::new(client_mutex, peer, stream, torrent_mutex.clone(), self.throttle.clone());
+					let result = c.answer_handshake(&asked_for).and_then(|_| c.run());
+					let error = result.as_ref().err().map(|e| e.to_string());
+					torrent_mutex.lock().unwrap().emit(Event::PeerDisconnected { info_hash: info_hash.clone(), addr, error });
+					self.manager_mutex.lock().unwrap().connection_closed(&info_hash, &addr, result.is_err());
+				});
+			}
+			_ => debug!(peer:% = peer_addr; "Dropping connection over IPv6, which isn't supported")
+		}
+	}
+
+	/// Answers the encryption handshake, if any, and reads the peer's handshake to find the
+	/// torrent it asks for. Returns the stream along with the torrent, the info hash the session
+	/// knows it by and the one the peer asked for
+	fn route(&self, stream: Transport) -> Result<Routed, Error> {
+		let info_hashes = session::all_info_hashes(&self.torrents);
+		let (mut stream, skey) = mse::accept(stream, &info_hashes, self.policy)?;
+		let info_hash = connection::read_handshake(&mut stream)?;
+		// an encrypted peer proved it knows the torrent it named in the encryption handshake
+		if skey.is_some_and(|skey| skey != info_hash) {
+			return Err(Error::Protocol(String::from("Handshake for a different torrent than the encryption handshake")))
+		}
+		match session::find_torrent(&self.torrents, &info_hash) {
+			Some((_, ref torrent_mutex)) if torrent_mutex.lock().unwrap().is_stopped() => {
+				Err(Error::Protocol(String::from("Handshake for a paused torrent")))
+			},
+			Some((key, torrent_mutex)) => Ok((stream, key, info_hash, torrent_mutex)),
+			None => Err(Error::Protocol(String::from("Handshake for an unknown torrent")))
+		}
+	}
+}
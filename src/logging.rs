@@ -0,0 +1,177 @@
+use log::{self, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use log::kv::{self, Key, Value, VisitSource};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+use util;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+// whether LOGGER is the global logger, which the level settings only apply to
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Writes log records to stderr along with the context they carry: the `info_hash` of the
+/// torrent, the address of the `peer` and the type of `message`. Peers and torrents can be given
+/// levels of their own, so that a single peer can be traced without the noise of all the others
+#[derive(Debug)]
+pub struct Logger {
+    levels: RwLock<Levels>,
+}
+
+#[derive(Debug, Clone)]
+struct Levels {
+    default: LevelFilter,
+    // keyed by the peer's address and the torrent's info hash as they appear in records
+    peers: HashMap<String, LevelFilter>,
+    torrents: HashMap<String, LevelFilter>,
+}
+
+impl Levels {
+    fn new(default: LevelFilter) -> Self {
+        Levels {
+            default,
+            peers: HashMap::new(),
+            torrents: HashMap::new(),
+        }
+    }
+
+    /// Returns the most verbose level of all, which records have to pass to reach the logger
+    fn max(&self) -> LevelFilter {
+        self.peers.values().chain(self.torrents.values()).fold(self.default, |max, &level| max.max(level))
+    }
+
+    /// Returns whether a record of the given level about the given peer and torrent is written.
+    /// The level of a peer takes precedence over the level of its torrent
+    fn allows(&self, level: Level, peer: Option<&str>, info_hash: Option<&str>) -> bool {
+        let filter = peer.and_then(|p| self.peers.get(p))
+            .or_else(|| info_hash.and_then(|h| self.torrents.get(h)))
+            .cloned()
+            .unwrap_or(self.default);
+        level <= filter
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.read().unwrap().max()
+    }
+
+    fn log(&self, record: &Record) {
+        let context = record.key_values();
+        let peer = context.get(Key::from_str("peer")).map(|v| v.to_string());
+        let info_hash = context.get(Key::from_str("info_hash")).map(|v| v.to_string());
+        if self.levels.read().unwrap().allows(record.level(), peer.as_deref(), info_hash.as_deref()) {
+            let _ = writeln!(io::stderr(), "{}", format(record));
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// Formats a record as its level, module and text followed by its context
+fn format(record: &Record) -> String {
+    let mut line = format!("{:<5} {}: {}", record.level(), record.target(), record.args());
+    let _ = record.key_values().visit(&mut Context(&mut line));
+    line
+}
+
+struct Context<'a>(&'a mut String);
+
+impl<'a, 'kvs> VisitSource<'kvs> for Context<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push_str(&format!(" {}={}", key, value));
+        Ok(())
+    }
+}
+
+/// Installs the logger as the global logger, writing records up to the given level. Fails if
+/// another logger was installed first
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(LOGGER.get_or_init(|| Logger { levels: RwLock::new(Levels::new(level)) }))?;
+    INSTALLED.store(true, Ordering::SeqCst);
+    set_level(level);
+    Ok(())
+}
+
+fn update<F: FnOnce(&mut Levels)>(f: F) {
+    if let (Some(logger), true) = (LOGGER.get(), INSTALLED.load(Ordering::SeqCst)) {
+        let mut levels = logger.levels.write().unwrap();
+        f(&mut levels);
+        log::set_max_level(levels.max());
+    }
+}
+
+/// Returns the level of records which no peer or torrent level applies to, or `Off` if the
+/// logger isn't installed
+pub fn level() -> LevelFilter {
+    match (LOGGER.get(), INSTALLED.load(Ordering::SeqCst)) {
+        (Some(logger), true) => logger.levels.read().unwrap().default,
+        _ => LevelFilter::Off
+    }
+}
+
+/// Changes the level of records which no peer or torrent level applies to
+pub fn set_level(level: LevelFilter) {
+    update(|levels| levels.default = level);
+}
+
+/// Sets the level of records about the given peer, such as `Trace` to follow every message of a
+/// misbehaving peer. `None` makes the peer's records follow the other levels again
+pub fn set_peer_level(addr: &SocketAddr, level: Option<LevelFilter>) {
+    let addr = addr.to_string();
+    update(|levels| match level {
+        Some(level) => { levels.peers.insert(addr, level); },
+        None => { levels.peers.remove(&addr); }
+    });
+}
+
+/// Sets the level of records about the torrent with the given info hash. `None` makes them
+/// follow the default level again
+pub fn set_torrent_level(info_hash: &[u8], level: Option<LevelFilter>) {
+    let info_hash = util::to_hex(info_hash);
+    update(|levels| match level {
+        Some(level) => { levels.torrents.insert(info_hash, level); },
+        None => { levels.torrents.remove(&info_hash); }
+    });
+}
+
+#[cfg(test)]
+mod logging_tests {
+    use super::{format, Levels};
+    use log::{Level, LevelFilter, Record};
+
+    #[test]
+    fn levels_test() {
+        let mut levels = Levels::new(LevelFilter::Warn);
+        assert!(levels.allows(Level::Warn, None, None));
+        assert!(!levels.allows(Level::Info, Some("10.0.0.1:6881"), Some("ab")));
+        assert_eq!(levels.max(), LevelFilter::Warn);
+
+        levels.torrents.insert(String::from("ab"), LevelFilter::Debug);
+        levels.peers.insert(String::from("10.0.0.1:6881"), LevelFilter::Trace);
+        assert_eq!(levels.max(), LevelFilter::Trace);
+        assert!(levels.allows(Level::Trace, Some("10.0.0.1:6881"), Some("ab")));
+        assert!(levels.allows(Level::Debug, Some("10.0.0.2:6881"), Some("ab")));
+        assert!(!levels.allows(Level::Trace, Some("10.0.0.2:6881"), Some("ab")));
+        assert!(!levels.allows(Level::Debug, Some("10.0.0.2:6881"), Some("cd")));
+
+        // a quiet peer stays quiet even in a verbose torrent
+        levels.peers.insert(String::from("10.0.0.3:6881"), LevelFilter::Error);
+        assert!(!levels.allows(Level::Warn, Some("10.0.0.3:6881"), Some("ab")));
+    }
+
+    #[test]
+    fn format_test() {
+        let context = [("info_hash", "ab"), ("peer", "10.0.0.1:6881"), ("message", "piece")];
+        let line = format(&Record::builder()
+            .args(format_args!("Received {}", 7))
+            .level(Level::Trace)
+            .target("bittorrent::connection")
+            .key_values(&context)
+            .build());
+        assert_eq!(line, "TRACE bittorrent::connection: Received 7 info_hash=ab peer=10.0.0.1:6881 message=piece");
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use url::Url;
+use bencode::{self, Bencode, ToBencode};
+use connection::{self, EXTENSION_FLAG, PROTOCOL};
+use error::Error;
+use hash;
+use message::Message;
+use metainfo::{self, MetaInfo};
+use mse::{self, EncryptionPolicy};
+use peer::Peer;
+use stream::{PeerStream, TransportKind};
+use util::{self, bytes_to_u32};
+
+// the info dictionary is sent in pieces of this size (BEP 9)
+const METADATA_PIECE_SIZE: usize = 16384;
+// larger metadata is refused so that a peer can't make us allocate without bound
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+// a bitfield or a metadata piece along with its header always fits
+const MAX_MESSAGE_LENGTH: u32 = 1 << 20;
+// the extended message id we ask peers to send metadata messages with
+const UT_METADATA_ID: u8 = 1;
+const READ_TIMEOUT: u64 = 30;
+
+/// A magnet link, which names a torrent by its info hash so that its metadata can be fetched from
+/// peers instead of a `.torrent` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Magnet {
+    pub info_hash: Vec<u8>,
+    // the display name, which stands in for the torrent's name until its metadata arrives
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    pub web_seeds: Vec<String>,
+    // peers to fetch the metadata from, given by `x.pe`
+    pub peers: Vec<Peer>,
+}
+
+impl Magnet {
+    /// Parses a `magnet:?xt=urn:btih:...` link, whose info hash is either 40 hex digits or 32
+    /// base32 characters
+    pub fn parse(uri: &str) -> Result<Magnet, Error> {
+        let url = Url::parse(uri).map_err(|e| Error::invalid("magnet", &e.to_string()))?;
+        if url.scheme() != "magnet" {
+            return Err(Error::invalid("magnet", "not a magnet link"))
+        }
+
+        let mut magnet = Magnet {
+            info_hash: vec![],
+            name: None,
+            trackers: vec![],
+            web_seeds: vec![],
+            peers: vec![],
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => if let Some(hash) = value.strip_prefix("urn:btih:") {
+                    magnet.info_hash = parse_info_hash(hash)?;
+                },
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                "ws" => magnet.web_seeds.push(value.into_owned()),
+                "x.pe" => match value.parse::<SocketAddr>() {
+                    Ok(addr) => magnet.peers.push(Peer::from_addr(addr)),
+                    Err(_) => return Err(Error::invalid("x.pe", "not an address and port"))
+                },
+                _ => {}
+            }
+        }
+
+        if magnet.info_hash.is_empty() {
+            return Err(Error::missing("xt"))
+        }
+        Ok(magnet)
+    }
+}
+
+fn parse_info_hash(hash: &str) -> Result<Vec<u8>, Error> {
+    let decoded = match hash.len() {
+        40 => util::from_hex(hash),
+        32 => base32_decode(hash),
+        _ => None
+    };
+    decoded.ok_or_else(|| Error::invalid("xt", "not a hex or base32 info hash"))
+}
+
+/// Decodes unpadded base32 (RFC 4648), ignoring case
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// Fetches the info dictionary of the magnet link's torrent from the first of the given peers
+/// which sends it, and builds the torrent's metainfo from it and the link's trackers
+pub fn fetch_metadata(magnet: &Magnet, peers: &[Peer], peer_id: &str, transport: TransportKind,
+                      policy: EncryptionPolicy) -> Result<MetaInfo, Error> {
+    for peer in peers {
+        let addr = SocketAddr::new(peer.ip, peer.port);
+        match fetch_info(&addr, &magnet.info_hash, peer_id, transport, policy) {
+            Ok(info) => return metainfo::from_info(&info, &magnet.trackers, &magnet.web_seeds),
+            Err(e) => debug!(info_hash = util::to_hex(&magnet.info_hash), peer:% = addr; "Fetching metadata failed: {}", e)
+        }
+    }
+    Err(Error::Protocol(String::from("No peer sent the torrent's metadata")))
+}
+
+fn fetch_info(addr: &SocketAddr, info_hash: &[u8], peer_id: &str, transport: TransportKind,
+              policy: EncryptionPolicy) -> Result<Vec<u8>, Error> {
+    let mut stream = mse::connect(addr, transport, info_hash, policy)?;
+    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT)))?;
+    request_info(&mut stream, info_hash, peer_id)
+}
+
+/// Exchanges handshakes with a peer and downloads the info dictionary from it piece by piece.
+/// Returns the dictionary once its hash matches the info hash
+fn request_info(stream: &mut PeerStream, info_hash: &[u8], peer_id: &str) -> Result<Vec<u8>, Error> {
+    let mut handshake = vec![PROTOCOL.len() as u8];
+    handshake.extend(PROTOCOL.bytes());
+    let mut reserved = [0; 8];
+    reserved[5] |= EXTENSION_FLAG;
+    handshake.extend(&reserved);
+    handshake.extend(info_hash);
+    handshake.extend(peer_id.bytes());
+    stream.write_all(&handshake)?;
+
+    let (reserved, peer_hash) = connection::read_handshake_with_reserved(stream)?;
+    if peer_hash != info_hash {
+        return Err(Error::Protocol(String::from("Handshake for a different torrent")))
+    }
+    if reserved[5] & EXTENSION_FLAG == 0 {
+        return Err(Error::Protocol(String::from("Peer doesn't support extension messages")))
+    }
+
+    let mut m = BTreeMap::new();
+    m.insert(b"ut_metadata".to_vec(), (UT_METADATA_ID as u32).to_bencode());
+    let mut extensions = BTreeMap::new();
+    extensions.insert(b"m".to_vec(), Bencode::Dict(m));
+    stream.write_all(&Message::Extended(0, Bencode::Dict(extensions).encode()).serialize())?;
+
+    let (metadata_id, size) = loop {
+        let (id, payload) = read_extended(stream)?;
+        if id == 0 {
+            break parse_extension_handshake(&payload)?
+        }
+    };
+
+    let num_pieces = size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..num_pieces {
+        let request = metadata_message(0, piece).encode();
+        stream.write_all(&Message::Extended(metadata_id, request).serialize())?;
+    }
+
+    let mut info = vec![0; size];
+    let mut received = vec![false; num_pieces];
+    while received.contains(&false) {
+        let (id, payload) = read_extended(stream)?;
+        if id != UT_METADATA_ID {
+            continue;
+        }
+        let (header, start) = bencode::decode_prefix(&payload)?;
+        let header = header.as_dict().ok_or_else(|| Error::invalid("ut_metadata", "not a dictionary"))?;
+        let piece = header.get_integer("piece").filter(|&p| p >= 0 && (p as usize) < num_pieces)
+            .ok_or_else(|| Error::invalid("ut_metadata.piece", "not a piece of the metadata"))? as usize;
+        match header.get_integer("msg_type") {
+            Some(1) => {
+                let offset = piece * METADATA_PIECE_SIZE;
+                let length = METADATA_PIECE_SIZE.min(size - offset);
+                if payload.len() - start != length {
+                    return Err(Error::Protocol(format!("Metadata piece {} has the wrong length", piece)))
+                }
+                info[offset..offset + length].copy_from_slice(&payload[start..]);
+                received[piece] = true;
+            },
+            Some(2) => return Err(Error::Protocol(format!("Peer rejected the request for metadata piece {}", piece))),
+            _ => {}
+        }
+    }
+
+    if hash::sha(&info) != info_hash {
+        return Err(Error::Protocol(String::from("Metadata doesn't match the info hash")))
+    }
+    Ok(info)
+}
+
+/// Builds the header of a metadata message: 0 requests a piece, 1 carries one and 2 rejects a
+/// request
+fn metadata_message(msg_type: u32, piece: usize) -> Bencode {
+    let mut m = BTreeMap::new();
+    m.insert(b"msg_type".to_vec(), msg_type.to_bencode());
+    m.insert(b"piece".to_vec(), (piece as u64).to_bencode());
+    Bencode::Dict(m)
+}
+
+/// Returns the id the peer wants metadata messages sent with and the size of the metadata from
+/// its extension handshake
+fn parse_extension_handshake(payload: &[u8]) -> Result<(u8, usize), Error> {
+    let value = bencode::decode(payload)?;
+    let handshake = value.as_dict().ok_or_else(|| Error::invalid("extension handshake", "not a dictionary"))?;
+    let id = handshake.get_dict("m").and_then(|m| m.get_integer("ut_metadata"))
+        .filter(|&id| id > 0 && id < 256)
+        .ok_or_else(|| Error::Protocol(String::from("Peer doesn't support metadata exchange")))?;
+    let size = handshake.get_integer("metadata_size")
+        .filter(|&s| s > 0 && s as usize <= MAX_METADATA_SIZE)
+        .ok_or_else(|| Error::invalid("metadata_size", "missing or too large"))?;
+    Ok((id as u8, size as usize))
+}
+
+/// Reads messages until an extension message arrives, and returns its extended id and payload.
+/// Other messages are of no use before the metadata is known
+fn read_extended(stream: &mut PeerStream) -> Result<(u8, Vec<u8>), Error> {
+    loop {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length)?;
+        let length = bytes_to_u32(&length);
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(Error::Protocol(format!("Message of {} bytes is too long", length)))
+        }
+        if length == 0 {
+            continue;
+        }
+
+        let mut body = vec![0; length as usize];
+        stream.read_exact(&mut body)?;
+        if body[0] == 20 {
+            if let Message::Extended(id, payload) = Message::new(&body[0], &body[1..])? {
+                return Ok((id, payload))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod magnet_tests {
+    use super::{fetch_metadata, metadata_message, Magnet};
+    use bencode::{Bencode, ToBencode};
+    use hash;
+    use mse::EncryptionPolicy;
+    use peer::Peer;
+    use stream::TransportKind;
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    #[test]
+    fn parse_test() {
+        let m = Magnet::parse("magnet:?xt=urn:btih:0123456789abcdef0123456789ABCDEF01234567&dn=a%20file\
+                               &tr=http%3A%2F%2Ftracker%2Fannounce&ws=http%3A%2F%2Fseed%2F&x.pe=127.0.0.1%3A6881").unwrap();
+        assert_eq!(m.info_hash, vec![0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23,
+                                     0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67]);
+        assert_eq!(m.name, Some(String::from("a file")));
+        assert_eq!(m.trackers, vec![String::from("http://tracker/announce")]);
+        assert_eq!(m.web_seeds, vec![String::from("http://seed/")]);
+        assert_eq!(m.peers, vec![Peer::from_bytes(&[127, 0, 0, 1, 0x1a, 0xe1])]);
+
+        let m = Magnet::parse("magnet:?xt=urn:btih:AEBAGBAFAYDQQCIKBMGA2DQPCAIREEYU").unwrap();
+        assert_eq!(m.info_hash, (1..21).collect::<Vec<u8>>());
+
+        assert!(Magnet::parse("http://example.com/").is_err());
+        assert!(Magnet::parse("magnet:?dn=name").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:0123").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef0123456g").is_err());
+    }
+
+    /// Answers a metadata fetch as a peer which has the given info dictionary
+    fn serve_metadata(listener: TcpListener, info: Vec<u8>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut handshake = vec![0; 68];
+        stream.read_exact(&mut handshake).unwrap();
+        assert_eq!(handshake[25] & 0x10, 0x10);
+        stream.write_all(&handshake).unwrap();
+
+        let mut ut_metadata = BTreeMap::new();
+        ut_metadata.insert(b"ut_metadata".to_vec(), 3u32.to_bencode());
+        let mut extensions = BTreeMap::new();
+        extensions.insert(b"m".to_vec(), Bencode::Dict(ut_metadata));
+        extensions.insert(b"metadata_size".to_vec(), (info.len() as u64).to_bencode());
+        let mut message = vec![20, 0];
+        message.extend(Bencode::Dict(extensions).encode());
+
+        // a bitfield before the extension handshake is skipped
+        stream.write_all(&[0, 0, 0, 2, 5, 0]).unwrap();
+        stream.write_all(&(message.len() as u32).to_be_bytes()).unwrap();
+        stream.write_all(&message).unwrap();
+
+        loop {
+            let mut length = [0; 4];
+            if stream.read_exact(&mut length).is_err() {
+                return;
+            }
+            let mut body = vec![0; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut body).unwrap();
+            if body[..2] != [20, 3] {
+                continue;
+            }
+            let piece = if body.ends_with(b"5:piecei0ee") { 0 } else { 1 };
+            let mut message = vec![20, 1];
+            message.extend(metadata_message(1, piece).encode());
+            message.extend(&info[piece * 16384..info.len().min((piece + 1) * 16384)]);
+            stream.write_all(&(message.len() as u32).to_be_bytes()).unwrap();
+            stream.write_all(&message).unwrap();
+        }
+    }
+
+    #[test]
+    fn fetch_metadata_test() {
+        let mut info = BTreeMap::new();
+        info.insert(b"length".to_vec(), Bencode::Integer(20000));
+        info.insert(b"name".to_vec(), "big".to_bencode());
+        info.insert(b"piece length".to_vec(), Bencode::Integer(16));
+        info.insert(b"pieces".to_vec(), Bencode::Bytes(vec![7; 20 * 1250]));
+        let info = Bencode::Dict(info).encode();
+        let info_hash = hash::sha(&info);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let served = info.clone();
+        thread::spawn(move || serve_metadata(listener, served));
+
+        let magnet = Magnet {
+            info_hash: info_hash.clone(),
+            name: None,
+            trackers: vec![String::from("http://tracker/announce")],
+            web_seeds: vec![],
+            peers: vec![],
+        };
+        let m = fetch_metadata(&magnet, &[Peer::from_addr(addr)], "-AZ2060-000000000000",
+                               TransportKind::Tcp, EncryptionPolicy::Disabled).unwrap();
+        assert_eq!(m.info_hash, info_hash);
+        assert_eq!(m.info.name, "big");
+        assert_eq!(m.info.num_pieces, 1250);
+        assert_eq!(m.announce.unwrap(), "http://tracker/announce");
+
+        // a peer which doesn't answer leaves nothing to fetch from
+        let magnet = Magnet { info_hash: vec![1; 20], ..magnet };
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        assert!(fetch_metadata(&magnet, &[Peer::from_addr(closed)], "-AZ2060-000000000000",
+                               TransportKind::Tcp, EncryptionPolicy::Disabled).is_err());
+    }
+}
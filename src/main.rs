@@ -1,52 +1,305 @@
-extern crate bencode;
-extern crate hyper;
-extern crate regex;
-extern crate urlencoding;
-extern crate url;
-extern crate byteorder;
-extern crate rand;
-extern crate mio;
-
-use std::{env, thread};
-use std::sync::{Arc, Mutex};
-use std::thread::JoinHandle;
-
-mod metainfo;
-mod tracker;
-mod tracker_response;
-mod hash;
-mod util;
-mod peer;
-mod block;
-mod piece;
-mod torrent;
-mod connection;
-mod message;
-mod ipc;
-mod listener;
+extern crate bittorrent;
+extern crate log;
+
+use std::{env, fs, process, thread};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bittorrent::{create, logging, metainfo, resume, rpc, streaming, verify};
+use log::LevelFilter;
+use bittorrent::{EncryptionPolicy, Error, Event, PickMode, Priority, Session, SessionConfig, TransportKind};
+
+const ENCRYPTION_POLICY: EncryptionPolicy = EncryptionPolicy::Enabled;
+// the environment variable holding the daemon's token, unless it's read from a file
+const TOKEN_VAR: &str = "BITTORRENT_TOKEN";
 
 pub fn main() {
     let args: Vec<String> = env::args().collect();
-    let filename = &args[1];
-    let m = metainfo::from_file(filename).unwrap();
-    let peer_id: String = util::create_peer_id();
-
-    let peers = tracker::retrieve_peers(&m, &peer_id, "8080").unwrap();
-    let torrent = torrent::Torrent::new(peer_id, m);
-    let torrent_mutex = Arc::new(Mutex::new(torrent));
-    let client_mutex = Arc::new(Mutex::new(peer::Peer::from_bytes(&[127, 0, 0, 1, 31, 144])));
-
-    let _ = listener::start("0.0.0.0", 8080, client_mutex.clone(), torrent_mutex.clone());
-
-    let threads: Vec<JoinHandle<()>> = peers.into_iter().map(|peer| {
-        let torrent_mutex = torrent_mutex.clone();
-        let client_mutex = client_mutex.clone();
-        thread::spawn(move || {
-            let _ = connection::Connection::connect(client_mutex, peer, torrent_mutex);
-        })
-    }).collect();
-
-    for t in threads {
-        t.join().unwrap();
+    let result = match args.get(1).map(|a| a.as_str()) {
+        Some("verify") => verify(&args[2..]),
+        Some("create") => create(&args[2..]),
+        Some("daemon") => daemon(&args[2..]),
+        _ => download(&args[1..])
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Reports invalid command line arguments and exits
+fn usage(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
+
+/// Creates a `.torrent` file from a file or directory.
+/// Usage: `create <path> [-o <out.torrent>] [--announce URL]... [--web-seed URL]...
+/// [--piece-length N] [--comment TEXT] [--private]`
+fn create(args: &[String]) -> Result<(), Error> {
+    let mut path = None;
+    let mut out = None;
+    let mut builder_args = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--private" => builder_args.push((args[i].as_str(), "")),
+            "-o" | "--announce" | "--web-seed" | "--piece-length" | "--comment" => {
+                let value = args.get(i + 1).unwrap_or_else(|| usage(&format!("Missing value for {}", args[i])));
+                if args[i] == "-o" {
+                    out = Some(value.clone());
+                } else {
+                    builder_args.push((args[i].as_str(), value.as_str()));
+                }
+                i += 1;
+            },
+            _ => path = Some(args[i].clone())
+        }
+        i += 1;
+    }
+
+    let path = path.unwrap_or_else(|| usage("No file or directory given"));
+    let mut builder = create::TorrentBuilder::new(&path);
+    for (option, value) in builder_args {
+        builder = match option {
+            "--private" => builder.private(true),
+            "--announce" => builder.announce(value),
+            "--web-seed" => builder.web_seed(value),
+            "--comment" => builder.comment(value),
+            _ => builder.piece_length(value.parse().unwrap_or_else(|_| usage("Invalid piece length")))
+        };
+    }
+
+    let out = match out {
+        Some(out) => out,
+        None => {
+            let name = Path::new(&path).file_name().unwrap_or_else(|| usage("Path has no file name"));
+            format!("{}.torrent", name.to_string_lossy())
+        }
+    };
+    builder.write_to(Path::new(&out))?;
+    println!("Created {}", out);
+    Ok(())
+}
+
+/// Hashes the data of the given torrent in the current directory without connecting to any peers.
+/// Usage: `verify <torrent> [--threads N] [--resume]`. Exits with a nonzero status if any piece
+/// doesn't match
+fn verify(args: &[String]) -> Result<(), Error> {
+    let threads_index = args.iter().position(|a| a == "--threads");
+    let threads = threads_index.and_then(|i| args.get(i + 1))
+        .map_or(4, |n| n.parse::<usize>().unwrap_or_else(|_| usage("Invalid number of threads")));
+    let filename = args.iter().enumerate()
+        .find(|&(i, a)| !a.starts_with("--") && threads_index.is_none_or(|t| i != t + 1))
+        .map(|(_, a)| a)
+        .unwrap_or_else(|| usage("No torrent file given"));
+    let m = metainfo::from_file(filename)?;
+    let dir = Path::new("");
+
+    let report = verify::verify(&m, dir, threads)?;
+    for file in report.files.iter() {
+        println!("{:?}\t{}/{} pieces\t{}", file.status, file.valid_pieces, file.total_pieces, file.path.display());
+    }
+    println!("{} of {} pieces valid", report.num_valid(), report.pieces.len());
+
+    if args.iter().any(|a| a == "--resume") {
+        report.resume_data(&m, dir).save(&resume::resume_path(dir, &m.info.name))?;
+    }
+    if !report.is_complete() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Runs the client until it is killed, with torrents added and controlled over JSON-RPC. Usage:
+/// `daemon [--token-file PATH] [--rpc-port PORT | --rpc-socket PATH] [--port PORT] [--utp]
+/// [--download-rate BYTES] [--upload-rate BYTES] [--count-overhead] [--log-level LEVEL]`. Calls
+/// are POSTed to `http://127.0.0.1:PORT/`, port 9091 by default, or sent a line at a time to the
+/// Unix socket, and have to carry the token. The token is the first line of the given file, or
+/// else the value of `BITTORRENT_TOKEN`, so that it doesn't show in the process list. Transmission
+/// clients such as `transmission-remote` connect to the HTTP port with the token as their
+/// password. Logs go to stderr at the given level, `info` by default
+fn daemon(args: &[String]) -> Result<(), Error> {
+    let value = |option: &str| args.iter().position(|a| a == option).map(|i| {
+        args.get(i + 1).unwrap_or_else(|| usage(&format!("Missing value for {}", option)))
+    });
+    let token = match value("--token-file") {
+        Some(path) => fs::read_to_string(path)?.lines().next().unwrap_or("").to_string(),
+        None => env::var(TOKEN_VAR).unwrap_or_default()
+    };
+    if token.is_empty() {
+        usage(&format!("No token given in --token-file or {}", TOKEN_VAR));
+    }
+    let rpc_port = value("--rpc-port").map_or(9091, |p| p.parse::<u16>().unwrap_or_else(|_| usage("Invalid port")));
+    let rpc_socket = value("--rpc-socket");
+    let port = value("--port").map_or(8080, |p| p.parse::<u16>().unwrap_or_else(|_| usage("Invalid port")));
+    let download_rate = value("--download-rate").map_or(0, |r| r.parse::<u64>().unwrap_or_else(|_| usage("Invalid rate")));
+    let upload_rate = value("--upload-rate").map_or(0, |r| r.parse::<u64>().unwrap_or_else(|_| usage("Invalid rate")));
+    let log_level = value("--log-level").map_or(LevelFilter::Info, |l| l.parse().unwrap_or_else(|_| usage("Invalid log level")));
+    let _ = logging::init(log_level);
+
+    let mut session = Session::new(SessionConfig {
+        port,
+        download_rate,
+        upload_rate,
+        count_overhead: args.iter().any(|a| a == "--count-overhead"),
+        transport: if args.iter().any(|a| a == "--utp") { TransportKind::Utp } else { TransportKind::Tcp },
+        policy: ENCRYPTION_POLICY,
+        ..SessionConfig::default()
+    });
+    let events = session.subscribe();
+    session.start()?;
+    let session = Arc::new(session);
+    let rpc = Arc::new(rpc::Rpc::new(session.clone(), &token));
+    match rpc_socket {
+        Some(path) => serve_unix(Path::new(path), rpc)?,
+        None => {
+            let addr = rpc::serve_http("127.0.0.1", rpc_port, rpc)?;
+            println!("Listening for JSON-RPC calls on http://{}/", addr);
+        }
+    }
+
+    loop {
+        if let Ok(event) = events.recv_timeout(Duration::from_secs(1)) {
+            report(&session, event);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn serve_unix(path: &Path, rpc: Arc<rpc::Rpc>) -> Result<(), Error> {
+    rpc::serve_unix(path, rpc)?;
+    println!("Listening for JSON-RPC calls on {}", path.display());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_: &Path, _: Arc<rpc::Rpc>) -> Result<(), Error> {
+    usage("Unix sockets aren't supported on this platform")
+}
+
+/// Downloads the given torrents, named by metainfo files or magnet links, into the current
+/// directory. Usage: `<torrent>... [--utp] [--sequential] [--priorities P,P,...] [--port PORT]
+/// [--download-rate BYTES] [--upload-rate BYTES] [--count-overhead] [--stream PORT]
+/// [--log-level LEVEL] [--trace-peer ADDR]`, where the priorities of each torrent's files, not
+/// counting padding files, are `skip`, `low`, `normal` or `high` and missing ones are normal.
+/// Rates are in bytes per second and count piece data only, unless `--count-overhead` is passed.
+/// With `--stream`, the files are served over HTTP on the given local port as they arrive.
+/// Logs go to stderr at the given level, `warn` by default, and everything about the peer given
+/// by `--trace-peer` is logged
+fn download(args: &[String]) -> Result<(), Error> {
+    // peers are connected to over TCP unless `--utp` is passed
+    let transport = if args.iter().any(|a| a == "--utp") { TransportKind::Utp } else { TransportKind::Tcp };
+    let value_options = ["--priorities", "--port", "--download-rate", "--upload-rate", "--stream", "--log-level", "--trace-peer"];
+    let value_indices: Vec<usize> = args.iter().enumerate()
+        .filter(|&(_, a)| value_options.contains(&a.as_str()))
+        .map(|(i, _)| i + 1)
+        .collect();
+    let value = |option: &str| args.iter().position(|a| a == option).map(|i| {
+        args.get(i + 1).unwrap_or_else(|| usage(&format!("Missing value for {}", option)))
+    });
+    let priorities: Vec<Priority> = match value("--priorities") {
+        Some(list) => list.split(',')
+            .map(|p| Priority::parse(p).unwrap_or_else(|| usage(&format!("Invalid priority {}", p))))
+            .collect(),
+        None => vec![]
+    };
+    let port = value("--port").map_or(8080, |p| p.parse::<u16>().unwrap_or_else(|_| usage("Invalid port")));
+    let download_rate = value("--download-rate").map_or(0, |r| r.parse::<u64>().unwrap_or_else(|_| usage("Invalid rate")));
+    let upload_rate = value("--upload-rate").map_or(0, |r| r.parse::<u64>().unwrap_or_else(|_| usage("Invalid rate")));
+    let stream_port = value("--stream").map(|p| p.parse::<u16>().unwrap_or_else(|_| usage("Invalid port")));
+    let log_level = value("--log-level").map_or(LevelFilter::Warn, |l| l.parse().unwrap_or_else(|_| usage("Invalid log level")));
+    let traced_peer = value("--trace-peer").map(|a| a.parse::<SocketAddr>().unwrap_or_else(|_| usage("Invalid peer address")));
+    let filenames: Vec<&String> = args.iter().enumerate()
+        .filter(|&(i, a)| !a.starts_with("--") && !value_indices.contains(&i))
+        .map(|(_, a)| a)
+        .collect();
+    if filenames.is_empty() {
+        usage("No torrent file given");
+    }
+
+    if logging::init(log_level).is_ok() {
+        if let Some(addr) = traced_peer {
+            logging::set_peer_level(&addr, Some(LevelFilter::Trace));
+        }
+    }
+
+    let mut session = Session::new(SessionConfig {
+        port,
+        download_rate,
+        upload_rate,
+        count_overhead: args.iter().any(|a| a == "--count-overhead"),
+        transport,
+        policy: ENCRYPTION_POLICY,
+        ..SessionConfig::default()
+    });
+    let events = session.subscribe();
+    session.start()?;
+    for filename in filenames {
+        let info_hash = if filename.starts_with("magnet:") {
+            session.add_magnet(filename, priorities.clone())?
+        } else {
+            session.add_torrent_file(filename, priorities.clone())?
+        };
+        if args.iter().any(|a| a == "--sequential") {
+            if let Some(torrent_mutex) = session.torrent(&info_hash) {
+                torrent_mutex.lock().unwrap().set_pick_mode(PickMode::Sequential);
+            }
+        }
+    }
+    if let Some(port) = stream_port {
+        let addr = streaming::start("127.0.0.1", port, session.torrents())?;
+        println!("Streaming files on http://{}/", addr);
+    }
+
+    while !session.is_complete() {
+        if let Ok(event) = events.recv_timeout(Duration::from_secs(1)) {
+            report(&session, event);
+        }
+    }
+
+    // keep serving the finished files until the process is stopped
+    if stream_port.is_some() {
+        loop {
+            thread::sleep(Duration::from_secs(60));
+        }
+    }
+    session.shutdown()
+}
+
+/// Prints what happened to one of the session's torrents
+fn report(session: &Session, event: Event) {
+    let name = |info_hash: &[u8]| session.status(info_hash).map_or_else(String::new, |s| s.name);
+    match event {
+        Event::TorrentAdded(info_hash) => {
+            if let Some(status) = session.status(&info_hash) {
+                println!("Added {} with {} of {} pieces", status.name, status.pieces_complete, status.num_pieces);
+            }
+        },
+        Event::TorrentRemoved(_) => {},
+        Event::TorrentPaused(info_hash) => println!("Paused {}", name(&info_hash)),
+        Event::TorrentResumed(info_hash) => println!("Resumed {}", name(&info_hash)),
+        Event::TorrentFinished(info_hash) => println!("Finished {}", name(&info_hash)),
+        Event::PieceVerified { info_hash, piece } => {
+            if let Some(status) = session.status(&info_hash) {
+                let eta = status.eta().map_or_else(|| String::from("unknown"), |eta| format!("{}s", eta.as_secs()));
+                println!("{}: piece {} verified, {:.1}% done at {:.1} KiB/s, {} left", status.name, piece,
+                         status.progress() * 100.0, status.stats.download_rate / 1024.0, eta);
+            }
+        },
+        Event::PieceFailed { info_hash, piece } => println!("{}: piece {} failed its hash check", name(&info_hash), piece),
+        Event::PeerConnected { info_hash, addr, encrypted } => {
+            println!("{}: connected to {} (encrypted: {})", name(&info_hash), addr, encrypted)
+        },
+        Event::PeerDisconnected { info_hash, addr, error: Some(e) } => {
+            println!("{}: connection to {} broke: {}", name(&info_hash), addr, e)
+        },
+        Event::PeerDisconnected { .. } => {},
+        Event::TrackerReply { url, peers, .. } => println!("{} sent {} peers", url, peers),
+        Event::TrackerError { url, error, .. } => println!("Announce to {} failed: {}", url, error),
+        Event::StorageError { info_hash, error } => eprintln!("{}: storage error: {}", name(&info_hash), error),
     }
 }
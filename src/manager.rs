@@ -0,0 +1,322 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use peer::Peer;
+
+/// Delay before the first retry of an address whose connection failed; every further failure
+/// doubles it, up to `MAX_BACKOFF`
+const BASE_BACKOFF: u64 = 5;
+const MAX_BACKOFF: u64 = 30 * 60;
+// how long to wait before reconnecting to a peer which closed the connection cleanly
+const RECONNECT_DELAY: u64 = 60;
+
+/// Describes where the address of a candidate peer was learned from. The client has no DHT,
+/// peer exchange or local service discovery of its own yet, so peers from those sources only come
+/// from embedders which find them and pass them to `Session::add_peers`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerSource {
+    Tracker,
+    // given by the `x.pe` parameters of a magnet link
+    Magnet,
+    Dht,
+    Pex,
+    Lsd,
+    Incoming,
+    // remembered in the resume file from a previous session
+    Resume,
+}
+
+impl PeerSource {
+    /// Returns whether the source finds peers without the torrent's trackers, which private
+    /// torrents must not use
+    pub fn is_trackerless(&self) -> bool {
+        matches!(*self, PeerSource::Dht | PeerSource::Pex | PeerSource::Lsd)
+    }
+
+    /// Returns whether peers from the source are worth remembering for the next session. Incoming
+    /// peers connect from ports they may not listen on, and resumed peers are remembered already
+    pub fn is_remembered(&self) -> bool {
+        !matches!(*self, PeerSource::Incoming | PeerSource::Resume)
+    }
+}
+
+#[derive(Debug)]
+struct Candidate {
+    peer: Peer,
+    source: PeerSource,
+    failures: u32,
+    retry_at: Instant,
+    connected: bool,
+}
+
+/// Keeps a pool of candidate peers for every torrent and decides which of them to connect to,
+/// so that the number of open connections never exceeds the global and per-torrent limits
+#[derive(Debug)]
+pub struct ConnectionManager {
+    candidates: HashMap<(Vec<u8>, SocketAddr), Candidate>,
+    // info hashes of private torrents (BEP 27), which only get peers from their trackers
+    private: HashSet<Vec<u8>>,
+    max_connections: usize,
+    max_connections_per_torrent: usize,
+}
+
+impl ConnectionManager {
+    pub fn new(max_connections: usize, max_connections_per_torrent: usize) -> Self {
+        ConnectionManager {
+            candidates: HashMap::new(),
+            private: HashSet::new(),
+            max_connections,
+            max_connections_per_torrent,
+        }
+    }
+
+    /// Marks the torrent with the given info hash as private or public
+    pub fn set_private(&mut self, info_hash: &[u8], private: bool) {
+        if private {
+            self.private.insert(info_hash.to_vec());
+        } else {
+            self.private.remove(info_hash);
+        }
+    }
+
+    /// Returns whether the info hash may be shared with trackerless peer discovery, such as DHT
+    /// announces, PEX messages or LSD broadcasts. This is never the case for private torrents
+    pub fn allows_discovery(&self, info_hash: &[u8]) -> bool {
+        !self.private.contains(info_hash)
+    }
+
+    /// Adds peers for the torrent with the given info hash to the candidate pool. Addresses that
+    /// are already known keep their connection state and backoff. Peers found through trackerless
+    /// discovery are ignored for private torrents
+    pub fn add_peers(&mut self, info_hash: &[u8], peers: Vec<Peer>, source: PeerSource) {
+        if source.is_trackerless() && !self.allows_discovery(info_hash) {
+            return
+        }
+
+        let now = Instant::now();
+        for peer in peers {
+            let key = (info_hash.to_vec(), SocketAddr::new(peer.ip, peer.port));
+            self.candidates.entry(key).or_insert(Candidate {
+                peer,
+                source,
+                failures: 0,
+                retry_at: now,
+                connected: false,
+            });
+        }
+    }
+
+    /// Returns the candidates which should be connected to now, given the free connection slots,
+    /// and marks them as connected. Addresses which failed the least are tried first
+    pub fn next_candidates(&mut self) -> Vec<(Vec<u8>, Peer)> {
+        let now = Instant::now();
+        let mut total = self.num_connections();
+        let mut per_torrent: HashMap<Vec<u8>, usize> = HashMap::new();
+        for (key, candidate) in self.candidates.iter() {
+            if candidate.connected {
+                *per_torrent.entry(key.0.clone()).or_insert(0) += 1;
+            }
+        }
+
+        let mut ready: Vec<(&(Vec<u8>, SocketAddr), &mut Candidate)> = self.candidates.iter_mut()
+            .filter(|(_, c)| !c.connected && c.source != PeerSource::Incoming && c.retry_at <= now)
+            .collect();
+        ready.sort_by_key(|(_, c)| c.failures);
+
+        let mut selected = vec![];
+        for (key, candidate) in ready {
+            if total >= self.max_connections {
+                break;
+            }
+
+            let count = per_torrent.entry(key.0.clone()).or_insert(0);
+            if *count >= self.max_connections_per_torrent {
+                continue;
+            }
+
+            *count += 1;
+            total += 1;
+            candidate.connected = true;
+            selected.push((key.0.clone(), candidate.peer.clone()));
+        }
+        selected
+    }
+
+    /// Registers a connection which a peer opened to us. Returns false if there is no free slot,
+    /// in which case the connection should be dropped
+    pub fn accept_incoming(&mut self, info_hash: &[u8], peer: Peer) -> bool {
+        let torrent_connections = self.num_connections_for(info_hash);
+        if self.num_connections() >= self.max_connections ||
+            torrent_connections >= self.max_connections_per_torrent {
+            return false
+        }
+
+        let key = (info_hash.to_vec(), SocketAddr::new(peer.ip, peer.port));
+        let candidate = self.candidates.entry(key).or_insert(Candidate {
+            peer,
+            source: PeerSource::Incoming,
+            failures: 0,
+            retry_at: Instant::now(),
+            connected: false,
+        });
+
+        if candidate.connected {
+            return false
+        }
+        candidate.connected = true;
+        true
+    }
+
+    /// Frees the slot held by a connection. Failed addresses are retried after an exponentially
+    /// growing delay, while peers which closed the connection cleanly are retried after a fixed
+    /// delay. Incoming peers connected from an ephemeral port, so they are forgotten
+    pub fn connection_closed(&mut self, info_hash: &[u8], addr: &SocketAddr, failed: bool) {
+        let key = (info_hash.to_vec(), *addr);
+        let forget = match self.candidates.get_mut(&key) {
+            Some(candidate) => {
+                candidate.connected = false;
+                if failed {
+                    candidate.failures += 1;
+                    candidate.retry_at = Instant::now() + backoff(candidate.failures);
+                } else {
+                    candidate.failures = 0;
+                    candidate.retry_at = Instant::now() + Duration::from_secs(RECONNECT_DELAY);
+                }
+                candidate.source == PeerSource::Incoming
+            },
+            None => false
+        };
+
+        if forget {
+            self.candidates.remove(&key);
+        }
+    }
+
+    /// Forgets every candidate of the torrent with the given info hash, such as once it was
+    /// removed. Its open connections close on their own and no longer count against the limits
+    pub fn remove_torrent(&mut self, info_hash: &[u8]) {
+        self.candidates.retain(|key, _| key.0 != info_hash);
+        self.private.remove(info_hash);
+    }
+
+    /// Returns the number of open connections across all torrents
+    pub fn num_connections(&self) -> usize {
+        self.candidates.values().filter(|c| c.connected).count()
+    }
+
+    /// Returns the number of open connections for the torrent with the given info hash
+    pub fn num_connections_for(&self, info_hash: &[u8]) -> usize {
+        self.candidates.iter()
+            .filter(|&(key, c)| c.connected && key.0 == info_hash)
+            .count()
+    }
+
+    /// Returns the number of known peers for the torrent with the given info hash
+    #[cfg(test)]
+    pub fn num_candidates_for(&self, info_hash: &[u8]) -> usize {
+        self.candidates.keys().filter(|key| key.0 == info_hash).count()
+    }
+}
+
+/// Returns how long to wait before retrying an address after its `failures`-th failed attempt
+pub fn backoff(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    Duration::from_secs((BASE_BACKOFF << exponent).min(MAX_BACKOFF))
+}
+
+#[cfg(test)]
+mod manager_tests {
+    use super::{ConnectionManager, PeerSource, backoff};
+    use peer::Peer;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn peers(n: u8) -> Vec<Peer> {
+        (0..n).map(|i| Peer::from_bytes(&[10, 0, 0, i, 26, 225])).collect()
+    }
+
+    #[test]
+    fn connection_limits_test() {
+        let mut m = ConnectionManager::new(3, 2);
+        m.add_peers(&[1], peers(4), PeerSource::Tracker);
+        m.add_peers(&[2], peers(4), PeerSource::Tracker);
+
+        let selected = m.next_candidates();
+        assert_eq!(selected.len(), 3);
+        assert!(m.num_connections_for(&[1]) <= 2);
+        assert!(m.num_connections_for(&[2]) <= 2);
+        assert_eq!(m.next_candidates(), vec![]);
+        assert!(!m.accept_incoming(&[1], Peer::from_bytes(&[10, 0, 1, 1, 0, 80])));
+
+        let (ref info_hash, ref peer) = selected[0];
+        m.connection_closed(info_hash, &SocketAddr::new(peer.ip, peer.port), false);
+        assert_eq!(m.next_candidates().len(), 1);
+        assert_eq!(m.num_connections(), 3);
+    }
+
+    #[test]
+    fn failed_peer_backoff_test() {
+        let mut m = ConnectionManager::new(10, 10);
+        m.add_peers(&[1], peers(1), PeerSource::Tracker);
+
+        let (info_hash, peer) = m.next_candidates().remove(0);
+        m.connection_closed(&info_hash, &SocketAddr::new(peer.ip, peer.port), true);
+        assert_eq!(m.num_candidates_for(&[1]), 1);
+        assert_eq!(m.next_candidates(), vec![]);
+
+        assert_eq!(backoff(1), Duration::from_secs(5));
+        assert_eq!(backoff(2), Duration::from_secs(10));
+        assert_eq!(backoff(4), Duration::from_secs(40));
+        assert_eq!(backoff(100), Duration::from_secs(30 * 60));
+    }
+
+    #[test]
+    fn incoming_peer_test() {
+        let mut m = ConnectionManager::new(10, 10);
+        let peer = Peer::from_bytes(&[10, 0, 1, 1, 200, 10]);
+        let addr = SocketAddr::new(peer.ip, peer.port);
+
+        assert!(m.accept_incoming(&[1], peer.clone()));
+        assert!(!m.accept_incoming(&[1], peer));
+        assert_eq!(m.num_connections_for(&[1]), 1);
+
+        m.connection_closed(&[1], &addr, false);
+        assert_eq!(m.num_candidates_for(&[1]), 0);
+    }
+
+    #[test]
+    fn private_torrent_test() {
+        let mut m = ConnectionManager::new(10, 10);
+        m.set_private(&[1], true);
+        assert!(!m.allows_discovery(&[1]));
+        assert!(m.allows_discovery(&[2]));
+
+        m.add_peers(&[1], peers(2), PeerSource::Dht);
+        m.add_peers(&[1], peers(1), PeerSource::Pex);
+        assert_eq!(m.num_candidates_for(&[1]), 0);
+        m.add_peers(&[1], peers(2), PeerSource::Tracker);
+        assert_eq!(m.num_candidates_for(&[1]), 2);
+        m.add_peers(&[2], peers(3), PeerSource::Dht);
+        assert_eq!(m.num_candidates_for(&[2]), 3);
+
+        m.set_private(&[1], false);
+        assert!(m.allows_discovery(&[1]));
+    }
+
+    #[test]
+    fn remove_torrent_test() {
+        let mut m = ConnectionManager::new(10, 10);
+        m.set_private(&[1], true);
+        m.add_peers(&[1], peers(3), PeerSource::Tracker);
+        m.add_peers(&[2], peers(2), PeerSource::Tracker);
+        assert_eq!(m.next_candidates().len(), 5);
+
+        m.remove_torrent(&[1]);
+        assert_eq!(m.num_candidates_for(&[1]), 0);
+        assert_eq!(m.num_connections(), 2);
+        assert!(m.allows_discovery(&[1]));
+        let addr = SocketAddr::new(peers(1)[0].ip, peers(1)[0].port);
+        m.connection_closed(&[1], &addr, true);
+        assert_eq!(m.num_candidates_for(&[1]), 0);
+    }
+}
@@ -0,0 +1,146 @@
+use hash::sha256;
+
+/// Size of the blocks whose SHA-256 hashes form the leaves of a file's merkle tree (BEP 52)
+pub const BLOCK_SIZE: usize = 16384;
+
+fn parent(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut both = left.to_vec();
+    both.extend_from_slice(right);
+    sha256(&both)
+}
+
+/// Returns the root of a tree with the given number of leaves which are all zero hashes. Layers
+/// of a tree are padded with these beyond the end of the file
+pub fn pad_hash(leaves: usize) -> Vec<u8> {
+    let mut hash = vec![0; 32];
+    let mut width = 1;
+    while width < leaves {
+        hash = parent(&hash, &hash);
+        width *= 2;
+    }
+    hash
+}
+
+/// Computes the layer above the given one, where `pad` stands in for missing entries. Returns the
+/// new layer and the padding hash for it
+fn layer_up(layer: &[Vec<u8>], pad: &[u8]) -> (Vec<Vec<u8>>, Vec<u8>) {
+    let up = layer.chunks(2)
+        .map(|pair| parent(&pair[0], pair.get(1).map_or(pad, |h| &h[..])))
+        .collect();
+    (up, parent(pad, pad))
+}
+
+/// Returns the root of the tree whose lowest layer is `hashes` padded with `pad` to `width`
+/// entries, which must be a power of two
+pub fn root(hashes: &[Vec<u8>], width: usize, pad: &[u8]) -> Vec<u8> {
+    let mut layer = hashes.to_vec();
+    let mut pad = pad.to_vec();
+    let mut width = width.max(1);
+    while width > 1 {
+        let (up, up_pad) = layer_up(&layer, &pad);
+        layer = up;
+        pad = up_pad;
+        width /= 2;
+    }
+    layer.pop().unwrap_or(pad)
+}
+
+/// Returns the SHA-256 hash of every block of the data
+pub fn block_hashes(data: &[u8]) -> Vec<Vec<u8>> {
+    data.chunks(BLOCK_SIZE).map(sha256).collect()
+}
+
+/// Returns the number of leaves of the tree over a piece or file of the given length, which is
+/// its number of blocks rounded up to a power of two
+pub fn num_leaves(length: u64) -> usize {
+    (length.div_ceil(BLOCK_SIZE as u64) as usize).next_power_of_two()
+}
+
+/// Returns the pieces root of a file and its piece layer, which holds the root of the subtree of
+/// every piece. Files no longer than a piece have no piece layer. Torrents are checked piece by
+/// piece, so this only builds the hashes of test torrents
+#[cfg(test)]
+pub fn file_hashes(data: &[u8], piece_length: u32) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let leaves = block_hashes(data);
+    let zero = pad_hash(1);
+    if data.len() <= piece_length as usize {
+        return (root(&leaves, num_leaves(data.len() as u64), &zero), vec![])
+    }
+
+    let per_piece = piece_length as usize / BLOCK_SIZE;
+    let layer: Vec<Vec<u8>> = leaves.chunks(per_piece).map(|c| root(c, per_piece, &zero)).collect();
+    (root(&layer, layer.len().next_power_of_two(), &pad_hash(per_piece)), layer)
+}
+
+/// Returns the sibling hashes which prove that the entry at `index` of a layer padded with `pad`
+/// to `width` entries belongs to the tree, from the bottom up
+pub fn proof(hashes: &[Vec<u8>], width: usize, pad: &[u8], index: usize) -> Vec<Vec<u8>> {
+    let mut layer = hashes.to_vec();
+    let mut pad = pad.to_vec();
+    let mut width = width;
+    let mut index = index;
+    let mut uncles = vec![];
+    while width > 1 {
+        uncles.push(layer.get(index ^ 1).cloned().unwrap_or_else(|| pad.clone()));
+        let (up, up_pad) = layer_up(&layer, &pad);
+        layer = up;
+        pad = up_pad;
+        width /= 2;
+        index /= 2;
+    }
+    uncles
+}
+
+/// Checks that `hash`, the entry at `index` of some layer of a tree, leads up to `root` through
+/// the given sibling hashes
+#[cfg(test)]
+pub fn verify_proof(hash: &[u8], index: usize, proof: &[Vec<u8>], root: &[u8]) -> bool {
+    let mut hash = hash.to_vec();
+    let mut index = index;
+    for uncle in proof {
+        hash = if index.is_multiple_of(2) { parent(&hash, uncle) } else { parent(uncle, &hash) };
+        index /= 2;
+    }
+    index == 0 && hash == root
+}
+
+#[cfg(test)]
+mod merkle_tests {
+    use super::{file_hashes, num_leaves, pad_hash, proof, root, verify_proof, block_hashes, BLOCK_SIZE};
+    use hash::sha256;
+
+    #[test]
+    fn file_hashes_test() {
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let leaves = block_hashes(&data);
+        assert_eq!(leaves.len(), 6);
+        assert_eq!(num_leaves(data.len() as u64), 8);
+
+        // a small file's root is the root over its blocks
+        let (small_root, small_layer) = file_hashes(&data[..BLOCK_SIZE], 32768);
+        assert_eq!(small_root, sha256(&data[..BLOCK_SIZE]));
+        assert!(small_layer.is_empty());
+
+        // pieces of two blocks give three subtree roots, and the layer is padded with a fourth
+        let (file_root, layer) = file_hashes(&data, 2 * BLOCK_SIZE as u32);
+        assert_eq!(layer.len(), 3);
+        assert_eq!(layer[2], root(&leaves[4..], 2, &pad_hash(1)));
+        assert_eq!(file_root, root(&leaves, 8, &pad_hash(1)));
+        assert_eq!(file_root, root(&layer, 4, &pad_hash(2)));
+    }
+
+    #[test]
+    fn proof_test() {
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE).map(|i| (i % 241) as u8).collect();
+        let leaves = block_hashes(&data);
+        let file_root = root(&leaves, 8, &pad_hash(1));
+
+        for index in 0..leaves.len() {
+            let uncles = proof(&leaves, 8, &pad_hash(1), index);
+            assert_eq!(uncles.len(), 3);
+            assert!(verify_proof(&leaves[index], index, &uncles, &file_root));
+            assert!(!verify_proof(&leaves[index], index ^ 1, &uncles, &file_root));
+        }
+        assert!(!verify_proof(&sha256(b"forged"), 0, &proof(&leaves, 8, &pad_hash(1), 0), &file_root));
+    }
+}
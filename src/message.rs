@@ -1,5 +1,6 @@
 use::std::fmt;
 use util::{bytes_to_u32, u32_to_bytes};
+use error::Error;
 
 #[derive(PartialEq)]
 pub enum Message {
@@ -12,8 +13,47 @@ pub enum Message {
     Bitfield(Vec<u8>),
     Request(u32, u32, u32),
     Piece(u32, u32, Vec<u8>),
-    Cancel(u32),
+    Cancel(u32, u32, u32),
     Port,
+    // an extension message (BEP 10): the extended message id, where 0 is the extension
+    // handshake, followed by its payload
+    Extended(u8, Vec<u8>),
+    HashRequest(HashRequest),
+    // the requested hashes followed by the uncle hashes which prove them
+    Hashes(HashRequest, Vec<Vec<u8>>),
+    HashReject(HashRequest),
+}
+
+/// Asks for `length` hashes from a layer of a file's merkle tree (BEP 52), starting at `index`.
+/// The base layer counts up from the leaves, and `proof_layers` says how many layers of uncle
+/// hashes should be sent along to prove them
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashRequest {
+    pub pieces_root: Vec<u8>,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    fn parse(body: &[u8]) -> HashRequest {
+        HashRequest {
+            pieces_root: body[0..32].to_vec(),
+            base_layer: bytes_to_u32(&body[32..36]),
+            index: bytes_to_u32(&body[36..40]),
+            length: bytes_to_u32(&body[40..44]),
+            proof_layers: bytes_to_u32(&body[44..48]),
+        }
+    }
+
+    fn serialize(self, payload: &mut Vec<u8>) {
+        payload.extend(self.pieces_root);
+        payload.extend(u32_to_bytes(self.base_layer));
+        payload.extend(u32_to_bytes(self.index));
+        payload.extend(u32_to_bytes(self.length));
+        payload.extend(u32_to_bytes(self.proof_layers));
+    }
 }
 
 /// Constructs messages to be passed between peers. Messages are structured as arrays of bytes:
@@ -22,8 +62,25 @@ pub enum Message {
 ///     5 holds the id of the message
 ///     6-* contains the payload
 impl Message {
-    pub fn new(id: &u8, body: &[u8]) -> Message {
-        match *id {
+    /// Parses a message from its id and payload. Returns a protocol error for unknown ids and
+    /// payloads of the wrong length
+    pub fn new(id: &u8, body: &[u8]) -> Result<Message, Error> {
+        let expected_length = match *id {
+            4 => Some(4),
+            6 | 8 => Some(12),
+            7 => if body.len() >= 8 { None } else { Some(8) },
+            20 => if body.is_empty() { Some(1) } else { None },
+            21 | 23 => Some(48),
+            22 => if body.len() >= 48 && (body.len() - 48).is_multiple_of(32) { None } else { Some(48) },
+            _ => None
+        };
+        if let Some(length) = expected_length {
+            if body.len() != length {
+                return Err(Error::Protocol(format!("Message {} has a payload of {} bytes", id, body.len())))
+            }
+        }
+
+        let message = match *id {
             0 => Message::Choke,
             1 => Message::Unchoke,
             2 => Message::Interested,
@@ -42,9 +99,40 @@ impl Message {
                 let data = body[8..].to_owned();
                 Message::Piece(index, offset, data)
             },
-            8 => Message::Cancel(bytes_to_u32(body)),
+            8 => {
+                let index = bytes_to_u32(&body[0..4]);
+                let offset = bytes_to_u32(&body[4..8]);
+                let length = bytes_to_u32(&body[8..12]);
+                Message::Cancel(index, offset, length)
+            },
             9 => Message::Port,
-            _ => panic!("Bad message id: {}", id)
+            20 => Message::Extended(body[0], body[1..].to_vec()),
+            21 => Message::HashRequest(HashRequest::parse(body)),
+            22 => Message::Hashes(HashRequest::parse(body), body[48..].chunks(32).map(|h| h.to_vec()).collect()),
+            23 => Message::HashReject(HashRequest::parse(body)),
+            _ => return Err(Error::Protocol(format!("Bad message id: {}", id)))
+        };
+        Ok(message)
+    }
+
+    /// Returns the name of the message's type, which log records are tagged with
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Message::KeepAlive => "keep-alive",
+            Message::Choke => "choke",
+            Message::Unchoke => "unchoke",
+            Message::Interested => "interested",
+            Message::NotInterested => "not-interested",
+            Message::Have(_) => "have",
+            Message::Bitfield(_) => "bitfield",
+            Message::Request(..) => "request",
+            Message::Piece(..) => "piece",
+            Message::Cancel(..) => "cancel",
+            Message::Port => "port",
+            Message::Extended(..) => "extended",
+            Message::HashRequest(_) => "hash-request",
+            Message::Hashes(..) => "hashes",
+            Message::HashReject(_) => "hash-reject",
         }
     }
 
@@ -76,8 +164,33 @@ impl Message {
                 payload.extend(u32_to_bytes(offset).into_iter());
                 payload.extend(data);
             },
-            Message::Cancel(_) => payload.push(8),
+            Message::Cancel(index, offset, amount) => {
+                payload.push(8);
+                payload.extend(u32_to_bytes(index));
+                payload.extend(u32_to_bytes(offset));
+                payload.extend(u32_to_bytes(amount));
+            },
             Message::Port => payload.push(9),
+            Message::Extended(id, data) => {
+                payload.push(20);
+                payload.push(id);
+                payload.extend(data);
+            },
+            Message::HashRequest(request) => {
+                payload.push(21);
+                request.serialize(&mut payload);
+            },
+            Message::Hashes(request, hashes) => {
+                payload.push(22);
+                request.serialize(&mut payload);
+                for hash in hashes {
+                    payload.extend(hash);
+                }
+            },
+            Message::HashReject(request) => {
+                payload.push(23);
+                request.serialize(&mut payload);
+            },
         };
 
         let mut size = u32_to_bytes(payload.len() as u32);
@@ -98,47 +211,51 @@ impl fmt::Debug for Message {
              Message::Bitfield(ref bytes) => write!(f, "Bitfield({:?})", bytes),
              Message::Request(ref index, ref offset, ref length) => write!(f, "Request({}, {}, {})", index, offset, length),
              Message::Piece(ref index, ref offset, ref data) => write!(f, "Piece({}, {}, size={})", index, offset, data.len()),
-             Message::Cancel(piece_index) => write!(f, "Cancel other requests for piece {}", piece_index),
+             Message::Cancel(ref index, ref offset, ref length) => write!(f, "Cancel({}, {}, {})", index, offset, length),
              Message::Port => write!(f, "Port"),
+             Message::Extended(id, ref data) => write!(f, "Extended({}, size={})", id, data.len()),
+             Message::HashRequest(ref r) => write!(f, "HashRequest(layer={}, index={}, length={})", r.base_layer, r.index, r.length),
+             Message::Hashes(ref r, ref hashes) => write!(f, "Hashes(layer={}, index={}, count={})", r.base_layer, r.index, hashes.len()),
+             Message::HashReject(ref r) => write!(f, "HashReject(layer={}, index={}, length={})", r.base_layer, r.index, r.length),
         }
     }
 }
 
 #[cfg(test)]
 mod message_tests {
-    use super::Message;
+    use super::{Message, HashRequest};
 
     #[test]
     fn make_and_serialize_message_test() {
-        let mut msg = Message::new(&0, &[]);
+        let mut msg = Message::new(&0, &[]).unwrap();
         assert_eq!(msg, Message::Choke);
         assert_eq!(msg.serialize(), vec![
             0, 0, 0, 1,
             0,
         ]);
 
-        msg = Message::new(&1, &[]);
+        msg = Message::new(&1, &[]).unwrap();
         assert_eq!(msg, Message::Unchoke);
         assert_eq!(msg.serialize(), vec![
             0, 0, 0, 1,
             1,
         ]);
 
-        msg = Message::new(&2, &[]);
+        msg = Message::new(&2, &[]).unwrap();
         assert_eq!(msg, Message::Interested);
         assert_eq!(msg.serialize(), vec![
             0, 0, 0, 1,
             2,
         ]);
 
-        msg = Message::new(&3, &[]);
+        msg = Message::new(&3, &[]).unwrap();
         assert_eq!(msg, Message::NotInterested);
         assert_eq!(msg.serialize(), vec![
             0, 0, 0, 1,
             3,
         ]);
 
-        msg = Message::new(&4, &[0, 0, 1, 1]);
+        msg = Message::new(&4, &[0, 0, 1, 1]).unwrap();
         assert_eq!(msg, Message::Have(257));
         assert_eq!(msg.serialize(), vec![
             0, 0, 0, 5,
@@ -146,7 +263,7 @@ mod message_tests {
             0, 0, 1, 1,
         ]);
 
-        msg = Message::new(&5, &[0, 0, 1, 1]);
+        msg = Message::new(&5, &[0, 0, 1, 1]).unwrap();
         assert_eq!(msg, Message::Bitfield(vec![0, 0, 1, 1]));
         assert_eq!(msg.serialize(), vec![
             0, 0, 0, 5,
@@ -154,7 +271,7 @@ mod message_tests {
             0, 0, 1, 1,
         ]);
 
-        msg = Message::new(&6, &[0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 1, 3]);
+        msg = Message::new(&6, &[0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 1, 3]).unwrap();
         assert_eq!(msg, Message::Request(257, 258, 259));
         assert_eq!(msg.serialize(), vec![
             0, 0, 0, 13,
@@ -164,7 +281,7 @@ mod message_tests {
             0, 0, 1, 3,
         ]);
 
-        msg = Message::new(&7, &[0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 1, 3, 4, 5]);
+        msg = Message::new(&7, &[0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 1, 3, 4, 5]).unwrap();
         assert_eq!(msg, Message::Piece(257, 258, vec![0, 0, 1, 3, 4, 5]));
         assert_eq!(msg.serialize(), vec![
             0, 0, 0, 15,
@@ -174,21 +291,65 @@ mod message_tests {
             0, 0, 1, 3, 4, 5,
         ]);
 
-        msg = Message::new(&8, &[0, 0, 0, 0]);
-        assert_eq!(msg, Message::Cancel(0));
+        msg = Message::new(&8, &[0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 1, 3]).unwrap();
+        assert_eq!(msg, Message::Cancel(257, 258, 259));
         assert_eq!(msg.serialize(), vec![
-            0, 0, 0, 1,
+            0, 0, 0, 13,
             8,
+            0, 0, 1, 1,
+            0, 0, 1, 2,
+            0, 0, 1, 3,
         ]);
 
-        msg = Message::new(&9, &[]);
+        msg = Message::new(&9, &[]).unwrap();
         assert_eq!(msg, Message::Port);
         assert_eq!(msg.serialize(), vec![
             0, 0, 0, 1,
             9,
         ]);
 
+        msg = Message::new(&20, &[1, b'd', b'e']).unwrap();
+        assert_eq!(msg, Message::Extended(1, b"de".to_vec()));
+        assert_eq!(msg.serialize(), vec![
+            0, 0, 0, 4,
+            20, 1, b'd', b'e',
+        ]);
+
         msg = Message::KeepAlive;
         assert_eq!(msg.serialize(), vec![0, 0, 0, 0]);
     }
+
+    #[test]
+    fn invalid_message_test() {
+        assert!(Message::new(&20, &[]).is_err());
+        assert!(Message::new(&4, &[0, 1]).is_err());
+        assert!(Message::new(&6, &[0, 0, 0, 1]).is_err());
+        assert!(Message::new(&8, &[0, 0, 0, 1]).is_err());
+        assert!(Message::new(&7, &[0, 0, 0, 1]).is_err());
+        assert!(Message::new(&21, &[0; 47]).is_err());
+        assert!(Message::new(&22, &[0; 50]).is_err());
+    }
+
+    #[test]
+    fn cancel_round_trip_test() {
+        let bytes = Message::Cancel(3, 16384, 16384).serialize();
+        assert_eq!(bytes.len(), 17);
+        assert_eq!(Message::new(&bytes[4], &bytes[5..]).unwrap(), Message::Cancel(3, 16384, 16384));
+    }
+
+    #[test]
+    fn hash_messages_test() {
+        let request = HashRequest { pieces_root: vec![7; 32], base_layer: 1, index: 4, length: 2, proof_layers: 3 };
+        let bytes = Message::HashRequest(request.clone()).serialize();
+        assert_eq!(bytes[..5], [0, 0, 0, 49, 21]);
+        assert_eq!(Message::new(&bytes[4], &bytes[5..]).unwrap(), Message::HashRequest(request.clone()));
+
+        let hashes = vec![vec![1; 32], vec![2; 32], vec![3; 32]];
+        let bytes = Message::Hashes(request.clone(), hashes.clone()).serialize();
+        assert_eq!(bytes.len(), 4 + 1 + 48 + 96);
+        assert_eq!(Message::new(&bytes[4], &bytes[5..]).unwrap(), Message::Hashes(request.clone(), hashes));
+
+        let bytes = Message::HashReject(request.clone()).serialize();
+        assert_eq!(Message::new(&23, &bytes[5..]).unwrap(), Message::HashReject(request));
+    }
 }
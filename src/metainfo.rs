@@ -1,16 +1,33 @@
 use bencode;
-use bencode::{Bencode, FromBencode};
+use bencode::{Bencode, Value, FromBencode, ToBencode};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::io::prelude::*;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str;
 use hash;
-use util::*;
+use merkle;
+use error::Error;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MetaInfo {
-    pub announce: String,
+    // the tracker to announce to, if any. Torrents may rely on web seeds or DHT nodes alone
+    pub announce: Option<String>,
+    // further trackers from the `announce-list` (BEP 12), which are tried in order when
+    // `announce` can't be reached
+    pub announce_list: Vec<String>,
     pub created_by: String,
     pub info: Info,
+    // identifies the torrent to trackers and peers: the SHA-1 hash of the info dictionary, or the
+    // truncated SHA-256 hash for v2-only torrents
     pub info_hash: Vec<u8>,
+    // web seeds (BEP 19) from which pieces can be downloaded over HTTP
+    pub url_list: Vec<String>,
+    // the SHA-256 hash of the info dictionary of v2 and hybrid torrents (BEP 52)
+    pub info_hash_v2: Option<Vec<u8>>,
+    // the piece layer of every file larger than a piece, keyed by the file's pieces root
+    pub piece_layers: HashMap<Vec<u8>, Vec<Vec<u8>>>,
 }
 
 impl FromBencode for MetaInfo {
@@ -18,33 +35,50 @@ impl FromBencode for MetaInfo {
 
     /// Attempts to construct a MetaInfo object from a Bencode object. Returns a Result containing either
     ///     1) a MetaInfo object, if a proper Bencode object was passed in
-    ///     2) DictMatchErr otherwise
-    fn from_bencode(bn: &bencode::Bencode) -> Result<MetaInfo, Error> {
-        match bn {
-            &Bencode::Dict(ref m) => {
-                let b = decode_field_as_bytes(m, "info")?;
-                let info: Bencode = bencode::from_vec(b.to_owned()).unwrap();
-                let decoded: Result<Info, Error> = FromBencode::from_bencode(&info);
-                let info_hash = hash::sha(&b);
-
-                let announce = decode_field_as_string(m, "announce")?;
-                let created_by;
-                match decode_field_as_string(m, "created by") {
-                    Ok(s) => created_by = s,
-                    Err(_) => created_by = String::from("")
-                }
+    ///     2) an Error describing the missing or invalid field otherwise
+    fn from_bencode(bn: &Value) -> Result<MetaInfo, Error> {
+        metainfo_from_bencode(bn, true)
+    }
+}
 
-                let metainfo = MetaInfo {
-                    announce: announce,
-                    created_by: created_by,
-                    info: decoded?,
-                    info_hash: info_hash,
-                };
+/// Parses a metainfo dictionary, which needs a tracker, web seeds or DHT nodes to find peers
+/// through unless `needs_tracker` is false
+fn metainfo_from_bencode(bn: &Value, needs_tracker: bool) -> Result<MetaInfo, Error> {
+    match *bn {
+        Value::Dict(ref m) => {
+            let info = m.get("info").ok_or_else(|| Error::missing("info"))?;
+            let decoded: Result<Info, Error> = FromBencode::from_bencode(info);
+            // the info hash covers the exact bytes of the info dictionary in the file
+            let raw_info = info.as_dict().ok_or_else(|| Error::invalid("info", "not a dictionary"))?.raw();
 
-                Ok(metainfo)
-            }
-            _ => Err(Error::DictMatchErr)
+            let mut announce_list = trackers(m, needs_tracker)?;
+            let announce = if announce_list.is_empty() { None } else { Some(announce_list.remove(0)) };
+            let created_by = string_field(m, "created by").unwrap_or_default();
+            let url_list = url_list(m)?;
+            let info = decoded?;
+            let piece_layers = piece_layers(m, &info)?;
+
+            let info_hash_v2 = if info.meta_version == 2 { Some(hash::sha256(raw_info)) } else { None };
+            // hybrid torrents are known to v1 peers by their SHA-1 hash
+            let info_hash = match info_hash_v2 {
+                Some(ref v2) if info.pieces.is_empty() => v2[..20].to_vec(),
+                _ => hash::sha(raw_info)
+            };
+
+            let metainfo = MetaInfo {
+                announce,
+                announce_list,
+                created_by,
+                info,
+                info_hash,
+                url_list,
+                info_hash_v2,
+                piece_layers,
+            };
+
+            Ok(metainfo)
         }
+        _ => Err(Error::invalid("metainfo", "not a dictionary"))
     }
 }
 
@@ -56,6 +90,151 @@ pub struct Info {
     pub num_pieces: u32,
     pub name: String,
     pub length: u64,
+    // private torrents (BEP 27) only get peers from their own trackers
+    pub private: bool,
+    // the files of a multi-file torrent, which are stored in a directory with the torrent's name.
+    // Empty for a single-file torrent
+    pub files: Vec<FileInfo>,
+    // the attributes of the file of a single-file torrent
+    pub attr: FileAttr,
+    // 2 for v2 and hybrid torrents (BEP 52), 1 otherwise
+    pub meta_version: u32,
+    // the files listed in the file tree of a v2 or hybrid torrent. Empty for v1 torrents
+    pub file_tree: Vec<TreeFile>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    // the components of the file's path within the torrent's directory
+    pub path: Vec<String>,
+    pub length: u64,
+    pub attr: FileAttr,
+    // for symlinks, the path components of the target relative to the torrent's directory
+    pub symlink_path: Option<Vec<String>>,
+}
+
+/// The attributes of a file (BEP 47). Unknown attributes are ignored
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FileAttr {
+    // a padding file, whose data is all zeros and which is never written to disk
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    // a symlink, which has no data of its own
+    pub symlink: bool,
+}
+
+impl FileAttr {
+    fn parse(attr: &str) -> FileAttr {
+        FileAttr {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeFile {
+    // the components of the file's path; just the torrent's name for a single-file torrent
+    pub path: Vec<String>,
+    pub length: u64,
+    // the root of the SHA-256 merkle tree over the file's blocks, absent for empty files
+    pub pieces_root: Option<Vec<u8>>,
+    pub attr: FileAttr,
+    pub symlink_path: Option<Vec<String>>,
+}
+
+/// How the data of a piece is checked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PieceHash {
+    /// Against the SHA-1 hash of the data, as in v1 torrents
+    Sha1,
+    /// Against the root of a SHA-256 merkle tree over the data's blocks, padded with zero leaves
+    /// to the given width, as in v2 torrents
+    Merkle(usize),
+}
+
+impl PieceHash {
+    /// Returns whether the data matches the expected hash
+    pub fn matches(&self, hash: &[u8], data: &[u8]) -> bool {
+        match *self {
+            PieceHash::Sha1 => hash::sha(data) == hash,
+            PieceHash::Merkle(leaves) => merkle::root(&merkle::block_hashes(data), leaves, &merkle::pad_hash(1)) == hash,
+        }
+    }
+}
+
+/// Describes where a piece lies within the torrent's data and how its data is checked
+#[derive(Debug, Clone, PartialEq)]
+pub struct PieceInfo {
+    pub offset: u64,
+    pub length: u32,
+    pub hash: Vec<u8>,
+    pub hash_type: PieceHash,
+}
+
+impl MetaInfo {
+    /// Returns every tracker of the torrent in the order they are tried
+    pub fn trackers(&self) -> Vec<String> {
+        self.announce.iter().chain(self.announce_list.iter()).cloned().collect()
+    }
+
+    /// Returns the pieces of the torrent. The pieces of v1 and hybrid torrents are checked against
+    /// their SHA-1 hashes. Every file of a v2-only torrent starts a new piece, which is checked
+    /// against the file's merkle tree
+    pub fn piece_infos(&self) -> Vec<PieceInfo> {
+        let info = &self.info;
+        if !info.pieces.is_empty() {
+            return info.pieces.iter().enumerate().map(|(i, hash)| PieceInfo {
+                offset: i as u64 * info.piece_length as u64,
+                length: info.piece_size(i),
+                hash: hash.clone(),
+                hash_type: PieceHash::Sha1,
+            }).collect()
+        }
+
+        let piece_length = info.piece_length as u64;
+        let mut pieces = vec![];
+        let mut offset = 0;
+        for file in info.file_tree.iter() {
+            if let Some(ref root) = file.pieces_root {
+                if file.length <= piece_length {
+                    pieces.push(PieceInfo {
+                        offset,
+                        length: file.length as u32,
+                        hash: root.clone(),
+                        hash_type: PieceHash::Merkle(merkle::num_leaves(file.length)),
+                    });
+                } else if let Some(layer) = self.piece_layers.get(root) {
+                    for (i, hash) in layer.iter().enumerate() {
+                        let start = i as u64 * piece_length;
+                        pieces.push(PieceInfo {
+                            offset: offset + start,
+                            length: (file.length - start).min(piece_length) as u32,
+                            hash: hash.clone(),
+                            hash_type: PieceHash::Merkle(merkle::num_leaves(piece_length)),
+                        });
+                    }
+                }
+            }
+            offset += file.length;
+        }
+        pieces
+    }
+
+    /// Returns the info hashes which peers may use for the torrent in handshakes. Hybrid torrents
+    /// are known by both their SHA-1 hash and their truncated SHA-256 hash
+    pub fn info_hashes(&self) -> Vec<Vec<u8>> {
+        let mut hashes = vec![self.info_hash.clone()];
+        if let Some(ref v2) = self.info_hash_v2 {
+            if v2[..20] != self.info_hash[..] {
+                hashes.push(v2[..20].to_vec());
+            }
+        }
+        hashes
+    }
 }
 
 impl FromBencode for Info {
@@ -63,28 +242,431 @@ impl FromBencode for Info {
 
     /// Attempts to construct an Info object from a Bencode object. Returns a Result containing
     /// either:
-    ///     1) an Info object, if a proper Bencode object was passed in
-    ///     2) DictMatchErr otherwise
-    fn from_bencode(bencode: &bencode::Bencode) -> Result<Info, Error> {
-        match bencode {
-            &Bencode::Dict(ref m) => {
-                let pieces_bytes = decode_field_as_content_bytes(m, "pieces")?;
+    ///     1) an Info object, if a proper and consistent Bencode object was passed in
+    ///     2) an Error naming the field which is missing or invalid, and why, otherwise
+    fn from_bencode(bn: &Value) -> Result<Info, Error> {
+        match *bn {
+            Value::Dict(ref m) => {
+                let piece_length = integer_field(m, "piece length", "info.piece length")?;
+                if piece_length <= 0 || piece_length > u32::MAX as i64 {
+                    return Err(Error::invalid("info.piece length", "must be positive and fit in 32 bits"))
+                }
+
+                let meta_version = match m.get("meta version") {
+                    Some(value) => match value.as_integer() {
+                        Some(2) => 2,
+                        Some(version) => return Err(Error::invalid("info.meta version", &format!("version {} is not supported", version))),
+                        None => return Err(Error::invalid("info.meta version", "not an integer"))
+                    },
+                    None => 1
+                };
+                if meta_version == 2 && (piece_length < merkle::BLOCK_SIZE as i64 || !(piece_length as u64).is_power_of_two()) {
+                    return Err(Error::invalid("info.piece length", "must be a power of two of at least 16 KiB in v2 torrents"))
+                }
+                let file_tree = if meta_version == 2 { file_tree(m)? } else { vec![] };
+
+                // v2-only torrents have no SHA-1 piece hashes
+                let v2_only = meta_version == 2 && m.get("pieces").is_none();
+                let pieces_bytes = match m.get("pieces") {
+                    Some(value) => value.as_bytes().ok_or_else(|| Error::invalid("info.pieces", "not a string"))?,
+                    None if v2_only => &[],
+                    None => return Err(Error::missing("info.pieces"))
+                };
+                if pieces_bytes.len() % 20 != 0 {
+                    return Err(Error::invalid("info.pieces", &format!("length {} is not a multiple of 20", pieces_bytes.len())))
+                }
                 let pieces: Vec<Vec<u8>> = pieces_bytes.chunks(20).map(|v| v.to_owned()).collect();
-                let num_pieces = pieces.len() as u32;
-                let length = decode_field_as_string(m, "length")?;
-                let piece_length = decode_field_as_string(m, "piece length")?;
-                let name = decode_field_as_string(m, "name")?;
+
+                let name = text_field(m, "name", "info.name")?;
+                check_path_component(&name, "info.name")?;
+
+                let files = match (m.get("files"), m.get("length")) {
+                    // a v2-only torrent lays out its data in the order of the file tree
+                    (None, None) if v2_only => match file_tree.as_slice() {
+                        [file] if file.path == [name.clone()] => vec![],
+                        tree => tree.iter().map(|f| FileInfo {
+                            path: f.path.clone(),
+                            length: f.length,
+                            attr: f.attr,
+                            symlink_path: f.symlink_path.clone(),
+                        }).collect()
+                    },
+                    (Some(_), Some(_)) => return Err(Error::invalid("info", "has both `length` and `files`")),
+                    (None, None) => return Err(Error::missing("info.length")),
+                    (Some(value), None) => {
+                        let list = value.as_list().ok_or_else(|| Error::invalid("info.files", "not a list"))?;
+                        if list.is_empty() {
+                            return Err(Error::invalid("info.files", "is empty"))
+                        }
+                        let mut files = vec![];
+                        for (i, file) in list.iter().enumerate() {
+                            files.push(file_info_from_bencode(file, &format!("info.files[{}]", i))?);
+                        }
+                        files
+                    },
+                    (None, Some(_)) => vec![]
+                };
+                let length = if v2_only {
+                    file_tree.iter().try_fold(0u64, |total, f| total.checked_add(f.length))
+                        .ok_or_else(|| Error::invalid("info.file tree", "lengths add up to more than 2^64 bytes"))?
+                } else if files.is_empty() {
+                    let length = integer_field(m, "length", "info.length")?;
+                    if length < 0 {
+                        return Err(Error::invalid("info.length", "is negative"))
+                    }
+                    length as u64
+                } else {
+                    files.iter().try_fold(0u64, |total, f| total.checked_add(f.length))
+                        .ok_or_else(|| Error::invalid("info.files", "lengths add up to more than 2^64 bytes"))?
+                };
+                if length == 0 {
+                    return Err(Error::invalid("info.length", "torrent contains no data"))
+                }
+
+                let attr = match file_tree.as_slice() {
+                    [file] if v2_only && files.is_empty() => file.attr,
+                    _ => file_attr(m, "info.attr")?
+                };
+
+                let private = match m.get("private") {
+                    Some(value) => value.as_integer().ok_or_else(|| Error::invalid("info.private", "not an integer"))? == 1,
+                    None => false
+                };
+
+                let num_pieces = if v2_only {
+                    let pieces: u64 = file_tree.iter().map(|f| f.length.div_ceil(piece_length as u64)).sum();
+                    u32::try_from(pieces)
+                        .map_err(|_| Error::invalid("info.file tree", &format!("has {} pieces, too many to count", pieces)))?
+                } else {
+                    let expected_pieces = length.div_ceil(piece_length as u64);
+                    if pieces.len() as u64 != expected_pieces {
+                        return Err(Error::invalid("info.pieces", &format!(
+                            "has {} hashes but a length of {} with pieces of {} bytes needs {}",
+                            pieces.len(), length, piece_length, expected_pieces)))
+                    }
+                    u32::try_from(pieces.len()).map_err(|_| Error::invalid("info.pieces", "has too many hashes to count"))?
+                };
+
+                if meta_version == 2 && !v2_only {
+                    check_hybrid_files(&name, length, &files, &file_tree)?;
+                }
 
                 let info = Info {
-                    piece_length: piece_length.parse::<u32>().unwrap(),
+                    piece_length: piece_length as u32,
                     pieces: pieces,
                     num_pieces: num_pieces,
                     name: name,
-                    length: length.parse::<u64>().unwrap(),
+                    length,
+                    private,
+                    files,
+                    attr,
+                    meta_version,
+                    file_tree,
                 };
                 Ok(info)
             }
-            _ => Err(Error::DictMatchErr)
+            _ => Err(Error::invalid("info", "not a dictionary"))
+        }
+    }
+}
+
+fn file_info_from_bencode(bn: &Value, field: &str) -> Result<FileInfo, Error> {
+    match *bn {
+        Value::Dict(ref m) => {
+            let length = integer_field(m, "length", &format!("{}.length", field))?;
+            if length < 0 {
+                return Err(Error::invalid(&format!("{}.length", field), "is negative"))
+            }
+
+            // prefer the explicitly UTF-8 path when the torrent has one
+            let key = if m.get("path.utf-8").is_some() { "path.utf-8" } else { "path" };
+            let path = match path_field(m, key, &format!("{}.{}", field, key))? {
+                Some(path) => path,
+                None => return Err(Error::missing(&format!("{}.{}", field, key)))
+            };
+
+            let attr = file_attr(m, &format!("{}.attr", field))?;
+            let symlink_path = symlink_path(m, attr, length, field)?;
+            Ok(FileInfo { path, length: length as u64, attr, symlink_path })
+        }
+        _ => Err(Error::invalid(field, "not a dictionary"))
+    }
+}
+
+/// Reads a non-empty list of path components, none of which may leave the torrent's directory
+fn path_field(m: &bencode::Dict, key: &str, field: &str) -> Result<Option<Vec<String>>, Error> {
+    let components = match m.get(key) {
+        Some(value) => value.as_list().ok_or_else(|| Error::invalid(field, "not a list"))?,
+        None => return Ok(None)
+    };
+    if components.is_empty() {
+        return Err(Error::invalid(field, "is empty"))
+    }
+
+    let mut path = vec![];
+    for (i, component) in components.iter().enumerate() {
+        let component_field = format!("{}[{}]", field, i);
+        let bytes = component.as_bytes().ok_or_else(|| Error::invalid(&component_field, "not a string"))?;
+        let text = str::from_utf8(bytes).map_err(|_| Error::invalid(&component_field, "not valid UTF-8"))?;
+        check_path_component(text, &component_field)?;
+        path.push(text.to_string());
+    }
+    Ok(Some(path))
+}
+
+/// Reads the optional `attr` string of a file
+fn file_attr(m: &bencode::Dict, field: &str) -> Result<FileAttr, Error> {
+    match m.get("attr") {
+        Some(value) => value.as_str().map(FileAttr::parse).ok_or_else(|| Error::invalid(field, "not a string")),
+        None => Ok(FileAttr::default())
+    }
+}
+
+/// Reads the target of a symlink, which only symlinks must have. Symlinks hold no data
+fn symlink_path(m: &bencode::Dict, attr: FileAttr, length: i64, field: &str) -> Result<Option<Vec<String>>, Error> {
+    if attr.symlink && length > 0 {
+        return Err(Error::invalid(&format!("{}.length", field), "must be 0 for a symlink"))
+    }
+    let field = format!("{}.symlink path", field);
+    match path_field(m, "symlink path", &field)? {
+        Some(_) if !attr.symlink => Err(Error::invalid(&field, "given for a file which is not a symlink")),
+        None if attr.symlink => Err(Error::missing(&field)),
+        path => Ok(path)
+    }
+}
+
+/// Reads the file tree of a v2 torrent, where every file is a path of nested dictionaries ending
+/// in one with an empty key. Files are returned in key order, which is how their data is laid out
+fn file_tree(m: &bencode::Dict) -> Result<Vec<TreeFile>, Error> {
+    let tree = match m.get("file tree") {
+        Some(value) => value.as_dict().ok_or_else(|| Error::invalid("info.file tree", "not a dictionary"))?,
+        None => return Err(Error::missing("info.file tree"))
+    };
+
+    let mut files = vec![];
+    tree_files(tree, &mut vec![], "info.file tree", &mut files)?;
+    if files.is_empty() {
+        return Err(Error::invalid("info.file tree", "has no files"))
+    }
+    Ok(files)
+}
+
+fn tree_files(node: &bencode::Dict, path: &mut Vec<String>, field: &str, files: &mut Vec<TreeFile>) -> Result<(), Error> {
+    if let Some(value) = node.get("") {
+        if path.is_empty() || node.len() > 1 {
+            return Err(Error::invalid(field, "is both a file and a directory"))
+        }
+        let file = value.as_dict().ok_or_else(|| Error::invalid(field, "not a dictionary"))?;
+        let length = integer_field(file, "length", &format!("{}.length", field))?;
+        if length < 0 {
+            return Err(Error::invalid(&format!("{}.length", field), "is negative"))
+        }
+
+        let root_field = format!("{}.pieces root", field);
+        let pieces_root = match file.get("pieces root") {
+            Some(value) => match value.as_bytes() {
+                Some(root) if root.len() == 32 => Some(root.to_vec()),
+                _ => return Err(Error::invalid(&root_field, "not a 32 byte hash"))
+            },
+            None if length > 0 => return Err(Error::missing(&root_field)),
+            None => None
+        };
+        let attr = file_attr(file, &format!("{}.attr", field))?;
+        let symlink_path = symlink_path(file, attr, length, field)?;
+        files.push(TreeFile { path: path.clone(), length: length as u64, pieces_root, attr, symlink_path });
+        return Ok(())
+    }
+
+    for (key, value) in node.iter() {
+        let name = str::from_utf8(key).map_err(|_| Error::invalid(field, "has a name which is not valid UTF-8"))?;
+        let child_field = format!("{}.{}", field, name);
+        check_path_component(name, &child_field)?;
+        let child = value.as_dict().ok_or_else(|| Error::invalid(&child_field, "not a dictionary"))?;
+        path.push(name.to_string());
+        tree_files(child, path, &child_field, files)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Makes sure the v1 file list of a hybrid torrent describes the same files as its file tree,
+/// apart from the padding files which align v1 files to pieces
+fn check_hybrid_files(name: &str, length: u64, files: &[FileInfo], tree: &[TreeFile]) -> Result<(), Error> {
+    let v1: Vec<(Vec<String>, u64)> = if files.is_empty() {
+        vec![(vec![name.to_string()], length)]
+    } else {
+        files.iter()
+            .filter(|f| !f.attr.padding)
+            .map(|f| (f.path.clone(), f.length))
+            .collect()
+    };
+    let v2: Vec<(Vec<String>, u64)> = tree.iter().map(|f| (f.path.clone(), f.length)).collect();
+    if v1 != v2 {
+        return Err(Error::invalid("info.file tree", "doesn't match the v1 file list"))
+    }
+    Ok(())
+}
+
+/// Reads the piece layers of a v2 torrent and checks that every file larger than a piece has one
+/// which leads up to the file's pieces root. They're required, since the client doesn't fetch
+/// missing layers from peers
+fn piece_layers(m: &bencode::Dict, info: &Info) -> Result<HashMap<Vec<u8>, Vec<Vec<u8>>>, Error> {
+    let mut layers = HashMap::new();
+    if info.meta_version != 2 {
+        return Ok(layers)
+    }
+
+    if let Some(value) = m.get("piece layers") {
+        let dict = value.as_dict().ok_or_else(|| Error::invalid("piece layers", "not a dictionary"))?;
+        for (root, value) in dict.iter() {
+            match value.as_bytes() {
+                Some(bytes) if bytes.len() % 32 == 0 => {
+                    layers.insert(root.to_vec(), bytes.chunks(32).map(|h| h.to_vec()).collect());
+                },
+                _ => return Err(Error::invalid("piece layers", "has a layer which is not a list of 32 byte hashes"))
+            }
+        }
+    }
+
+    let piece_length = info.piece_length as u64;
+    let pad = merkle::pad_hash(merkle::num_leaves(piece_length));
+    for file in info.file_tree.iter().filter(|f| f.length > piece_length) {
+        let field = format!("piece layers.{}", file.path.join("/"));
+        let root = file.pieces_root.as_ref().ok_or_else(|| Error::missing(&field))?;
+        let layer: &Vec<Vec<u8>> = layers.get(root).ok_or_else(|| Error::missing(&field))?;
+        if layer.len() as u64 != file.length.div_ceil(piece_length) {
+            return Err(Error::invalid(&field, &format!("has {} hashes for a file of {} bytes", layer.len(), file.length)))
+        }
+        if merkle::root(layer, layer.len().next_power_of_two(), &pad) != *root {
+            return Err(Error::invalid(&field, "doesn't match the file's pieces root"))
+        }
+    }
+    Ok(layers)
+}
+
+/// Reads the trackers to announce to: the `announce` URL followed by the others of the
+/// `announce-list` (BEP 12), tier by tier. Torrents with web seeds or DHT nodes may have none
+fn trackers(m: &bencode::Dict, needs_tracker: bool) -> Result<Vec<String>, Error> {
+    let has_announce = m.get("announce").is_some() || m.get("announce.utf-8").is_some();
+    if needs_tracker && !has_announce && ["announce-list", "url-list", "nodes"].iter().all(|key| m.get(key).is_none()) {
+        return Err(Error::missing("announce"))
+    }
+
+    let mut trackers = vec![];
+    if has_announce {
+        trackers.push(text_field(m, "announce", "announce")?);
+    }
+    let listed = m.get_list("announce-list").unwrap_or(&[]).iter()
+        .filter_map(|tier| tier.as_list())
+        .flat_map(|tier| tier.iter().filter_map(|url| url.as_str()));
+    for url in listed {
+        if !trackers.iter().any(|t| t == url) {
+            trackers.push(url.to_string());
+        }
+    }
+    trackers.retain(|t| !t.is_empty());
+    Ok(trackers)
+}
+
+/// Reads the web seed URLs, which may be given as a single string or a list of strings
+fn url_list(m: &bencode::Dict) -> Result<Vec<String>, Error> {
+    let values = match m.get("url-list") {
+        Some(value @ Value::Bytes(_)) => vec![value.clone()],
+        Some(Value::List(list)) => list.clone(),
+        Some(_) => return Err(Error::invalid("url-list", "not a string or list")),
+        None => vec![]
+    };
+
+    let mut urls = vec![];
+    for (i, value) in values.iter().enumerate() {
+        let url = value.as_str().ok_or_else(|| Error::invalid(&format!("url-list[{}]", i), "not a UTF-8 string"))?;
+        if !url.is_empty() {
+            urls.push(url.to_string());
+        }
+    }
+    Ok(urls)
+}
+
+/// Reads an integer field, telling a missing field apart from one of the wrong type
+fn integer_field(m: &bencode::Dict, key: &str, field: &str) -> Result<i64, Error> {
+    match m.get(key) {
+        Some(value) => value.as_integer().ok_or_else(|| Error::invalid(field, "not an integer")),
+        None => Err(Error::missing(field))
+    }
+}
+
+/// Reads a text field, preferring its `.utf-8` variant if present. The text must be valid UTF-8
+fn text_field(m: &bencode::Dict, key: &str, field: &str) -> Result<String, Error> {
+    let utf8_key = format!("{}.utf-8", key);
+    let (bytes, field) = match (m.get(&utf8_key), m.get(key)) {
+        (Some(value), _) => (value.as_bytes(), format!("{}.utf-8", field)),
+        (None, Some(value)) => (value.as_bytes(), field.to_string()),
+        (None, None) => return Err(Error::missing(field))
+    };
+    let bytes = bytes.ok_or_else(|| Error::invalid(&field, "not a string"))?;
+    str::from_utf8(bytes).map(|s| s.to_string()).map_err(|_| Error::invalid(&field, "not valid UTF-8"))
+}
+
+/// Makes sure a file name or path component can't escape the torrent's directory
+fn check_path_component(component: &str, field: &str) -> Result<(), Error> {
+    if component.is_empty() || component == "." || component == ".." {
+        return Err(Error::invalid(field, &format!("`{}` is not a valid file name", component)))
+    }
+    if component.contains(['/', '\\', '\0']) {
+        return Err(Error::invalid(field, &format!("`{}` contains a path separator or NUL", component)))
+    }
+    Ok(())
+}
+
+/// Reads a byte string field as text, replacing any bytes which aren't valid UTF-8
+fn string_field(m: &bencode::Dict, field: &str) -> Result<String, Error> {
+    let bytes = m.get_bytes(field).ok_or_else(|| Error::missing(field))?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+impl Info {
+    /// Returns the relative path and length of every file in the torrent, in the order their data
+    /// is laid out in pieces
+    pub fn files(&self) -> Vec<(PathBuf, u64)> {
+        if self.files.is_empty() {
+            return vec![(PathBuf::from(&self.name), self.length)]
+        }
+
+        self.files.iter().map(|f| {
+            let mut path = PathBuf::from(&self.name);
+            path.extend(f.path.iter());
+            (path, f.length)
+        }).collect()
+    }
+
+    /// Returns the attributes of every file, in the same order as `files`, along with the target
+    /// of every symlink relative to the directory containing it
+    pub fn file_attrs(&self) -> Vec<(FileAttr, Option<PathBuf>)> {
+        if self.files.is_empty() {
+            return vec![(self.attr, None)]
+        }
+
+        self.files.iter().map(|f| {
+            let target = f.symlink_path.as_ref().map(|target| {
+                let mut relative: PathBuf = f.path[1..].iter().map(|_| "..").collect();
+                relative.extend(target.iter());
+                relative
+            });
+            (f.attr, target)
+        }).collect()
+    }
+
+    /// Returns the length of the piece with the given index; every piece but the last one has the
+    /// full piece length. Pieces without a v1 hash, which is all of them for a v2-only torrent,
+    /// have a length of 0
+    pub fn piece_size(&self, index: usize) -> u32 {
+        let n = self.pieces.len();
+        if index >= n {
+            0
+        } else if index == n - 1 {
+            (self.length - self.piece_length as u64 * (n as u64 - 1)) as u32
+        } else {
+            self.piece_length
         }
     }
 }
@@ -96,26 +678,130 @@ impl FromBencode for Info {
 ///
 /// # Example
 ///
-/// ```
-/// # use metainfo;
+/// ```no_run
+/// # use bittorrent::metainfo;
 /// let m = metainfo::from_file("data/flagfromserver.torrent");
 /// ```
-pub fn from_file(filename: &String) -> Result<MetaInfo, Error> {
-    let mut f = File::open(filename).unwrap();
+pub fn from_file<P: AsRef<Path>>(filename: P) -> Result<MetaInfo, Error> {
+    let mut f = File::open(filename)?;
     let mut s = Vec::new();
-    f.read_to_end(&mut s).unwrap();
+    f.read_to_end(&mut s)?;
+    from_bytes(&s)
+}
 
-    let torrent: Bencode = bencode::from_vec(s).unwrap();
+/// Parses the contents of a metainfo file
+pub fn from_bytes(bytes: &[u8]) -> Result<MetaInfo, Error> {
+    let torrent = bencode::decode(bytes)?;
     FromBencode::from_bencode(&torrent)
 }
 
+/// Builds the metainfo of a torrent from the bytes of its info dictionary, as fetched from peers
+/// for a magnet link, along with the trackers and web seeds the link named. A link may name
+/// neither, as its peers can be given directly
+pub fn from_info(info: &[u8], trackers: &[String], url_list: &[String]) -> Result<MetaInfo, Error> {
+    // canonical bencode encodes back to the same bytes, so the info hash is unchanged
+    let info = bencode::decode(info)?;
+    let mut torrent = BTreeMap::new();
+    if let Some(announce) = trackers.first() {
+        torrent.insert(b"announce".to_vec(), announce.to_bencode());
+    }
+    if trackers.len() > 1 {
+        let tiers = trackers.iter().map(|t| Bencode::List(vec![t.to_bencode()])).collect();
+        torrent.insert(b"announce-list".to_vec(), Bencode::List(tiers));
+    }
+    torrent.insert(b"info".to_vec(), info.to_bencode());
+    if !url_list.is_empty() {
+        torrent.insert(b"url-list".to_vec(), url_list.to_vec().to_bencode());
+    }
+    let bytes = Bencode::Dict(torrent).encode();
+    metainfo_from_bencode(&bencode::decode(&bytes)?, false)
+}
+
+/// Builds the metainfo of a v1 torrent holding the given data, for tests. The data is split into
+/// files of the given paths and lengths, with `/` between path components, or kept in a single
+/// file named after the torrent if no files are given. The info hash is all ones
+#[cfg(test)]
+pub fn test_metainfo(name: &str, piece_length: u32, data: &[u8], files: &[(&str, u64)]) -> MetaInfo {
+    let pieces: Vec<Vec<u8>> = data.chunks(piece_length as usize).map(hash::sha).collect();
+    MetaInfo {
+        announce: Some(String::from("http://127.0.0.1:1/announce")),
+        announce_list: vec![],
+        created_by: String::new(),
+        info: Info {
+            piece_length,
+            num_pieces: pieces.len() as u32,
+            pieces,
+            name: String::from(name),
+            length: data.len() as u64,
+            private: false,
+            files: files.iter().map(|&(path, length)| FileInfo {
+                path: path.split('/').map(String::from).collect(),
+                length,
+                attr: FileAttr::default(),
+                symlink_path: None,
+            }).collect(),
+            attr: FileAttr::default(),
+            meta_version: 1,
+            file_tree: vec![],
+        },
+        info_hash: vec![1; 20],
+        url_list: vec![],
+        info_hash_v2: None,
+        piece_layers: HashMap::new(),
+    }
+}
+
 #[cfg(test)]
 mod metainfo_tests {
     use bencode;
     use std::io::prelude::*;
     use std::fs::File;
     use super::{MetaInfo, FromBencode};
-    use util::*;
+    use bencode::{Bencode, ToBencode};
+    use std::collections::BTreeMap;
+    use error::Error;
+    use hash;
+    use merkle;
+    use super::FileAttr;
+    use std::path::PathBuf;
+
+    /// Decodes a metainfo file whose info dictionary has the given entries
+    fn decode_info(entries: Vec<(&str, Bencode)>) -> Result<MetaInfo, Error> {
+        decode_torrent(entries, BTreeMap::new())
+    }
+
+    fn dict(entries: Vec<(&[u8], Bencode)>) -> Bencode {
+        Bencode::Dict(entries.into_iter().map(|(k, v)| (k.to_vec(), v)).collect())
+    }
+
+    /// Decodes a metainfo file with the given info entries and additional top level entries
+    fn decode_torrent(entries: Vec<(&str, Bencode)>, top: BTreeMap<Vec<u8>, Bencode>) -> Result<MetaInfo, Error> {
+        let info: BTreeMap<Vec<u8>, Bencode> = entries.into_iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v))
+            .collect();
+        let mut m = top;
+        m.insert(b"announce".to_vec(), "http://tracker/announce".to_bencode());
+        m.insert(b"info".to_vec(), Bencode::Dict(info));
+
+        let bytes = Bencode::Dict(m).encode();
+        let torrent = bencode::decode(&bytes).unwrap();
+        FromBencode::from_bencode(&torrent)
+    }
+
+    fn file(path: Vec<&str>, length: i64) -> Bencode {
+        let mut m = BTreeMap::new();
+        m.insert(b"length".to_vec(), Bencode::Integer(length));
+        m.insert(b"path".to_vec(), Bencode::List(path.into_iter().map(|c| c.to_bencode()).collect()));
+        Bencode::Dict(m)
+    }
+
+    fn invalid_field(result: Result<MetaInfo, Error>) -> String {
+        match result {
+            Err(Error::InvalidField(field, _)) => field,
+            Err(Error::MissingField(field)) => format!("missing {}", field),
+            other => panic!("Expected a field error, got {:?}", other)
+        }
+    }
 
     #[test]
     fn flagfromserver_torrent_test() {
@@ -123,15 +809,330 @@ mod metainfo_tests {
         let mut s = Vec::new();
         f.read_to_end(&mut s).unwrap();
 
-        let torrent: bencode::Bencode = bencode::from_vec(s).unwrap();
+        let torrent = bencode::decode(&s).unwrap();
         let decoded: Result<MetaInfo, Error> = FromBencode::from_bencode(&torrent);
 
         match decoded {
             Ok(metainfo) => {
-                assert_eq!(metainfo.announce, "http://thomasballinger.com:6969/announce");
+                assert_eq!(metainfo.announce.unwrap(), "http://thomasballinger.com:6969/announce");
                 assert_eq!(metainfo.info.name, "flag.jpg");
             }
             _ => panic!("Decoded bencode incorrectly")
         }
     }
+
+    #[test]
+    fn valid_info_test() {
+        let m = decode_info(vec![
+            ("files", Bencode::List(vec![file(vec!["a", "b.txt"], 10), file(vec!["c"], 0), file(vec!["d"], 20)])),
+            ("name", "dir".to_bencode()),
+            ("piece length", Bencode::Integer(16)),
+            ("pieces", Bencode::Bytes(vec![0; 40])),
+        ]).unwrap();
+        assert_eq!(m.info.length, 30);
+        assert_eq!(m.info.files[0].path, vec!["a", "b.txt"]);
+    }
+
+    #[test]
+    fn piece_size_test() {
+        let m = decode_info(vec![
+            ("length", Bencode::Integer(30)),
+            ("name", "a".to_bencode()),
+            ("piece length", Bencode::Integer(16)),
+            ("pieces", Bencode::Bytes(vec![0; 40])),
+        ]).unwrap();
+        // the last piece holds whatever is left of the total length
+        assert_eq!((m.info.piece_size(0), m.info.piece_size(1)), (16, 14));
+        assert_eq!(m.piece_infos().iter().map(|p| p.length).collect::<Vec<u32>>(), vec![16, 14]);
+        assert_eq!(m.info.piece_size(2), 0);
+
+        let mut v2_only = m.info.clone();
+        v2_only.pieces = vec![];
+        assert_eq!(v2_only.piece_size(0), 0);
+    }
+
+    #[test]
+    fn invalid_info_test() {
+        let single = |name: Bencode, length: i64, piece_length: i64, pieces: usize| decode_info(vec![
+            ("length", Bencode::Integer(length)),
+            ("name", name),
+            ("piece length", Bencode::Integer(piece_length)),
+            ("pieces", Bencode::Bytes(vec![0; pieces])),
+        ]);
+
+        assert!(single("ok".to_bencode(), 30, 16, 40).is_ok());
+        assert_eq!(invalid_field(single("ok".to_bencode(), 30, 16, 39)), "info.pieces");
+        assert_eq!(invalid_field(single("ok".to_bencode(), 30, 16, 60)), "info.pieces");
+        assert_eq!(invalid_field(single("ok".to_bencode(), 30, 0, 40)), "info.piece length");
+        assert_eq!(invalid_field(single("ok".to_bencode(), -1, 16, 0)), "info.length");
+        assert_eq!(invalid_field(single("ok".to_bencode(), 0, 16, 0)), "info.length");
+        assert_eq!(invalid_field(single("..".to_bencode(), 30, 16, 40)), "info.name");
+        assert_eq!(invalid_field(single("a/b".to_bencode(), 30, 16, 40)), "info.name");
+        assert_eq!(invalid_field(single(Bencode::Bytes(vec![0xff]), 30, 16, 40)), "info.name");
+        assert_eq!(invalid_field(single(Bencode::Integer(1), 30, 16, 40)), "info.name");
+
+        assert_eq!(invalid_field(decode_info(vec![
+            ("name", "ok".to_bencode()),
+            ("piece length", Bencode::Integer(16)),
+            ("pieces", Bencode::Bytes(vec![0; 20])),
+        ])), "missing info.length");
+
+        let files = |files: Vec<Bencode>| decode_info(vec![
+            ("files", Bencode::List(files)),
+            ("name", "dir".to_bencode()),
+            ("piece length", Bencode::Integer(16)),
+            ("pieces", Bencode::Bytes(vec![0; 20])),
+        ]);
+        assert_eq!(invalid_field(files(vec![])), "info.files");
+        assert_eq!(invalid_field(files(vec![file(vec!["a"], 5), file(vec!["..", "etc"], 5)])), "info.files[1].path[0]");
+        assert_eq!(invalid_field(files(vec![file(vec![], 5)])), "info.files[0].path");
+        assert_eq!(invalid_field(files(vec![file(vec!["a"], -5)])), "info.files[0].length");
+        let huge = || file(vec!["a"], i64::MAX);
+        assert_eq!(invalid_field(files(vec![huge(), huge(), huge()])), "info.files");
+    }
+
+    #[test]
+    fn announce_test() {
+        let info = dict(vec![
+            (b"length", Bencode::Integer(16)),
+            (b"name", "a".to_bencode()),
+            (b"piece length", Bencode::Integer(16)),
+            (b"pieces", Bencode::Bytes(vec![0; 20])),
+        ]);
+        let decode = |entry: Option<(&[u8], Bencode)>| {
+            let mut entries = vec![(&b"info"[..], info.clone())];
+            entries.extend(entry);
+            super::from_bytes(&dict(entries).encode())
+        };
+
+        assert_eq!(invalid_field(decode(None)), "missing announce");
+        let tiers = Bencode::List(vec![Bencode::List(vec!["http://b/announce".to_bencode()])]);
+        assert_eq!(decode(Some((b"announce-list", tiers))).unwrap().announce.unwrap(), "http://b/announce");
+        let tiers = Bencode::List(vec![
+            Bencode::List(vec!["http://b/announce".to_bencode(), "http://c/announce".to_bencode()]),
+            Bencode::List(vec!["http://b/announce".to_bencode()]),
+        ]);
+        assert_eq!(decode(Some((b"announce-list", tiers))).unwrap().trackers(), vec!["http://b/announce", "http://c/announce"]);
+        let m = decode(Some((b"url-list", "http://seed/a".to_bencode()))).unwrap();
+        assert_eq!(m.announce, None);
+        assert_eq!(m.url_list, vec!["http://seed/a"]);
+        assert_eq!(decode(Some((b"nodes", Bencode::List(vec![])))).unwrap().announce, None);
+    }
+
+    #[test]
+    fn utf8_name_test() {
+        let m = decode_info(vec![
+            ("length", Bencode::Integer(5)),
+            ("name", Bencode::Bytes(vec![0xe9, 0x74, 0xe9])),
+            ("name.utf-8", "\u{e9}t\u{e9}".to_bencode()),
+            ("piece length", Bencode::Integer(16)),
+            ("pieces", Bencode::Bytes(vec![0; 20])),
+        ]).unwrap();
+        assert_eq!(m.info.name, "\u{e9}t\u{e9}");
+    }
+
+    /// Returns the file tree entry of a file with the given data and its piece layer
+    fn tree_file(data: &[u8], piece_length: u32) -> (Bencode, Vec<u8>) {
+        let (root, layer) = merkle::file_hashes(data, piece_length);
+        let file = dict(vec![(b"", dict(vec![
+            (b"length", Bencode::Integer(data.len() as i64)),
+            (b"pieces root", Bencode::Bytes(root)),
+        ]))]);
+        (file, layer.concat())
+    }
+
+    #[test]
+    fn v2_info_test() {
+        let big: Vec<u8> = (0..3 * merkle::BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let small = vec![9; 100];
+        let (big_file, big_layer) = tree_file(&big, 32768);
+        let (small_file, _) = tree_file(&small, 32768);
+        let big_root = merkle::file_hashes(&big, 32768).0;
+
+        let v2 = |piece_length: i64, version: i64, layer: Option<Vec<u8>>| {
+            let mut top = BTreeMap::new();
+            if let Some(layer) = layer {
+                top.insert(b"piece layers".to_vec(), dict(vec![(&big_root, Bencode::Bytes(layer))]));
+            }
+            decode_torrent(vec![
+                ("file tree", dict(vec![
+                    (b"dir", dict(vec![(b"big.bin", big_file.clone())])),
+                    (b"small.txt", small_file.clone()),
+                ])),
+                ("meta version", Bencode::Integer(version)),
+                ("name", "v2".to_bencode()),
+                ("piece length", Bencode::Integer(piece_length)),
+            ], top)
+        };
+
+        let m = v2(32768, 2, Some(big_layer.clone())).unwrap();
+        assert_eq!(m.info.meta_version, 2);
+        assert!(m.info.pieces.is_empty());
+        assert_eq!(m.info.length, big.len() as u64 + 100);
+        assert_eq!(m.info.num_pieces, 3);
+        assert_eq!(m.info.files[0].path, vec!["dir", "big.bin"]);
+        assert_eq!(m.info.files[1].path, vec!["small.txt"]);
+        assert_eq!(m.info_hash[..], m.info_hash_v2.as_ref().unwrap()[..20]);
+        assert_eq!(m.info_hashes().len(), 1);
+
+        let data = [&big[..], &small[..]].concat();
+        let pieces = m.piece_infos();
+        assert_eq!(pieces.iter().map(|p| (p.offset, p.length)).collect::<Vec<_>>(),
+                   vec![(0, 32768), (32768, 16484), (49252, 100)]);
+        for piece in pieces.iter() {
+            let piece_data = &data[piece.offset as usize..(piece.offset + piece.length as u64) as usize];
+            assert!(piece.hash_type.matches(&piece.hash, piece_data));
+        }
+
+        let mut corrupt = big_layer.clone();
+        corrupt[0] ^= 1;
+        assert_eq!(invalid_field(v2(32768, 2, Some(corrupt))), "piece layers.dir/big.bin");
+        assert_eq!(invalid_field(v2(32768, 2, Some(big_layer[..32].to_vec()))), "piece layers.dir/big.bin");
+        assert_eq!(invalid_field(v2(32768, 2, None)), "missing piece layers.dir/big.bin");
+        assert_eq!(invalid_field(v2(20000, 2, Some(big_layer.clone()))), "info.piece length");
+        assert_eq!(invalid_field(v2(32768, 3, Some(big_layer))), "info.meta version");
+
+        // files too large to count their pieces in 32 bits, or their bytes in 64
+        let huge = |count: u8| decode_info(vec![
+            ("file tree", Bencode::Dict((0..count).map(|i| (vec![b'a' + i], dict(vec![(b"", dict(vec![
+                (b"length", Bencode::Integer(i64::MAX)),
+                (b"pieces root", Bencode::Bytes(vec![1; 32])),
+            ]))]))).collect())),
+            ("meta version", Bencode::Integer(2)),
+            ("name", "v2".to_bencode()),
+            ("piece length", Bencode::Integer(16384)),
+        ]);
+        assert_eq!(invalid_field(huge(1)), "info.file tree");
+        assert_eq!(invalid_field(huge(3)), "info.file tree");
+    }
+
+    #[test]
+    fn hybrid_info_test() {
+        let data = vec![3; 20000];
+        let (file, _) = tree_file(&data, 32768);
+        let hybrid = |length: i64| decode_info(vec![
+            ("file tree", dict(vec![(b"h.bin", file.clone())])),
+            ("length", Bencode::Integer(length)),
+            ("meta version", Bencode::Integer(2)),
+            ("name", "h.bin".to_bencode()),
+            ("piece length", Bencode::Integer(32768)),
+            ("pieces", Bencode::Bytes(hash::sha(&data))),
+        ]);
+
+        let m = hybrid(20000).unwrap();
+        assert!(m.info.files.is_empty());
+        assert_eq!(m.info.file_tree.len(), 1);
+        assert_eq!(m.info_hash.len(), 20);
+        assert_eq!(m.info_hashes(), vec![m.info_hash.clone(), m.info_hash_v2.clone().unwrap()[..20].to_vec()]);
+        // hybrid torrents keep checking pieces against their SHA-1 hashes
+        assert_eq!(m.piece_infos()[0].hash, hash::sha(&data));
+        assert_eq!(invalid_field(hybrid(20001)), "info.file tree");
+    }
+
+    #[test]
+    fn file_attrs_test() {
+        let with = |file: Bencode, entries: Vec<(&[u8], Bencode)>| match file {
+            Bencode::Dict(mut m) => {
+                m.extend(entries.into_iter().map(|(k, v)| (k.to_vec(), v)));
+                Bencode::Dict(m)
+            },
+            _ => unreachable!()
+        };
+        let files = |files: Vec<Bencode>| decode_info(vec![
+            ("files", Bencode::List(files)),
+            ("name", "dir".to_bencode()),
+            ("piece length", Bencode::Integer(16)),
+            ("pieces", Bencode::Bytes(vec![0; 40])),
+        ]);
+
+        let m = files(vec![
+            with(file(vec!["run.sh"], 10), vec![(b"attr", "xh".to_bencode())]),
+            with(file(vec![".pad", "6"], 6), vec![(b"attr", "p".to_bencode())]),
+            with(file(vec!["sub", "link"], 0), vec![
+                (b"attr", "l".to_bencode()),
+                (b"symlink path", Bencode::List(vec!["run.sh".to_bencode()])),
+            ]),
+            file(vec!["data"], 16),
+        ]).unwrap();
+        let attrs = m.info.file_attrs();
+        assert!(attrs[0].0.executable && attrs[0].0.hidden && !attrs[0].0.padding);
+        assert!(attrs[1].0.padding);
+        assert_eq!(attrs[2], (FileAttr { symlink: true, ..FileAttr::default() }, Some(PathBuf::from("../run.sh"))));
+        assert_eq!(attrs[3], (FileAttr::default(), None));
+
+        assert_eq!(invalid_field(files(vec![
+            with(file(vec!["link"], 0), vec![(b"attr", "l".to_bencode())]),
+            file(vec!["data"], 32),
+        ])), "missing info.files[0].symlink path");
+        assert_eq!(invalid_field(files(vec![
+            with(file(vec!["data"], 32), vec![(b"symlink path", Bencode::List(vec!["a".to_bencode()]))]),
+        ])), "info.files[0].symlink path");
+        assert_eq!(invalid_field(files(vec![
+            with(file(vec!["link"], 0), vec![
+                (b"attr", "l".to_bencode()),
+                (b"symlink path", Bencode::List(vec!["..".to_bencode()])),
+            ]),
+            file(vec!["data"], 32),
+        ])), "info.files[0].symlink path[0]");
+        assert_eq!(invalid_field(files(vec![
+            with(file(vec!["link"], 5), vec![
+                (b"attr", "l".to_bencode()),
+                (b"symlink path", Bencode::List(vec!["data".to_bencode()])),
+            ]),
+            file(vec!["data"], 27),
+        ])), "info.files[0].length");
+    }
+
+    #[test]
+    fn hybrid_padding_test() {
+        let a = vec![1; 20000];
+        let b = vec![2; 100];
+        let (a_file, _) = tree_file(&a, 32768);
+        let (b_file, _) = tree_file(&b, 32768);
+        let mut data = a.clone();
+        data.resize(32768, 0);
+        data.extend(&b);
+
+        let pad = match file(vec![".pad", "12768"], 12768) {
+            Bencode::Dict(mut m) => {
+                m.insert(b"attr".to_vec(), "p".to_bencode());
+                Bencode::Dict(m)
+            },
+            _ => unreachable!()
+        };
+        let m = decode_info(vec![
+            ("file tree", dict(vec![(b"a", a_file), (b"b", b_file)])),
+            ("files", Bencode::List(vec![file(vec!["a"], 20000), pad, file(vec!["b"], 100)])),
+            ("meta version", Bencode::Integer(2)),
+            ("name", "hybrid".to_bencode()),
+            ("piece length", Bencode::Integer(32768)),
+            ("pieces", Bencode::Bytes([hash::sha(&data[..32768]), hash::sha(&data[32768..])].concat())),
+        ]).unwrap();
+        assert_eq!(m.info.files.len(), 3);
+        assert_eq!(m.info.file_tree.len(), 2);
+        assert!(m.info.file_attrs()[1].0.padding);
+    }
+
+    #[test]
+    fn from_info_test() {
+        let mut info = BTreeMap::new();
+        info.insert(b"length".to_vec(), Bencode::Integer(5));
+        info.insert(b"name".to_vec(), "x".to_bencode());
+        info.insert(b"piece length".to_vec(), Bencode::Integer(16384));
+        info.insert(b"pieces".to_vec(), Bencode::Bytes(vec![0; 20]));
+        let bytes = Bencode::Dict(info).encode();
+
+        let urls = vec![String::from("http://seed/x")];
+        let trackers = vec![String::from("http://tracker/announce"), String::from("http://backup/announce")];
+        let m = super::from_info(&bytes, &trackers, &urls).unwrap();
+        assert_eq!(m.info_hash, hash::sha(&bytes));
+        assert_eq!(m.trackers(), trackers);
+        assert_eq!(m.url_list, urls);
+        assert_eq!(m.info.name, "x");
+
+        // a link naming neither trackers nor web seeds leaves the torrent without a tracker
+        let m = super::from_info(&bytes, &[], &[]).unwrap();
+        assert_eq!((m.announce, m.announce_list), (None, vec![]));
+        assert!(super::from_info(b"i1e", &[], &[]).is_err());
+    }
 }
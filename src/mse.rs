@@ -0,0 +1,354 @@
+use num_bigint::BigUint;
+use rand::{thread_rng, Rng};
+use std::net::SocketAddr;
+use std::io::{Read, Write, Error, ErrorKind};
+use std::thread;
+use std::time::Duration;
+use stream::{PeerStream, Transport, TransportKind};
+use util::u32_to_bytes;
+use hash;
+
+/// The 768 bit safe prime used for the Diffie-Hellman key exchange of Message Stream Encryption
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_LENGTH: usize = 96;
+const PRIVATE_KEY_LENGTH: usize = 20;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0; 8];
+const HANDSHAKE_TIMEOUT: u64 = 30;
+const PROTOCOL: &str = "BitTorrent protocol";
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// Decides whether connections use Message Stream Encryption. `Enabled` prefers RC4 but falls back
+/// to plaintext for peers which don't support it, while `Forced` refuses any plaintext connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionPolicy {
+    Disabled,
+    Enabled,
+    Forced,
+}
+
+/// The RC4 stream cipher, used to obfuscate the handshake and, if selected, the whole connection
+#[derive(Debug, Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts the given data in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+/// Builds the cipher for one direction of a connection from the shared secret and the info hash,
+/// discarding the first 1024 bytes of keystream as the specification requires
+fn cipher(label: &str, secret: &[u8], skey: &[u8]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash::sha(&concat(&[label.as_bytes(), secret, skey])));
+    rc4.apply(&mut [0; 1024]);
+    rc4
+}
+
+/// Connects to a peer over the given transport according to the encryption policy. With
+/// `Enabled`, peers which don't answer the encrypted handshake are reconnected to in plaintext
+pub fn connect(addr: &SocketAddr, kind: TransportKind, info_hash: &[u8], policy: EncryptionPolicy) -> Result<PeerStream, Error> {
+    let stream = Transport::connect(addr, kind)?;
+    match policy {
+        EncryptionPolicy::Disabled => Ok(PeerStream::plain(stream)),
+        EncryptionPolicy::Forced => initiate(stream, info_hash, CRYPTO_RC4),
+        EncryptionPolicy::Enabled => {
+            match initiate(stream, info_hash, CRYPTO_RC4 | CRYPTO_PLAINTEXT) {
+                Ok(s) => Ok(s),
+                Err(e) => {
+                    debug!(peer:% = addr; "Encrypted handshake failed ({}), retrying in plaintext", e);
+                    Ok(PeerStream::plain(Transport::connect(addr, kind)?))
+                }
+            }
+        }
+    }
+}
+
+/// Performs the encrypted handshake as the connecting side, offering the given crypto methods,
+/// and returns a stream using the method selected by the peer
+pub fn initiate(mut stream: Transport, skey: &[u8], crypto_provide: u32) -> Result<PeerStream, Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)))?;
+    let (private_key, public_key) = generate_keys();
+    stream.write_all(&concat(&[&public_key, &random_pad()]))?;
+
+    let their_key = read_exact(&mut stream, KEY_LENGTH)?;
+    let secret = shared_secret(&private_key, &their_key);
+    let mut encryptor = cipher("keyA", &secret, skey);
+    let mut decryptor = cipher("keyB", &secret, skey);
+
+    // VC, crypto_provide, an empty PadC and an empty initial payload
+    let mut payload = concat(&[&VC, &u32_to_bytes(crypto_provide), &[0, 0], &[0, 0]]);
+    encryptor.apply(&mut payload);
+    let req1 = hash::sha(&concat(&[b"req1", &secret]));
+    let req2 = hash::sha(&concat(&[b"req2", skey]));
+    let req3 = hash::sha(&concat(&[b"req3", &secret]));
+    stream.write_all(&concat(&[&req1, &xor(&req2, &req3), &payload]))?;
+
+    // the peer's answer starts after PadB with VC encrypted under its key
+    let mut expected_vc = VC.to_vec();
+    decryptor.apply(&mut expected_vc);
+    synchronize(&mut stream, &expected_vc, MAX_PAD + VC.len())?;
+
+    let mut answer = read_exact(&mut stream, 6)?;
+    decryptor.apply(&mut answer);
+    let crypto_select = ((answer[2] as u32) << 8) | answer[3] as u32;
+    let pad_length = ((answer[4] as usize) << 8) | answer[5] as usize;
+    if pad_length > MAX_PAD {
+        return Err(Error::new(ErrorKind::InvalidData, "PadD is too long"))
+    }
+    let mut pad = read_exact(&mut stream, pad_length)?;
+    decryptor.apply(&mut pad);
+    stream.set_read_timeout(None)?;
+
+    if crypto_select & crypto_provide == 0 || crypto_select.count_ones() != 1 {
+        return Err(Error::new(ErrorKind::InvalidData, "Peer selected a crypto method which wasn't provided"))
+    }
+
+    if crypto_select == CRYPTO_RC4 {
+        Ok(PeerStream::encrypted(stream, encryptor, decryptor))
+    } else {
+        Ok(PeerStream::plain(stream))
+    }
+}
+
+/// Accepts a connection which a peer opened to us, answering either a plaintext or an encrypted
+/// handshake as the policy allows. The encrypted handshake identifies the torrent by its hashed
+/// info hash, so the info hashes of every torrent we serve are needed to match it. Returns the
+/// stream and, for encrypted connections, the matched info hash
+pub fn accept(mut stream: Transport, info_hashes: &[Vec<u8>], policy: EncryptionPolicy) -> Result<(PeerStream, Option<Vec<u8>>), Error> {
+    if is_plaintext_handshake(&stream)? {
+        if policy == EncryptionPolicy::Forced {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Plaintext connections are not allowed"))
+        }
+        return Ok((PeerStream::plain(stream), None))
+    }
+
+    if policy == EncryptionPolicy::Disabled {
+        return Err(Error::new(ErrorKind::PermissionDenied, "Encrypted connections are not allowed"))
+    }
+
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)))?;
+    let their_key = read_exact(&mut stream, KEY_LENGTH)?;
+    let (private_key, public_key) = generate_keys();
+    stream.write_all(&concat(&[&public_key, &random_pad()]))?;
+    let secret = shared_secret(&private_key, &their_key);
+
+    let req1 = hash::sha(&concat(&[b"req1", &secret]));
+    synchronize(&mut stream, &req1, MAX_PAD + req1.len())?;
+
+    let req3 = hash::sha(&concat(&[b"req3", &secret]));
+    let req2 = xor(&read_exact(&mut stream, 20)?, &req3);
+    let skey = match info_hashes.iter().find(|h| hash::sha(&concat(&[b"req2", h])) == req2) {
+        Some(h) => h.clone(),
+        None => return Err(Error::new(ErrorKind::NotFound, "Peer requested an unknown torrent"))
+    };
+
+    let mut encryptor = cipher("keyB", &secret, &skey);
+    let mut decryptor = cipher("keyA", &secret, &skey);
+
+    let mut request = read_exact(&mut stream, 14)?;
+    decryptor.apply(&mut request);
+    if request[0..8] != VC {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid verification constant"))
+    }
+    let crypto_provide = ((request[10] as u32) << 8) | request[11] as u32;
+    let pad_length = ((request[12] as usize) << 8) | request[13] as usize;
+    if pad_length > MAX_PAD {
+        return Err(Error::new(ErrorKind::InvalidData, "PadC is too long"))
+    }
+
+    let mut pad = read_exact(&mut stream, pad_length + 2)?;
+    decryptor.apply(&mut pad);
+    let initial_length = ((pad[pad_length] as usize) << 8) | pad[pad_length + 1] as usize;
+    let mut initial_payload = read_exact(&mut stream, initial_length)?;
+    decryptor.apply(&mut initial_payload);
+
+    let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if crypto_provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Forced {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(Error::new(ErrorKind::PermissionDenied, "No acceptable crypto method provided"))
+    };
+
+    let mut answer = concat(&[&VC, &u32_to_bytes(crypto_select), &[0, 0]]);
+    encryptor.apply(&mut answer);
+    stream.write_all(&answer)?;
+    stream.set_read_timeout(None)?;
+
+    let peer_stream = if crypto_select == CRYPTO_RC4 {
+        PeerStream::encrypted(stream, encryptor, decryptor)
+    } else {
+        PeerStream::plain(stream)
+    };
+    Ok((peer_stream.with_pending(initial_payload), Some(skey)))
+}
+
+/// Checks whether the peer started with a regular BitTorrent handshake, without consuming it
+fn is_plaintext_handshake(stream: &Transport) -> Result<bool, Error> {
+    let mut buf = [0; 20];
+    for _ in 0..(HANDSHAKE_TIMEOUT * 100) {
+        let n = stream.peek(&mut buf)?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Peer closed the connection"))
+        }
+        if buf[0] != PROTOCOL.len() as u8 {
+            return Ok(false)
+        }
+        if n == buf.len() {
+            return Ok(&buf[1..] == PROTOCOL.as_bytes())
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Err(Error::new(ErrorKind::TimedOut, "Timed out waiting for handshake"))
+}
+
+/// Generates a random private key and the matching public key to send to the peer
+fn generate_keys() -> (BigUint, Vec<u8>) {
+    let bytes: Vec<u8> = thread_rng().gen_iter::<u8>().take(PRIVATE_KEY_LENGTH).collect();
+    let private_key = BigUint::from_bytes_be(&bytes);
+    let public_key = BigUint::from(GENERATOR).modpow(&private_key, &prime());
+    (private_key, pad_key(public_key.to_bytes_be()))
+}
+
+fn shared_secret(private_key: &BigUint, their_key: &[u8]) -> Vec<u8> {
+    let secret = BigUint::from_bytes_be(their_key).modpow(private_key, &prime());
+    pad_key(secret.to_bytes_be())
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).unwrap()
+}
+
+/// Left-pads a key with zeros to the 96 bytes sent over the wire
+fn pad_key(key: Vec<u8>) -> Vec<u8> {
+    let mut padded = vec![0; KEY_LENGTH - key.len()];
+    padded.extend(key);
+    padded
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = thread_rng();
+    let length = rng.gen_range(0, MAX_PAD + 1);
+    rng.gen_iter::<u8>().take(length).collect()
+}
+
+/// Reads from the stream until the given pattern has been read, skipping at most `limit` bytes
+/// of random padding before it
+fn synchronize(stream: &mut Transport, pattern: &[u8], limit: usize) -> Result<(), Error> {
+    let mut buf = vec![];
+    let mut byte = [0];
+    while buf.len() < limit {
+        stream.read_exact(&mut byte)?;
+        buf.push(byte[0]);
+        if buf.ends_with(pattern) {
+            return Ok(())
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "Could not synchronize encrypted handshake"))
+}
+
+fn read_exact(stream: &mut Transport, n: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; n];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn concat(parts: &[&[u8]]) -> Vec<u8> {
+    parts.iter().flat_map(|p| p.iter().cloned()).collect()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+#[cfg(test)]
+mod mse_tests {
+    use super::{Rc4, EncryptionPolicy, accept, initiate, CRYPTO_RC4, CRYPTO_PLAINTEXT};
+    use std::net::{TcpListener, TcpStream};
+    use std::io::{Read, Write};
+    use stream::Transport;
+    use std::thread;
+
+    #[test]
+    fn rc4_test() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, vec![0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    fn handshake(crypto_provide: u32, policy: EncryptionPolicy) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = vec![7; 20];
+
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut s = initiate(Transport::Tcp(stream), &[7; 20], crypto_provide).unwrap();
+            s.write_all(b"hello").unwrap();
+            s.is_encrypted()
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let (mut s, skey) = accept(Transport::Tcp(stream), &[vec![1; 20], info_hash.clone()], policy).unwrap();
+        let mut buf = [0; 5];
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(skey, Some(info_hash));
+        assert_eq!(client.join().unwrap(), s.is_encrypted());
+        s.is_encrypted()
+    }
+
+    #[test]
+    fn encrypted_handshake_test() {
+        assert!(handshake(CRYPTO_RC4 | CRYPTO_PLAINTEXT, EncryptionPolicy::Enabled));
+        assert!(handshake(CRYPTO_RC4, EncryptionPolicy::Forced));
+        assert!(!handshake(CRYPTO_PLAINTEXT, EncryptionPolicy::Enabled));
+    }
+
+    #[test]
+    fn forced_policy_rejects_plaintext_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut handshake = vec![19];
+            handshake.extend(b"BitTorrent protocol".iter());
+            stream.write_all(&handshake).unwrap();
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        client.join().unwrap();
+        assert!(accept(Transport::Tcp(stream.try_clone().unwrap()), &[], EncryptionPolicy::Forced).is_err());
+        assert!(accept(Transport::Tcp(stream), &[], EncryptionPolicy::Enabled).is_ok());
+    }
+}
@@ -1,4 +1,4 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Peer {
@@ -23,6 +23,35 @@ impl Peer {
         }
     }
 
+    pub fn from_addr(addr: SocketAddr) -> Self {
+        Peer {
+            ip: addr.ip(),
+            port: addr.port(),
+            have: None,
+            choked: None,
+            interested: None
+        }
+    }
+
+    /// Returns the peer representing this client, listening on the given port
+    pub fn local(port: u16) -> Self {
+        Peer::from_bytes(&[127, 0, 0, 1, (port >> 8) as u8, port as u8])
+    }
+
+    /// Returns the compact representation of an IPv4 peer: four bytes of address followed by two
+    /// bytes of port. IPv6 peers have no compact form
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        match self.ip {
+            IpAddr::V4(ip) => {
+                let mut bytes = ip.octets().to_vec();
+                bytes.push((self.port >> 8) as u8);
+                bytes.push(self.port as u8);
+                Some(bytes)
+            },
+            IpAddr::V6(_) => None
+        }
+    }
+
     pub fn register(&mut self, pieces: usize) {
         match self.have {
             None => self.have = Some(vec![false; pieces]),
@@ -59,6 +88,12 @@ mod peer_tests {
         })
     }
 
+    #[test]
+    fn peer_to_bytes_test() {
+        let bytes = [127, 0, 0, 1, 31, 144];
+        assert_eq!(Peer::from_bytes(&bytes).to_bytes(), Some(bytes.to_vec()));
+    }
+
     #[test]
     fn register_test() {
         let bytes = [127, 0, 0, 1, 31, 144];
@@ -1,11 +1,45 @@
 use block::Block;
-use std::fs::File;
-use std::io;
-use std::io::{Error, Write, Seek};
-use hash;
+use error::Error;
+use metainfo::{PieceHash, PieceInfo};
+use storage::Storage;
 
 static BLOCK_SIZE: u32 = 16384; // 2^14
 
+/// How urgently a file or piece is wanted. Pieces of higher priority are requested first, and
+/// skipped pieces aren't requested at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// Parses the name of a priority as given on the command line
+    pub fn parse(name: &str) -> Option<Priority> {
+        match name {
+            "skip" => Some(Priority::Skip),
+            "low" => Some(Priority::Low),
+            "normal" => Some(Priority::Normal),
+            "high" => Some(Priority::High),
+            _ => None
+        }
+    }
+}
+
+/// What storing a block did to its piece
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stored {
+    // other blocks of the piece are still missing
+    Incomplete,
+    // the piece is whole, matched its hash and was written to storage
+    Verified,
+    // the piece is whole but didn't match its hash, so its blocks were thrown away
+    HashFailed,
+}
+
 /// Represents a Piece of the file to be downloaded, where a Piece is made of many Blocks
 #[derive(Debug, PartialEq)]
 pub struct Piece {
@@ -18,6 +52,10 @@ pub struct Piece {
     pub blocks: Vec<Block>,
     pub hash: Vec<u8>,
     pub is_complete: bool,
+    // position of the piece's first byte within the torrent's data
+    pub offset: u64,
+    pub hash_type: PieceHash,
+    pub priority: Priority,
 }
 
 /// Represents a portion of the data to be downloaded which is described in the metainfo file and
@@ -46,39 +84,62 @@ impl Piece {
             index: index,
             hash: hash,
             blocks: blocks,
-            is_complete: false
+            is_complete: false,
+            offset: index as u64 * piece_length as u64,
+            hash_type: PieceHash::Sha1,
+            priority: Priority::Normal,
         }
     }
 
-    pub fn store(&mut self, file: &mut File, block_index: u32, data: Vec<u8>) -> Result<(), Error> {
+    /// Creates the piece with the given index from its description in the metainfo
+    pub fn from_info(index: u32, piece_length: u32, info: &PieceInfo) -> Self {
+        let mut piece = Piece::new(info.length, index, piece_length, info.hash.clone());
+        piece.offset = info.offset;
+        piece.hash_type = info.hash_type;
+        piece
+    }
+
+    /// Returns whether the data matches the piece's hash
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.hash_type.matches(&self.hash, data)
+    }
+
+    /// Stores a block of the piece. Once every block is there, the piece is checked against its
+    /// hash and written to storage if it matches
+    pub fn store(&mut self, storage: &mut dyn Storage, block_index: u32, data: Vec<u8>) -> Result<Stored, Error> {
         {
-            let block = &mut self.blocks[block_index as usize];
+            let block = match self.blocks.get_mut(block_index as usize) {
+                Some(block) if block.length as usize == data.len() => block,
+                _ => return Err(Error::Protocol(format!("Unexpected block {} of piece {}", block_index, self.index)))
+            };
             block.data = Some(data);
         }
 
-        if self.have_all_blocks() {
-            // concatenate data from blocks together
-            let mut data = vec![];
-            for block in self.blocks.iter() {
-                data.extend(block.data.clone().unwrap());
-            }
+        if !self.have_all_blocks() {
+            return Ok(Stored::Incomplete)
+        }
 
-            // validate that piece data matches SHA1 hash
-            if self.hash == hash::sha(&data) {
-                println!("Piece {} is complete and correct, writing to the file.", self.index);
-                let offset = self.index as u64 * self.piece_length as u64;
-                try!(file.seek(io::SeekFrom::Start(offset)));
-                try!(file.write_all(&data));
-                self.clear_block_data();
-                self.is_complete = true;
-            } else {
-                println!("Piece is corrupt, deleting downloaded piece data!");
-                println!("Expected {:?}", self.hash);
-                println!("Got {:?}", hash::sha(&data));
-                self.clear_block_data();
-            }
+        // concatenate data from blocks together
+        let mut data = vec![];
+        for block in self.blocks.iter() {
+            data.extend(block.data.clone().unwrap());
+        }
+        self.clear_block_data();
+
+        // validate that piece data matches its hash
+        if self.matches(&data) {
+            let offset = self.offset();
+            storage.write_piece(offset, &data).map_err(Error::Storage)?;
+            self.is_complete = true;
+            Ok(Stored::Verified)
+        } else {
+            Ok(Stored::HashFailed)
         }
-        Ok(())
+    }
+
+    /// Returns the position of the piece's first byte within the torrent's data
+    pub fn offset(&self) -> u64 {
+        self.offset
     }
 
     pub fn next_block_to_request(&self) -> Option<&Block> {
@@ -113,8 +174,9 @@ impl Piece {
 
 #[cfg(test)]
 mod piece_tests {
-    use super::Piece;
+    use super::{Piece, Priority};
     use block::Block;
+    use metainfo::PieceHash;
 
     #[test]
     fn make_piece_test() {
@@ -126,6 +188,9 @@ mod piece_tests {
             blocks: vec![Block::new(0, 256)],
             hash: vec![1, 2, 3],
             is_complete: false,
+            offset: 16,
+            hash_type: PieceHash::Sha1,
+            priority: Priority::Normal,
         });
     }
 
@@ -142,6 +207,15 @@ mod piece_tests {
         assert_eq!(p.next_block_to_request(), None);
     }
 
+    #[test]
+    fn parse_priority_test() {
+        assert_eq!(Priority::parse("skip"), Some(Priority::Skip));
+        assert_eq!(Priority::parse("high"), Some(Priority::High));
+        assert_eq!(Priority::parse("urgent"), None);
+        assert!(Priority::Skip < Priority::Low && Priority::Normal < Priority::High);
+        assert_eq!(Priority::default(), Priority::Normal);
+    }
+
     #[test]
     fn have_all_blocks_test() {
         let mut p = Piece::new(256, 4, 4, vec![1, 2, 3]);
@@ -158,6 +232,9 @@ mod piece_tests {
             }],
             hash: vec![1, 2, 3],
             is_complete: false,
+            offset: 16,
+            hash_type: PieceHash::Sha1,
+            priority: Priority::Normal,
         };
         assert_eq!(p.have_all_blocks(), true);
     }
@@ -175,6 +252,9 @@ mod piece_tests {
             }],
             hash: vec![1, 2, 3],
             is_complete: false,
+            offset: 16,
+            hash_type: PieceHash::Sha1,
+            priority: Priority::Normal,
         };
 
         p.clear_block_data();
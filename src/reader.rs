@@ -0,0 +1,167 @@
+use error::Error;
+use ipc::IpcMessage;
+use piece::Priority;
+use torrent::Torrent;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+/// Reads one file of a torrent while it downloads. Reads block until the pieces holding the data
+/// are verified, and move the torrent's playback cursor along for the deadline pick mode
+#[derive(Debug)]
+pub struct FileReader {
+    torrent: Arc<Mutex<Torrent>>,
+    // position of the file's first byte within the torrent's data
+    start: u64,
+    length: u64,
+    position: u64,
+    // receives a message whenever the torrent stores a block
+    stored: Receiver<IpcMessage>,
+}
+
+impl FileReader {
+    /// Creates a reader over the file of the torrent with the given index
+    pub fn new(torrent_mutex: Arc<Mutex<Torrent>>, index: usize) -> Result<Self, Error> {
+        let (tx, rx) = channel::<IpcMessage>();
+        let (start, length) = {
+            let mut torrent = torrent_mutex.lock().unwrap();
+            let files = torrent.metainfo.info.files();
+            let attrs = torrent.metainfo.info.file_attrs();
+            match attrs.get(index) {
+                Some(&(attr, _)) if !attr.padding => {},
+                _ => return Err(Error::invalid("file", &format!("the torrent has no file with index {}", index)))
+            }
+            let start = files[..index].iter().map(|&(_, length)| length).sum();
+            torrent.register_peer(tx);
+            (start, files[index].1)
+        };
+
+        Ok(FileReader {
+            torrent: torrent_mutex,
+            start,
+            length,
+            position: 0,
+            stored: rx,
+        })
+    }
+
+    /// Returns the length of the file
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.length {
+            return Ok(0)
+        }
+
+        let offset = self.start + self.position;
+        loop {
+            {
+                let mut torrent = self.torrent.lock().unwrap();
+                if torrent.is_stopped() {
+                    return Err(io::Error::other("The torrent was removed"))
+                }
+                torrent.set_cursor(offset);
+                let index = torrent.pieces.partition_point(|p| p.offset() + p.length as u64 <= offset);
+                let (complete, priority, end) = match torrent.pieces.get(index) {
+                    Some(piece) => (piece.is_complete, piece.priority, piece.offset() + piece.length as u64),
+                    None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "Read past the end of the torrent"))
+                };
+
+                if complete {
+                    let end = end.min(self.start + self.length);
+                    let length = (end - offset).min(buf.len() as u64) as u32;
+                    let data = torrent.read(offset, length)?;
+                    buf[..data.len()].copy_from_slice(&data);
+                    self.position += data.len() as u64;
+                    return Ok(data.len())
+                }
+                if priority == Priority::Skip {
+                    return Err(io::Error::other("The data belongs to a skipped file"))
+                }
+            }
+
+            // wait until the torrent stores another block, checking again now and then in case
+            // the piece was verified some other way
+            let _ = self.stored.recv_timeout(Duration::from_secs(1));
+            while self.stored.try_recv().is_ok() {}
+        }
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.length.checked_add_signed(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                self.torrent.lock().unwrap().set_cursor(self.start + position);
+                Ok(position)
+            },
+            None => Err(io::Error::new(ErrorKind::InvalidInput, "Seek before the start of the file"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod reader_tests {
+    use super::FileReader;
+    use metainfo::test_metainfo;
+    use piece::Priority;
+    use storage::{Storage, MemoryStorage};
+    use torrent::Torrent;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use util::create_peer_id;
+
+    #[test]
+    fn blocking_read_test() {
+        // two files of 10 and 22 bytes over pieces of 8 bytes
+        let data: Vec<u8> = (0..32).collect();
+        let m = test_metainfo("reader", 8, &data, &[("a", 10), ("b", 22)]);
+
+        let mut storage = MemoryStorage::new();
+        storage.set_len(32).unwrap();
+        let torrent = Arc::new(Mutex::new(Torrent::with_storage(create_peer_id(), m, Box::new(storage))));
+        torrent.lock().unwrap().store_piece(1, data[8..16].to_vec()).unwrap();
+
+        let mut reader = FileReader::new(torrent.clone(), 1).unwrap();
+        assert_eq!(reader.length(), 22);
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], &data[10..16]);
+
+        let writer = torrent.clone();
+        let later = data.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            writer.lock().unwrap().store_piece(3, later[24..].to_vec()).unwrap();
+            writer.lock().unwrap().store_piece(2, later[16..24].to_vec()).unwrap();
+        });
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, &data[16..]);
+
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), 18);
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &data[28..]);
+        assert!(reader.seek(SeekFrom::Current(-30)).is_err());
+
+        // nothing ever arrives for a skipped file
+        torrent.lock().unwrap().set_file_priorities(vec![Priority::Skip, Priority::Normal]).unwrap();
+        let mut skipped = FileReader::new(torrent.clone(), 0).unwrap();
+        assert!(skipped.read(&mut buf).is_err());
+        assert!(FileReader::new(torrent, 2).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use bencode;
+use bencode::{Bencode, Value, FromBencode, ToBencode};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use peer::Peer;
+use util::*;
+use error::Error;
+
+/// The length and modification time of a data file, which tell whether the file was changed since
+/// the resume file was written
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileState {
+    pub length: u64,
+    pub mtime: u64,
+}
+
+impl FileState {
+    /// Reads the state of the file at the given path. A missing file has a length and
+    /// modification time of zero
+    pub fn of(path: &Path) -> FileState {
+        match fs::metadata(path) {
+            Ok(metadata) => {
+                let mtime = metadata.modified().ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_secs());
+                FileState { length: metadata.len(), mtime }
+            },
+            Err(_) => FileState { length: 0, mtime: 0 }
+        }
+    }
+}
+
+/// Returns where the resume file of the torrent with the given name is kept, next to its data in
+/// the given directory
+pub fn resume_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.resume", name))
+}
+
+/// Remembers which pieces of a torrent were downloaded and verified, so that restarting the client
+/// doesn't require hashing all existing data again
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeData {
+    pub have: Vec<bool>,
+    pub files: Vec<FileState>,
+    pub peers: Vec<Peer>,
+}
+
+impl ResumeData {
+    /// Checks whether the resume data can be trusted for a torrent with the given data files and
+    /// number of pieces, which is the case if none of the files changed since it was written
+    pub fn is_valid_for(&self, files: &[PathBuf], num_pieces: usize) -> bool {
+        self.have.len() == num_pieces &&
+            self.files.len() == files.len() &&
+            files.iter().zip(self.files.iter()).all(|(path, state)| FileState::of(path) == *state)
+    }
+
+    /// Reads resume data from the given path. Returns None if the file is missing or invalid
+    pub fn load(path: &Path) -> Option<ResumeData> {
+        let mut f = File::open(path).ok()?;
+        let mut s = Vec::new();
+        f.read_to_end(&mut s).ok()?;
+
+        let b = bencode::decode(&s).ok()?;
+        FromBencode::from_bencode(&b).ok()
+    }
+
+    /// Writes the resume data to the given path, replacing the previous file only once the new
+    /// one is completely written
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let bytes = self.to_bencode().encode();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(&bytes)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+impl ToBencode for ResumeData {
+    fn to_bencode(&self) -> Bencode {
+        let files = self.files.iter().map(|state| {
+            let mut m = BTreeMap::new();
+            m.insert(b"length".to_vec(), state.length.to_bencode());
+            m.insert(b"mtime".to_vec(), state.mtime.to_bencode());
+            Bencode::Dict(m)
+        }).collect();
+
+        let mut peers = vec![];
+        for peer in self.peers.iter() {
+            if let Some(bytes) = peer.to_bytes() {
+                peers.extend(bytes);
+            }
+        }
+
+        let mut m = BTreeMap::new();
+        m.insert(b"bitfield".to_vec(), Bencode::Bytes(bitfield_to_bytes(&self.have)));
+        m.insert(b"files".to_vec(), Bencode::List(files));
+        m.insert(b"pieces".to_vec(), Bencode::Integer(self.have.len() as i64));
+        m.insert(b"peers".to_vec(), Bencode::Bytes(peers));
+        Bencode::Dict(m)
+    }
+}
+
+impl FromBencode for ResumeData {
+    type Err = Error;
+
+    /// Attempts to construct a ResumeData object from a Bencode object. Returns a Result
+    /// containing either
+    ///     1) a ResumeData object, if a proper Bencode object was passed in
+    ///     2) an Error describing the missing or invalid field otherwise
+    fn from_bencode(bn: &Value) -> Result<ResumeData, Error> {
+        match *bn {
+            Value::Dict(ref m) => {
+                let num_pieces = m.get_integer("pieces").ok_or_else(|| Error::missing("pieces"))? as usize;
+                let have = match m.get_bytes("bitfield") {
+                    Some(bytes) if bytes.len() * 8 >= num_pieces => bytes_to_bitfield(bytes, num_pieces),
+                    _ => return Err(Error::invalid("bitfield", "missing or shorter than the number of pieces"))
+                };
+
+                let mut files = vec![];
+                for file in m.get_list("files").ok_or_else(|| Error::missing("files"))?.iter() {
+                    files.push(file_state_from_bencode(file)?);
+                }
+
+                let peers = match m.get_bytes("peers") {
+                    Some(bytes) => bytes.chunks(6)
+                        .filter(|c| c.len() == 6)
+                        .map(Peer::from_bytes)
+                        .collect(),
+                    None => vec![]
+                };
+
+                Ok(ResumeData { have, files, peers })
+            }
+            _ => Err(Error::invalid("resume", "not a dictionary"))
+        }
+    }
+}
+
+fn file_state_from_bencode(bn: &Value) -> Result<FileState, Error> {
+    match *bn {
+        Value::Dict(ref m) => {
+            match (m.get_integer("length"), m.get_integer("mtime")) {
+                (Some(length), Some(mtime)) => {
+                    Ok(FileState { length: length as u64, mtime: mtime as u64 })
+                },
+                _ => Err(Error::missing("length"))
+            }
+        }
+        _ => Err(Error::invalid("files", "entry is not a dictionary"))
+    }
+}
+
+#[cfg(test)]
+mod resume_tests {
+    use super::{ResumeData, FileState};
+    use peer::Peer;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn save_and_load_test() {
+        let dir = env::temp_dir().join("bittorrent_resume_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let data = dir.join("data");
+        fs::write(&data, vec![0; 10]).unwrap();
+
+        let resume = ResumeData {
+            have: vec![true, false, false, true, true, false, true, false, true],
+            files: vec![FileState::of(&data)],
+            peers: vec![Peer::from_bytes(&[127, 0, 0, 1, 31, 144])],
+        };
+        let path = dir.join("data.resume");
+        resume.save(&path).unwrap();
+
+        let loaded = ResumeData::load(&path).unwrap();
+        assert_eq!(loaded, resume);
+        assert!(loaded.is_valid_for(&[data.clone()], 9));
+        assert!(!loaded.is_valid_for(&[data.clone()], 10));
+
+        fs::write(&data, vec![0; 11]).unwrap();
+        assert!(!loaded.is_valid_for(&[data], 9));
+        assert_eq!(ResumeData::load(&dir.join("missing.resume")), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,528 @@
+use hyper::header::{Authorization, Basic, Bearer, ContentType};
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{Handler, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use bandwidth::Limits;
+use error::Error;
+use json::{self, object, Json};
+use magnet::Magnet;
+use piece::Priority;
+use session::{Session, TorrentStatus};
+use stats::Stats;
+use torrent::PeerStatus;
+use transmission::{self, Transmission};
+use util;
+
+// the standard JSON-RPC error codes, followed by ours for calls without the right token and for
+// calls which the session couldn't carry out
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const UNAUTHORIZED: i64 = -32001;
+const FAILED: i64 = -32000;
+// larger request bodies are refused
+const MAX_REQUEST_LENGTH: u64 = 1024 * 1024;
+const RPC_THREADS: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        RpcError { code, message: message.to_string() }
+    }
+
+    fn invalid_params(message: &str) -> Self {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> RpcError {
+        RpcError::new(FAILED, &e.to_string())
+    }
+}
+
+/// Answers JSON-RPC 2.0 calls which control a session. Every call has to carry the token the
+/// server was created with. The methods are:
+///
+/// - `torrent.add` with a `path` to a metainfo file or a `magnet` link, and optional file
+///   `priorities` which leave out padding files, returning the `info_hash`. A magnet link's
+///   metadata is fetched in the background, and its torrent shows up once that succeeded
+/// - `torrent.remove`, `torrent.pause` and `torrent.resume` with an `info_hash`
+/// - `torrent.status` with an `info_hash`, or without one for every torrent
+/// - `torrent.peers` with an `info_hash`
+/// - `limits.get` and `limits.set`, which apply to the session, or to a torrent given by its
+///   `info_hash`, or to one of its peers given by its `peer` address as well. Rates are bytes per
+///   second, where zero means unlimited, and `count_overhead` applies to the whole session
+/// - `session.stats`, returning the bytes transferred for all torrents
+///
+/// Info hashes are hex digits
+pub struct Rpc {
+    session: Arc<Session>,
+    token: String,
+}
+
+impl Rpc {
+    pub fn new(session: Arc<Session>, token: &str) -> Self {
+        Rpc { session, token: token.to_string() }
+    }
+
+    /// Handles a request, or a batch of requests, given as JSON text. The token is the one the
+    /// transport carried, such as an HTTP bearer token; otherwise each request has to carry it as
+    /// its `token` member. Returns the response text, or None if there is nothing to answer, as
+    /// for notifications
+    pub fn handle(&self, text: &str, token: Option<&str>) -> Option<String> {
+        let request = match json::parse(text) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(Json::Null, RpcError::new(PARSE_ERROR, &e.to_string())).to_string())
+        };
+
+        let response = match request {
+            Json::Array(ref requests) if requests.is_empty() => {
+                Some(error_response(Json::Null, RpcError::new(INVALID_REQUEST, "empty batch")))
+            },
+            Json::Array(ref requests) => {
+                let responses: Vec<Json> = requests.iter().filter_map(|r| self.call(r, token)).collect();
+                if responses.is_empty() { None } else { Some(Json::Array(responses)) }
+            },
+            ref request => self.call(request, token)
+        };
+        response.map(|r| r.to_string())
+    }
+
+    /// Carries out a single request. Returns its response, or None for a notification
+    fn call(&self, request: &Json, token: Option<&str>) -> Option<Json> {
+        let id = request.get("id").cloned();
+        let method = match (request.get("jsonrpc").and_then(Json::as_str), request.get("method").and_then(Json::as_str)) {
+            (Some("2.0"), Some(method)) => method,
+            _ => return Some(error_response(id.unwrap_or(Json::Null), RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 request")))
+        };
+
+        let token = token.or_else(|| request.get("token").and_then(Json::as_str));
+        let result = if token.is_some_and(|t| util::constant_time_eq(t.as_bytes(), self.token.as_bytes())) {
+            let params = request.get("params").cloned().unwrap_or_else(|| object(vec![]));
+            debug!("Calling {}", method);
+            self.dispatch(method, &params)
+        } else {
+            Err(RpcError::new(UNAUTHORIZED, "missing or wrong token"))
+        };
+
+        let id = id?;
+        Some(match result {
+            Ok(result) => object(vec![("jsonrpc", Json::from("2.0")), ("id", id), ("result", result)]),
+            Err(e) => error_response(id, e)
+        })
+    }
+
+    fn dispatch(&self, method: &str, params: &Json) -> Result<Json, RpcError> {
+        match method {
+            "torrent.add" => self.add(params),
+            "torrent.remove" => {
+                let info_hash = info_hash(params)?;
+                match self.session.remove(&info_hash) {
+                    Some(_) => Ok(Json::Bool(true)),
+                    None => Err(unknown_torrent())
+                }
+            },
+            "torrent.pause" => self.found(self.session.pause(&info_hash(params)?)),
+            "torrent.resume" => self.found(self.session.resume(&info_hash(params)?)),
+            "torrent.status" => match params.get("info_hash") {
+                Some(_) => self.session.status(&info_hash(params)?).map(|s| status_json(&s)).ok_or_else(unknown_torrent),
+                None => Ok(Json::Array(self.session.statuses().iter().map(status_json).collect()))
+            },
+            "torrent.peers" => match self.session.peers(&info_hash(params)?) {
+                Some(peers) => Ok(Json::Array(peers.iter().map(peer_json).collect())),
+                None => Err(unknown_torrent())
+            },
+            "limits.get" => self.limits(params).map(|limits| self.limits_json(&limits)),
+            "limits.set" => {
+                let limits = self.limits(params)?;
+                let rate = |key: &str| match params.get(key) {
+                    Some(value) => value.as_u64().map(Some).ok_or_else(|| RpcError::invalid_params(&format!("{} is not a rate", key))),
+                    None => Ok(None)
+                };
+                let (download_rate, upload_rate) = (rate("download_rate")?, rate("upload_rate")?);
+                let count_overhead = match params.get("count_overhead") {
+                    Some(value) => Some(value.as_bool().ok_or_else(|| RpcError::invalid_params("count_overhead is not a boolean"))?),
+                    None => None
+                };
+
+                if let Some(rate) = download_rate {
+                    limits.set_download_rate(rate);
+                }
+                if let Some(rate) = upload_rate {
+                    limits.set_upload_rate(rate);
+                }
+                if let Some(count_overhead) = count_overhead {
+                    self.session.set_count_overhead(count_overhead);
+                }
+                Ok(self.limits_json(&limits))
+            },
+            "session.stats" => {
+                let mut stats = stats_json(&self.session.stats());
+                if let Json::Object(ref mut members) = stats {
+                    members.insert(String::from("torrents"), Json::from(self.session.info_hashes().len()));
+                }
+                Ok(stats)
+            },
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, &format!("unknown method {}", method)))
+        }
+    }
+
+    fn found(&self, found: bool) -> Result<Json, RpcError> {
+        if found { Ok(Json::Bool(true)) } else { Err(unknown_torrent()) }
+    }
+
+    fn add(&self, params: &Json) -> Result<Json, RpcError> {
+        let priorities = match params.get("priorities") {
+            Some(list) => list.as_array()
+                .ok_or_else(|| RpcError::invalid_params("priorities is not a list"))?
+                .iter()
+                .map(|p| p.as_str().and_then(Priority::parse).ok_or_else(|| RpcError::invalid_params("unknown priority")))
+                .collect::<Result<Vec<Priority>, RpcError>>()?,
+            None => vec![]
+        };
+
+        let info_hash = match (params.get("path").and_then(Json::as_str), params.get("magnet").and_then(Json::as_str)) {
+            (Some(path), None) => self.session.add_torrent_file(path, priorities)?,
+            (None, Some(uri)) => {
+                let magnet = Magnet::parse(uri)?;
+                if self.session.torrent(&magnet.info_hash).is_some() {
+                    return Err(Error::invalid("info hash", "the torrent was already added").into())
+                }
+                let (session, uri) = (self.session.clone(), uri.to_string());
+                thread::spawn(move || {
+                    if let Err(e) = session.add_magnet(&uri, priorities) {
+                        warn!("Adding magnet link {} failed: {}", uri, e);
+                    }
+                });
+                magnet.info_hash
+            },
+            _ => return Err(RpcError::invalid_params("expected either a path or a magnet link"))
+        };
+        Ok(object(vec![("info_hash", Json::from(util::to_hex(&info_hash)))]))
+    }
+
+    /// Returns the limits the parameters name: a peer's, a torrent's or the session's
+    fn limits(&self, params: &Json) -> Result<Limits, RpcError> {
+        if params.get("info_hash").is_none() {
+            return Ok(self.session.limits())
+        }
+        let info_hash = info_hash(params)?;
+        match params.get("peer") {
+            Some(peer) => {
+                let addr = peer.as_str().and_then(|p| p.parse::<SocketAddr>().ok())
+                    .ok_or_else(|| RpcError::invalid_params("peer is not an address and port"))?;
+                self.session.peer_limits(&info_hash, &addr).ok_or_else(|| RpcError::new(FAILED, "unknown peer"))
+            },
+            None => self.session.torrent_limits(&info_hash).ok_or_else(unknown_torrent)
+        }
+    }
+
+    fn limits_json(&self, limits: &Limits) -> Json {
+        object(vec![
+            ("download_rate", Json::from(limits.download_rate())),
+            ("upload_rate", Json::from(limits.upload_rate())),
+            ("count_overhead", Json::from(self.session.count_overhead())),
+        ])
+    }
+}
+
+fn unknown_torrent() -> RpcError {
+    RpcError::new(FAILED, "unknown torrent")
+}
+
+fn error_response(id: Json, error: RpcError) -> Json {
+    let error = object(vec![("code", Json::from(error.code)), ("message", Json::from(error.message))]);
+    object(vec![("jsonrpc", Json::from("2.0")), ("id", id), ("error", error)])
+}
+
+fn info_hash(params: &Json) -> Result<Vec<u8>, RpcError> {
+    params.get("info_hash").and_then(Json::as_str).and_then(util::from_hex)
+        .ok_or_else(|| RpcError::invalid_params("info_hash is not hex digits"))
+}
+
+fn stats_json(stats: &Stats) -> Json {
+    object(vec![
+        ("downloaded", Json::from(stats.downloaded)),
+        ("uploaded", Json::from(stats.uploaded)),
+        ("download_overhead", Json::from(stats.download_overhead)),
+        ("upload_overhead", Json::from(stats.upload_overhead)),
+        ("download_rate", Json::from(stats.download_rate)),
+        ("upload_rate", Json::from(stats.upload_rate)),
+    ])
+}
+
+fn status_json(status: &TorrentStatus) -> Json {
+    object(vec![
+        ("info_hash", Json::from(util::to_hex(&status.info_hash))),
+        ("name", Json::from(status.name.as_str())),
+        ("wanted", Json::from(status.wanted)),
+        ("verified", Json::from(status.verified)),
+        ("progress", Json::from(status.progress())),
+        ("num_pieces", Json::from(status.num_pieces)),
+        ("pieces_complete", Json::from(status.pieces_complete)),
+        ("complete", Json::from(status.complete)),
+        ("paused", Json::from(status.stopped)),
+        ("stats", stats_json(&status.stats)),
+        ("eta", Json::from(status.eta().map(|eta| eta.as_secs()))),
+        ("ratio", Json::from(status.ratio())),
+    ])
+}
+
+fn peer_json(peer: &PeerStatus) -> Json {
+    object(vec![
+        ("addr", Json::from(peer.addr.to_string())),
+        ("stats", stats_json(&peer.stats)),
+        ("interested", Json::from(peer.interested)),
+        ("unchoked", Json::from(peer.unchoked)),
+    ])
+}
+
+struct RpcHandler {
+    rpc: Arc<Rpc>,
+    transmission: Transmission,
+}
+
+impl Handler for RpcHandler {
+    fn handle(&self, mut req: Request, mut res: Response<Fresh>) {
+        if req.method != Method::Post {
+            *res.status_mut() = StatusCode::MethodNotAllowed;
+            let _ = res.send(b"");
+            return;
+        }
+
+        let token = req.headers.get::<Authorization<Bearer>>().map(|a| a.0.token.clone())
+            .or_else(|| req.headers.get::<Authorization<Basic>>().and_then(|a| a.0.password.clone()));
+        let is_transmission = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.split('?').next() == Some(transmission::RPC_PATH),
+            _ => false
+        };
+        if is_transmission {
+            // clients ask for the password when refused, and retry with the session id they're given
+            if !self.transmission.is_authorized(token.as_deref()) {
+                *res.status_mut() = StatusCode::Unauthorized;
+                res.headers_mut().set_raw("WWW-Authenticate", vec![b"Basic realm=\"Transmission\"".to_vec()]);
+                let _ = res.send(b"");
+                return;
+            }
+            let session_id = self.transmission.session_id().as_bytes().to_vec();
+            res.headers_mut().set_raw(transmission::SESSION_ID_HEADER, vec![session_id.clone()]);
+            if req.headers.get_raw(transmission::SESSION_ID_HEADER) != Some(&[session_id][..]) {
+                *res.status_mut() = StatusCode::Conflict;
+                let _ = res.send(b"");
+                return;
+            }
+        }
+
+        let mut body = String::new();
+        if (&mut req).take(MAX_REQUEST_LENGTH).read_to_string(&mut body).is_err() {
+            *res.status_mut() = StatusCode::BadRequest;
+            let _ = res.send(b"");
+            return;
+        }
+
+        if is_transmission {
+            res.headers_mut().set(ContentType::json());
+            let _ = res.send(self.transmission.handle(&body).as_bytes());
+            return;
+        }
+        match self.rpc.handle(&body, token.as_deref()) {
+            Some(response) => {
+                res.headers_mut().set(ContentType::json());
+                let _ = res.send(response.as_bytes());
+            },
+            None => {
+                *res.status_mut() = StatusCode::NoContent;
+                let _ = res.send(b"");
+            }
+        }
+    }
+}
+
+/// Answers calls POSTed over HTTP on the given address in the background, taking the token from
+/// an `Authorization: Bearer` header if there is one, or from the password of basic
+/// authentication. Calls of the Transmission RPC protocol are answered at `/transmission/rpc`.
+/// Returns the address the server listens on
+pub fn serve_http(host: &str, port: u16, rpc: Arc<Rpc>) -> Result<SocketAddr, Error> {
+    let server = Server::http((host, port)).map_err(|e| Error::Io(io::Error::other(e.to_string())))?;
+    let transmission = Transmission::new(rpc.session.clone(), &rpc.token);
+    let mut listening = server.handle_threads(RpcHandler { rpc, transmission }, RPC_THREADS)
+        .map_err(|e| Error::Io(io::Error::other(e.to_string())))?;
+    // lets the server run on once the handle is dropped
+    let _ = listening.close();
+    Ok(listening.socket)
+}
+
+/// Answers calls over a Unix socket at the given path in the background, one request per line,
+/// with every response on a line of its own. A socket left behind by an earlier run is replaced
+#[cfg(unix)]
+pub fn serve_unix(path: &::std::path::Path, rpc: Arc<Rpc>) -> Result<(), Error> {
+    use std::fs;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    if fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Accepting an RPC connection failed: {}", e);
+                    continue;
+                }
+            };
+            let rpc = rpc.clone();
+            thread::spawn(move || {
+                let mut writer = match stream.try_clone() {
+                    Ok(writer) => writer,
+                    Err(_) => return
+                };
+                for line in BufReader::new(stream.take(MAX_REQUEST_LENGTH)).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    if let Some(response) = rpc.handle(&line, None) {
+                        if writeln!(writer, "{}", response).is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod rpc_tests {
+    use super::Rpc;
+    use json::{self, Json};
+    use metainfo::test_metainfo;
+    use session::{Session, SessionConfig};
+    use storage::MemoryStorage;
+    use torrent::Torrent;
+    use std::sync::Arc;
+    use util::create_peer_id;
+
+    fn rpc() -> Rpc {
+        let m = test_metainfo("one", 16, &[0; 16], &[]);
+        let session = Session::new(SessionConfig::default());
+        session.add(Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new()))).unwrap();
+        Rpc::new(Arc::new(session), "secret")
+    }
+
+    fn call(rpc: &Rpc, text: &str) -> Json {
+        json::parse(&rpc.handle(text, None).unwrap()).unwrap()
+    }
+
+    const HASH: &str = "0101010101010101010101010101010101010101";
+
+    #[test]
+    fn auth_test() {
+        let rpc = rpc();
+        let response = call(&rpc, r#"{"jsonrpc": "2.0", "id": 1, "method": "session.stats"}"#);
+        assert_eq!(response.get("error").and_then(|e| e.get("code")), Some(&Json::Number(-32001.0)));
+        let response = call(&rpc, r#"{"jsonrpc": "2.0", "id": 1, "method": "session.stats", "token": "wrong"}"#);
+        assert!(response.get("error").is_some());
+
+        let response = json::parse(&rpc.handle(r#"{"jsonrpc": "2.0", "id": 1, "method": "session.stats"}"#, Some("secret")).unwrap()).unwrap();
+        assert_eq!(response.get("result").and_then(|r| r.get("torrents")), Some(&Json::Number(1.0)));
+        // notifications aren't answered
+        assert_eq!(rpc.handle(r#"{"jsonrpc": "2.0", "method": "session.stats", "token": "secret"}"#, None), None);
+    }
+
+    #[test]
+    fn errors_test() {
+        let rpc = rpc();
+        let code = |response: Json| response.get("error").and_then(|e| e.get("code")).and_then(|c| match *c {
+            Json::Number(n) => Some(n as i64),
+            _ => None
+        });
+        assert_eq!(code(call(&rpc, "{")), Some(-32700));
+        assert_eq!(code(call(&rpc, r#"{"id": 1, "method": "session.stats"}"#)), Some(-32600));
+        assert_eq!(code(call(&rpc, r#"{"jsonrpc": "2.0", "id": 1, "method": "nope", "token": "secret"}"#)), Some(-32601));
+        assert_eq!(code(call(&rpc, r#"{"jsonrpc": "2.0", "id": 1, "method": "torrent.pause", "params": {"info_hash": "xyz"}, "token": "secret"}"#)), Some(-32602));
+        assert_eq!(code(call(&rpc, r#"{"jsonrpc": "2.0", "id": 1, "method": "torrent.add", "params": {}, "token": "secret"}"#)), Some(-32602));
+        let response = call(&rpc, r#"{"jsonrpc": "2.0", "id": 1, "method": "torrent.status", "params": {"info_hash": "0202020202020202020202020202020202020202"}, "token": "secret"}"#);
+        assert_eq!(code(response), Some(-32000));
+    }
+
+    #[test]
+    fn torrent_methods_test() {
+        let rpc = rpc();
+        let request = |id: u32, method: &str, params: &str| {
+            format!(r#"{{"jsonrpc": "2.0", "id": {}, "method": "{}", "params": {}, "token": "secret"}}"#, id, method, params)
+        };
+        let hash_params = format!(r#"{{"info_hash": "{}"}}"#, HASH);
+
+        let batch = format!("[{}, {}, {}]", request(1, "torrent.pause", &hash_params), request(2, "torrent.status", &hash_params),
+                            request(3, "torrent.peers", &hash_params));
+        let responses = call(&rpc, &batch);
+        let responses = responses.as_array().unwrap();
+        assert_eq!(responses[0].get("result"), Some(&Json::Bool(true)));
+        let status = responses[1].get("result").unwrap();
+        assert_eq!(status.get("info_hash").and_then(Json::as_str), Some(HASH));
+        assert_eq!(status.get("name").and_then(Json::as_str), Some("one"));
+        assert_eq!(status.get("paused"), Some(&Json::Bool(true)));
+        assert_eq!(status.get("eta"), Some(&Json::Null));
+        assert_eq!(status.get("stats").and_then(|s| s.get("downloaded")), Some(&Json::Number(0.0)));
+        assert_eq!(responses[2].get("result"), Some(&Json::Array(vec![])));
+
+        let response = call(&rpc, &request(4, "torrent.resume", &hash_params));
+        assert_eq!(response.get("result"), Some(&Json::Bool(true)));
+        let response = call(&rpc, &request(5, "torrent.status", "{}"));
+        assert_eq!(response.get("result").and_then(|r| r.as_array()).map(|r| r.len()), Some(1));
+        let response = call(&rpc, &request(6, "torrent.add", &format!(r#"{{"magnet": "magnet:?xt=urn:btih:{}"}}"#, HASH)));
+        assert!(response.get("error").is_some());
+        let response = call(&rpc, &request(7, "torrent.remove", &hash_params));
+        assert_eq!(response.get("result"), Some(&Json::Bool(true)));
+        assert!(rpc.session.info_hashes().is_empty());
+
+        // the metadata is fetched in the background, where it fails as the link names no peers
+        let other = "04".repeat(20);
+        let response = call(&rpc, &request(8, "torrent.add", &format!(r#"{{"magnet": "magnet:?xt=urn:btih:{}"}}"#, other)));
+        assert_eq!(response.get("result").and_then(|r| r.get("info_hash")).and_then(Json::as_str), Some(other.as_str()));
+    }
+
+    #[test]
+    fn limits_test() {
+        let rpc = rpc();
+        let request = |method: &str, params: &str| {
+            format!(r#"{{"jsonrpc": "2.0", "id": 1, "method": "{}", "params": {}, "token": "secret"}}"#, method, params)
+        };
+
+        let response = call(&rpc, &request("limits.set", r#"{"download_rate": 1000, "count_overhead": true}"#));
+        let result = response.get("result").unwrap();
+        assert_eq!(result.get("download_rate"), Some(&Json::Number(1000.0)));
+        assert_eq!(result.get("count_overhead"), Some(&Json::Bool(true)));
+        assert_eq!(rpc.session.limits().download_rate(), 1000);
+
+        let params = format!(r#"{{"info_hash": "{}", "upload_rate": 500}}"#, HASH);
+        call(&rpc, &request("limits.set", &params));
+        assert_eq!(rpc.session.torrent_limits(&[1; 20]).unwrap().upload_rate(), 500);
+        assert_eq!(rpc.session.limits().upload_rate(), 0);
+
+        let params = format!(r#"{{"info_hash": "{}", "peer": "10.0.0.1:6881"}}"#, HASH);
+        assert!(call(&rpc, &request("limits.get", &params)).get("error").is_some());
+        assert!(call(&rpc, &request("limits.set", r#"{"upload_rate": -1}"#)).get("error").is_some());
+    }
+}
@@ -0,0 +1,913 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use bandwidth::{Limits, Throttle};
+use choker::RECHOKE_INTERVAL;
+use connection::Connection;
+use error::Error;
+use event::{Event, Events};
+use listener;
+use magnet::{self, Magnet};
+use manager::{ConnectionManager, PeerSource};
+use metainfo::{self, MetaInfo};
+use mse::EncryptionPolicy;
+use peer::Peer;
+use piece::Priority;
+use stats::Stats;
+use stream::TransportKind;
+use torrent::{PeerStatus, Torrent};
+use tracker::{self, AnnounceEvent, Progress};
+use tracker_response::TrackerResponse;
+use util;
+use webseed;
+
+// the least time between regular announces, whatever the tracker asks for, the time until the
+// first regular one after a tracker already heard that the torrent started, and the time before
+// trying again when the tracker couldn't be reached
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1800);
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+// the most peers remembered for a torrent's next session, where the latest ones are kept
+const MAX_KNOWN_PEERS: usize = 200;
+
+/// A torrent of a session along with every hash peers may know it by
+#[derive(Debug, Clone)]
+pub struct SessionTorrent {
+    pub torrent: Arc<Mutex<Torrent>>,
+    pub info_hashes: Vec<Vec<u8>>,
+    announces: Arc<Mutex<Announces>>,
+}
+
+// a removed torrent along with the thread telling its tracker, if any
+type Removed = (Arc<Mutex<Torrent>>, Option<JoinHandle<()>>);
+
+/// Keeps track of a torrent's announces to its trackers
+#[derive(Debug)]
+struct Announces {
+    // when the next regular announce is due
+    next: Instant,
+    // the tracker which last answered, which hears that we stopped, and whether the trackers
+    // heard about the torrent starting and being complete
+    tracker: Option<String>,
+    started: bool,
+    completed: bool,
+}
+
+/// The torrents of a session keyed by info hash, shared with the threads serving connections
+pub type Torrents = Arc<Mutex<HashMap<Vec<u8>, SessionTorrent>>>;
+
+/// Finds the torrent which peers know by the given hash. Returns the info hash the session keys it
+/// by along with the torrent
+pub fn find_torrent(torrents: &Torrents, info_hash: &[u8]) -> Option<(Vec<u8>, Arc<Mutex<Torrent>>)> {
+    torrents.lock().unwrap().iter()
+        .find(|&(_, t)| t.info_hashes.iter().any(|h| h.as_slice() == info_hash))
+        .map(|(key, t)| (key.clone(), t.torrent.clone()))
+}
+
+/// Returns every hash which peers may know one of the torrents by
+pub fn all_info_hashes(torrents: &Torrents) -> Vec<Vec<u8>> {
+    torrents.lock().unwrap().values().flat_map(|t| t.info_hashes.iter().cloned()).collect()
+}
+
+/// Settings shared by all torrents of a session
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub host: String,
+    pub port: u16,
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    // bytes per second across all torrents, where zero means unlimited
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    // whether protocol overhead counts against the rate limits, or only piece data
+    pub count_overhead: bool,
+    pub transport: TransportKind,
+    pub policy: EncryptionPolicy,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            host: String::from("0.0.0.0"),
+            port: 8080,
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            download_rate: 0,
+            upload_rate: 0,
+            count_overhead: false,
+            transport: TransportKind::Tcp,
+            policy: EncryptionPolicy::Enabled,
+        }
+    }
+}
+
+/// A snapshot of a torrent's progress
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentStatus {
+    pub info_hash: Vec<u8>,
+    pub name: String,
+    // the total size of all pieces
+    pub size: u64,
+    // the total size of the pieces which aren't skipped, and how much of it is verified
+    pub wanted: u64,
+    pub verified: u64,
+    pub num_pieces: usize,
+    pub pieces_complete: usize,
+    pub complete: bool,
+    pub stopped: bool,
+    pub stats: Stats,
+}
+
+impl TorrentStatus {
+    fn of(info_hash: &[u8], torrent: &Torrent) -> TorrentStatus {
+        let wanted = torrent.pieces.iter().filter(|p| p.priority != Priority::Skip);
+        TorrentStatus {
+            info_hash: info_hash.to_vec(),
+            name: torrent.metainfo.info.name.clone(),
+            size: torrent.pieces.iter().map(|p| p.length as u64).sum(),
+            wanted: wanted.clone().map(|p| p.length as u64).sum(),
+            verified: wanted.filter(|p| p.is_complete).map(|p| p.length as u64).sum(),
+            num_pieces: torrent.pieces.len(),
+            pieces_complete: torrent.pieces.iter().filter(|p| p.is_complete).count(),
+            complete: torrent.is_complete(),
+            stopped: torrent.is_stopped(),
+            stats: torrent.stats(),
+        }
+    }
+
+    /// Returns the bytes of the wanted data which aren't verified yet
+    pub fn left(&self) -> u64 {
+        self.wanted - self.verified
+    }
+
+    /// Returns how long the rest of the wanted data takes to download at the current rate, or
+    /// None if nothing is being downloaded
+    pub fn eta(&self) -> Option<Duration> {
+        self.stats.eta(self.left())
+    }
+
+    /// Returns the bytes uploaded for every byte downloaded
+    pub fn ratio(&self) -> f64 {
+        self.stats.ratio()
+    }
+
+    /// Returns the verified fraction of the wanted data, from 0 to 1
+    pub fn progress(&self) -> f64 {
+        if self.wanted == 0 {
+            1.0
+        } else {
+            self.verified as f64 / self.wanted as f64
+        }
+    }
+}
+
+/// Downloads any number of torrents at once. The torrents share one listen socket, which hands
+/// incoming connections to the torrent their handshake asks for, as well as the connection and
+/// bandwidth limits
+#[derive(Debug)]
+pub struct Session {
+    config: SessionConfig,
+    peer_id: String,
+    torrents: Torrents,
+    manager: Arc<Mutex<ConnectionManager>>,
+    limits: Limits,
+    count_overhead: Arc<AtomicBool>,
+    events: Events,
+    // cleared on shutdown, which ends the background thread
+    running: Arc<AtomicBool>,
+}
+
+impl Session {
+    pub fn new(config: SessionConfig) -> Self {
+        let manager = ConnectionManager::new(config.max_connections, config.max_connections_per_torrent);
+        let limits = Limits::new(config.download_rate, config.upload_rate);
+        let count_overhead = Arc::new(AtomicBool::new(config.count_overhead));
+        Session {
+            config,
+            peer_id: util::create_peer_id(),
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+            manager: Arc::new(Mutex::new(manager)),
+            limits,
+            count_overhead,
+            events: Events::new(),
+            running: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    /// Returns the port the session accepts peers on, once it's started
+    pub fn port(&self) -> u16 {
+        self.config.port
+    }
+
+    /// Returns the torrents of the session, such as for serving their files
+    pub fn torrents(&self) -> Torrents {
+        self.torrents.clone()
+    }
+
+    /// Returns a receiver for the events of all torrents of the session from now on
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Starts accepting incoming connections, and connecting to the peers of the session's
+    /// torrents in the background as connection slots become free. Returns the address the
+    /// session listens on
+    pub fn start(&mut self) -> Result<SocketAddr, Error> {
+        let addr = listener::start(&self.config.host, self.config.port, self.torrents.clone(), self.manager.clone(),
+                                   self.config.policy, self.throttle())?;
+        // announces name the port actually bound, which differs when any port was asked for
+        self.config.port = addr.port();
+
+        let torrents = self.torrents.clone();
+        let manager_mutex = self.manager.clone();
+        let throttle = self.throttle();
+        let config = self.config.clone();
+        let running = self.running.clone();
+        let events = self.events.clone();
+        let peer_id = self.peer_id.clone();
+        let mut ticks = 0;
+        thread::spawn(move || while running.load(Ordering::SeqCst) {
+            if ticks % RECHOKE_INTERVAL.as_secs() == 0 {
+                let torrent_mutexes: Vec<Arc<Mutex<Torrent>>> = torrents.lock().unwrap().values().map(|t| t.torrent.clone()).collect();
+                for torrent_mutex in torrent_mutexes {
+                    torrent_mutex.lock().unwrap().rechoke();
+                }
+            }
+            ticks += 1;
+
+            for (info_hash, event) in due_announces(&torrents) {
+                let (torrents, manager_mutex, events, peer_id) = (torrents.clone(), manager_mutex.clone(), events.clone(), peer_id.clone());
+                let port = config.port;
+                thread::spawn(move || {
+                    let peers = announce(&torrents, &events, &peer_id, port, &info_hash, event);
+                    add_peers(&torrents, &manager_mutex, &info_hash, peers, PeerSource::Tracker);
+                });
+            }
+
+            let candidates = manager_mutex.lock().unwrap().next_candidates();
+            for (info_hash, peer) in candidates {
+                let addr = SocketAddr::new(peer.ip, peer.port);
+                let torrent_mutex = match torrents.lock().unwrap().get(&info_hash) {
+                    Some(t) if t.torrent.lock().unwrap().is_downloading() => t.torrent.clone(),
+                    _ => {
+                        manager_mutex.lock().unwrap().connection_closed(&info_hash, &addr, false);
+                        continue;
+                    }
+                };
+
+                let manager_mutex = manager_mutex.clone();
+                let throttle = throttle.clone();
+                let client_mutex = Arc::new(Mutex::new(Peer::local(config.port)));
+                let (transport, policy) = (config.transport, config.policy);
+                thread::spawn(move || {
+                    let result = Connection::connect(client_mutex, peer, torrent_mutex, transport, policy, throttle);
+                    manager_mutex.lock().unwrap().connection_closed(&info_hash, &addr, result.is_err());
+                });
+            }
+            thread::sleep(Duration::from_secs(1));
+        });
+        Ok(addr)
+    }
+
+    /// Adds a torrent which stores its data in the current directory, downloading its files with
+    /// the given priorities, like `add`. Returns its info hash
+    pub fn add_torrent(&self, metainfo: MetaInfo, priorities: Vec<Priority>) -> Result<Vec<u8>, Error> {
+        if self.torrents.lock().unwrap().contains_key(&metainfo.info_hash) {
+            return Err(Error::invalid("info hash", "the torrent was already added"))
+        }
+
+        // the torrent is still downloaded from remembered peers and web seeds without a tracker
+        let torrent = Torrent::with_priorities(self.peer_id.clone(), metainfo, priorities)?;
+        self.add(torrent)
+    }
+
+    /// Adds the torrent described by a metainfo file, like `add_torrent`
+    pub fn add_torrent_file<P: AsRef<Path>>(&self, path: P, priorities: Vec<Priority>) -> Result<Vec<u8>, Error> {
+        self.add_torrent(metainfo::from_file(path)?, priorities)
+    }
+
+    /// Adds the torrent described by the contents of a metainfo file, like `add_torrent`
+    pub fn add_torrent_bytes(&self, bytes: &[u8], priorities: Vec<Priority>) -> Result<Vec<u8>, Error> {
+        self.add_torrent(metainfo::from_bytes(bytes)?, priorities)
+    }
+
+    /// Adds the torrent named by a magnet link. Blocks until its metadata has been fetched from
+    /// the peers given by the link or its trackers, then adds it like `add_torrent`
+    pub fn add_magnet(&self, uri: &str, priorities: Vec<Priority>) -> Result<Vec<u8>, Error> {
+        let magnet = Magnet::parse(uri)?;
+        if self.torrents.lock().unwrap().contains_key(&magnet.info_hash) {
+            return Err(Error::invalid("info hash", "the torrent was already added"))
+        }
+
+        let mut peers = vec![];
+        let mut answered = None;
+        for url in magnet.trackers.iter() {
+            // the size isn't known before the metadata, so any amount marks us as downloading
+            let progress = Progress { uploaded: 0, downloaded: 0, left: 1 };
+            let result = tracker::announce(url, &magnet.info_hash, &self.peer_id, &self.config.port.to_string(),
+                                           progress, Some(AnnounceEvent::Started));
+            if let Some(response) = tracker_result(&self.events, &magnet.info_hash, url, result) {
+                answered = answered.or_else(|| Some(url.clone()));
+                peers.extend(response.peers.into_iter().filter(|p| !magnet.peers.contains(p)));
+            }
+        }
+
+        let candidates: Vec<Peer> = magnet.peers.iter().chain(peers.iter()).cloned().collect();
+        let metainfo = magnet::fetch_metadata(&magnet, &candidates, &self.peer_id, self.config.transport,
+                                              self.config.policy)?;
+        let torrent = Torrent::with_priorities(self.peer_id.clone(), metainfo, priorities)?;
+        let info_hash = self.insert(torrent, answered)?;
+        self.add_peers(&info_hash, magnet.peers.clone(), PeerSource::Magnet);
+        self.add_peers(&info_hash, peers, PeerSource::Tracker);
+        Ok(info_hash)
+    }
+
+    /// Adds a torrent to the session, along with the peers it remembered from its last session,
+    /// and starts downloading from its web seeds. Its tracker hears that it started once the
+    /// session runs. Returns its info hash
+    pub fn add(&self, torrent: Torrent) -> Result<Vec<u8>, Error> {
+        self.insert(torrent, None)
+    }
+
+    /// Adds a torrent like `add`, where `tracker` is the tracker which already heard that the
+    /// torrent started, if any
+    fn insert(&self, mut torrent: Torrent, tracker: Option<String>) -> Result<Vec<u8>, Error> {
+        torrent.set_events(self.events.clone());
+        let info_hash = torrent.metainfo.info_hash.clone();
+        let info_hashes = torrent.metainfo.info_hashes();
+        let private = torrent.metainfo.info.private;
+        let resumed_peers = torrent.known_peers().to_vec();
+        let announces = Announces {
+            next: Instant::now() + DEFAULT_ANNOUNCE_INTERVAL,
+            started: tracker.is_some(),
+            tracker,
+            // torrents which were complete to begin with never finish
+            completed: torrent.is_complete(),
+        };
+        let torrent_mutex = Arc::new(Mutex::new(torrent));
+
+        {
+            let mut torrents = self.torrents.lock().unwrap();
+            if torrents.contains_key(&info_hash) {
+                return Err(Error::invalid("info hash", "the torrent was already added"))
+            }
+            torrents.insert(info_hash.clone(), SessionTorrent {
+                torrent: torrent_mutex.clone(),
+                info_hashes,
+                announces: Arc::new(Mutex::new(announces)),
+            });
+        }
+        self.events.emit(Event::TorrentAdded(info_hash.clone()));
+
+        {
+            let mut manager = self.manager.lock().unwrap();
+            manager.set_private(&info_hash, private);
+            manager.add_peers(&info_hash, resumed_peers, PeerSource::Resume);
+        }
+
+        start_web_seeds(&torrent_mutex, &self.throttle());
+        Ok(info_hash)
+    }
+
+    /// Pauses the torrent with the given info hash, closing its connections, and tells its tracker
+    /// that it stopped. No peers are connected to or accepted for it, and its tracker isn't
+    /// announced to, until it's resumed. Returns false if there is no such torrent
+    pub fn pause(&self, info_hash: &[u8]) -> bool {
+        let (torrent_mutex, announces) = match self.torrents.lock().unwrap().get(info_hash) {
+            Some(t) => (t.torrent.clone(), t.announces.clone()),
+            None => return false
+        };
+        let (paused, progress) = {
+            let mut torrent = torrent_mutex.lock().unwrap();
+            let paused = !torrent.is_stopped();
+            torrent.stop();
+            (paused, progress(info_hash, &torrent))
+        };
+        if paused {
+            let url = {
+                // the tracker hears that the torrent started again once it's resumed
+                let mut announces = announces.lock().unwrap();
+                announces.started = false;
+                announces.tracker.clone()
+            };
+            self.announce_stopped(info_hash, url, progress);
+            self.events.emit(Event::TorrentPaused(info_hash.to_vec()));
+        }
+        true
+    }
+
+    /// Resumes the torrent with the given info hash after it was paused. Returns false if there
+    /// is no such torrent
+    pub fn resume(&self, info_hash: &[u8]) -> bool {
+        let torrent_mutex = match self.torrent(info_hash) {
+            Some(torrent_mutex) => torrent_mutex,
+            None => return false
+        };
+        let resumed = {
+            let mut torrent = torrent_mutex.lock().unwrap();
+            let resumed = torrent.is_stopped();
+            torrent.resume();
+            resumed
+        };
+        if resumed {
+            start_web_seeds(&torrent_mutex, &self.throttle());
+            self.events.emit(Event::TorrentResumed(info_hash.to_vec()));
+        }
+        true
+    }
+
+    /// Adds candidate peers for the torrent with the given info hash, which are remembered for
+    /// its next session too. Peers which a private torrent may not use are ignored
+    pub fn add_peers(&self, info_hash: &[u8], peers: Vec<Peer>, source: PeerSource) {
+        add_peers(&self.torrents, &self.manager, info_hash, peers, source);
+    }
+
+    /// Stops the torrent with the given info hash and removes it from the session. Its data is
+    /// left on disk. Returns the removed torrent
+    pub fn remove(&self, info_hash: &[u8]) -> Option<Arc<Mutex<Torrent>>> {
+        self.remove_torrent(info_hash).map(|(torrent_mutex, _)| torrent_mutex)
+    }
+
+    /// Removes a torrent like `remove`. The tracker which last answered hears that we stopped on
+    /// another thread, which is returned along with the torrent
+    fn remove_torrent(&self, info_hash: &[u8]) -> Option<Removed> {
+        let removed = self.torrents.lock().unwrap().remove(info_hash)?;
+        let progress = {
+            let mut torrent = removed.torrent.lock().unwrap();
+            torrent.stop();
+            progress(info_hash, &torrent)
+        };
+        self.manager.lock().unwrap().remove_torrent(info_hash);
+        self.events.emit(Event::TorrentRemoved(info_hash.to_vec()));
+
+        let url = removed.announces.lock().unwrap().tracker.clone();
+        let stopping = self.announce_stopped(info_hash, url, progress);
+        Some((removed.torrent, stopping))
+    }
+
+    /// Tells the tracker which last answered, if any, that the torrent stopped. The announce is
+    /// made on another thread, which is returned
+    fn announce_stopped(&self, info_hash: &[u8], url: Option<String>, progress: Progress) -> Option<JoinHandle<()>> {
+        let url = url?;
+        let (info_hash, peer_id, port) = (info_hash.to_vec(), self.peer_id.clone(), self.config.port.to_string());
+        Some(thread::spawn(move || {
+            // the torrent is stopped or gone, so the outcome isn't reported
+            let result = tracker::announce(&url, &info_hash, &peer_id, &port, progress, Some(AnnounceEvent::Stopped));
+            if let Err(e) = result {
+                debug!(info_hash = util::to_hex(&info_hash); "Announcing the stop to {} failed: {}", url, e);
+            }
+        }))
+    }
+
+    /// Returns the torrent with the given info hash
+    pub fn torrent(&self, info_hash: &[u8]) -> Option<Arc<Mutex<Torrent>>> {
+        self.torrents.lock().unwrap().get(info_hash).map(|t| t.torrent.clone())
+    }
+
+    /// Returns the info hashes of all torrents of the session
+    pub fn info_hashes(&self) -> Vec<Vec<u8>> {
+        self.torrents.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the progress of the torrent with the given info hash
+    pub fn status(&self, info_hash: &[u8]) -> Option<TorrentStatus> {
+        let torrent_mutex = self.torrent(info_hash)?;
+        let torrent = torrent_mutex.lock().unwrap();
+        Some(TorrentStatus::of(info_hash, &torrent))
+    }
+
+    /// Returns the peers connected for the torrent with the given info hash, along with the bytes
+    /// transferred with each
+    pub fn peers(&self, info_hash: &[u8]) -> Option<Vec<PeerStatus>> {
+        let torrent_mutex = self.torrent(info_hash)?;
+        let peers = torrent_mutex.lock().unwrap().peers();
+        Some(peers)
+    }
+
+    /// Returns the bytes transferred for all torrents of the session since they were added
+    pub fn stats(&self) -> Stats {
+        self.statuses().iter().fold(Stats::default(), |total, status| Stats {
+            downloaded: total.downloaded + status.stats.downloaded,
+            uploaded: total.uploaded + status.stats.uploaded,
+            download_overhead: total.download_overhead + status.stats.download_overhead,
+            upload_overhead: total.upload_overhead + status.stats.upload_overhead,
+            download_rate: total.download_rate + status.stats.download_rate,
+            upload_rate: total.upload_rate + status.stats.upload_rate,
+        })
+    }
+
+    /// Returns the progress of every torrent of the session
+    pub fn statuses(&self) -> Vec<TorrentStatus> {
+        self.info_hashes().iter().filter_map(|h| self.status(h)).collect()
+    }
+
+    /// Returns whether every torrent of the session is complete
+    pub fn is_complete(&self) -> bool {
+        let torrents: Vec<Arc<Mutex<Torrent>>> = self.torrents.lock().unwrap().values().map(|t| t.torrent.clone()).collect();
+        torrents.iter().all(|t| t.lock().unwrap().is_complete())
+    }
+
+    /// Returns the session's limits, which every connection's transfers pass through
+    fn throttle(&self) -> Throttle {
+        Throttle::new(self.limits.clone(), self.count_overhead.clone())
+    }
+
+    /// Returns the limits of the transfers across all torrents
+    pub fn limits(&self) -> Limits {
+        self.limits.clone()
+    }
+
+    /// Changes the download rate in bytes per second across all torrents, where zero means
+    /// unlimited
+    pub fn set_download_rate(&self, rate: u64) {
+        self.limits.set_download_rate(rate);
+    }
+
+    /// Changes the upload rate in bytes per second across all torrents, where zero means
+    /// unlimited
+    pub fn set_upload_rate(&self, rate: u64) {
+        self.limits.set_upload_rate(rate);
+    }
+
+    /// Returns whether protocol overhead counts against the rate limits
+    pub fn count_overhead(&self) -> bool {
+        self.count_overhead.load(Ordering::SeqCst)
+    }
+
+    /// Changes whether protocol overhead counts against the rate limits of the session, its
+    /// torrents and their peers, or only piece data
+    pub fn set_count_overhead(&self, count_overhead: bool) {
+        self.count_overhead.store(count_overhead, Ordering::SeqCst);
+    }
+
+    /// Returns the limits of the torrent with the given info hash, which apply to its transfers
+    /// with all its peers on top of the session's limits
+    pub fn torrent_limits(&self, info_hash: &[u8]) -> Option<Limits> {
+        let torrent_mutex = self.torrent(info_hash)?;
+        let limits = torrent_mutex.lock().unwrap().limits();
+        Some(limits)
+    }
+
+    /// Returns the limits of the transfers with a connected peer of the torrent with the given
+    /// info hash, which apply until the peer disconnects
+    pub fn peer_limits(&self, info_hash: &[u8], addr: &SocketAddr) -> Option<Limits> {
+        let torrent_mutex = self.torrent(info_hash)?;
+        let limits = torrent_mutex.lock().unwrap().peer_limits(addr);
+        limits
+    }
+
+    /// Stops every torrent, saving its resume data, and stops connecting to peers. Returns once
+    /// the trackers heard that we stopped. Incoming connections are turned away from then on,
+    /// since no torrent is left to route them to
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.running.store(false, Ordering::SeqCst);
+        let mut result = Ok(());
+        let mut stopping = vec![];
+        for info_hash in self.info_hashes() {
+            if let Some((torrent_mutex, handle)) = self.remove_torrent(&info_hash) {
+                // the other torrents are still saved when one fails
+                if let Err(e) = torrent_mutex.lock().unwrap().save_resume() {
+                    result = Err(e);
+                }
+                stopping.extend(handle);
+            }
+        }
+        for handle in stopping {
+            let _ = handle.join();
+        }
+        result
+    }
+}
+
+/// Downloads the torrent's missing pieces from its web seeds, each on its own thread and within
+/// the limits of the given throttle
+fn start_web_seeds(torrent_mutex: &Arc<Mutex<Torrent>>, throttle: &Throttle) {
+    let (web_seeds, info_hash) = {
+        let torrent = torrent_mutex.lock().unwrap();
+        (torrent.metainfo.url_list.clone(), util::to_hex(&torrent.metainfo.info_hash))
+    };
+    for url in web_seeds.iter() {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            warn!(info_hash = info_hash.as_str(); "Skipping web seed {}: only HTTP and HTTPS are supported", url);
+            continue;
+        }
+        let seed = webseed::WebSeed::new(url);
+        let (torrent_mutex, throttle) = (torrent_mutex.clone(), throttle.clone());
+        thread::spawn(move || webseed::run(seed, torrent_mutex, throttle));
+    }
+}
+
+/// Adds candidate peers for the torrent with the given info hash, as `Session::add_peers` does
+fn add_peers(torrents: &Torrents, manager_mutex: &Mutex<ConnectionManager>, info_hash: &[u8], peers: Vec<Peer>,
+             source: PeerSource) {
+    let torrent_mutex = torrents.lock().unwrap().get(info_hash).map(|t| t.torrent.clone());
+    if let Some(torrent_mutex) = torrent_mutex {
+        let mut torrent = torrent_mutex.lock().unwrap();
+        if source.is_trackerless() && torrent.metainfo.info.private {
+            return
+        }
+        if source.is_remembered() {
+            let mut known = torrent.known_peers().to_vec();
+            known.extend(peers.iter().filter(|p| !known.contains(p)).cloned().collect::<Vec<Peer>>());
+            let excess = known.len().saturating_sub(MAX_KNOWN_PEERS);
+            known.drain(..excess);
+            torrent.set_known_peers(known);
+        }
+    }
+    manager_mutex.lock().unwrap().add_peers(info_hash, peers, source);
+}
+
+/// Returns what an announce tells the tracker about the torrent
+fn progress(info_hash: &[u8], torrent: &Torrent) -> Progress {
+    let status = TorrentStatus::of(info_hash, torrent);
+    Progress {
+        uploaded: status.stats.uploaded,
+        downloaded: status.stats.downloaded,
+        left: status.left(),
+    }
+}
+
+/// Returns the torrents with a tracker which are due to be announced, along with the event to
+/// announce: `Started` when it was added or resumed, and `Completed` once it finished. Their next
+/// announce is put off until the tracker answers
+fn due_announces(torrents: &Torrents) -> Vec<(Vec<u8>, Option<AnnounceEvent>)> {
+    let now = Instant::now();
+    let mut due = vec![];
+    for (info_hash, t) in torrents.lock().unwrap().iter() {
+        let (complete, stopped, has_tracker) = {
+            let torrent = t.torrent.lock().unwrap();
+            (torrent.is_complete(), torrent.is_stopped(), !torrent.metainfo.trackers().is_empty())
+        };
+        if stopped || !has_tracker {
+            continue;
+        }
+        let mut announces = t.announces.lock().unwrap();
+        let event = if !announces.started {
+            announces.started = true;
+            Some(AnnounceEvent::Started)
+        } else if complete && !announces.completed {
+            announces.completed = true;
+            Some(AnnounceEvent::Completed)
+        } else {
+            None
+        };
+        if event.is_some() || now >= announces.next {
+            announces.next = now + RETRY_INTERVAL;
+            due.push((info_hash.clone(), event));
+        }
+    }
+    due
+}
+
+/// Announces the torrent's progress along with the given event to each of its trackers in turn
+/// until one answers, and reports the outcomes. The next regular announce is due after the
+/// interval the tracker asks for. Returns the peers the tracker sent
+fn announce(torrents: &Torrents, events: &Events, peer_id: &str, port: u16, info_hash: &[u8],
+            event: Option<AnnounceEvent>) -> Vec<Peer> {
+    let (trackers, progress, announces) = match torrents.lock().unwrap().get(info_hash) {
+        Some(t) => {
+            let torrent = t.torrent.lock().unwrap();
+            (torrent.metainfo.trackers(), progress(info_hash, &torrent), t.announces.clone())
+        },
+        None => return vec![]
+    };
+    if trackers.is_empty() {
+        return vec![]
+    }
+
+    let mut response = None;
+    for url in trackers {
+        let result = tracker::announce(&url, info_hash, peer_id, &port.to_string(), progress, event);
+        if let Some(r) = tracker_result(events, info_hash, &url, result) {
+            response = Some((url, r));
+            break;
+        }
+    }
+    let mut announces = announces.lock().unwrap();
+    let response = response.map(|(url, r)| {
+        announces.tracker = Some(url);
+        r
+    });
+    announces.next = Instant::now() + response.as_ref()
+        .map_or(RETRY_INTERVAL, |r| Duration::from_secs(r.interval as u64).max(MIN_ANNOUNCE_INTERVAL));
+    response.map_or(vec![], |r| r.peers)
+}
+
+/// Reports the outcome of an announce to a tracker and returns the tracker's response, if any
+fn tracker_result(events: &Events, info_hash: &[u8], url: &str, result: Result<TrackerResponse, Error>) -> Option<TrackerResponse> {
+    let (info_hash, url) = (info_hash.to_vec(), url.to_string());
+    match result {
+        Ok(response) => {
+            events.emit(Event::TrackerReply { info_hash, url, peers: response.peers.len() });
+            Some(response)
+        },
+        Err(e) => {
+            events.emit(Event::TrackerError { info_hash, url, error: e.to_string() });
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::{due_announces, find_torrent, Session, SessionConfig};
+    use event::Event;
+    use manager::PeerSource;
+    use metainfo::test_metainfo;
+    use mse;
+    use peer::Peer;
+    use storage::MemoryStorage;
+    use stream::Transport;
+    use torrent::Torrent;
+    use tracker::AnnounceEvent;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use util::create_peer_id;
+
+    fn torrent(name: &str, info_hash: u8) -> Torrent {
+        let mut m = test_metainfo(name, 16, &[0; 16], &[]);
+        m.info.private = info_hash == 2;
+        m.info_hash = vec![info_hash; 20];
+        Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new()))
+    }
+
+    fn handshake(info_hash: &[u8]) -> Vec<u8> {
+        let mut message = vec![19];
+        message.extend(b"BitTorrent protocol");
+        message.extend(vec![0; 8]);
+        message.extend(info_hash);
+        message.extend(vec![b'p'; 20]);
+        message
+    }
+
+    #[test]
+    fn add_and_remove_test() {
+        let session = Session::new(SessionConfig::default());
+        let events = session.subscribe();
+        assert_eq!(session.add(torrent("one", 1)).unwrap(), vec![1; 20]);
+        assert_eq!(session.add(torrent("two", 2)).unwrap(), vec![2; 20]);
+        assert!(session.add(torrent("again", 1)).is_err());
+
+        let mut hashes = session.info_hashes();
+        hashes.sort();
+        assert_eq!(hashes, vec![vec![1; 20], vec![2; 20]]);
+        assert_eq!(find_torrent(&session.torrents(), &[2; 20]).unwrap().0, vec![2; 20]);
+        assert!(find_torrent(&session.torrents(), &[3; 20]).is_none());
+        assert!(!session.manager.lock().unwrap().allows_discovery(&[2; 20]));
+
+        let removed = session.remove(&[1; 20]).unwrap();
+        assert!(removed.lock().unwrap().is_stopped());
+        assert!(session.torrent(&[1; 20]).is_none());
+        assert!(session.remove(&[1; 20]).is_none());
+
+        let received: Vec<Event> = events.try_iter().collect();
+        assert_eq!(received, vec![Event::TorrentAdded(vec![1; 20]), Event::TorrentAdded(vec![2; 20]),
+                                  Event::TorrentRemoved(vec![1; 20])]);
+    }
+
+    #[test]
+    fn status_and_shutdown_test() {
+        let session = Session::new(SessionConfig::default());
+        session.add(torrent("one", 1)).unwrap();
+        let status = session.status(&[1; 20]).unwrap();
+        assert_eq!(status.name, "one");
+        assert_eq!((status.size, status.wanted, status.verified), (16, 16, 0));
+        assert_eq!((status.num_pieces, status.pieces_complete), (1, 0));
+        assert!(!status.complete && !status.stopped);
+        assert_eq!(status.progress(), 0.0);
+        assert_eq!((status.left(), status.eta(), status.ratio()), (16, None, 0.0));
+        assert_eq!(session.peers(&[1; 20]), Some(vec![]));
+        assert!(session.status(&[2; 20]).is_none());
+
+        session.torrent(&[1; 20]).unwrap().lock().unwrap().pieces[0].is_complete = true;
+        assert_eq!(session.statuses()[0].progress(), 1.0);
+
+        let torrent = session.torrent(&[1; 20]).unwrap();
+        session.shutdown().unwrap();
+        assert!(session.info_hashes().is_empty());
+        assert!(torrent.lock().unwrap().is_stopped());
+    }
+
+    #[test]
+    fn pause_and_resume_test() {
+        let session = Session::new(SessionConfig::default());
+        session.add(torrent("one", 1)).unwrap();
+        let events = session.subscribe();
+        assert!(session.pause(&[1; 20]));
+        assert!(session.pause(&[1; 20]));
+        assert!(session.status(&[1; 20]).unwrap().stopped);
+        assert!(session.resume(&[1; 20]));
+        assert!(!session.torrent(&[1; 20]).unwrap().lock().unwrap().is_stopped());
+        assert!(!session.pause(&[2; 20]) && !session.resume(&[2; 20]));
+
+        // pausing a paused torrent reports nothing
+        let received: Vec<Event> = events.try_iter().collect();
+        assert_eq!(received, vec![Event::TorrentPaused(vec![1; 20]), Event::TorrentResumed(vec![1; 20])]);
+    }
+
+    #[test]
+    fn announce_events_test() {
+        let session = Session::new(SessionConfig::default());
+        session.add(torrent("one", 1)).unwrap();
+
+        // a torrent is announced as started at once, and again after it was paused
+        assert_eq!(due_announces(&session.torrents), vec![(vec![1; 20], Some(AnnounceEvent::Started))]);
+        assert_eq!(due_announces(&session.torrents), vec![]);
+        session.pause(&[1; 20]);
+        assert_eq!(due_announces(&session.torrents), vec![]);
+        session.resume(&[1; 20]);
+        assert_eq!(due_announces(&session.torrents), vec![(vec![1; 20], Some(AnnounceEvent::Started))]);
+    }
+
+    #[test]
+    fn limits_test() {
+        let session = Session::new(SessionConfig { upload_rate: 1000, ..SessionConfig::default() });
+        session.add(torrent("one", 1)).unwrap();
+        assert_eq!((session.limits.download_rate(), session.limits.upload_rate()), (0, 1000));
+        session.set_download_rate(500);
+        session.set_upload_rate(0);
+        assert_eq!((session.limits.download_rate(), session.limits.upload_rate()), (500, 0));
+        session.set_count_overhead(true);
+        assert!(session.count_overhead());
+
+        // the torrent's limits are shared with its connections, so changes reach them at once
+        session.torrent_limits(&[1; 20]).unwrap().set_upload_rate(200);
+        assert_eq!(session.torrent(&[1; 20]).unwrap().lock().unwrap().limits().upload_rate(), 200);
+        assert!(session.torrent_limits(&[2; 20]).is_none());
+        assert!(session.peer_limits(&[1; 20], &"10.0.0.1:6881".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn route_incoming_test() {
+        let mut session = Session::new(SessionConfig { host: String::from("127.0.0.1"), port: 0, ..SessionConfig::default() });
+        session.add(torrent("one", 1)).unwrap();
+        session.add(torrent("two", 2)).unwrap();
+        let mut hybrid = torrent("three", 3);
+        hybrid.metainfo.info_hash_v2 = Some(vec![4; 32]);
+        session.add(hybrid).unwrap();
+        let addr = session.start().unwrap();
+
+        // the answer carries the info hash of the torrent which was asked for
+        let events = session.subscribe();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&handshake(&[2; 20])).unwrap();
+        let mut answer = vec![0; 68];
+        stream.read_exact(&mut answer).unwrap();
+        assert_eq!(&answer[28..48], &[2; 20]);
+        // the session announces its torrents as started meanwhile, to a tracker which isn't there
+        match events.iter().find(|e| !matches!(e, Event::TrackerError { .. })).unwrap() {
+            Event::PeerConnected { info_hash, .. } => assert_eq!(info_hash, vec![2; 20]),
+            other => panic!("Expected a connected peer, got {:?}", other)
+        }
+
+        // a peer asking for the v2 hash of a hybrid torrent gets that hash back
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&handshake(&[4; 20])).unwrap();
+        stream.read_exact(&mut answer).unwrap();
+        assert_eq!(&answer[28..48], &[4; 20]);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&handshake(&[5; 20])).unwrap();
+        let mut rest = vec![];
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+
+        // an encrypted peer has to ask for the torrent its encryption handshake named
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut stream = mse::initiate(Transport::Tcp(stream), &[1; 20], mse::CRYPTO_RC4).unwrap();
+        stream.write_all(&handshake(&[2; 20])).unwrap();
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn known_peers_test() {
+        let session = Session::new(SessionConfig::default());
+        session.add(torrent("public", 1)).unwrap();
+        session.add(torrent("private", 2)).unwrap();
+        let peers = |from: usize, count: usize| -> Vec<Peer> {
+            (from..from + count).map(|i| Peer::from_addr(format!("10.0.{}.{}:6881", i / 256, i % 256).parse().unwrap())).collect()
+        };
+        let known = |info_hash: u8| session.torrent(&[info_hash; 20]).unwrap().lock().unwrap().known_peers().len();
+
+        // a private torrent neither uses nor remembers peers found without its tracker
+        session.add_peers(&[2; 20], peers(0, 2), PeerSource::Dht);
+        assert_eq!(known(2), 0);
+        session.add_peers(&[2; 20], peers(0, 2), PeerSource::Tracker);
+        assert_eq!(known(2), 2);
+        session.add_peers(&[1; 20], peers(0, 2), PeerSource::Incoming);
+        assert_eq!(known(1), 0);
+
+        // only the latest peers are remembered
+        session.add_peers(&[1; 20], peers(0, 300), PeerSource::Pex);
+        let torrent = session.torrent(&[1; 20]).unwrap();
+        assert_eq!(torrent.lock().unwrap().known_peers(), &peers(100, 200)[..]);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// how far back rates look, and how finely the samples within that time are kept
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+const RATE_RESOLUTION: Duration = Duration::from_millis(100);
+
+/// Estimates a transfer rate from the bytes transferred over the last few seconds
+#[derive(Debug, Clone)]
+pub struct Rate {
+    // bytes transferred, summed over slices of time starting at the given instants
+    samples: VecDeque<(Instant, u64)>,
+    started: Instant,
+}
+
+impl Rate {
+    pub fn new() -> Self {
+        Rate {
+            samples: VecDeque::new(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        let now = Instant::now();
+        match self.samples.back_mut() {
+            Some(&mut (at, ref mut sum)) if now.duration_since(at) < RATE_RESOLUTION => *sum += bytes,
+            _ => self.samples.push_back((now, bytes))
+        }
+        while self.samples.front().is_some_and(|&(at, _)| now.duration_since(at) > RATE_WINDOW) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Returns the bytes per second over the window, or over the time since the rate was created
+    /// if that is shorter
+    pub fn per_second(&self) -> f64 {
+        let now = Instant::now();
+        let recent: u64 = self.samples.iter()
+            .filter(|&&(at, _)| now.duration_since(at) <= RATE_WINDOW)
+            .map(|&(_, bytes)| bytes)
+            .sum();
+        let span = now.duration_since(self.started).min(RATE_WINDOW).max(Duration::from_secs(1));
+        recent as f64 / span.as_secs_f64()
+    }
+}
+
+impl Default for Rate {
+    fn default() -> Self {
+        Rate::new()
+    }
+}
+
+/// Counts the bytes transferred in one direction. The payload is the data of pieces, and the
+/// overhead everything else the protocol sends, such as handshakes, requests and message headers
+#[derive(Debug, Clone, Default)]
+pub struct Counter {
+    payload: u64,
+    overhead: u64,
+    // the rate of the payload
+    rate: Rate,
+}
+
+impl Counter {
+    pub fn add(&mut self, payload: u64, overhead: u64) {
+        self.payload += payload;
+        self.overhead += overhead;
+        self.rate.add(payload);
+    }
+
+    /// Returns the bytes of payload per second over the last few seconds
+    pub fn rate(&self) -> f64 {
+        self.rate.per_second()
+    }
+}
+
+/// Counts the bytes transferred with a peer, or with all peers of a torrent
+#[derive(Debug, Clone, Default)]
+pub struct TransferStats {
+    pub download: Counter,
+    pub upload: Counter,
+}
+
+impl TransferStats {
+    pub fn new() -> Self {
+        TransferStats::default()
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            downloaded: self.download.payload,
+            uploaded: self.upload.payload,
+            download_overhead: self.download.overhead,
+            upload_overhead: self.upload.overhead,
+            download_rate: self.download.rate(),
+            upload_rate: self.upload.rate(),
+        }
+    }
+}
+
+/// A snapshot of the bytes transferred with a peer or a torrent's peers since they connected or
+/// the torrent was added
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Stats {
+    // bytes of piece data
+    pub downloaded: u64,
+    pub uploaded: u64,
+    // bytes of everything else
+    pub download_overhead: u64,
+    pub upload_overhead: u64,
+    // bytes of piece data per second over the last few seconds
+    pub download_rate: f64,
+    pub upload_rate: f64,
+}
+
+impl Stats {
+    /// Returns how long downloading the given number of bytes takes at the current rate, or None
+    /// if nothing is being downloaded
+    pub fn eta(&self, left: u64) -> Option<Duration> {
+        if left == 0 {
+            Some(Duration::from_secs(0))
+        } else if self.download_rate > 0.0 {
+            Some(Duration::from_secs_f64(left as f64 / self.download_rate))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the bytes uploaded for every byte downloaded, or zero before anything was
+    /// downloaded
+    pub fn ratio(&self) -> f64 {
+        if self.downloaded == 0 {
+            0.0
+        } else {
+            self.uploaded as f64 / self.downloaded as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use super::{Rate, Stats, TransferStats};
+    use std::time::Duration;
+
+    #[test]
+    fn rate_test() {
+        let mut rate = Rate::new();
+        assert_eq!(rate.per_second(), 0.0);
+        rate.add(1000);
+        rate.add(500);
+        // under a second since the rate was created still counts as a second
+        assert_eq!(rate.samples.len(), 1);
+        assert_eq!(rate.per_second(), 1500.0);
+    }
+
+    #[test]
+    fn transfer_stats_test() {
+        let mut stats = TransferStats::new();
+        stats.download.add(16384, 13);
+        stats.download.add(0, 5);
+        stats.upload.add(8192, 81);
+
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.downloaded, snapshot.download_overhead), (16384, 18));
+        assert_eq!((snapshot.uploaded, snapshot.upload_overhead), (8192, 81));
+        assert_eq!(snapshot.download_rate, 16384.0);
+        assert_eq!(snapshot.ratio(), 0.5);
+        assert_eq!(snapshot.eta(32768), Some(Duration::from_secs(2)));
+        assert_eq!(snapshot.eta(0), Some(Duration::from_secs(0)));
+        assert_eq!(Stats::default().eta(1), None);
+        assert_eq!(Stats::default().ratio(), 0.0);
+    }
+}
@@ -0,0 +1,524 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use metainfo::{FileAttr, PieceHash};
+
+/// Stores the data of a torrent. The data is addressed as one contiguous range of bytes, as if
+/// all files of the torrent were concatenated, so implementations decide how it is laid out
+pub trait Storage: Send + fmt::Debug {
+    /// Reads `length` bytes starting at the given offset
+    fn read_block(&mut self, offset: u64, length: u32) -> Result<Vec<u8>, Error>;
+
+    /// Writes the data of a verified piece starting at the given offset
+    fn write_piece(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>;
+
+    /// Makes sure all written data has reached the underlying storage
+    fn flush(&mut self) -> Result<(), Error>;
+
+    /// Grows or truncates the stored data to the given total length
+    fn set_len(&mut self, length: u64) -> Result<(), Error>;
+
+    /// Called once all data of the file with the given index has been verified, so that its
+    /// attributes can be applied
+    fn finish_file(&mut self, _index: usize) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Sets which files are skipped, given in the same order as the files. Skipped files aren't
+    /// created, so data of theirs which shares a piece with a wanted file is kept elsewhere
+    fn set_skipped(&mut self, _skipped: &[bool]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Checks whether the stored data at the given offset matches a piece's hash. Data which
+    /// can't be read doesn't match
+    fn verify(&mut self, offset: u64, length: u32, hash: &[u8], hash_type: PieceHash) -> Result<bool, Error> {
+        match self.read_block(offset, length) {
+            Ok(data) => Ok(hash_type.matches(hash, &data)),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof || e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StorageFile {
+    path: PathBuf,
+    // position of the file's first byte within the torrent's data
+    offset: u64,
+    length: u64,
+    handle: Option<File>,
+    attr: FileAttr,
+    // the target of a symlink, relative to the directory containing it
+    link: Option<PathBuf>,
+    // whether the file's data is kept in the part file instead
+    skipped: bool,
+}
+
+/// Returns where the part file of the torrent with the given name is kept, next to its data in the
+/// given directory
+pub fn part_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!(".{}.parts", name))
+}
+
+/// Stores torrent data in files on disk, splitting it across the torrent's files in order
+#[derive(Debug)]
+pub struct FileStorage {
+    files: Vec<StorageFile>,
+    writable: bool,
+    // holds the data of skipped files at the same offsets as within the torrent's data, so it is
+    // sparse where the filesystem allows
+    part_path: Option<PathBuf>,
+    part: Option<File>,
+}
+
+impl FileStorage {
+    /// Creates a storage from the paths and lengths of the torrent's files. Files are opened,
+    /// and created if missing, the first time they are accessed
+    pub fn new(files: Vec<(PathBuf, u64)>) -> Self {
+        let mut offset = 0;
+        let files = files.into_iter().map(|(path, length)| {
+            let file = StorageFile {
+                path, offset, length,
+                handle: None,
+                attr: FileAttr::default(),
+                link: None,
+                skipped: false,
+            };
+            offset += length;
+            file
+        }).collect();
+
+        FileStorage { files, writable: true, part_path: None, part: None }
+    }
+
+    /// Creates a storage which only reads existing files and never creates or modifies them
+    pub fn read_only(files: Vec<(PathBuf, u64)>) -> Self {
+        FileStorage { writable: false, ..FileStorage::new(files) }
+    }
+
+    /// Sets the attributes of the files, given in the same order as the files. Padding files are
+    /// never written to disk and read as zeros, and symlinks are only created once finished
+    pub fn with_attrs(mut self, attrs: Vec<(FileAttr, Option<PathBuf>)>) -> Self {
+        for (file, (attr, link)) in self.files.iter_mut().zip(attrs) {
+            file.attr = attr;
+            file.link = link;
+        }
+        self
+    }
+
+    /// Sets the file which keeps the data of skipped files. Without one, files can't be skipped
+    pub fn with_part_file(mut self, path: PathBuf) -> Self {
+        self.part_path = Some(path);
+        self
+    }
+
+    /// Returns whether the file only exists in the torrent's layout and not on disk
+    fn is_virtual(&self, index: usize) -> bool {
+        let attr = self.files[index].attr;
+        attr.padding || attr.symlink
+    }
+
+    fn open(&mut self, index: usize) -> Result<&mut File, Error> {
+        let writable = self.writable;
+        let file = &mut self.files[index];
+        if file.handle.is_none() {
+            if writable {
+                if let Some(parent) = file.path.parent() {
+                    if !parent.as_os_str().is_empty() {
+                        fs::create_dir_all(parent)?;
+                    }
+                }
+            }
+            let handle = OpenOptions::new().read(true).write(writable).create(writable).truncate(false)
+                .open(&file.path)?;
+            file.handle = Some(handle);
+        }
+        Ok(file.handle.as_mut().unwrap())
+    }
+
+    /// Opens the part file, which is only created when data is written to it
+    fn open_part(&mut self) -> Result<&mut File, Error> {
+        if self.part.is_none() {
+            let path = match self.part_path {
+                Some(ref path) => path,
+                None => return Err(Error::new(ErrorKind::NotFound, "No part file for skipped files"))
+            };
+            let handle = OpenOptions::new().read(true).write(self.writable).create(self.writable).truncate(false)
+                .open(path)?;
+            self.part = Some(handle);
+        }
+        Ok(self.part.as_mut().unwrap())
+    }
+
+    /// Opens the file holding the data of the file with the given index, and returns it along with
+    /// the position of the file's data within it
+    fn open_data(&mut self, index: usize) -> Result<(&mut File, u64), Error> {
+        if self.files[index].skipped {
+            let offset = self.files[index].offset;
+            Ok((self.open_part()?, offset))
+        } else {
+            Ok((self.open(index)?, 0))
+        }
+    }
+
+    /// Moves the data a file which is no longer skipped kept in the part file into the file itself
+    fn unskip(&mut self, index: usize) -> Result<(), Error> {
+        let (offset, length) = (self.files[index].offset, self.files[index].length);
+        let mut data = vec![];
+        if let Ok(part) = self.open_part() {
+            part.seek(SeekFrom::Start(offset))?;
+            Read::by_ref(part).take(length).read_to_end(&mut data)?;
+        }
+        self.files[index].skipped = false;
+        if !data.is_empty() {
+            let file = self.open(index)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&data)?;
+        }
+        Ok(())
+    }
+
+    /// Splits a range of the torrent's data into the parts stored by each file, as triples of
+    /// the file index, the offset within the file and the length of the part
+    fn spans(&self, offset: u64, length: u64) -> Vec<(usize, u64, u64)> {
+        let end = offset + length;
+        self.files.iter().enumerate()
+            .filter(|&(_, f)| f.offset < end && offset < f.offset + f.length)
+            .map(|(i, f)| {
+                let start = offset.max(f.offset);
+                let stop = end.min(f.offset + f.length);
+                (i, start - f.offset, stop - start)
+            })
+            .collect()
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&mut self, offset: u64, length: u32) -> Result<Vec<u8>, Error> {
+        let mut data = vec![];
+        for (index, file_offset, part_length) in self.spans(offset, length as u64) {
+            if self.is_virtual(index) {
+                data.resize(data.len() + part_length as usize, 0);
+                continue;
+            }
+            let (file, base) = self.open_data(index)?;
+            file.seek(SeekFrom::Start(base + file_offset))?;
+            let start = data.len();
+            data.resize(start + part_length as usize, 0);
+            file.read_exact(&mut data[start..])?;
+        }
+
+        if data.len() != length as usize {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Read past the end of the torrent"))
+        }
+        Ok(data)
+    }
+
+    fn write_piece(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let mut written = 0;
+        for (index, file_offset, part_length) in self.spans(offset, data.len() as u64) {
+            if self.is_virtual(index) {
+                written += part_length as usize;
+                continue;
+            }
+            let (file, base) = self.open_data(index)?;
+            file.seek(SeekFrom::Start(base + file_offset))?;
+            file.write_all(&data[written..written + part_length as usize])?;
+            written += part_length as usize;
+        }
+
+        if written != data.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Wrote past the end of the torrent"))
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        for file in self.files.iter_mut() {
+            if let Some(ref mut handle) = file.handle {
+                handle.sync_data()?;
+            }
+        }
+        if let Some(ref mut part) = self.part {
+            part.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn set_len(&mut self, length: u64) -> Result<(), Error> {
+        for index in 0..self.files.len() {
+            if self.is_virtual(index) || self.files[index].skipped {
+                continue;
+            }
+            let (offset, file_length) = (self.files[index].offset, self.files[index].length);
+            let target = file_length.min(length.saturating_sub(offset));
+            let file = self.open(index)?;
+            if file.metadata()?.len() != target {
+                file.set_len(target)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_file(&mut self, index: usize) -> Result<(), Error> {
+        if !self.writable {
+            return Ok(())
+        }
+
+        let file = &self.files[index];
+        if let Some(ref target) = file.link {
+            if fs::symlink_metadata(&file.path).is_err() {
+                if let Some(parent) = file.path.parent() {
+                    if !parent.as_os_str().is_empty() {
+                        fs::create_dir_all(parent)?;
+                    }
+                }
+                symlink(target, &file.path)?;
+            }
+        } else if file.attr.executable {
+            set_executable(&file.path)?;
+        }
+        Ok(())
+    }
+
+    fn set_skipped(&mut self, skipped: &[bool]) -> Result<(), Error> {
+        if !self.writable {
+            return Ok(())
+        }
+
+        for (index, &skip) in skipped.iter().enumerate().take(self.files.len()) {
+            let file = &mut self.files[index];
+            if skip && !file.skipped {
+                // files which already exist keep their data, so only stop being downloaded
+                if self.part_path.is_some() && file.handle.is_none() && !file.path.exists() {
+                    file.skipped = true;
+                }
+            } else if !skip && file.skipped {
+                self.unskip(index)?;
+            }
+        }
+
+        if self.files.iter().all(|f| !f.skipped) {
+            if let Some(ref path) = self.part_path {
+                self.part = None;
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> Result<(), Error> {
+    ::std::os::unix::fs::symlink(target, path)
+}
+
+// symlinks need special privileges elsewhere, so they are skipped
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    // executable by everyone who may read the file
+    let mode = permissions.mode();
+    permissions.set_mode(mode | ((mode & 0o444) >> 2));
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Keeps torrent data in memory, which is mainly useful for tests
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage { data: vec![] }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&mut self, offset: u64, length: u32) -> Result<Vec<u8>, Error> {
+        let start = offset as usize;
+        let end = start + length as usize;
+        if end > self.data.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Read past the end of the torrent"))
+        }
+        Ok(self.data[start..end].to_vec())
+    }
+
+    fn write_piece(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let start = offset as usize;
+        if start + data.len() > self.data.len() {
+            self.data.resize(start + data.len(), 0);
+        }
+        self.data[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_len(&mut self, length: u64) -> Result<(), Error> {
+        self.data.resize(length as usize, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use super::{Storage, FileStorage, MemoryStorage};
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use hash;
+    use metainfo::{FileAttr, PieceHash};
+
+    #[test]
+    fn memory_storage_test() {
+        let mut s = MemoryStorage::new();
+        s.set_len(8).unwrap();
+        s.write_piece(4, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(s.read_block(2, 4).unwrap(), vec![0, 0, 1, 2]);
+        assert!(s.read_block(6, 4).is_err());
+        assert!(s.verify(4, 4, &hash::sha(&[1, 2, 3, 4]), PieceHash::Sha1).unwrap());
+        assert!(!s.verify(0, 4, &hash::sha(&[1, 2, 3, 4]), PieceHash::Sha1).unwrap());
+        assert!(!s.verify(6, 4, &hash::sha(&[1, 2, 3, 4]), PieceHash::Sha1).unwrap());
+    }
+
+    #[test]
+    fn read_only_storage_test() {
+        let dir = env::temp_dir().join("bittorrent_read_only_storage_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), [1, 2, 3]).unwrap();
+
+        let mut s = FileStorage::read_only(vec![(dir.join("a"), 3), (dir.join("b/c"), 2)]);
+        assert_eq!(s.read_block(0, 3).unwrap(), vec![1, 2, 3]);
+        assert!(s.verify(0, 3, &hash::sha(&[1, 2, 3]), PieceHash::Sha1).unwrap());
+        // missing files are neither created nor written
+        assert!(!s.verify(3, 2, &hash::sha(&[0, 0]), PieceHash::Sha1).unwrap());
+        assert!(s.write_piece(0, &[4, 5, 6]).is_err());
+        assert!(!dir.join("b").exists());
+        assert_eq!(fs::read(dir.join("a")).unwrap(), vec![1, 2, 3]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn file_storage_spans_files_test() {
+        let dir = env::temp_dir().join("bittorrent_file_storage_test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut s = FileStorage::new(vec![
+            (dir.join("a"), 3),
+            (dir.join("b/c"), 2),
+            (dir.join("d"), 5),
+        ]);
+
+        s.set_len(10).unwrap();
+        s.write_piece(2, &[1, 2, 3, 4]).unwrap();
+        s.flush().unwrap();
+
+        assert_eq!(fs::read(dir.join("a")).unwrap(), vec![0, 0, 1]);
+        assert_eq!(fs::read(dir.join("b/c")).unwrap(), vec![2, 3]);
+        assert_eq!(fs::read(dir.join("d")).unwrap(), vec![4, 0, 0, 0, 0]);
+        assert_eq!(s.read_block(1, 5).unwrap(), vec![0, 1, 2, 3, 4]);
+        assert!(s.read_block(8, 4).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn file_attrs_test() {
+        let dir = env::temp_dir().join("bittorrent_file_attrs_test");
+        let _ = fs::remove_dir_all(&dir);
+        let padding = FileAttr { padding: true, ..FileAttr::default() };
+        let executable = FileAttr { executable: true, ..FileAttr::default() };
+        let symlink = FileAttr { symlink: true, ..FileAttr::default() };
+        let mut s = FileStorage::new(vec![
+            (dir.join("run.sh"), 3),
+            (dir.join(".pad/5"), 5),
+            (dir.join("data"), 2),
+            (dir.join("link"), 0),
+        ]).with_attrs(vec![
+            (executable, None),
+            (padding, None),
+            (FileAttr::default(), None),
+            (symlink, Some(PathBuf::from("data"))),
+        ]);
+
+        s.set_len(10).unwrap();
+        s.write_piece(0, &[1, 2, 3, 0, 0, 0, 0, 0, 4, 5]).unwrap();
+        assert!(!dir.join(".pad").exists());
+        assert!(fs::symlink_metadata(dir.join("link")).is_err());
+        assert_eq!(s.read_block(2, 8).unwrap(), vec![3, 0, 0, 0, 0, 0, 4, 5]);
+
+        for index in 0..4 {
+            s.finish_file(index).unwrap();
+        }
+        if cfg!(unix) {
+            assert_eq!(fs::read(dir.join("link")).unwrap(), vec![4, 5]);
+            assert!(is_executable(&dir.join("run.sh")));
+            assert!(!is_executable(&dir.join("data")));
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn part_file_test() {
+        let dir = env::temp_dir().join("bittorrent_part_file_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut s = FileStorage::new(vec![
+            (dir.join("a"), 3),
+            (dir.join("b"), 4),
+            (dir.join("c"), 3),
+        ]).with_part_file(dir.join(".parts"));
+
+        s.set_skipped(&[true, false, true]).unwrap();
+        s.set_len(10).unwrap();
+        s.write_piece(0, &[1, 2, 3, 4, 5]).unwrap();
+        s.write_piece(5, &[6, 7, 8, 9, 10]).unwrap();
+        assert!(!dir.join("a").exists());
+        assert!(!dir.join("c").exists());
+        assert_eq!(fs::read(dir.join("b")).unwrap(), vec![4, 5, 6, 7]);
+        assert_eq!(s.read_block(0, 10).unwrap(), vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+
+        // data kept aside moves into a file once it is wanted
+        s.set_skipped(&[false, false, true]).unwrap();
+        assert_eq!(fs::read(dir.join("a")).unwrap(), vec![1, 2, 3]);
+        assert!(dir.join(".parts").exists());
+        s.set_skipped(&[false, false, false]).unwrap();
+        assert_eq!(fs::read(dir.join("c")).unwrap(), vec![8, 9, 10]);
+        assert!(!dir.join(".parts").exists());
+        assert_eq!(s.read_block(0, 10).unwrap(), vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    fn is_executable(path: &Path) -> bool {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).unwrap().permissions().mode() & 0o111 != 0
+    }
+
+    #[cfg(not(unix))]
+    fn is_executable(_path: &Path) -> bool {
+        false
+    }
+}
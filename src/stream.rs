@@ -0,0 +1,151 @@
+use std::net::{TcpStream, SocketAddr};
+use std::io::{Read, Write, Error};
+use std::time::Duration;
+use mse::Rc4;
+use utp::UtpStream;
+
+/// The protocols over which a connection to a peer can be made
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportKind {
+    Tcp,
+    Utp,
+}
+
+/// A connected socket to a peer, over either TCP or uTP
+#[derive(Debug)]
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl Transport {
+    pub fn connect(addr: &SocketAddr, kind: TransportKind) -> Result<Transport, Error> {
+        match kind {
+            TransportKind::Tcp => Ok(Transport::Tcp(TcpStream::connect(addr)?)),
+            TransportKind::Utp => Ok(Transport::Utp(UtpStream::connect(addr)?)),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match *self {
+            Transport::Tcp(ref s) => s.set_read_timeout(timeout),
+            Transport::Utp(ref s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    /// Reads received data without removing it from the stream
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            Transport::Tcp(ref s) => s.peek(buf),
+            Transport::Utp(ref s) => s.peek(buf),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.read(buf),
+            Transport::Utp(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.write(buf),
+            Transport::Utp(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.flush(),
+            Transport::Utp(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// Wraps the socket of a peer connection. Once an encrypted handshake has negotiated RC4, all
+/// data is transparently encrypted on write and decrypted on read
+#[derive(Debug)]
+pub struct PeerStream {
+    stream: Transport,
+    encryptor: Option<Rc4>,
+    decryptor: Option<Rc4>,
+    // data which was received during the handshake but belongs to the message stream
+    pending: Vec<u8>,
+}
+
+impl PeerStream {
+    pub fn plain(stream: Transport) -> Self {
+        PeerStream {
+            stream,
+            encryptor: None,
+            decryptor: None,
+            pending: vec![],
+        }
+    }
+
+    pub fn encrypted(stream: Transport, encryptor: Rc4, decryptor: Rc4) -> Self {
+        PeerStream {
+            stream,
+            encryptor: Some(encryptor),
+            decryptor: Some(decryptor),
+            pending: vec![],
+        }
+    }
+
+    /// Queues already decoded bytes to be returned by the next reads, before any data from the
+    /// socket
+    pub fn with_pending(mut self, pending: Vec<u8>) -> Self {
+        self.pending = pending;
+        self
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryptor.is_some()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if !self.pending.is_empty() {
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            return Ok(n)
+        }
+
+        let n = self.stream.read(buf)?;
+        if let Some(ref mut decryptor) = self.decryptor {
+            decryptor.apply(&mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self.encryptor {
+            Some(ref mut encryptor) => {
+                // the cipher state has already advanced, so the whole buffer must be written
+                let mut data = buf.to_vec();
+                encryptor.apply(&mut data);
+                self.stream.write_all(&data)?;
+                Ok(buf.len())
+            },
+            None => self.stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.stream.flush()
+    }
+}
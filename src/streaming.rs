@@ -0,0 +1,364 @@
+use hyper::header::{AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, ContentType, Range, RangeUnit};
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{Handler, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use url::percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use error::Error;
+use metainfo::Info;
+use piece::Priority;
+use reader::FileReader;
+use session::Torrents;
+use torrent::{PickMode, Torrent};
+use util;
+
+// how far past the requested position pieces are fetched ahead of all others
+const STREAM_WINDOW: u64 = 4 * 1024 * 1024;
+// every request being streamed occupies a thread while it waits for data
+const STREAM_THREADS: usize = 8;
+
+/// Serves the files of a session's torrents over HTTP while they download, so that they can be
+/// played or fetched before the torrents are complete. Each file is served under its path within
+/// its torrent, like on a web seed, and `/` lists the files
+struct StreamHandler {
+    torrents: Torrents,
+    // the files being served, keyed by their torrent's info hash and their index
+    streams: Mutex<HashMap<(Vec<u8>, usize), Stream>>,
+}
+
+/// A file being served to one or more clients, which share a reader. The torrent's pick mode and
+/// the file's priority from before the first response are put back once the last one ends
+struct Stream {
+    reader: Arc<Mutex<FileReader>>,
+    responses: usize,
+    pick_mode: PickMode,
+    priority: Priority,
+}
+
+/// Reads a shared file reader from a position of its own
+struct StreamReader {
+    reader: Arc<Mutex<FileReader>>,
+    position: u64,
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        reader.seek(SeekFrom::Start(self.position))?;
+        let read = reader.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+/// Starts serving the files of the torrents on the given address in the background, including
+/// those of torrents added later. Returns the address the server listens on
+pub fn start(host: &str, port: u16, torrents: Torrents) -> Result<SocketAddr, Error> {
+    let server = Server::http((host, port)).map_err(|e| Error::Io(io::Error::other(e.to_string())))?;
+    let mut listening = server.handle_threads(StreamHandler { torrents, streams: Mutex::new(HashMap::new()) }, STREAM_THREADS)
+        .map_err(|e| Error::Io(io::Error::other(e.to_string())))?;
+    // lets the server run on once the handle is dropped
+    let _ = listening.close();
+    Ok(listening.socket)
+}
+
+/// Returns the path under which every file of the torrent is served, with padding files left out
+fn file_paths(info: &Info) -> Vec<Option<String>> {
+    info.files().into_iter().zip(info.file_attrs()).map(|((path, _), (attr, _))| {
+        if attr.padding {
+            return None
+        }
+        let components: Vec<String> = path.iter()
+            .map(|c| utf8_percent_encode(&c.to_string_lossy(), PATH_SEGMENT_ENCODE_SET).collect())
+            .collect();
+        Some(format!("/{}", components.join("/")))
+    }).collect()
+}
+
+/// Returns the position of a file among the files which aren't padding, which file priorities
+/// are given for
+fn visible_index(info: &Info, index: usize) -> usize {
+    info.file_attrs()[..index].iter().filter(|&&(attr, _)| !attr.padding).count()
+}
+
+/// Returns the inclusive range of bytes of a file of the given length which the Range header asks
+/// for. None means the whole file, as for a missing header or one asking for several ranges, and
+/// an error means the range can't be satisfied
+fn requested_range(range: Option<&Range>, length: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match range {
+        Some(Range::Bytes(specs)) if specs.len() == 1 => &specs[0],
+        _ => return Ok(None)
+    };
+
+    match *spec {
+        ByteRangeSpec::FromTo(start, end) if start < length => Ok(Some((start, end.min(length - 1)))),
+        ByteRangeSpec::AllFrom(start) if start < length => Ok(Some((start, length - 1))),
+        ByteRangeSpec::Last(n) if n > 0 && length > 0 => Ok(Some((length - n.min(length), length - 1))),
+        _ => Err(())
+    }
+}
+
+/// Guesses the content type of a file from its extension, so that browsers play common media
+/// instead of downloading it
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "txt" => "text/plain",
+        _ => "application/octet-stream"
+    }
+}
+
+impl StreamHandler {
+    /// Returns the path of every file being served, along with its torrent and its index within
+    /// the torrent
+    fn files(&self) -> Vec<(String, Arc<Mutex<Torrent>>, usize)> {
+        let torrents: Vec<Arc<Mutex<Torrent>>> = self.torrents.lock().unwrap().values().map(|t| t.torrent.clone()).collect();
+        let mut files = vec![];
+        for torrent_mutex in torrents {
+            let paths = file_paths(&torrent_mutex.lock().unwrap().metainfo.info);
+            for (index, path) in paths.into_iter().enumerate() {
+                if let Some(path) = path {
+                    files.push((path, torrent_mutex.clone(), index));
+                }
+            }
+        }
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        files
+    }
+
+    fn respond(&self, request: &Request, mut response: Response<Fresh>) -> io::Result<()> {
+        let path = match request.uri {
+            RequestUri::AbsolutePath(ref path) => path.split('?').next().unwrap_or("").to_string(),
+            _ => String::new()
+        };
+        if request.method != Method::Get && request.method != Method::Head {
+            *response.status_mut() = StatusCode::MethodNotAllowed;
+            return response.send(b"")
+        }
+
+        let files = self.files();
+        if path == "/" {
+            let listing: Vec<&str> = files.iter().map(|f| f.0.as_str()).collect();
+            response.headers_mut().set(ContentType("text/plain".parse().unwrap()));
+            return response.send(format!("{}\n", listing.join("\n")).as_bytes())
+        }
+
+        // clients may encode paths differently, so they are compared decoded
+        let decode = |p: &str| percent_decode(p.as_bytes()).decode_utf8_lossy().into_owned();
+        let (torrent_mutex, index) = match files.into_iter().find(|f| decode(&f.0) == decode(&path)) {
+            Some((_, torrent_mutex, index)) => (torrent_mutex, index),
+            None => {
+                *response.status_mut() = StatusCode::NotFound;
+                return response.send(b"Not found")
+            }
+        };
+
+        let key = (torrent_mutex.lock().unwrap().metainfo.info_hash.clone(), index);
+        let reader = self.open(&key, &torrent_mutex)?;
+        let result = self.send_file(request, response, &path, &torrent_mutex, reader);
+        self.close(&key, &torrent_mutex);
+        result
+    }
+
+    /// Returns the reader of the file with the given key, which is created along with the file's
+    /// stream by the first response. The file is fetched from then on, even if it was skipped
+    fn open(&self, key: &(Vec<u8>, usize), torrent_mutex: &Arc<Mutex<Torrent>>) -> Result<Arc<Mutex<FileReader>>, Error> {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(stream) = streams.get_mut(key) {
+            stream.responses += 1;
+            return Ok(stream.reader.clone())
+        }
+
+        let reader = Arc::new(Mutex::new(FileReader::new(torrent_mutex.clone(), key.1)?));
+        let (pick_mode, priority) = {
+            let mut torrent = torrent_mutex.lock().unwrap();
+            let visible = visible_index(&torrent.metainfo.info, key.1);
+            let mut priorities = torrent.file_priorities();
+            let priority = priorities[visible];
+            if priority == Priority::Skip {
+                priorities[visible] = Priority::Normal;
+                torrent.set_file_priorities(priorities)?;
+            }
+            (torrent.pick_mode(), priority)
+        };
+        streams.insert(key.clone(), Stream { reader: reader.clone(), responses: 1, pick_mode, priority });
+        Ok(reader)
+    }
+
+    /// Ends a response for the file with the given key. The last one puts back the torrent's pick
+    /// mode and skips the file again if it was skipped before
+    fn close(&self, key: &(Vec<u8>, usize), torrent_mutex: &Arc<Mutex<Torrent>>) {
+        let mut streams = self.streams.lock().unwrap();
+        let done = match streams.get_mut(key) {
+            Some(stream) => {
+                stream.responses -= 1;
+                stream.responses == 0
+            },
+            None => false
+        };
+        if !done {
+            return
+        }
+
+        let stream = streams.remove(key).unwrap();
+        let mut torrent = torrent_mutex.lock().unwrap();
+        torrent.set_pick_mode(stream.pick_mode);
+        if stream.priority == Priority::Skip {
+            let visible = visible_index(&torrent.metainfo.info, key.1);
+            let mut priorities = torrent.file_priorities();
+            priorities[visible] = Priority::Skip;
+            if let Err(e) = torrent.set_file_priorities(priorities) {
+                warn!(info_hash = util::to_hex(&key.0).as_str(); "Skipping file {} again failed: {}", key.1, e);
+            }
+        }
+    }
+
+    /// Sends the requested range of a file, which is fetched before anything else while it's
+    /// being sent
+    fn send_file(&self, request: &Request, mut response: Response<Fresh>, path: &str, torrent_mutex: &Arc<Mutex<Torrent>>,
+                 reader: Arc<Mutex<FileReader>>) -> io::Result<()> {
+        let length = reader.lock().unwrap().length();
+        let (start, end) = match requested_range(request.headers.get::<Range>(), length) {
+            Ok(Some((start, last))) => {
+                *response.status_mut() = StatusCode::PartialContent;
+                response.headers_mut().set(ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((start, last)),
+                    instance_length: Some(length),
+                }));
+                (start, last + 1)
+            },
+            Ok(None) => (0, length),
+            Err(()) => {
+                *response.status_mut() = StatusCode::RangeNotSatisfiable;
+                response.headers_mut().set(ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(length) }));
+                return response.send(b"")
+            }
+        };
+
+        {
+            let mut torrent = torrent_mutex.lock().unwrap();
+            let piece_length = torrent.metainfo.info.piece_length as u64;
+            torrent.set_pick_mode(PickMode::Deadline((end - start).max(piece_length).min(STREAM_WINDOW)));
+        }
+        reader.lock().unwrap().seek(SeekFrom::Start(start))?;
+
+        response.headers_mut().set(AcceptRanges(vec![RangeUnit::Bytes]));
+        response.headers_mut().set(ContentLength(end - start));
+        response.headers_mut().set(ContentType(content_type(path).parse().unwrap()));
+        let mut body = response.start()?;
+        if request.method == Method::Get {
+            io::copy(&mut StreamReader { reader, position: start }.take(end - start), &mut body)?;
+        }
+        body.end()
+    }
+}
+
+impl Handler for StreamHandler {
+    fn handle(&self, request: Request, response: Response<Fresh>) {
+        if let Err(e) = self.respond(&request, response) {
+            warn!("Streaming {} failed: {}", request.uri, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod streaming_tests {
+    use super::{requested_range, start};
+    use hyper::Client;
+    use hyper::header::{ByteRangeSpec, Range};
+    use hyper::status::StatusCode;
+    use metainfo::test_metainfo;
+    use piece::Priority;
+    use session::{Session, SessionConfig};
+    use storage::{Storage, MemoryStorage};
+    use torrent::{PickMode, Torrent};
+    use std::io::Read;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use util::create_peer_id;
+
+    #[test]
+    fn requested_range_test() {
+        let range = |spec| Range::Bytes(vec![spec]);
+        assert_eq!(requested_range(None, 100), Ok(None));
+        assert_eq!(requested_range(Some(&range(ByteRangeSpec::FromTo(10, 19))), 100), Ok(Some((10, 19))));
+        assert_eq!(requested_range(Some(&range(ByteRangeSpec::FromTo(90, 200))), 100), Ok(Some((90, 99))));
+        assert_eq!(requested_range(Some(&range(ByteRangeSpec::AllFrom(40))), 100), Ok(Some((40, 99))));
+        assert_eq!(requested_range(Some(&range(ByteRangeSpec::Last(30))), 100), Ok(Some((70, 99))));
+        assert_eq!(requested_range(Some(&range(ByteRangeSpec::Last(300))), 100), Ok(Some((0, 99))));
+        assert_eq!(requested_range(Some(&range(ByteRangeSpec::AllFrom(100))), 100), Err(()));
+        assert_eq!(requested_range(Some(&Range::bytes_multi(vec![(0, 1), (5, 6)])), 100), Ok(None));
+    }
+
+    #[test]
+    fn stream_file_test() {
+        // a 10 byte file followed by a 30000 byte file, over pieces of 16KiB
+        let data: Vec<u8> = (0..30010).map(|i| (i % 251) as u8).collect();
+        let m = test_metainfo("media", 16384, &data, &[("readme.txt", 10), ("my movie.mp4", 30000)]);
+
+        let mut storage = MemoryStorage::new();
+        storage.set_len(data.len() as u64).unwrap();
+        let session = Session::new(SessionConfig::default());
+        let info_hash = session.add(Torrent::with_storage(create_peer_id(), m, Box::new(storage))).unwrap();
+        let torrent = session.torrent(&info_hash).unwrap();
+        torrent.lock().unwrap().set_file_priorities(vec![Priority::Normal, Priority::Skip]).unwrap();
+        torrent.lock().unwrap().set_pick_mode(PickMode::Sequential);
+        torrent.lock().unwrap().store_piece(0, data[..16384].to_vec()).unwrap();
+        let addr = start("127.0.0.1", 0, session.torrents()).unwrap();
+        let url = format!("http://127.0.0.1:{}", addr.port());
+        let client = Client::new();
+
+        let mut listing = String::new();
+        client.get(&format!("{}/", url)).send().unwrap().read_to_string(&mut listing).unwrap();
+        assert_eq!(listing, "/media/my%20movie.mp4\n/media/readme.txt\n");
+        assert_eq!(client.get(&format!("{}/media/other", url)).send().unwrap().status, StatusCode::NotFound);
+
+        // the second piece arrives while the range is being served
+        let writer = torrent.clone();
+        let later = data.clone();
+        let serving = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let mut torrent = writer.lock().unwrap();
+            let state = (torrent.file_priorities()[1], torrent.pick_mode());
+            torrent.store_piece(1, later[16384..].to_vec()).unwrap();
+            state
+        });
+        let mut response = client.get(&format!("{}/media/my%20movie.mp4", url))
+            .header(Range::bytes(16000, 16999))
+            .send().unwrap();
+        assert_eq!(response.status, StatusCode::PartialContent);
+        let mut body = vec![];
+        response.read_to_end(&mut body).unwrap();
+        assert_eq!(body, &data[16010..17010]);
+
+        // the skipped file was wanted while it was served, with the range fetched first, and is
+        // skipped again once the response is over
+        assert_eq!(serving.join().unwrap(), (Priority::Normal, PickMode::Deadline(16384)));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while torrent.lock().unwrap().pick_mode() != PickMode::Sequential && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(torrent.lock().unwrap().pick_mode(), PickMode::Sequential);
+        assert_eq!(torrent.lock().unwrap().file_priorities()[1], Priority::Skip);
+
+        let response = client.get(&format!("{}/media/readme.txt", url))
+            .header(Range::Bytes(vec![ByteRangeSpec::AllFrom(10)]))
+            .send().unwrap();
+        assert_eq!(response.status, StatusCode::RangeNotSatisfiable);
+        let mut body = vec![];
+        client.get(&format!("{}/media/readme.txt", url)).send().unwrap().read_to_end(&mut body).unwrap();
+        assert_eq!(body, &data[..10]);
+    }
+}
@@ -1,109 +1,644 @@
+use bandwidth::Limits;
+use choker::{self, Candidate, OPTIMISTIC_ROUNDS, UNCHOKE_SLOTS};
+use event::{Event, Events};
 use metainfo::MetaInfo;
 use ipc::IpcMessage;
-use piece::Piece;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
-use std::io::Error;
+use merkle;
+use message::HashRequest;
+use piece::{Piece, Priority, Stored};
+use storage::{self, Storage, FileStorage};
+use resume;
+use util;
+use resume::{ResumeData, FileState};
+use peer::Peer;
+use rand::{thread_rng, Rng};
+use stats::{Stats, TransferStats};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use error::Error;
 use std::sync::mpsc::{Sender};
+use std::time::{Duration, Instant};
+
+// the resume file is written once this many pieces were verified since it was last written, or
+// once this much time passed, rather than after every piece
+const RESUME_SAVE_PIECES: u32 = 64;
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How the next piece to download is picked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PickMode {
+    /// Pieces of higher priority first, and otherwise in the order of the data
+    Priority,
+    /// Every wanted piece in the order of the data, regardless of priority
+    Sequential,
+    /// Pieces within the given number of bytes after the playback cursor first, nearest first,
+    /// and then by priority. Meant for playing a file while it downloads
+    Deadline(u64),
+}
 
 #[derive(Debug)]
 pub struct Torrent {
     pub metainfo: MetaInfo,
     pub peer_id: String,
-    file: File,
+    storage: Box<dyn Storage>,
     pub pieces: Vec<Piece>,
     peer_channels: Vec<Sender<IpcMessage>>,
+    // the data files, in the order of the metainfo's files, and the resume file, which only exist
+    // when the data is stored on disk. Padding files are listed but never stored
+    files: Vec<PathBuf>,
+    resume_path: Option<PathBuf>,
+    // pieces verified since the resume file was last written, and when that was
+    unsaved_pieces: u32,
+    saved_at: Instant,
+    known_peers: Vec<Peer>,
+    file_priorities: Vec<Priority>,
+    pick_mode: PickMode,
+    // position within the torrent's data which is being played, for the deadline mode
+    cursor: u64,
+    stopped: bool,
+    events: Events,
+    // the connected peers, and the bytes transferred with all peers since the torrent was created
+    peers: HashMap<SocketAddr, PeerState>,
+    stats: TransferStats,
+    optimistic: Option<SocketAddr>,
+    rechokes: u32,
+    limits: Limits,
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    stats: TransferStats,
+    limits: Limits,
+    // whether the peer wants pieces from us, and whether the choker lets it have them
+    interested: bool,
+    unchoked: bool,
+}
+
+/// A snapshot of a connected peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    pub stats: Stats,
+    pub interested: bool,
+    pub unchoked: bool,
 }
 
 /// Represents the entire torrent, including metainfo derived from the `.torrent` file as well as
-/// the client's id, the file to be downloaded and the pieces of the file
+/// the client's id, the storage for the downloaded data and the pieces of the file
 impl Torrent {
-    pub fn new(peer_id: String, metainfo: MetaInfo) -> Self {
-        let filename = metainfo.clone().info.name;
-        let piece_length = metainfo.info.piece_length;
-        let num_pieces = metainfo.info.num_pieces;
-        let path = Path::new(&filename);
+    /// Creates a torrent which stores its data in the current directory. Pieces recorded in a
+    /// matching resume file are marked as complete; otherwise any existing data is hashed to find
+    /// the pieces which were already downloaded
+    pub fn new(peer_id: String, metainfo: MetaInfo) -> Result<Self, Error> {
+        Torrent::with_priorities(peer_id, metainfo, vec![])
+    }
 
-        if !path.exists() {
-            let f = File::create(path).unwrap();
-            let _ = f.set_len(metainfo.info.length);
-        }
+    /// Creates a torrent like `new` which downloads its files with the given priorities, as for
+    /// `set_file_priorities`. Skipped files are never created
+    pub fn with_priorities(peer_id: String, metainfo: MetaInfo, priorities: Vec<Priority>) -> Result<Self, Error> {
+        let files = metainfo.info.files();
+        let attrs = metainfo.info.file_attrs();
+        let paths: Vec<PathBuf> = files.iter().map(|(f, _)| f.clone()).collect();
+        // padding files are never stored on disk
+        let has_data = paths.iter().zip(attrs.iter())
+            .any(|(f, &(attr, _))| !attr.padding && FileState::of(f).length > 0);
+        let resume_path = resume::resume_path(Path::new(""), &metainfo.info.name);
+        let part_path = storage::part_path(Path::new(""), &metainfo.info.name);
 
-        let file = OpenOptions::new().write(true).open(path).unwrap();
-        let mut pieces: Vec<Piece> = vec![];
-        let n = metainfo.info.pieces.len();
+        let storage = FileStorage::new(files).with_attrs(attrs).with_part_file(part_path);
+        let mut torrent = Torrent::with_storage(peer_id, metainfo, Box::new(storage));
+        torrent.files = paths;
+        torrent.set_file_priorities(priorities)?;
 
-        for i in 0..n {
-            let length = {
-                if i == n - 1 {
-                    num_pieces % piece_length
-                } else {
-                    piece_length
+        match ResumeData::load(&resume_path) {
+            Some(ref resume) if resume.is_valid_for(&torrent.files, torrent.pieces.len()) => {
+                for (piece, have) in torrent.pieces.iter_mut().zip(resume.have.iter()) {
+                    piece.is_complete = *have;
                 }
-            };
-
-            let piece = Piece::new(length, i as u32, piece_length, metainfo.info.pieces[i as usize].clone());
-            pieces.push(piece);
+                torrent.known_peers = resume.peers.clone();
+                let length = torrent.metainfo.info.length;
+                torrent.finish_files(0, length)?;
+            },
+            _ => {
+                if has_data {
+                    torrent.recheck()?;
+                }
+            }
         }
 
+        torrent.resume_path = Some(resume_path);
+        Ok(torrent)
+    }
+
+    /// Creates a torrent which stores its data in the given storage
+    pub fn with_storage(peer_id: String, metainfo: MetaInfo, storage: Box<dyn Storage>) -> Self {
+        let piece_length = metainfo.info.piece_length;
+        let pieces: Vec<Piece> = metainfo.piece_infos().iter().enumerate()
+            .map(|(i, info)| Piece::from_info(i as u32, piece_length, info))
+            .collect();
+        let file_priorities = vec![Priority::Normal; metainfo.info.files().len()];
+
         Torrent {
             metainfo: metainfo,
             peer_id: peer_id,
-            file: file,
+            storage,
             pieces: pieces,
-            peer_channels: vec![]
+            peer_channels: vec![],
+            files: vec![],
+            resume_path: None,
+            unsaved_pieces: 0,
+            saved_at: Instant::now(),
+            known_peers: vec![],
+            file_priorities,
+            pick_mode: PickMode::Priority,
+            cursor: 0,
+            stopped: false,
+            events: Events::new(),
+            peers: HashMap::new(),
+            stats: TransferStats::new(),
+            optimistic: None,
+            rechokes: 0,
+            limits: Limits::unlimited(),
+        }
+    }
+
+    /// Sets where the torrent reports its events, such as to the subscribers of a session
+    pub fn set_events(&mut self, events: Events) {
+        self.events = events;
+    }
+
+    /// Reports an event to the torrent's subscribers
+    pub fn emit(&self, event: Event) {
+        self.events.emit(event);
+    }
+
+    /// Stops downloading the torrent, telling its connections to close
+    pub fn stop(&mut self) {
+        self.stopped = true;
+        for channel in self.peer_channels.drain(..) {
+            let _ = channel.send(IpcMessage::Stop);
+        }
+        // pieces verified since the last save aren't hashed again next time
+        if self.unsaved_pieces > 0 {
+            if let Err(e) = self.save_resume() {
+                self.emit(Event::StorageError { info_hash: self.metainfo.info_hash.clone(), error: e.to_string() });
+            }
+        }
+    }
+
+    /// Lets the torrent download again after it was stopped
+    pub fn resume(&mut self) {
+        self.stopped = false;
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn pick_mode(&self) -> PickMode {
+        self.pick_mode
+    }
+
+    pub fn set_pick_mode(&mut self, mode: PickMode) {
+        self.pick_mode = mode;
+    }
+
+    /// Moves the playback cursor, which the deadline mode downloads ahead of, to the given
+    /// position within the torrent's data
+    pub fn set_cursor(&mut self, offset: u64) {
+        self.cursor = offset;
+    }
+
+    /// Reads stored data of the torrent, regardless of whether it was verified
+    pub fn read(&mut self, offset: u64, length: u32) -> Result<Vec<u8>, Error> {
+        self.storage.read_block(offset, length).map_err(Error::Storage)
+    }
+
+    /// Reads a block of a complete piece to send to a peer. Returns None if the piece isn't
+    /// complete or the block lies outside of it
+    pub fn read_block(&mut self, piece_index: u32, offset: u32, length: u32) -> Result<Option<Vec<u8>>, Error> {
+        let start = match self.pieces.get(piece_index as usize) {
+            Some(piece) if piece.is_complete && offset as u64 + length as u64 <= piece.length as u64 =>
+                piece.offset() + offset as u64,
+            _ => return Ok(None)
+        };
+        self.read(start, length).map(Some)
+    }
+
+    /// Returns the priority of every file, in the same order as the files. Padding files are left
+    /// out, as they aren't downloaded on their own
+    pub fn file_priorities(&self) -> Vec<Priority> {
+        self.visible_files().into_iter().map(|index| self.file_priorities[index]).collect()
+    }
+
+    /// Sets the priorities of the files, given in the same order as the files but without padding
+    /// files, where missing priorities are normal. A piece gets the highest priority of the files
+    /// it overlaps, so skipped files are only downloaded where they share a piece with a wanted
+    /// file
+    pub fn set_file_priorities(&mut self, priorities: Vec<Priority>) -> Result<(), Error> {
+        let files = self.metainfo.info.files();
+        let visible = self.visible_files();
+        if priorities.len() > visible.len() {
+            return Err(Error::invalid("file priorities", &format!("{} given for {} files", priorities.len(), visible.len())))
+        }
+        let mut all = vec![Priority::Normal; files.len()];
+        for (&index, &priority) in visible.iter().zip(priorities.iter()) {
+            all[index] = priority;
+        }
+        let priorities = all;
+
+        let ranges = self.file_ranges();
+        // padding files are never wanted on their own
+        let wanted: Vec<(u64, u64, Priority)> = ranges.iter().zip(self.metainfo.info.file_attrs()).zip(priorities.iter())
+            .filter(|&((_, (ref attr, _)), _)| !attr.padding)
+            .map(|((&(start, end), _), &priority)| (start, end, priority))
+            .collect();
+        for piece in self.pieces.iter_mut() {
+            let (start, end) = (piece.offset(), piece.offset() + piece.length as u64);
+            piece.priority = wanted.iter()
+                .filter(|&&(file_start, file_end, _)| file_start < end && start < file_end)
+                .map(|&(_, _, priority)| priority)
+                .max()
+                .unwrap_or(Priority::Skip);
+        }
+
+        let skipped: Vec<bool> = priorities.iter().map(|&p| p == Priority::Skip).collect();
+        self.storage.set_skipped(&skipped).map_err(Error::Storage)?;
+        self.storage.set_len(self.metainfo.info.length).map_err(Error::Storage)?;
+
+        // files which were skipped may have been complete all along
+        let unskipped: Vec<usize> = (0..files.len())
+            .filter(|&i| self.file_priorities[i] == Priority::Skip && !skipped[i])
+            .collect();
+        self.file_priorities = priorities;
+        for index in unskipped {
+            let (start, end) = ranges[index];
+            if self.is_range_complete(start, end) {
+                self.storage.finish_file(index).map_err(Error::Storage)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hashes the stored data of every piece and marks the pieces which match as complete, and
+    /// finishes the files which are. Returns the number of complete pieces
+    pub fn recheck(&mut self) -> Result<usize, Error> {
+        let mut complete = 0;
+        for piece in self.pieces.iter_mut() {
+            piece.is_complete = self.storage.verify(piece.offset(), piece.length, &piece.hash, piece.hash_type)
+                .map_err(Error::Storage)?;
+            if piece.is_complete {
+                complete += 1;
+            }
+        }
+        let length = self.metainfo.info.length;
+        self.finish_files(0, length)?;
+        Ok(complete)
+    }
+
+    /// Writes the resume file recording the complete pieces, the state of the data files and the
+    /// known peers. Does nothing if the data isn't stored on disk
+    pub fn save_resume(&mut self) -> Result<(), Error> {
+        self.unsaved_pieces = 0;
+        self.saved_at = Instant::now();
+        match self.resume_path {
+            Some(ref path) => {
+                let resume = ResumeData {
+                    have: self.pieces.iter().map(|p| p.is_complete).collect(),
+                    files: self.files.iter().map(|f| FileState::of(f)).collect(),
+                    peers: self.known_peers.clone(),
+                };
+                resume.save(path)
+            },
+            None => Ok(())
         }
     }
 
+    /// Returns the peers remembered from the previous session
+    pub fn known_peers(&self) -> &[Peer] {
+        &self.known_peers
+    }
+
+    /// Sets the peers to remember for the next session
+    pub fn set_known_peers(&mut self, peers: Vec<Peer>) {
+        self.known_peers = peers;
+    }
+
     /// Given a piece index, block index, a vector of bytes for a block, we store
     /// the new block at its position within the piece and return whether or not
     /// the piece is complete to determine if we should keep requesting blocks
     pub fn store(&mut self, piece_index: u32, block_index: u32, data: Vec<u8>) -> Result<bool, Error> {
-        {
+        let result = self.store_block(piece_index, block_index, data);
+        if let Err(Error::Storage(ref e)) = result {
+            self.emit(Event::StorageError { info_hash: self.metainfo.info_hash.clone(), error: e.to_string() });
+        }
+        result
+    }
+
+    fn store_block(&mut self, piece_index: u32, block_index: u32, data: Vec<u8>) -> Result<bool, Error> {
+        let info_hash = self.metainfo.info_hash.clone();
+        let block_length = data.len() as u32;
+        let verified = {
+            if piece_index as usize >= self.pieces.len() {
+                return Err(Error::Protocol(format!("Piece index {} out of range", piece_index)))
+            }
             let piece = &mut self.pieces[piece_index as usize];
-            try!(piece.store(&mut self.file, block_index, data));
+            let was_complete = piece.is_complete;
+            match piece.store(&mut *self.storage, block_index, data)? {
+                Stored::Verified => !was_complete,
+                Stored::HashFailed => {
+                    self.events.emit(Event::PieceFailed { info_hash: info_hash.clone(), piece: piece_index });
+                    false
+                },
+                Stored::Incomplete => false
+            }
+        };
+
+        if verified {
+            self.emit(Event::PieceVerified { info_hash: info_hash.clone(), piece: piece_index });
+            self.peer_channels.retain(|channel| channel.send(IpcMessage::Have(piece_index)).is_ok());
+            let (start, end) = {
+                let piece = &self.pieces[piece_index as usize];
+                (piece.offset(), piece.offset() + piece.length as u64)
+            };
+            self.finish_files(start, end)?;
+            self.unsaved_pieces += 1;
+            if self.unsaved_pieces >= RESUME_SAVE_PIECES || self.saved_at.elapsed() >= RESUME_SAVE_INTERVAL || self.is_complete() {
+                self.save_resume()?;
+            }
+        }
+
+        // connections and readers which went away are forgotten
+        self.peer_channels.retain(|channel| {
+            channel.send(IpcMessage::CancelRequest(piece_index, block_index, block_length)).is_ok()
+        });
+
+        let is_complete = self.is_complete();
+        if is_complete {
+            self.storage.flush().map_err(Error::Storage)?;
+            if verified {
+                self.emit(Event::TorrentFinished(info_hash));
+            }
+        }
+        Ok(is_complete)
+    }
+
+    /// Lets the storage apply the attributes of the complete files which overlap the given range
+    /// of the torrent's data. Empty files, such as symlinks, are finished along with the whole
+    /// torrent
+    fn finish_files(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        let finished: Vec<usize> = self.file_ranges().into_iter().enumerate()
+            .filter(|&(index, (file_start, file_end))| {
+                self.file_priorities[index] != Priority::Skip &&
+                    (file_start == file_end || (file_start < end && start < file_end)) &&
+                    self.is_range_complete(file_start, file_end)
+            })
+            .map(|(index, _)| index)
+            .collect();
+
+        for index in finished {
+            self.storage.finish_file(index).map_err(Error::Storage)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the indices of the files which aren't padding files
+    fn visible_files(&self) -> Vec<usize> {
+        self.metainfo.info.file_attrs().into_iter().enumerate()
+            .filter(|&(_, (attr, _))| !attr.padding)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Returns the start and end of every file within the torrent's data
+    fn file_ranges(&self) -> Vec<(u64, u64)> {
+        let mut offset = 0;
+        self.metainfo.info.files().into_iter().map(|(_, length)| {
+            offset += length;
+            (offset - length, offset)
+        }).collect()
+    }
+
+    /// Returns whether every piece overlapping the given range of the torrent's data is complete.
+    /// Empty ranges, such as those of empty files, are complete along with the whole torrent
+    fn is_range_complete(&self, start: u64, end: u64) -> bool {
+        if start == end {
+            return self.pieces.iter().all(|p| p.is_complete || p.priority == Priority::Skip)
+        }
+        let first = self.pieces.partition_point(|p| p.offset() + p.length as u64 <= start);
+        self.pieces[first..].iter().take_while(|p| p.offset() < end).all(|p| p.is_complete)
+    }
+
+    /// Stores the data of a whole piece, such as one downloaded from a web seed, by splitting it
+    /// into the piece's blocks. Returns whether the torrent is complete
+    pub fn store_piece(&mut self, piece_index: u32, data: Vec<u8>) -> Result<bool, Error> {
+        let block_lengths: Vec<usize> = match self.pieces.get(piece_index as usize) {
+            Some(piece) if piece.length as usize == data.len() => piece.blocks.iter().map(|b| b.length as usize).collect(),
+            _ => return Err(Error::Protocol(format!("Unexpected data for piece {}", piece_index)))
+        };
+
+        let mut is_complete = false;
+        let mut offset = 0;
+        for (block_index, length) in block_lengths.into_iter().enumerate() {
+            is_complete = self.store(piece_index, block_index as u32, data[offset..offset + length].to_vec())?;
+            offset += length;
         }
+        Ok(is_complete)
+    }
 
-        for channel in self.peer_channels.iter() {
-            let _ = channel.send(IpcMessage::CancelRequest(piece_index, block_index));
+    /// Answers a peer's request for hashes from the piece layer of a file (BEP 52). Returns the
+    /// requested hashes followed by the uncle hashes proving them, or None if the torrent doesn't
+    /// have them
+    pub fn hashes(&self, request: &HashRequest) -> Option<Vec<Vec<u8>>> {
+        let per_piece = merkle::num_leaves(self.metainfo.info.piece_length as u64);
+        let layer = self.metainfo.piece_layers.get(&request.pieces_root)?;
+        let (index, length) = (request.index as usize, request.length as usize);
+        let width = layer.len().next_power_of_two();
+        if request.base_layer != per_piece.trailing_zeros() || length < 2 || !length.is_power_of_two() ||
+            index % length != 0 || index + length > width {
+            return None
         }
 
-        Ok(self.is_complete())
+        let pad = merkle::pad_hash(per_piece);
+        let mut hashes: Vec<Vec<u8>> = (index..index + length)
+            .map(|i| layer.get(i).cloned().unwrap_or_else(|| pad.clone()))
+            .collect();
+        let subtrees: Vec<Vec<u8>> = layer.chunks(length).map(|c| merkle::root(c, length, &pad)).collect();
+        let uncles = merkle::proof(&subtrees, width / length, &merkle::pad_hash(per_piece * length), index / length);
+        hashes.extend(uncles.into_iter().take(request.proof_layers as usize));
+        Some(hashes)
     }
 
+    /// Returns the indices of the wanted pieces in the order the pick mode downloads them
+    fn pick_order<'a>(&'a self) -> Box<dyn Iterator<Item = usize> + 'a> {
+        let by_priority = move || [Priority::High, Priority::Normal, Priority::Low].iter()
+            .flat_map(move |&priority| self.pieces.iter().filter(move |p| p.priority == priority))
+            .map(|p| p.index as usize);
+
+        match self.pick_mode {
+            PickMode::Priority => Box::new(by_priority()),
+            PickMode::Sequential => Box::new(self.pieces.iter()
+                .filter(|p| p.priority != Priority::Skip)
+                .map(|p| p.index as usize)),
+            PickMode::Deadline(window) => {
+                let (cursor, end) = (self.cursor, self.cursor + window);
+                let urgent = move |p: &Piece| p.priority != Priority::Skip &&
+                    p.offset() < end && cursor < p.offset() + p.length as u64;
+                Box::new(self.pieces.iter().filter(move |p| urgent(p)).map(|p| p.index as usize)
+                    .chain(by_priority().filter(move |&i| !urgent(&self.pieces[i]))))
+            }
+        }
+    }
 
     /// Loops through pieces and checks if peer has requested piece
     /// If so, it returns the next block's information in a triple of
-    /// the piece length, the block index, and the block length
+    /// the piece length, the block index, and the block length. Pieces
+    /// are picked as the pick mode says and skipped pieces are never requested
     pub fn next_block_to_request(&self, peer_has_pieces: &[bool]) -> Option<(u32, u32, u32)> {
-        for piece in self.pieces.iter() {
-            if peer_has_pieces[piece.index as usize] {
-                match piece.next_block_to_request() {
-                    Some(block) => {
-                        return Some((piece.index, block.index, block.length))
-                    },
-                    None => {}
+        for index in self.pick_order() {
+            if peer_has_pieces[index] {
+                let piece = &self.pieces[index];
+                if let Some(block) = piece.next_block_to_request() {
+                    return Some((piece.index, block.index, block.length))
                 }
             }
         }
         None
     }
 
+    /// Returns whether the torrent is neither complete nor stopped, so that it needs peers
+    pub fn is_downloading(&self) -> bool {
+        !self.stopped && !self.is_complete()
+    }
+
     /// Returns a boolean that represents whether all the pieces for the
-    /// torrent has been retrieved
-    fn is_complete(&self) -> bool {
+    /// torrent has been retrieved, not counting skipped pieces
+    pub fn is_complete(&self) -> bool {
         for piece in self.pieces.iter() {
-            if !piece.is_complete {
+            if !piece.is_complete && piece.priority != Priority::Skip {
                 return false
             }
         }
-        println!("Torrent is complete");
         true
     }
 
+    /// Returns the complete pieces as the bytes of a bitfield message, or None if no piece is
+    /// complete yet
+    pub fn bitfield(&self) -> Option<Vec<u8>> {
+        if !self.pieces.iter().any(|p| p.is_complete) {
+            return None
+        }
+        let have: Vec<bool> = self.pieces.iter().map(|p| p.is_complete).collect();
+        Some(util::bitfield_to_bytes(&have))
+    }
+
     pub fn register_peer(&mut self, channel: Sender<IpcMessage>) {
         self.peer_channels.push(channel);
     }
+
+    /// Starts counting the bytes transferred with the peer at the given address, which just
+    /// connected. Returns the peer's own limits, which apply until it disconnects
+    pub fn add_peer(&mut self, addr: SocketAddr) -> Limits {
+        let peer = PeerState::default();
+        let limits = peer.limits.clone();
+        self.peers.insert(addr, peer);
+        limits
+    }
+
+    /// Returns the limits of the torrent's transfers with all its peers
+    pub fn limits(&self) -> Limits {
+        self.limits.clone()
+    }
+
+    /// Returns the limits of the transfers with the connected peer at the given address
+    pub fn peer_limits(&self, addr: &SocketAddr) -> Option<Limits> {
+        self.peers.get(addr).map(|p| p.limits.clone())
+    }
+
+    /// Forgets the peer at the given address once its connection closed
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+        if self.optimistic == Some(*addr) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Counts bytes received from the peer at the given address
+    pub fn record_download(&mut self, addr: &SocketAddr, payload: u64, overhead: u64) {
+        self.stats.download.add(payload, overhead);
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.stats.download.add(payload, overhead);
+        }
+    }
+
+    /// Counts bytes received from a web seed, which isn't one of the connected peers
+    pub fn record_seed_download(&mut self, payload: u64, overhead: u64) {
+        self.stats.download.add(payload, overhead);
+    }
+
+    /// Counts bytes sent to the peer at the given address
+    pub fn record_upload(&mut self, addr: &SocketAddr, payload: u64, overhead: u64) {
+        self.stats.upload.add(payload, overhead);
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.stats.upload.add(payload, overhead);
+        }
+    }
+
+    /// Returns the bytes transferred with all peers since the torrent was created
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
+    /// Returns the connected peers along with the bytes transferred with each
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        self.peers.iter().map(|(&addr, p)| PeerStatus {
+            addr,
+            stats: p.stats.snapshot(),
+            interested: p.interested,
+            unchoked: p.unchoked,
+        }).collect()
+    }
+
+    /// Records whether the peer at the given address wants pieces from us
+    pub fn set_interested(&mut self, addr: &SocketAddr, interested: bool) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.interested = interested;
+        }
+    }
+
+    /// Returns whether the choker lets the peer at the given address download from us
+    pub fn is_unchoked(&self, addr: &SocketAddr) -> bool {
+        self.peers.get(addr).is_some_and(|p| p.unchoked)
+    }
+
+    /// Decides which peers may download from us, ranking them by how fast they send us data or,
+    /// once the torrent is complete, how fast we send them data. Every few rounds the optimistic
+    /// unchoke moves on to another interested peer at random
+    pub fn rechoke(&mut self) {
+        let seeding = self.is_complete();
+        let candidates: Vec<Candidate> = self.peers.iter().map(|(&addr, p)| Candidate {
+            addr,
+            interested: p.interested,
+            rate: if seeding { p.stats.upload.rate() } else { p.stats.download.rate() },
+        }).collect();
+
+        if self.rechokes.is_multiple_of(OPTIMISTIC_ROUNDS) {
+            let others: Vec<SocketAddr> = candidates.iter()
+                .filter(|c| c.interested && Some(c.addr) != self.optimistic)
+                .map(|c| c.addr)
+                .collect();
+            if let Some(&addr) = thread_rng().choose(&others) {
+                self.optimistic = Some(addr);
+            }
+        }
+        self.rechokes += 1;
+
+        let unchoked = choker::choose(&candidates, UNCHOKE_SLOTS, self.optimistic);
+        for (addr, peer) in self.peers.iter_mut() {
+            peer.unchoked = unchoked.contains(addr);
+        }
+    }
 }
 
 impl PartialEq for Torrent {
@@ -120,13 +655,24 @@ impl PartialEq for Torrent {
 
 #[cfg(test)]
 mod torrent_tests {
-    use super::Torrent;
-    use piece::Piece;
+    use super::{Torrent, PickMode};
+    use event::{Event, Events};
+    use piece::{Piece, Priority};
     use block::Block;
-    use metainfo::{MetaInfo, Info};
-    use std::fs::File;
+    use metainfo::{test_metainfo, MetaInfo, Info, FileAttr, PieceHash, TreeFile};
+    use message::HashRequest;
+    use merkle;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use stats::TransferStats;
+    use bandwidth::Limits;
+    use storage::{Storage, FileStorage, MemoryStorage};
+    use resume::ResumeData;
+    use std::env;
     use std::path::Path;
     use std::fs;
+    use std::time::Instant;
+    use std::sync::mpsc::channel;
     use util::create_peer_id;
 
     #[test]
@@ -137,37 +683,311 @@ mod torrent_tests {
             pieces: vec![vec![1, 2, 3]],
             num_pieces: 3,
             name: filename.clone(),
-            length: 12
+            length: 12,
+            private: false,
+            files: vec![],
+            attr: FileAttr::default(),
+            meta_version: 1,
+            file_tree: vec![],
         };
 
         let m = MetaInfo {
-            announce: String::from("https://google.com/announce"),
+            announce: Some(String::from("https://google.com/announce")),
+            announce_list: vec![],
             created_by: String::from("tov"),
             info: i,
-            info_hash: vec![2, 3, 4]
+            info_hash: vec![2, 3, 4],
+            url_list: vec![],
+            info_hash_v2: None,
+            piece_layers: HashMap::new(),
         };
 
         let path = Path::new(&filename);
-        let _ = File::create(path);
-        let f = File::open(path).unwrap();
         let peer_id = create_peer_id();
 
-        let t = Torrent::new(peer_id.clone(), m.clone());
+        let t = Torrent::new(peer_id.clone(), m.clone()).unwrap();
         assert_eq!(t, Torrent {
             metainfo: m,
             peer_id: peer_id,
-            file: f,
+            storage: Box::new(MemoryStorage::new()),
             pieces: vec![Piece {
-                length: 3,
+                length: 12,
                 index: 0,
                 piece_length: 12,
-                blocks: vec![Block::new(0, 3)],
+                blocks: vec![Block::new(0, 12)],
                 hash: vec![1, 2, 3],
                 is_complete: false,
+                offset: 0,
+                hash_type: PieceHash::Sha1,
+                priority: Priority::Normal,
             }],
-            peer_channels: vec![]
+            peer_channels: vec![],
+            files: vec![],
+            resume_path: None,
+            unsaved_pieces: 0,
+            saved_at: Instant::now(),
+            known_peers: vec![],
+            file_priorities: vec![Priority::Normal],
+            pick_mode: PickMode::Priority,
+            cursor: 0,
+            stopped: false,
+            events: Events::new(),
+            peers: HashMap::new(),
+            stats: TransferStats::new(),
+            optimistic: None,
+            rechokes: 0,
+            limits: Limits::unlimited(),
         });
 
         let _ = fs::remove_file(path);
     }
+
+    #[test]
+    fn store_in_memory_test() {
+        let data = vec![7; 20000];
+        let m = test_metainfo("memory.txt", 20000, &data, &[]);
+
+        let mut storage = MemoryStorage::new();
+        storage.set_len(20000).unwrap();
+        let mut t = Torrent::with_storage(create_peer_id(), m, Box::new(storage));
+        let events = Events::new();
+        let received = events.subscribe();
+        t.set_events(events);
+
+        // a corrupt piece is thrown away and downloaded again
+        assert!(!t.store(0, 0, vec![0; 16384]).unwrap());
+        assert!(!t.store(0, 1, data[16384..].to_vec()).unwrap());
+        assert!(!t.pieces[0].is_complete);
+
+        assert!(!t.store(0, 0, data[..16384].to_vec()).unwrap());
+        assert!(t.store(0, 1, data[16384..].to_vec()).unwrap());
+        assert_eq!(t.storage.read_block(0, 20000).unwrap(), data);
+        assert!(!Path::new("memory.txt").exists());
+        assert_eq!(received.try_iter().collect::<Vec<Event>>(), vec![
+            Event::PieceFailed { info_hash: vec![1; 20], piece: 0 },
+            Event::PieceVerified { info_hash: vec![1; 20], piece: 0 },
+            Event::TorrentFinished(vec![1; 20]),
+        ]);
+    }
+
+    #[test]
+    fn resume_save_test() {
+        let data: Vec<u8> = (0..24).collect();
+        let path = env::temp_dir().join("bittorrent_resume_save_test.resume");
+        let _ = fs::remove_file(&path);
+        let mut t = Torrent::with_storage(create_peer_id(), test_metainfo("saves", 8, &data, &[]), Box::new(MemoryStorage::new()));
+        t.resume_path = Some(path.clone());
+
+        // a verified piece alone doesn't write the resume file, but stopping does
+        t.store_piece(0, data[..8].to_vec()).unwrap();
+        assert!(!path.exists());
+        t.stop();
+        assert_eq!(ResumeData::load(&path).unwrap().have, vec![true, false, false]);
+
+        // and so does finishing the torrent
+        fs::remove_file(&path).unwrap();
+        t.store_piece(1, data[8..16].to_vec()).unwrap();
+        assert!(!path.exists());
+        t.store_piece(2, data[16..].to_vec()).unwrap();
+        assert_eq!(ResumeData::load(&path).unwrap().have, vec![true; 3]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn peers_and_choking_test() {
+        let data: Vec<u8> = (0..24).collect();
+        let m = test_metainfo("peers", 8, &data, &[]);
+
+        let mut t = Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new()));
+        assert_eq!(t.bitfield(), None);
+        t.store_piece(1, data[8..16].to_vec()).unwrap();
+        assert_eq!(t.bitfield(), Some(vec![0b0100_0000]));
+        assert_eq!(t.read_block(1, 2, 4).unwrap(), Some(vec![10, 11, 12, 13]));
+        assert_eq!(t.read_block(1, 6, 4).unwrap(), None);
+        assert_eq!(t.read_block(0, 0, 4).unwrap(), None);
+
+        let addrs: Vec<SocketAddr> = (1..7).map(|port| SocketAddr::from(([10, 0, 0, 1], port))).collect();
+        for &addr in addrs.iter() {
+            t.add_peer(addr);
+            t.set_interested(&addr, true);
+        }
+        t.record_download(&addrs[0], 8, 13);
+        t.record_download(&addrs[1], 0, 68);
+        t.record_upload(&addrs[1], 4, 13);
+        // bytes of peers which aren't connected count for the torrent only
+        t.record_upload(&SocketAddr::from(([10, 0, 0, 2], 1)), 0, 5);
+        let stats = t.stats();
+        assert_eq!((stats.downloaded, stats.download_overhead, stats.uploaded, stats.upload_overhead), (8, 81, 4, 18));
+        let peer = t.peers().into_iter().find(|p| p.addr == addrs[1]).unwrap();
+        assert_eq!((peer.stats.downloaded, peer.stats.download_overhead, peer.stats.uploaded), (0, 68, 4));
+
+        // the fastest peer is unchoked for its rate, along with others up to the slots
+        assert!(!t.is_unchoked(&addrs[0]));
+        t.rechoke();
+        assert!(t.is_unchoked(&addrs[0]));
+        assert_eq!(t.peers().iter().filter(|p| p.unchoked).count(), 4);
+        assert!(t.optimistic.is_some());
+
+        t.remove_peer(&addrs[0]);
+        assert_eq!(t.peers().len(), 5);
+        assert!(!t.is_unchoked(&addrs[0]));
+    }
+
+    #[test]
+    fn file_priorities_test() {
+        // three files of 10, 14 and 6 bytes over pieces of 8 bytes
+        let data: Vec<u8> = (0..30).collect();
+        let m = test_metainfo("priorities", 8, &data, &[("a", 10), ("b", 14), ("c", 6)]);
+
+        let mut t = Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new()));
+        assert!(t.set_file_priorities(vec![Priority::Normal; 4]).is_err());
+        t.set_file_priorities(vec![Priority::Skip, Priority::Low, Priority::High]).unwrap();
+        let priorities: Vec<Priority> = t.pieces.iter().map(|p| p.priority).collect();
+        assert_eq!(priorities, vec![Priority::Skip, Priority::Low, Priority::Low, Priority::High]);
+
+        let all = vec![true; 4];
+        assert_eq!(t.next_block_to_request(&all), Some((3, 0, 6)));
+        t.store_piece(3, data[24..].to_vec()).unwrap();
+        assert_eq!(t.next_block_to_request(&all), Some((1, 0, 8)));
+        t.store_piece(1, data[8..16].to_vec()).unwrap();
+        assert!(!t.is_complete());
+        assert!(t.store_piece(2, data[16..24].to_vec()).unwrap());
+        assert_eq!(t.next_block_to_request(&all), None);
+
+        t.set_file_priorities(vec![]).unwrap();
+        assert_eq!(t.file_priorities(), vec![Priority::Normal; 3]);
+        assert_eq!(t.next_block_to_request(&all), Some((0, 0, 8)));
+    }
+
+    #[test]
+    fn padding_priorities_test() {
+        // a padding file between two files, which priorities leave out
+        let mut m = test_metainfo("padded", 8, &[0; 24], &[("a", 6), (".pad/2", 2), ("b", 16)]);
+        m.info.files[1].attr.padding = true;
+
+        let mut t = Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new()));
+        assert!(t.set_file_priorities(vec![Priority::Normal; 3]).is_err());
+        t.set_file_priorities(vec![Priority::Skip, Priority::High]).unwrap();
+        assert_eq!(t.file_priorities(), vec![Priority::Skip, Priority::High]);
+        let priorities: Vec<Priority> = t.pieces.iter().map(|p| p.priority).collect();
+        assert_eq!(priorities, vec![Priority::Skip, Priority::High, Priority::High]);
+    }
+
+    #[test]
+    fn pick_mode_test() {
+        let m = test_metainfo("pick", 8, &[0; 32], &[("a", 16), ("b", 16)]);
+
+        let mut t = Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new()));
+        t.set_file_priorities(vec![Priority::Low, Priority::High]).unwrap();
+        assert_eq!(t.pick_order().collect::<Vec<usize>>(), vec![2, 3, 0, 1]);
+
+        t.set_pick_mode(PickMode::Sequential);
+        assert_eq!(t.pick_order().collect::<Vec<usize>>(), vec![0, 1, 2, 3]);
+        assert_eq!(t.next_block_to_request(&[false, true, true, true]), Some((1, 0, 8)));
+
+        // pieces overlapping the window after the cursor come first
+        t.set_pick_mode(PickMode::Deadline(8));
+        t.set_cursor(4);
+        assert_eq!(t.pick_mode(), PickMode::Deadline(8));
+        assert_eq!(t.pick_order().collect::<Vec<usize>>(), vec![0, 1, 2, 3]);
+        t.set_cursor(9);
+        assert_eq!(t.pick_order().collect::<Vec<usize>>(), vec![1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn peer_channels_test() {
+        let data: Vec<u8> = (0..16).collect();
+        let m = test_metainfo("channels", 8, &data, &[]);
+        let mut t = Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new()));
+        let (open, open_rx) = channel();
+        let (closed, closed_rx) = channel();
+        t.register_peer(open);
+        t.register_peer(closed);
+        drop(closed_rx);
+
+        // channels whose receiver is gone are dropped once a send fails
+        t.store_piece(0, data[..8].to_vec()).unwrap();
+        assert_eq!(t.peer_channels.len(), 1);
+        assert!(open_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn recheck_test() {
+        let data: Vec<u8> = (0..30).collect();
+        let m = test_metainfo("recheck.txt", 12, &data, &[]);
+
+        let mut storage = MemoryStorage::new();
+        storage.set_len(30).unwrap();
+        storage.write_piece(0, &data[0..12]).unwrap();
+        storage.write_piece(24, &data[24..30]).unwrap();
+
+        let mut t = Torrent::with_storage(create_peer_id(), m, Box::new(storage));
+        assert_eq!(t.recheck().unwrap(), 2);
+        let complete: Vec<bool> = t.pieces.iter().map(|p| p.is_complete).collect();
+        assert_eq!(complete, vec![true, false, true]);
+    }
+
+    #[test]
+    fn recheck_finishes_files_test() {
+        let dir = env::temp_dir().join("bittorrent_recheck_finishes_files_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..12).collect();
+        fs::write(dir.join("run.sh"), &data).unwrap();
+
+        let mut m = test_metainfo("dir", 12, &data, &[("run.sh", 12), ("link", 0)]);
+        m.info.files[0].attr.executable = true;
+        m.info.files[1].attr.symlink = true;
+        m.info.files[1].symlink_path = Some(vec![String::from("run.sh")]);
+        let storage = FileStorage::new(vec![(dir.join("run.sh"), 12), (dir.join("link"), 0)])
+            .with_attrs(m.info.file_attrs());
+
+        // data found complete on disk is finished like downloaded data
+        let mut t = Torrent::with_storage(create_peer_id(), m, Box::new(storage));
+        assert_eq!(t.recheck().unwrap(), 1);
+        if cfg!(unix) {
+            use std::os::unix::fs::PermissionsExt;
+            assert!(fs::metadata(dir.join("run.sh")).unwrap().permissions().mode() & 0o111 != 0);
+            assert_eq!(fs::read(dir.join("link")).unwrap(), data);
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn v2_torrent_test() {
+        let data: Vec<u8> = (0..5 * 16384).map(|i| (i % 239) as u8).collect();
+        let (root, layer) = merkle::file_hashes(&data, 16384);
+        let mut m = test_metainfo("v2.bin", 16384, &data, &[]);
+        m.info.pieces = vec![];
+        m.info.meta_version = 2;
+        m.info.file_tree = vec![TreeFile {
+            path: vec![String::from("v2.bin")],
+            length: data.len() as u64,
+            pieces_root: Some(root.clone()),
+            attr: FileAttr::default(),
+            symlink_path: None,
+        }];
+        m.info_hash_v2 = Some(vec![2; 32]);
+        m.piece_layers.insert(root.clone(), layer.clone());
+
+        let mut storage = MemoryStorage::new();
+        storage.set_len(data.len() as u64).unwrap();
+        let mut t = Torrent::with_storage(create_peer_id(), m, Box::new(storage));
+        assert_eq!(t.pieces.len(), 5);
+        t.store_piece(1, vec![0; 16384]).unwrap();
+        assert!(!t.pieces[1].is_complete);
+        t.store_piece(1, data[16384..32768].to_vec()).unwrap();
+        assert!(t.pieces[1].is_complete);
+
+        // the last two entries of the padded piece layer, proven up to the root
+        let request = HashRequest { pieces_root: root.clone(), base_layer: 0, index: 4, length: 2, proof_layers: 2 };
+        let hashes = t.hashes(&request).unwrap();
+        assert_eq!(hashes.len(), 4);
+        assert_eq!(hashes[0], layer[4]);
+        let subtree = merkle::root(&hashes[..2], 2, &merkle::pad_hash(1));
+        assert!(merkle::verify_proof(&subtree, 2, &hashes[2..], &root));
+        assert_eq!(t.hashes(&HashRequest { index: 3, ..request.clone() }), None);
+        assert_eq!(t.hashes(&HashRequest { base_layer: 1, ..request }), None);
+    }
 }
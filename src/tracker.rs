@@ -1,23 +1,21 @@
 use bencode;
-use bencode::{Bencode, FromBencode};
+use bencode::FromBencode;
 use hyper::{Client, header};
-use metainfo::MetaInfo;
 use url::percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
 use std::io::Read;
+use std::time::Duration;
 use tracker_response::TrackerResponse;
-use util::Error;
-use peer::Peer;
+use error::Error;
 
-#[derive(Debug)]
-pub enum TrackerError {
-    RetrievePeerError
-}
+// how long to wait for a tracker to answer, so that announces, such as those made while the
+// session shuts down, can't hang
+const TRACKER_TIMEOUT: u64 = 30;
 
 /// Encodes parameters into a url
 ///
 /// # Example
 /// ```
-/// # use tracker;
+/// # use bittorrent::tracker::parameterize;
 /// let params: Vec<(&str, &str)> = vec![("peer_id", "l33t"), ("port", "8080")];
 /// assert_eq!("peer_id=l33t&port=8080".to_string(), parameterize(params));
 /// ```
@@ -40,16 +38,45 @@ mod parameterize_tests {
     }
 }
 
-/// Sends a request to the tracker specified by the MetaInfo's announce attribute and returns a
-/// list of `peer`s and `peer_id`s.
-pub fn retrieve_peers(metainfo: &MetaInfo, peer_id: &str, port: &str) -> Result<Vec<Peer>, TrackerError> {
-    let uploaded = 0.to_string();
-    let downloaded = 0.to_string();
-    let left = metainfo.info.length.to_string();
+/// What an announce tells the tracker about a torrent: the bytes of piece data sent and received
+/// since it was added, and the bytes still missing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+/// Why an announce is sent, unless it's one of the regular announces
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn name(&self) -> &'static str {
+        match *self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+/// Announces our progress on the torrent with the given info hash to the tracker at the given
+/// url, along with the event which caused the announce, if any. Returns the tracker's response,
+/// which carries peers and the time to wait before the next regular announce
+pub fn announce(url: &str, info_hash: &[u8], peer_id: &str, port: &str, progress: Progress,
+                event: Option<AnnounceEvent>) -> Result<TrackerResponse, Error> {
+    let uploaded = progress.uploaded.to_string();
+    let downloaded = progress.downloaded.to_string();
+    let left = progress.left.to_string();
     let compact = 1.to_string();
-    let percent_encoded_hash: String = percent_encode(&metainfo.info_hash, DEFAULT_ENCODE_SET).collect();
+    let percent_encoded_hash: String = percent_encode(info_hash, DEFAULT_ENCODE_SET).collect();
 
-    let params: Vec<(&str, &str)> = vec![
+    let mut params: Vec<(&str, &str)> = vec![
         ("info_hash", percent_encoded_hash.as_ref()),
         ("peer_id", peer_id),
         ("port", port),
@@ -57,23 +84,24 @@ pub fn retrieve_peers(metainfo: &MetaInfo, peer_id: &str, port: &str) -> Result<
         ("downloaded", downloaded.as_ref()),
         ("left", left.as_ref()),
         ("compact", compact.as_ref()),
-        ("event", "started")
     ];
+    if let Some(event) = event {
+        params.push(("event", event.name()));
+    }
     let query_params = parameterize(params);
-    let query_url = format!("{}?{}", metainfo.announce, query_params);
-    let client = Client::new();
+    let query_url = format!("{}?{}", url, query_params);
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(TRACKER_TIMEOUT)));
+    client.set_write_timeout(Some(Duration::from_secs(TRACKER_TIMEOUT)));
 
     match client.get(&query_url).header(header::Connection::close()).send() {
         Ok(mut response) => {
             let mut s = Vec::new();
-            response.read_to_end(&mut s).unwrap();
-
-            let trackers: Bencode = bencode::from_vec(s).unwrap();
-            let decoded: Result<TrackerResponse, Error> = FromBencode::from_bencode(&trackers);
-            let peers = decoded.unwrap().peers;
+            response.read_to_end(&mut s)?;
 
-            Ok(peers)
+            let trackers = bencode::decode(&s)?;
+            FromBencode::from_bencode(&trackers)
         }
-        Err(_) => Err(TrackerError::RetrievePeerError)
+        Err(e) => Err(Error::Tracker(e.to_string()))
     }
 }
@@ -1,6 +1,5 @@
-use bencode;
-use bencode::{Bencode, FromBencode};
-use util::*;
+use bencode::{Value, FromBencode};
+use error::Error;
 use peer::Peer;
 
 #[derive(Debug)]
@@ -17,14 +16,17 @@ impl FromBencode for TrackerResponse {
     /// Attempts to construct a TrackerResponse object from a Bencode object. Returns a Result
     /// containing either
     ///     1) a TrackerResponse object, if a proper Bencode object was passed in
-    ///     2) DictMatchErr otherwise
-    fn from_bencode(bn: &bencode::Bencode) -> Result<TrackerResponse, Error> {
-        match bn {
-            &Bencode::Dict(ref m) => {
-                let interval = decode_field_as_string(m, "interval")?;
-                let complete = decode_field_as_string(m, "complete")?;
-                let incomplete = decode_field_as_string(m, "incomplete")?;
-                let peers_bytes = decode_field_as_content_bytes(m, "peers")?;
+    ///     2) an Error if the tracker reported a failure or a field is missing
+    fn from_bencode(bn: &Value) -> Result<TrackerResponse, Error> {
+        match *bn {
+            Value::Dict(ref m) => {
+                if let Some(reason) = m.get_bytes("failure reason") {
+                    return Err(Error::Tracker(String::from_utf8_lossy(reason).into_owned()))
+                }
+                let interval = m.get_integer("interval").ok_or_else(|| Error::missing("interval"))?;
+                let complete = m.get_integer("complete").ok_or_else(|| Error::missing("complete"))?;
+                let incomplete = m.get_integer("incomplete").ok_or_else(|| Error::missing("incomplete"))?;
+                let peers_bytes = m.get_bytes("peers").ok_or_else(|| Error::missing("peers"))?;
 
                 let mut peers = vec![];
                 for i in 0..(peers_bytes.len() / 6) {
@@ -34,15 +36,15 @@ impl FromBencode for TrackerResponse {
                 }
 
                 let tracker_response = TrackerResponse {
-                    interval: interval.parse::<u32>().unwrap(),
-                    complete: complete.parse::<u32>().unwrap(),
-                    incomplete: incomplete.parse::<u32>().unwrap(),
+                    interval: interval as u32,
+                    complete: complete as u32,
+                    incomplete: incomplete as u32,
                     peers: peers
                 };
 
                 Ok(tracker_response)
             }
-            _ => Err(Error::DictMatchErr)
+            _ => Err(Error::Tracker(String::from("response is not a dictionary")))
         }
     }
 }
@@ -53,13 +55,13 @@ mod tracker_response_tests {
     use super::{TrackerResponse, FromBencode};
     use peer::Peer;
     use bencode;
-    use util::*;
+    use error::Error;
 
     #[test]
     fn flagfromserver_torrent_test() {
         let s = vec![100, 56, 58, 99, 111, 109, 112, 108, 101, 116, 101, 105, 49, 101, 49, 48, 58, 100, 111, 119, 110, 108, 111, 97, 100, 101, 100, 105, 51, 101, 49, 48, 58, 105, 110, 99, 111, 109, 112, 108, 101, 116, 101, 105, 50, 101, 56, 58, 105, 110, 116, 101, 114, 118, 97, 108, 105, 49, 56, 51, 56, 101, 49, 50, 58, 109, 105, 110, 32, 105, 110, 116, 101, 114, 118, 97, 108, 105, 57, 49, 57, 101, 53, 58, 112, 101, 101, 114, 115, 49, 56, 58, 98, 227, 182, 253, 31, 144, 165, 124, 144, 88, 31, 144, 96, 126, 104, 219, 238, 9, 101];
 
-        let torrent: bencode::Bencode = bencode::from_vec(s).unwrap();
+        let torrent = bencode::decode(&s).unwrap();
         let decoded: Result<TrackerResponse, Error> = FromBencode::from_bencode(&torrent);
 
         match decoded {
@@ -0,0 +1,453 @@
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use json::{self, object, Json};
+use magnet::Magnet;
+use metainfo::{self, MetaInfo};
+use rand::{thread_rng, Rng};
+use session::{Session, TorrentStatus};
+use util;
+
+/// The path Transmission clients send their calls to
+pub const RPC_PATH: &str = "/transmission/rpc";
+/// The header which carries the session id, guarding against requests forged by web pages
+pub const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+// the versions of the protocol the methods follow
+const RPC_VERSION: u64 = 17;
+const RPC_VERSION_MINIMUM: u64 = 1;
+// Transmission gives speeds in kilobytes of 1000 bytes per second
+const KILOBYTE: u64 = 1000;
+
+// the states a torrent's `status` field reports
+const STATUS_STOPPED: i64 = 0;
+const STATUS_DOWNLOADING: i64 = 4;
+const STATUS_SEEDING: i64 = 6;
+// reported for an `eta` or `uploadRatio` which isn't known
+const NOT_AVAILABLE: i64 = -1;
+
+/// The speed limits as Transmission sees them, which can be turned off without forgetting them
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SpeedLimits {
+    // in kilobytes per second
+    download: u64,
+    upload: u64,
+    download_enabled: bool,
+    upload_enabled: bool,
+}
+
+/// Answers calls of the Transmission RPC protocol, so that clients such as
+/// `transmission-remote` and the Transmission web interfaces can control a session. The methods
+/// are `torrent-add`, `torrent-get`, `torrent-start`, `torrent-stop`, `torrent-remove`,
+/// `session-get` and `session-set`. Torrents are named by ids handed out as they're first seen,
+/// or by their info hashes. A magnet link's metadata is fetched in the background, and its
+/// torrent shows up once that succeeded
+pub struct Transmission {
+    session: Arc<Session>,
+    token: String,
+    session_id: String,
+    // the info hashes of the torrents clients have seen, each at its id minus one
+    ids: Mutex<Vec<Vec<u8>>>,
+    speed_limits: Mutex<SpeedLimits>,
+}
+
+impl Transmission {
+    pub fn new(session: Arc<Session>, token: &str) -> Self {
+        let limits = session.limits();
+        let speed_limits = SpeedLimits {
+            download: limits.download_rate() / KILOBYTE,
+            upload: limits.upload_rate() / KILOBYTE,
+            download_enabled: limits.download_rate() > 0,
+            upload_enabled: limits.upload_rate() > 0,
+        };
+        Transmission {
+            session,
+            token: token.to_string(),
+            session_id: thread_rng().gen_ascii_chars().take(48).collect(),
+            ids: Mutex::new(vec![]),
+            speed_limits: Mutex::new(speed_limits),
+        }
+    }
+
+    /// Returns the session id which requests have to carry in their `X-Transmission-Session-Id`
+    /// header
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Returns whether the given password of HTTP basic authentication is the token. The user
+    /// name doesn't matter
+    pub fn is_authorized(&self, password: Option<&str>) -> bool {
+        password.is_some_and(|p| util::constant_time_eq(p.as_bytes(), self.token.as_bytes()))
+    }
+
+    /// Handles a request given as JSON text, returning the response text
+    pub fn handle(&self, text: &str) -> String {
+        let request = match json::parse(text) {
+            Ok(request) => request,
+            Err(e) => return response(None, Err(format!("invalid JSON: {}", e))).to_string()
+        };
+        let tag = request.get("tag").cloned();
+        let result = match request.get("method").and_then(Json::as_str) {
+            Some(method) => {
+                let arguments = request.get("arguments").cloned().unwrap_or_else(|| object(vec![]));
+                debug!("Calling {}", method);
+                self.dispatch(method, &arguments)
+            },
+            None => Err(String::from("no method given"))
+        };
+        response(tag, result).to_string()
+    }
+
+    fn dispatch(&self, method: &str, arguments: &Json) -> Result<Json, String> {
+        match method {
+            "torrent-add" => self.add(arguments),
+            "torrent-get" => {
+                let fields = arguments.get("fields").and_then(Json::as_array).ok_or("no fields given")?;
+                let fields: Vec<&str> = fields.iter().filter_map(Json::as_str).collect();
+                let torrents = self.select(arguments)?.iter()
+                    .filter_map(|h| self.session.status(h))
+                    .map(|status| self.torrent_json(&status, &fields))
+                    .collect();
+                Ok(object(vec![("torrents", Json::Array(torrents))]))
+            },
+            "torrent-start" => {
+                for info_hash in self.select(arguments)? {
+                    self.session.resume(&info_hash);
+                }
+                Ok(object(vec![]))
+            },
+            "torrent-stop" => {
+                for info_hash in self.select(arguments)? {
+                    self.session.pause(&info_hash);
+                }
+                Ok(object(vec![]))
+            },
+            "torrent-remove" => {
+                if arguments.get("delete-local-data").and_then(Json::as_bool) == Some(true) {
+                    return Err(String::from("deleting local data isn't supported"))
+                }
+                for info_hash in self.select(arguments)? {
+                    self.session.remove(&info_hash);
+                }
+                Ok(object(vec![]))
+            },
+            "session-get" => {
+                let fields: Option<Vec<&str>> = arguments.get("fields").and_then(Json::as_array)
+                    .map(|fields| fields.iter().filter_map(Json::as_str).collect());
+                match (self.session_json(), fields) {
+                    (Json::Object(members), Some(fields)) => {
+                        Ok(Json::Object(members.into_iter().filter(|(key, _)| fields.contains(&key.as_str())).collect()))
+                    },
+                    (all, _) => Ok(all)
+                }
+            },
+            "session-set" => {
+                let mut speed_limits = self.speed_limits.lock().unwrap();
+                let rate = |key: &str| match arguments.get(key) {
+                    Some(value) => value.as_u64().map(Some).ok_or_else(|| format!("{} is not a speed", key)),
+                    None => Ok(None)
+                };
+                let enabled = |key: &str| match arguments.get(key) {
+                    Some(value) => value.as_bool().map(Some).ok_or_else(|| format!("{} is not a boolean", key)),
+                    None => Ok(None)
+                };
+                let mut updated = *speed_limits;
+                updated.download = rate("speed-limit-down")?.unwrap_or(updated.download);
+                updated.upload = rate("speed-limit-up")?.unwrap_or(updated.upload);
+                updated.download_enabled = enabled("speed-limit-down-enabled")?.unwrap_or(updated.download_enabled);
+                updated.upload_enabled = enabled("speed-limit-up-enabled")?.unwrap_or(updated.upload_enabled);
+
+                // a limit which is turned off leaves the session unlimited
+                let limits = self.session.limits();
+                limits.set_download_rate(if updated.download_enabled { updated.download * KILOBYTE } else { 0 });
+                limits.set_upload_rate(if updated.upload_enabled { updated.upload * KILOBYTE } else { 0 });
+                *speed_limits = updated;
+                Ok(object(vec![]))
+            },
+            _ => Err(String::from("method name not recognized"))
+        }
+    }
+
+    fn add(&self, arguments: &Json) -> Result<Json, String> {
+        let filename = arguments.get("filename").and_then(Json::as_str);
+        // the metainfo to add, or None for a magnet link
+        let metainfo: Option<MetaInfo> = match (arguments.get("metainfo").and_then(Json::as_str), filename) {
+            (Some(encoded), _) => {
+                let bytes = util::from_base64(encoded).ok_or("metainfo is not base64")?;
+                Some(metainfo::from_bytes(&bytes).map_err(|e| e.to_string())?)
+            },
+            (None, Some(uri)) if uri.starts_with("magnet:") => None,
+            (None, Some(path)) => Some(metainfo::from_file(path).map_err(|e| e.to_string())?),
+            (None, None) => return Err(String::from("no filename or metainfo given"))
+        };
+        let (info_hash, magnet) = match metainfo {
+            Some(ref m) => (m.info_hash.clone(), None),
+            None => {
+                let magnet = Magnet::parse(filename.unwrap_or_default()).map_err(|e| e.to_string())?;
+                (magnet.info_hash.clone(), Some(magnet))
+            }
+        };
+        if self.session.torrent(&info_hash).is_some() {
+            return Ok(object(vec![("torrent-duplicate", self.added_json(&info_hash, None))]))
+        }
+
+        let paused = arguments.get("paused").and_then(Json::as_bool) == Some(true);
+        match metainfo {
+            Some(m) => {
+                self.session.add_torrent(m, vec![]).map_err(|e| e.to_string())?;
+                if paused {
+                    self.session.pause(&info_hash);
+                }
+            },
+            None => {
+                let (session, uri) = (self.session.clone(), filename.unwrap_or_default().to_string());
+                thread::spawn(move || match session.add_magnet(&uri, vec![]) {
+                    Ok(ref info_hash) if paused => { session.pause(info_hash); },
+                    Ok(_) => {},
+                    Err(e) => warn!("Adding magnet link {} failed: {}", uri, e)
+                });
+            }
+        }
+        let name = magnet.and_then(|m| m.name);
+        Ok(object(vec![("torrent-added", self.added_json(&info_hash, name))]))
+    }
+
+    /// Describes an added torrent, named by its metainfo or else by the given name, such as the
+    /// one a magnet link suggests while its metadata is fetched
+    fn added_json(&self, info_hash: &[u8], name: Option<String>) -> Json {
+        let name = self.session.status(info_hash).map(|s| s.name).or(name).unwrap_or_default();
+        object(vec![
+            ("id", Json::from(self.id(info_hash))),
+            ("name", Json::from(name)),
+            ("hashString", Json::from(util::to_hex(info_hash))),
+        ])
+    }
+
+    /// Returns the id of the torrent with the given info hash, handing out the next one the first
+    /// time it's asked for
+    fn id(&self, info_hash: &[u8]) -> usize {
+        let mut ids = self.ids.lock().unwrap();
+        match ids.iter().position(|h| h == info_hash) {
+            Some(index) => index + 1,
+            None => {
+                ids.push(info_hash.to_vec());
+                ids.len()
+            }
+        }
+    }
+
+    /// Returns the info hashes of the torrents the `ids` argument names: a single id, a list of
+    /// ids and info hashes, `recently-active` for the torrents which are transferring data, or all
+    /// torrents if it's missing. Unknown torrents are left out
+    fn select(&self, arguments: &Json) -> Result<Vec<Vec<u8>>, String> {
+        // torrents seen for the first time are numbered in the order of their info hashes
+        let mut info_hashes = self.session.info_hashes();
+        info_hashes.sort();
+        let mut numbered: Vec<(usize, Vec<u8>)> = info_hashes.into_iter().map(|h| (self.id(&h), h)).collect();
+        numbered.sort();
+        let info_hashes: Vec<Vec<u8>> = numbered.into_iter().map(|(_, h)| h).collect();
+        let named = |id: &Json| -> Result<Option<Vec<u8>>, String> {
+            if let Some(id) = id.as_u64() {
+                let ids = self.ids.lock().unwrap();
+                return Ok(id.checked_sub(1).and_then(|i| ids.get(i as usize)).cloned())
+            }
+            let hash = id.as_str().and_then(util::from_hex).ok_or("ids are not numbers or info hashes")?;
+            Ok(Some(hash))
+        };
+
+        let selected = match arguments.get("ids") {
+            None => return Ok(info_hashes),
+            Some(Json::String(s)) if s == "recently-active" => {
+                return Ok(info_hashes.into_iter()
+                    .filter(|h| self.session.status(h).is_some_and(|s| s.stats.download_rate > 0.0 || s.stats.upload_rate > 0.0))
+                    .collect())
+            },
+            Some(Json::Array(ids)) => ids.iter().map(named).collect::<Result<Vec<_>, String>>()?,
+            Some(id) => vec![named(id)?]
+        };
+        Ok(selected.into_iter().flatten().filter(|h| info_hashes.contains(h)).collect())
+    }
+
+    /// Returns the requested fields of a torrent which are known. Others are left out
+    fn torrent_json(&self, status: &TorrentStatus, fields: &[&str]) -> Json {
+        let peers = self.session.peers(&status.info_hash).map_or(0, |p| p.len());
+        let state = if status.stopped {
+            STATUS_STOPPED
+        } else if status.complete {
+            STATUS_SEEDING
+        } else {
+            STATUS_DOWNLOADING
+        };
+        let field = |name: &str| -> Option<Json> {
+            Some(match name {
+                "id" => Json::from(self.id(&status.info_hash)),
+                "hashString" => Json::from(util::to_hex(&status.info_hash)),
+                "name" => Json::from(status.name.as_str()),
+                "status" => Json::from(state),
+                "totalSize" => Json::from(status.size),
+                "sizeWhenDone" => Json::from(status.wanted),
+                "leftUntilDone" => Json::from(status.left()),
+                "haveValid" => Json::from(status.verified),
+                "percentDone" => Json::from(status.progress()),
+                "rateDownload" => Json::from(status.stats.download_rate as u64),
+                "rateUpload" => Json::from(status.stats.upload_rate as u64),
+                "downloadedEver" => Json::from(status.stats.downloaded),
+                "uploadedEver" => Json::from(status.stats.uploaded),
+                "uploadRatio" => match status.stats.downloaded {
+                    0 => Json::from(NOT_AVAILABLE),
+                    _ => Json::from(status.ratio())
+                },
+                "eta" => match status.eta() {
+                    Some(eta) => Json::from(eta.as_secs()),
+                    None => Json::from(NOT_AVAILABLE)
+                },
+                "peersConnected" => Json::from(peers),
+                "pieceCount" => Json::from(status.num_pieces),
+                "downloadDir" => Json::from(download_dir()),
+                "error" => Json::from(0u64),
+                "errorString" => Json::from(""),
+                _ => return None
+            })
+        };
+        Json::Object(fields.iter().filter_map(|&name| field(name).map(|value| (name.to_string(), value))).collect())
+    }
+
+    fn session_json(&self) -> Json {
+        let speed_limits = *self.speed_limits.lock().unwrap();
+        object(vec![
+            ("version", Json::from(format!("{} (rust-bittorrent)", env!("CARGO_PKG_VERSION")))),
+            ("rpc-version", Json::from(RPC_VERSION)),
+            ("rpc-version-minimum", Json::from(RPC_VERSION_MINIMUM)),
+            ("session-id", Json::from(self.session_id.as_str())),
+            ("peer-port", Json::from(self.session.port() as u64)),
+            ("download-dir", Json::from(download_dir())),
+            ("speed-limit-down", Json::from(speed_limits.download)),
+            ("speed-limit-down-enabled", Json::from(speed_limits.download_enabled)),
+            ("speed-limit-up", Json::from(speed_limits.upload)),
+            ("speed-limit-up-enabled", Json::from(speed_limits.upload_enabled)),
+            ("units", object(vec![
+                ("speed-units", Json::Array(vec!["kB/s", "MB/s", "GB/s", "TB/s"].into_iter().map(Json::from).collect())),
+                ("speed-bytes", Json::from(KILOBYTE)),
+            ])),
+        ])
+    }
+}
+
+/// Returns the directory torrents store their data in, which is the current directory
+fn download_dir() -> String {
+    env::current_dir().map(|d| d.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Builds a response, whose `result` is `success` or the reason the call failed
+fn response(tag: Option<Json>, result: Result<Json, String>) -> Json {
+    let (result, arguments) = match result {
+        Ok(arguments) => (String::from("success"), arguments),
+        Err(e) => (e, object(vec![]))
+    };
+    let mut members = vec![("result", Json::from(result)), ("arguments", arguments)];
+    if let Some(tag) = tag {
+        members.push(("tag", tag));
+    }
+    object(members)
+}
+
+#[cfg(test)]
+mod transmission_tests {
+    use super::Transmission;
+    use json::{self, Json};
+    use metainfo::test_metainfo;
+    use session::{Session, SessionConfig};
+    use storage::MemoryStorage;
+    use torrent::Torrent;
+    use std::sync::Arc;
+    use util::create_peer_id;
+
+    fn transmission() -> Transmission {
+        let session = Session::new(SessionConfig::default());
+        for info_hash in 1..3 {
+            let mut m = test_metainfo(&format!("torrent {}", info_hash), 16, &[0; 16], &[]);
+            m.info_hash = vec![info_hash; 20];
+            session.add(Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new()))).unwrap();
+        }
+        Transmission::new(Arc::new(session), "secret")
+    }
+
+    fn call(transmission: &Transmission, text: &str) -> Json {
+        json::parse(&transmission.handle(text)).unwrap()
+    }
+
+    #[test]
+    fn torrent_methods_test() {
+        let transmission = transmission();
+        assert!(transmission.is_authorized(Some("secret")));
+        assert!(!transmission.is_authorized(Some("wrong")) && !transmission.is_authorized(None));
+
+        let response = call(&transmission, r#"{"method": "torrent-get", "arguments": {"fields": ["id", "name", "status", "totalSize", "eta", "uploadRatio", "unknown"]}, "tag": 3}"#);
+        assert_eq!(response.get("result").and_then(Json::as_str), Some("success"));
+        assert_eq!(response.get("tag"), Some(&Json::Number(3.0)));
+        let torrents = response.get("arguments").and_then(|a| a.get("torrents")).and_then(Json::as_array).unwrap();
+        assert_eq!(torrents.len(), 2);
+        assert_eq!(torrents[0].get("id"), Some(&Json::Number(1.0)));
+        assert_eq!(torrents[1].get("id"), Some(&Json::Number(2.0)));
+        assert_eq!(torrents[0].get("status"), Some(&Json::Number(4.0)));
+        assert_eq!(torrents[0].get("totalSize"), Some(&Json::Number(16.0)));
+        assert_eq!(torrents[0].get("eta"), Some(&Json::Number(-1.0)));
+        assert!(torrents[0].get("unknown").is_none());
+        assert_eq!(torrents[0].get("name").and_then(Json::as_str), Some("torrent 1"));
+        let first_hash = vec![1; 20];
+
+        // torrents are named by ids and by info hashes alike
+        let request = format!(r#"{{"method": "torrent-stop", "arguments": {{"ids": [1, "{}"]}}}}"#, "03".repeat(20));
+        assert_eq!(call(&transmission, &request).get("result").and_then(Json::as_str), Some("success"));
+        assert!(transmission.session.status(&first_hash).unwrap().stopped);
+        let response = call(&transmission, r#"{"method": "torrent-get", "arguments": {"ids": 1, "fields": ["status"]}}"#);
+        assert_eq!(response.get("arguments").and_then(|a| a.get("torrents")).and_then(Json::as_array).unwrap()[0].get("status"),
+                   Some(&Json::Number(0.0)));
+        call(&transmission, r#"{"method": "torrent-start", "arguments": {"ids": [1]}}"#);
+        assert!(!transmission.session.status(&first_hash).unwrap().stopped);
+
+        let response = call(&transmission, r#"{"method": "torrent-remove", "arguments": {"ids": 1, "delete-local-data": true}}"#);
+        assert_ne!(response.get("result").and_then(Json::as_str), Some("success"));
+        call(&transmission, r#"{"method": "torrent-remove", "arguments": {"ids": 1}}"#);
+        assert!(transmission.session.torrent(&first_hash).is_none());
+        // ids aren't reused
+        let response = call(&transmission, r#"{"method": "torrent-get", "arguments": {"fields": ["id"]}}"#);
+        assert_eq!(response.get("arguments").and_then(|a| a.get("torrents")).and_then(Json::as_array).unwrap()[0].get("id"),
+                   Some(&Json::Number(2.0)));
+
+        let response = call(&transmission, r#"{"method": "torrent-add", "arguments": {"metainfo": "bm90IGJlbmNvZGU="}}"#);
+        assert_ne!(response.get("result").and_then(Json::as_str), Some("success"));
+        // a magnet link is added in the background, named by the link until its metadata arrives
+        let request = format!(r#"{{"method": "torrent-add", "arguments": {{"filename": "magnet:?xt=urn:btih:{}&dn=two"}}}}"#, "04".repeat(20));
+        let added = call(&transmission, &request).get("arguments").and_then(|a| a.get("torrent-added")).cloned().unwrap();
+        assert_eq!(added.get("name").and_then(Json::as_str), Some("two"));
+        assert_eq!(added.get("hashString").and_then(Json::as_str), Some("04".repeat(20).as_str()));
+        let response = call(&transmission, r#"{"method": "torrent-verify"}"#);
+        assert_eq!(response.get("result").and_then(Json::as_str), Some("method name not recognized"));
+        assert!(call(&transmission, "{").get("result").is_some());
+    }
+
+    #[test]
+    fn session_methods_test() {
+        let transmission = transmission();
+        let response = call(&transmission, r#"{"method": "session-get", "arguments": {"fields": ["rpc-version", "speed-limit-down-enabled"]}}"#);
+        let arguments = response.get("arguments").unwrap();
+        assert_eq!(arguments.get("rpc-version"), Some(&Json::Number(17.0)));
+        assert_eq!(arguments.get("speed-limit-down-enabled"), Some(&Json::Bool(false)));
+        assert!(arguments.get("session-id").is_none());
+        let response = call(&transmission, r#"{"method": "session-get"}"#);
+        assert_eq!(response.get("arguments").and_then(|a| a.get("session-id")).and_then(Json::as_str),
+                   Some(transmission.session_id()));
+
+        call(&transmission, r#"{"method": "session-set", "arguments": {"speed-limit-down": 50, "speed-limit-down-enabled": true, "speed-limit-up": 20}}"#);
+        let limits = transmission.session.limits();
+        assert_eq!((limits.download_rate(), limits.upload_rate()), (50000, 0));
+        call(&transmission, r#"{"method": "session-set", "arguments": {"speed-limit-up-enabled": true, "speed-limit-down-enabled": false}}"#);
+        assert_eq!((limits.download_rate(), limits.upload_rate()), (0, 20000));
+        let response = call(&transmission, r#"{"method": "session-get", "arguments": {"fields": ["speed-limit-down"]}}"#);
+        assert_eq!(response.get("arguments").and_then(|a| a.get("speed-limit-down")), Some(&Json::Number(50.0)));
+
+        let response = call(&transmission, r#"{"method": "session-set", "arguments": {"speed-limit-up": "fast"}}"#);
+        assert_ne!(response.get("result").and_then(Json::as_str), Some("success"));
+    }
+}
@@ -1,86 +1,94 @@
-use bencode::{Bencode};
-use std::collections::BTreeMap;
-use bencode::util::ByteString;
-use regex::Regex;
 use std::io::Cursor;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use rand::{thread_rng, Rng};
 
-#[derive(Debug)]
-pub enum Error {
-    DictMatchErr,
-    FieldNotFound
+pub fn bytes_to_u32(bytes: &[u8]) -> u32 {
+    let mut buf = Cursor::new(&bytes);
+    buf.read_u32::<BigEndian>().unwrap()
 }
 
-/// Takes a string which is denoted within quotation marks and returns that string, or the original
-/// string if no match is found
+pub fn u32_to_bytes(integer: u32) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.write_u32::<BigEndian>(integer).unwrap();
+    bytes
+}
+
+/// Packs a list of flags into bytes, with the first flag in the highest bit of the first byte, as
+/// in the bitfield message
 ///
 /// # Example
-///
 /// ```
-/// let s = parse_string("s\"pieces\"")?;
-/// assert_eq!(s, "pieces");
+/// # use bittorrent::util::bitfield_to_bytes;
+/// assert_eq!(bitfield_to_bytes(&[true, false, true]), vec![160]);
 /// ```
-pub fn parse_string(s: &str) -> String {
-    let re = Regex::new("\"(.*)\"").unwrap();
-
-    if re.is_match(s) {
-        let cap = re.captures(s).unwrap();
-        return (&cap[1]).to_string();
+pub fn bitfield_to_bytes(have: &[bool]) -> Vec<u8> {
+    let mut bytes = vec![0; have.len().div_ceil(8)];
+    for (i, _) in have.iter().enumerate().filter(|&(_, h)| *h) {
+        bytes[i / 8] |= 1 << (7 - i % 8);
     }
-
-    s.to_string()
+    bytes
 }
 
-/// Finds a value in the BTreeMap corresponding to a given key and returns a Result containing
-///     1) a Vec<u8> of the data, if it exists
-///     2) a FieldNotFound error otherwise
-pub fn decode_field_as_bytes(map: &BTreeMap<ByteString, Bencode>, field: &str) -> Result<Vec<u8>, Error> {
-    match map.get(&ByteString::from_str(field)) {
-        Some(contents) => Ok(contents.to_bytes().unwrap()),
-        None => Err(Error::FieldNotFound)
-    }
+/// Unpacks the first `n` flags from bytes packed by `bitfield_to_bytes`
+pub fn bytes_to_bitfield(bytes: &[u8], n: usize) -> Vec<bool> {
+    (0..n).map(|i| bytes[i / 8] & (1 << (7 - i % 8)) != 0).collect()
 }
 
-pub fn decode_field_as_content_bytes(map: &BTreeMap<ByteString, Bencode>, field: &str) -> Result<Vec<u8>, Error> {
-    if let Ok(mut contents) = decode_field_as_bytes(&map, field) {
-        if let Some(i) = contents.iter().position(|&b| b == 58) {
-            return Ok(contents.split_off(i).split_off(1));
-        }
-    }
-
-    Err(Error::FieldNotFound)
+/// Formats bytes, such as an info hash, as lowercase hex digits
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
-/// Finds a value in the BTreeMap corresponding to a given key and returns a Result containing
-///     1) a String of the data, if it exists
-///     2) a FieldNotFound error otherwise
-pub fn decode_field_as_string(map: &BTreeMap<ByteString, Bencode>, field: &str) -> Result<String, Error> {
-    match map.get(&ByteString::from_str(field)) {
-        Some(contents) => {
-            Ok(parse_string(&contents.to_string()))
-        }
-        None => Err(Error::FieldNotFound)
+/// Parses lowercase or uppercase hex digits, such as those of an info hash
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None
     }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
 }
 
-pub fn bytes_to_u32(bytes: &[u8]) -> u32 {
-    let mut buf = Cursor::new(&bytes);
-    buf.read_u32::<BigEndian>().unwrap()
+/// Compares secrets such as tokens without revealing through timing how much of them matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
-pub fn u32_to_bytes(integer: u32) -> Vec<u8> {
-    let mut bytes = vec![];
-    bytes.write_u32::<BigEndian>(integer).unwrap();
-    bytes
+/// Decodes standard base64 with or without padding, ignoring line breaks
+pub fn from_base64(text: &str) -> Option<Vec<u8>> {
+    let digit = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None
+    };
+    let digits = text.bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<Vec<u8>>();
+    let digits = digits.strip_suffix(b"==").or_else(|| digits.strip_suffix(b"=")).unwrap_or(&digits);
+    if digits.len() % 4 == 1 {
+        return None
+    }
+
+    let mut bytes = Vec::with_capacity(digits.len() * 3 / 4);
+    for chunk in digits.chunks(4) {
+        let mut group = 0u32;
+        for &c in chunk {
+            group = (group << 6) | digit(c)? as u32;
+        }
+        group <<= 6 * (4 - chunk.len()) as u32;
+        bytes.extend_from_slice(&group.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(bytes)
 }
 
 /// Generates an Azuerus-formatted 20-byte peer id, in which 
 /// the id is formatted as follows: -{client implementation}{version}-{random ascii characters}
 /// # Example
 /// ```
+/// # use bittorrent::util::create_peer_id;
 /// let peer_id = create_peer_id();
-/// assert_eq!(peer_id, peer_id.as_bytes().len());
+/// assert_eq!(peer_id.as_bytes().len(), 20);
 /// ```
 pub fn create_peer_id() -> String {
     let random_chars: String = thread_rng().gen_ascii_chars().take(12).collect();
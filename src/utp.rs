@@ -0,0 +1,864 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write, Error, ErrorKind};
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Weak, Mutex, MutexGuard, Condvar};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rand::{thread_rng, Rng};
+use util::{bytes_to_u32, u32_to_bytes};
+
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 20;
+const MAX_PACKET_SIZE: usize = 1400;
+// the largest payload which fits into one packet
+const MSS: usize = MAX_PACKET_SIZE - HEADER_SIZE;
+// LEDBAT grows the window while the one way queuing delay is below this many microseconds, and
+// shrinks it once the delay goes above it
+const TARGET_DELAY: f64 = 100_000.0;
+const GAIN: f64 = 1.0;
+const MIN_WINDOW: f64 = MSS as f64;
+const MAX_WINDOW: f64 = (1 << 20) as f64;
+const RECEIVE_WINDOW: usize = 1 << 20;
+const MAX_SEND_BUFFER: usize = 1 << 20;
+const MAX_OUT_OF_ORDER: usize = 1024;
+const MIN_TIMEOUT: u64 = 500;
+const INITIAL_TIMEOUT: u64 = 1000;
+const MAX_RETRANSMITS: u32 = 8;
+const CONNECT_TIMEOUT: u64 = 10;
+// how long a closed connection waits for the peer's FIN before giving up
+const LINGER_TIMEOUT: u64 = 10;
+// the base delay is the minimum delay seen over this many seconds, which lets it follow changes
+// in routing and clock drift
+const BASE_DELAY_INTERVAL: u64 = 60;
+const TICK: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn,
+}
+
+impl PacketType {
+    fn from_u8(n: u8) -> Option<PacketType> {
+        match n {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None
+        }
+    }
+}
+
+/// A uTP packet, whose 20 byte header is laid out as follows:
+///     1 holds the packet type in the high nibble and the version in the low nibble
+///     2 is the type of the first extension
+///     3-4 hold the connection id
+///     5-8 hold the send timestamp in microseconds
+///     9-12 hold the difference between the last received timestamp and its arrival
+///     13-16 hold the advertised receive window
+///     17-18 hold the sequence number
+///     19-20 hold the number of the last packet received in order
+#[derive(Debug, Clone, PartialEq)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.push(((self.kind as u8) << 4) | VERSION);
+        bytes.push(0);
+        bytes.extend(u16_to_bytes(self.connection_id));
+        bytes.extend(u32_to_bytes(self.timestamp));
+        bytes.extend(u32_to_bytes(self.timestamp_diff));
+        bytes.extend(u32_to_bytes(self.wnd_size));
+        bytes.extend(u16_to_bytes(self.seq_nr));
+        bytes.extend(u16_to_bytes(self.ack_nr));
+        bytes.extend(self.payload.iter());
+        bytes
+    }
+
+    /// Parses a datagram, skipping any extension headers. Returns None if it isn't a valid packet
+    fn parse(bytes: &[u8]) -> Option<Packet> {
+        if bytes.len() < HEADER_SIZE || bytes[0] & 0x0f != VERSION {
+            return None
+        }
+
+        let kind = PacketType::from_u8(bytes[0] >> 4)?;
+        let mut extension = bytes[1];
+        let mut offset = HEADER_SIZE;
+        while extension != 0 {
+            if bytes.len() < offset + 2 {
+                return None
+            }
+            extension = bytes[offset];
+            offset += 2 + bytes[offset + 1] as usize;
+        }
+        if bytes.len() < offset {
+            return None
+        }
+
+        Some(Packet {
+            kind,
+            connection_id: bytes_to_u16(&bytes[2..4]),
+            timestamp: bytes_to_u32(&bytes[4..8]),
+            timestamp_diff: bytes_to_u32(&bytes[8..12]),
+            wnd_size: bytes_to_u32(&bytes[12..16]),
+            seq_nr: bytes_to_u16(&bytes[16..18]),
+            ack_nr: bytes_to_u16(&bytes[18..20]),
+            payload: bytes[offset..].to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    SynSent,
+    Connected,
+    Closed,
+    Reset,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    packet: Packet,
+    sent_at: Instant,
+    retransmits: u32,
+}
+
+#[derive(Debug)]
+struct State {
+    status: Status,
+    // sequence number of the next packet to send
+    seq_nr: u16,
+    // sequence number of the last packet received in order
+    ack_nr: u16,
+    send_buffer: VecDeque<u8>,
+    in_flight: VecDeque<InFlight>,
+    recv_buffer: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    fin_seq: Option<u16>,
+    eof: bool,
+    close_requested: bool,
+    fin_sent_at: Option<Instant>,
+    read_timeout: Option<Duration>,
+    // congestion window in bytes, adjusted by LEDBAT
+    cwnd: f64,
+    peer_window: usize,
+    base_delay: Option<u32>,
+    next_base_delay: Option<u32>,
+    base_delay_updated_at: Instant,
+    rtt: f64,
+    rtt_var: f64,
+    timeout: Duration,
+    reply_micro: u32,
+}
+
+impl State {
+    fn new(status: Status, seq_nr: u16, ack_nr: u16) -> Self {
+        State {
+            status,
+            seq_nr,
+            ack_nr,
+            send_buffer: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            recv_buffer: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_seq: None,
+            eof: false,
+            close_requested: false,
+            fin_sent_at: None,
+            read_timeout: None,
+            cwnd: MIN_WINDOW * 2.0,
+            peer_window: RECEIVE_WINDOW,
+            base_delay: None,
+            next_base_delay: None,
+            base_delay_updated_at: Instant::now(),
+            rtt: 0.0,
+            rtt_var: 0.0,
+            timeout: Duration::from_millis(INITIAL_TIMEOUT),
+            reply_micro: 0,
+        }
+    }
+
+    /// Applies the LEDBAT controller to the window after `acked` bytes were acknowledged by a
+    /// packet which took `delay` microseconds to arrive, as measured by the peer
+    fn update_window(&mut self, acked: usize, delay: u32) {
+        if self.base_delay_updated_at.elapsed() >= Duration::from_secs(BASE_DELAY_INTERVAL) {
+            self.base_delay = self.next_base_delay.take();
+            self.base_delay_updated_at = Instant::now();
+        }
+        self.next_base_delay = Some(self.next_base_delay.map_or(delay, |d| d.min(delay)));
+        let base_delay = self.base_delay.map_or(delay, |d| d.min(delay));
+        self.base_delay = Some(base_delay);
+
+        let queuing_delay = (delay - base_delay) as f64;
+        let off_target = (TARGET_DELAY - queuing_delay) / TARGET_DELAY;
+        self.cwnd += GAIN * off_target * acked as f64 * MSS as f64 / self.cwnd;
+        self.cwnd = self.cwnd.clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    fn update_timeout(&mut self, sample: Duration) {
+        let sample = sample.as_secs() as f64 * 1000.0 + sample.subsec_nanos() as f64 / 1_000_000.0;
+        if self.rtt == 0.0 {
+            self.rtt = sample;
+            self.rtt_var = sample / 2.0;
+        } else {
+            self.rtt_var += ((self.rtt - sample).abs() - self.rtt_var) / 4.0;
+            self.rtt += (sample - self.rtt) / 8.0;
+        }
+        let timeout = (self.rtt + 4.0 * self.rtt_var) as u64;
+        self.timeout = Duration::from_millis(timeout.max(MIN_TIMEOUT));
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight.iter().map(|p| p.packet.payload.len()).sum()
+    }
+
+    /// Moves received data which is now in order into the receive buffer
+    fn deliver(&mut self) {
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            if let Some(payload) = self.out_of_order.remove(&next) {
+                self.recv_buffer.extend(payload);
+                self.ack_nr = next;
+            } else if self.fin_seq == Some(next) {
+                self.ack_nr = next;
+                self.eof = true;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        match self.status {
+            Status::Closed | Status::Reset => true,
+            _ => match self.fin_sent_at {
+                Some(sent_at) => self.in_flight.is_empty() &&
+                    (self.eof || sent_at.elapsed() >= Duration::from_secs(LINGER_TIMEOUT)),
+                None => false
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Shared {
+    fn new(state: State) -> Arc<Shared> {
+        Arc::new(Shared { state: Mutex::new(state), cond: Condvar::new() })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Drives a single connection: handles the packets routed to it, retransmits lost packets and
+/// sends buffered data as fast as the congestion window allows
+struct Worker {
+    shared: Arc<Shared>,
+    socket: Arc<UdpSocket>,
+    remote: SocketAddr,
+    send_id: u16,
+    packets: Receiver<Packet>,
+}
+
+impl Worker {
+    fn run(self) {
+        loop {
+            let packet = match self.packets.recv_timeout(Duration::from_millis(TICK)) {
+                Ok(packet) => Some(packet),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break
+            };
+
+            let mut state = self.shared.lock();
+            if let Some(packet) = packet {
+                self.handle(&mut state, packet);
+            }
+            self.check_timeouts(&mut state);
+            self.flush(&mut state);
+            self.shared.cond.notify_all();
+            if state.is_finished() {
+                break;
+            }
+        }
+
+        let mut state = self.shared.lock();
+        if state.status != Status::Reset {
+            state.status = Status::Closed;
+        }
+        self.shared.cond.notify_all();
+    }
+
+    fn handle(&self, state: &mut State, packet: Packet) {
+        state.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+        state.peer_window = packet.wnd_size as usize;
+
+        match packet.kind {
+            PacketType::Syn => {
+                self.send(state, PacketType::State, vec![]);
+                return
+            },
+            PacketType::Reset => {
+                state.status = Status::Reset;
+                return
+            },
+            _ => {}
+        }
+
+        if state.status == Status::SynSent {
+            if packet.kind != PacketType::State {
+                return
+            }
+            state.status = Status::Connected;
+            state.ack_nr = packet.seq_nr.wrapping_sub(1);
+        }
+
+        self.process_ack(state, &packet);
+        match packet.kind {
+            PacketType::Data => {
+                if seq_less_than(state.ack_nr, packet.seq_nr) && state.out_of_order.len() < MAX_OUT_OF_ORDER {
+                    state.out_of_order.insert(packet.seq_nr, packet.payload);
+                    state.deliver();
+                }
+                self.send(state, PacketType::State, vec![]);
+            },
+            PacketType::Fin => {
+                state.fin_seq = Some(packet.seq_nr);
+                state.deliver();
+                self.send(state, PacketType::State, vec![]);
+            },
+            _ => {}
+        }
+    }
+
+    fn process_ack(&self, state: &mut State, packet: &Packet) {
+        let mut acked = 0;
+        let mut rtt_sample = None;
+        while let Some(sent) = state.in_flight.pop_front() {
+            if !seq_less_than(packet.ack_nr, sent.packet.seq_nr) {
+                acked += sent.packet.payload.len();
+                if sent.retransmits == 0 {
+                    rtt_sample = Some(sent.sent_at.elapsed());
+                }
+            } else {
+                state.in_flight.push_front(sent);
+                break;
+            }
+        }
+
+        if let Some(sample) = rtt_sample {
+            state.update_timeout(sample);
+        }
+        if acked > 0 && packet.timestamp_diff != 0 {
+            state.update_window(acked, packet.timestamp_diff);
+        }
+    }
+
+    /// Retransmits the oldest unacknowledged packet once its timeout has expired, and shrinks the
+    /// window since the loss is taken as a sign of congestion
+    fn check_timeouts(&self, state: &mut State) {
+        let now = Instant::now();
+        let timeout = state.timeout;
+        let ack_nr = state.ack_nr;
+        let reply_micro = state.reply_micro;
+        let wnd_size = receive_window(state);
+
+        let expired = match state.in_flight.front_mut() {
+            Some(sent) if now.duration_since(sent.sent_at) >= timeout => {
+                if sent.retransmits >= MAX_RETRANSMITS {
+                    None
+                } else {
+                    sent.retransmits += 1;
+                    sent.sent_at = now;
+                    if sent.packet.kind != PacketType::Syn {
+                        sent.packet.ack_nr = ack_nr;
+                    }
+                    sent.packet.timestamp = now_micros();
+                    sent.packet.timestamp_diff = reply_micro;
+                    sent.packet.wnd_size = wnd_size;
+                    Some(Some(sent.packet.serialize()))
+                }
+            },
+            _ => Some(None)
+        };
+
+        match expired {
+            None => state.status = Status::Reset,
+            Some(Some(bytes)) => {
+                let _ = self.socket.send_to(&bytes, self.remote);
+                state.cwnd = MIN_WINDOW;
+                state.timeout = (timeout * 2).min(Duration::from_secs(60));
+            },
+            Some(None) => {}
+        }
+    }
+
+    fn flush(&self, state: &mut State) {
+        if state.status != Status::Connected {
+            return
+        }
+
+        while !state.send_buffer.is_empty() {
+            let size = MSS.min(state.send_buffer.len());
+            let window = (state.cwnd as usize).min(state.peer_window);
+            if !state.in_flight.is_empty() && state.bytes_in_flight() + size > window {
+                break;
+            }
+            let payload: Vec<u8> = state.send_buffer.drain(..size).collect();
+            self.send(state, PacketType::Data, payload);
+        }
+
+        if state.close_requested && state.fin_sent_at.is_none() && state.send_buffer.is_empty() {
+            self.send(state, PacketType::Fin, vec![]);
+            state.fin_sent_at = Some(Instant::now());
+        }
+    }
+
+    /// Sends a packet to the peer. Every packet type except acknowledgements takes up a sequence
+    /// number and is kept until it is acknowledged
+    fn send(&self, state: &mut State, kind: PacketType, payload: Vec<u8>) {
+        let packet = Packet {
+            kind,
+            connection_id: self.send_id,
+            timestamp: now_micros(),
+            timestamp_diff: state.reply_micro,
+            wnd_size: receive_window(state),
+            seq_nr: state.seq_nr,
+            ack_nr: state.ack_nr,
+            payload,
+        };
+        let _ = self.socket.send_to(&packet.serialize(), self.remote);
+
+        if kind != PacketType::State {
+            state.seq_nr = state.seq_nr.wrapping_add(1);
+            state.in_flight.push_back(InFlight { packet, sent_at: Instant::now(), retransmits: 0 });
+        }
+    }
+}
+
+/// Reads datagrams from a socket and routes each packet to the connection it belongs to. When
+/// listening, SYN packets from unknown connections create new streams
+struct Dispatcher {
+    socket: Arc<UdpSocket>,
+    streams: HashMap<(SocketAddr, u16), (Sender<Packet>, Weak<Shared>)>,
+    incoming: Option<(Sender<UtpStream>, Weak<()>)>,
+}
+
+impl Dispatcher {
+    fn spawn(mut self) -> Result<(), Error> {
+        self.socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        thread::spawn(move || {
+            let mut buf = vec![0; 65536];
+            loop {
+                if let Ok((n, src)) = self.socket.recv_from(&mut buf) {
+                    if let Some(packet) = Packet::parse(&buf[..n]) {
+                        self.route(packet, src);
+                    }
+                }
+
+                self.streams.retain(|_, (_, shared)| shared.upgrade().is_some());
+                if self.incoming.as_ref().is_some_and(|(_, alive)| alive.upgrade().is_none()) {
+                    self.incoming = None;
+                }
+                if self.streams.is_empty() && self.incoming.is_none() {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn route(&mut self, packet: Packet, src: SocketAddr) {
+        let key = if packet.kind == PacketType::Syn {
+            (src, packet.connection_id.wrapping_add(1))
+        } else {
+            (src, packet.connection_id)
+        };
+
+        if let Some((sender, _)) = self.streams.get(&key) {
+            if sender.send(packet).is_err() {
+                self.streams.remove(&key);
+            }
+            return
+        }
+
+        if packet.kind == PacketType::Syn && self.incoming.is_some() {
+            let shared = Shared::new(State::new(Status::Connected, thread_rng().gen(), packet.seq_nr));
+            let (tx, rx) = channel();
+            let worker = Worker {
+                shared: shared.clone(),
+                socket: self.socket.clone(),
+                remote: src,
+                send_id: packet.connection_id,
+                packets: rx,
+            };
+            let _ = tx.send(packet);
+            self.streams.insert(key, (tx, Arc::downgrade(&shared)));
+            thread::spawn(move || worker.run());
+
+            let stream = UtpStream { shared, remote: src };
+            let accepted = self.incoming.as_ref().map(|(sender, _)| sender.send(stream).is_ok());
+            if accepted == Some(false) {
+                self.incoming = None;
+            }
+        } else if packet.kind != PacketType::Reset {
+            // tell the peer that we don't know about this connection
+            let reset = Packet {
+                kind: PacketType::Reset,
+                connection_id: packet.connection_id,
+                timestamp: now_micros(),
+                timestamp_diff: 0,
+                wnd_size: 0,
+                seq_nr: 0,
+                ack_nr: packet.seq_nr,
+                payload: vec![],
+            };
+            let _ = self.socket.send_to(&reset.serialize(), src);
+        }
+    }
+}
+
+/// A reliable, ordered byte stream to a peer over uTP (BEP 29). uTP runs over UDP and uses the
+/// LEDBAT congestion controller, which backs off as soon as it notices queuing delay so that the
+/// transfer yields to other traffic on the link
+#[derive(Debug)]
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    remote: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn connect(addr: &SocketAddr) -> Result<UtpStream, Error> {
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = Arc::new(UdpSocket::bind(local)?);
+        let recv_id: u16 = thread_rng().gen();
+        let shared = Shared::new(State::new(Status::SynSent, 1, 0));
+
+        let (tx, rx) = channel();
+        let mut streams = HashMap::new();
+        streams.insert((*addr, recv_id), (tx, Arc::downgrade(&shared)));
+        Dispatcher { socket: socket.clone(), streams, incoming: None }.spawn()?;
+
+        let worker = Worker {
+            shared: shared.clone(),
+            socket,
+            remote: *addr,
+            send_id: recv_id.wrapping_add(1),
+            packets: rx,
+        };
+        {
+            // the SYN carries the id which the peer will use to address us
+            let mut state = shared.lock();
+            let packet = Packet {
+                kind: PacketType::Syn,
+                connection_id: recv_id,
+                timestamp: now_micros(),
+                timestamp_diff: 0,
+                wnd_size: RECEIVE_WINDOW as u32,
+                seq_nr: state.seq_nr,
+                ack_nr: 0,
+                payload: vec![],
+            };
+            worker.socket.send_to(&packet.serialize(), addr)?;
+            state.seq_nr = state.seq_nr.wrapping_add(1);
+            state.in_flight.push_back(InFlight { packet, sent_at: Instant::now(), retransmits: 0 });
+        }
+        thread::spawn(move || worker.run());
+
+        let deadline = Instant::now() + Duration::from_secs(CONNECT_TIMEOUT);
+        let mut state = shared.lock();
+        while state.status == Status::SynSent && Instant::now() < deadline {
+            state = shared.cond.wait_timeout(state, deadline - Instant::now()).unwrap().0;
+        }
+
+        match state.status {
+            Status::Connected => {
+                drop(state);
+                Ok(UtpStream { shared, remote: *addr })
+            },
+            Status::SynSent => {
+                state.status = Status::Reset;
+                Err(Error::new(ErrorKind::TimedOut, "uTP connection timed out"))
+            },
+            _ => Err(Error::new(ErrorKind::ConnectionRefused, "uTP connection refused"))
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.shared.lock().read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Reads received data without removing it from the stream
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let state = self.wait_readable()?;
+        let n = buf.len().min(state.recv_buffer.len());
+        for (b, r) in buf.iter_mut().zip(state.recv_buffer.iter()) {
+            *b = *r;
+        }
+        Ok(n)
+    }
+
+    /// Waits until there is data to read or the stream has ended, honoring the read timeout
+    fn wait_readable(&self) -> Result<MutexGuard<'_, State>, Error> {
+        let mut state = self.shared.lock();
+        let deadline = state.read_timeout.map(|t| Instant::now() + t);
+        while state.recv_buffer.is_empty() && !state.eof {
+            match state.status {
+                Status::Reset => return Err(Error::new(ErrorKind::ConnectionReset, "uTP connection reset")),
+                Status::Closed => return Ok(state),
+                _ => {}
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::new(ErrorKind::WouldBlock, "uTP read timed out"))
+                    }
+                    self.shared.cond.wait_timeout(state, deadline - now).unwrap().0
+                },
+                None => self.shared.cond.wait(state).unwrap()
+            };
+        }
+        Ok(state)
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut state = self.wait_readable()?;
+        let n = buf.len().min(state.recv_buffer.len());
+        for (b, r) in buf.iter_mut().zip(state.recv_buffer.drain(..n)) {
+            *b = r;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut state = self.shared.lock();
+        loop {
+            match state.status {
+                Status::Connected => {},
+                Status::Reset => return Err(Error::new(ErrorKind::ConnectionReset, "uTP connection reset")),
+                _ => return Err(Error::new(ErrorKind::NotConnected, "uTP connection closed"))
+            }
+            if state.send_buffer.len() < MAX_SEND_BUFFER {
+                break;
+            }
+            state = self.shared.cond.wait(state).unwrap();
+        }
+
+        let n = buf.len().min(MAX_SEND_BUFFER);
+        state.send_buffer.extend(buf[..n].iter());
+        Ok(n)
+    }
+
+    /// Waits until all written data has been acknowledged by the peer
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut state = self.shared.lock();
+        while !state.send_buffer.is_empty() || !state.in_flight.is_empty() {
+            if state.status != Status::Connected {
+                return Err(Error::new(ErrorKind::NotConnected, "uTP connection closed"))
+            }
+            state = self.shared.cond.wait(state).unwrap();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.shared.lock().close_requested = true;
+    }
+}
+
+/// Accepts uTP connections on a UDP socket
+#[derive(Debug)]
+pub struct UtpListener {
+    incoming: Receiver<UtpStream>,
+    local_addr: SocketAddr,
+    _alive: Arc<()>,
+}
+
+impl UtpListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UtpListener, Error> {
+        let socket = UdpSocket::bind(addr)?;
+        let local_addr = socket.local_addr()?;
+        let alive = Arc::new(());
+        let (tx, rx) = channel();
+        Dispatcher {
+            socket: Arc::new(socket),
+            streams: HashMap::new(),
+            incoming: Some((tx, Arc::downgrade(&alive))),
+        }.spawn()?;
+
+        Ok(UtpListener { incoming: rx, local_addr, _alive: alive })
+    }
+
+    /// Blocks until a peer opens a connection
+    pub fn accept(&self) -> Result<UtpStream, Error> {
+        self.incoming.recv().map_err(|_| Error::other("uTP listener closed"))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+/// Returns the window advertised to the peer, which is the free space in the receive buffer
+fn receive_window(state: &State) -> u32 {
+    RECEIVE_WINDOW.saturating_sub(state.recv_buffer.len()) as u32
+}
+
+/// Compares sequence numbers, which wrap around at 2^16
+fn seq_less_than(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+fn now_micros() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now.as_secs().wrapping_mul(1_000_000) + now.subsec_micros() as u64) as u32
+}
+
+fn u16_to_bytes(n: u16) -> Vec<u8> {
+    vec![(n >> 8) as u8, n as u8]
+}
+
+fn bytes_to_u16(bytes: &[u8]) -> u16 {
+    ((bytes[0] as u16) << 8) | bytes[1] as u16
+}
+
+#[cfg(test)]
+mod utp_tests {
+    use super::{Packet, PacketType, State, Status, UtpListener, UtpStream, seq_less_than, MSS, MIN_WINDOW};
+    use std::io::{Read, Write};
+    use std::thread;
+    use stream::Transport;
+    use mse::{accept, initiate, EncryptionPolicy, CRYPTO_RC4};
+
+    #[test]
+    fn packet_serialize_test() {
+        let packet = Packet {
+            kind: PacketType::Data,
+            connection_id: 258,
+            timestamp: 3,
+            timestamp_diff: 4,
+            wnd_size: 5,
+            seq_nr: 6,
+            ack_nr: 7,
+            payload: vec![8, 9],
+        };
+        let bytes = packet.serialize();
+        assert_eq!(bytes, vec![
+            0x01, 0,
+            1, 2,
+            0, 0, 0, 3,
+            0, 0, 0, 4,
+            0, 0, 0, 5,
+            0, 6,
+            0, 7,
+            8, 9,
+        ]);
+        assert_eq!(Packet::parse(&bytes), Some(packet));
+        assert_eq!(Packet::parse(&bytes[..10]), None);
+    }
+
+    #[test]
+    fn sequence_number_test() {
+        assert!(seq_less_than(1, 2));
+        assert!(seq_less_than(65535, 0));
+        assert!(!seq_less_than(2, 2));
+        assert!(!seq_less_than(0, 65535));
+    }
+
+    #[test]
+    fn ledbat_window_test() {
+        let mut state = State::new(Status::Connected, 0, 0);
+        let initial = state.cwnd;
+
+        // no queuing delay, so the window grows
+        state.update_window(MSS, 1000);
+        state.update_window(MSS, 1000);
+        assert!(state.cwnd > initial);
+
+        // the delay is far above the target, so the window shrinks to its minimum
+        for _ in 0..100 {
+            state.update_window(MSS, 1_001_000);
+        }
+        assert_eq!(state.cwnd, MIN_WINDOW);
+    }
+
+    #[test]
+    fn transfer_test() {
+        let listener = UtpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr();
+        let data: Vec<u8> = (0..500_000).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+
+        let client = thread::spawn(move || {
+            let mut stream = UtpStream::connect(&addr).unwrap();
+            stream.write_all(&data).unwrap();
+            let mut reply = vec![];
+            stream.read_to_end(&mut reply).unwrap();
+            reply
+        });
+
+        let mut stream = listener.accept().unwrap();
+        let mut received = vec![0; expected.len()];
+        stream.read_exact(&mut received).unwrap();
+        assert!(received == expected);
+
+        stream.write_all(b"thanks").unwrap();
+        stream.flush().unwrap();
+        drop(stream);
+        assert_eq!(client.join().unwrap(), b"thanks".to_vec());
+    }
+
+    #[test]
+    fn encrypted_transport_test() {
+        let listener = UtpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr();
+
+        let client = thread::spawn(move || {
+            let stream = Transport::Utp(UtpStream::connect(&addr).unwrap());
+            let mut s = initiate(stream, &[7; 20], CRYPTO_RC4).unwrap();
+            s.write_all(b"hello").unwrap();
+            s.flush().unwrap();
+        });
+
+        let stream = Transport::Utp(listener.accept().unwrap());
+        let (mut s, _) = accept(stream, &[vec![7; 20]], EncryptionPolicy::Forced).unwrap();
+        let mut buf = [0; 5];
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(s.is_encrypted());
+        client.join().unwrap();
+    }
+}
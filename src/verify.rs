@@ -0,0 +1,194 @@
+use metainfo::{MetaInfo, PieceInfo};
+use resume::{ResumeData, FileState};
+use storage::{Storage, FileStorage};
+use error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// The state of a single data file after verification
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileStatus {
+    /// Every piece overlapping the file matches its hash
+    Complete,
+    /// The file exists but at least one of its pieces doesn't match
+    Incomplete,
+    /// The file doesn't exist on disk
+    Missing,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub length: u64,
+    pub status: FileStatus,
+    // the number of pieces overlapping the file, and how many of those matched their hash
+    pub valid_pieces: usize,
+    pub total_pieces: usize,
+}
+
+/// The result of hashing a torrent's data on disk
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyReport {
+    pub pieces: Vec<bool>,
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    /// Returns whether every piece of the torrent matched its hash
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|&valid| valid)
+    }
+
+    /// Returns the number of pieces which matched their hash
+    pub fn num_valid(&self) -> usize {
+        self.pieces.iter().filter(|&&valid| valid).count()
+    }
+
+    /// Returns resume data recording the verified pieces, so that a later download of the
+    /// torrent from the given directory can skip hashing the data again. Like the torrent, it
+    /// records the state of every file, padding files included
+    pub fn resume_data(&self, metainfo: &MetaInfo, dir: &Path) -> ResumeData {
+        ResumeData {
+            have: self.pieces.clone(),
+            files: metainfo.info.files().iter().map(|(path, _)| FileState::of(&dir.join(path))).collect(),
+            peers: vec![],
+        }
+    }
+}
+
+/// Hashes every piece of the torrent's data stored in the given directory against the hashes in
+/// the metainfo, without creating or modifying any files. Pieces are spread over the given number
+/// of threads, each of which reads the data through its own handles
+pub fn verify(metainfo: &MetaInfo, dir: &Path, threads: usize) -> Result<VerifyReport, Error> {
+    let info = &metainfo.info;
+    let files: Vec<(PathBuf, u64)> = info.files().into_iter()
+        .map(|(path, length)| (dir.join(path), length))
+        .collect();
+    let attrs = info.file_attrs();
+    let piece_infos = metainfo.piece_infos();
+    let threads = threads.max(1).min(piece_infos.len().max(1));
+
+    let handles: Vec<_> = (0..threads).map(|t| {
+        let files = files.clone();
+        let attrs = attrs.clone();
+        let jobs: Vec<(usize, PieceInfo)> = (t..piece_infos.len()).step_by(threads)
+            .map(|i| (i, piece_infos[i].clone()))
+            .collect();
+
+        thread::spawn(move || -> Result<Vec<(usize, bool)>, Error> {
+            let mut storage = FileStorage::read_only(files).with_attrs(attrs);
+            let mut results = vec![];
+            for (index, piece) in jobs {
+                let valid = storage.verify(piece.offset, piece.length, &piece.hash, piece.hash_type).map_err(Error::Storage)?;
+                results.push((index, valid));
+            }
+            Ok(results)
+        })
+    }).collect();
+
+    let mut pieces = vec![false; piece_infos.len()];
+    for handle in handles {
+        let results = match handle.join() {
+            Ok(results) => results?,
+            Err(_) => return Err(Error::Storage(io::Error::other("Verification thread panicked")))
+        };
+        for (index, valid) in results {
+            pieces[index] = valid;
+        }
+    }
+
+    let mut reports = vec![];
+    let mut offset = 0;
+    for ((path, length), (attr, _)) in info.files().into_iter().zip(attrs) {
+        // padding files only exist in the layout of the data
+        if attr.padding {
+            offset += length;
+            continue;
+        }
+
+        let overlapping: Vec<bool> = piece_infos.iter().zip(pieces.iter())
+            .filter(|&(p, _)| p.offset < offset + length && offset < p.offset + p.length as u64)
+            .map(|(_, &valid)| valid)
+            .collect();
+        let valid_pieces = overlapping.iter().filter(|&&valid| valid).count();
+        let total_pieces = overlapping.len();
+
+        let status = if !dir.join(&path).is_file() {
+            FileStatus::Missing
+        } else if valid_pieces == total_pieces {
+            FileStatus::Complete
+        } else {
+            FileStatus::Incomplete
+        };
+
+        reports.push(FileReport { path, length, status, valid_pieces, total_pieces });
+        offset += length;
+    }
+
+    Ok(VerifyReport { pieces, files: reports })
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use super::{verify, FileStatus};
+    use metainfo::test_metainfo;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn verify_test() {
+        let dir = env::temp_dir().join("bittorrent_verify_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let data: Vec<u8> = (0..30).collect();
+        let m = test_metainfo("verify.txt", 12, &data, &[]);
+
+        let report = verify(&m, &dir, 2).unwrap();
+        assert_eq!(report.pieces, vec![false, false, false]);
+        assert_eq!(report.files[0].status, FileStatus::Missing);
+        assert!(!dir.join("verify.txt").exists());
+
+        let mut corrupt = data.clone();
+        corrupt[15] = 0;
+        fs::write(dir.join("verify.txt"), &corrupt).unwrap();
+        let report = verify(&m, &dir, 2).unwrap();
+        assert_eq!(report.pieces, vec![true, false, true]);
+        assert_eq!(report.files[0].status, FileStatus::Incomplete);
+        assert_eq!((report.files[0].valid_pieces, report.files[0].total_pieces), (2, 3));
+
+        fs::write(dir.join("verify.txt"), &data).unwrap();
+        let report = verify(&m, &dir, 4).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.files[0].status, FileStatus::Complete);
+        assert!(report.resume_data(&m, &dir).is_valid_for(&[dir.join("verify.txt")], 3));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn padded_resume_data_test() {
+        let dir = env::temp_dir().join("bittorrent_padded_resume_data_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("padded")).unwrap();
+
+        let mut data: Vec<u8> = (0..30).collect();
+        for byte in data[10..12].iter_mut() {
+            *byte = 0;
+        }
+        let mut m = test_metainfo("padded", 12, &data, &[("a.txt", 10), (".pad/2", 2), ("b.txt", 18)]);
+        m.info.files[1].attr.padding = true;
+        fs::write(dir.join("padded/a.txt"), &data[..10]).unwrap();
+        fs::write(dir.join("padded/b.txt"), &data[12..]).unwrap();
+
+        // the report leaves out the padding file, while the resume data covers every file
+        let report = verify(&m, &dir, 1).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.files.len(), 2);
+        let files: Vec<_> = m.info.files().into_iter().map(|(path, _)| dir.join(path)).collect();
+        assert!(report.resume_data(&m, &dir).is_valid_for(&files, 3));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
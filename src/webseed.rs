@@ -0,0 +1,410 @@
+use hyper::header::{self, Range};
+use hyper::status::StatusCode;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use bandwidth::{Direction, Throttle};
+use error::Error;
+use https;
+use util;
+use manager::backoff;
+use metainfo::{Info, PieceInfo};
+use piece::Priority;
+use torrent::Torrent;
+
+// how long to wait for a web seed to answer before treating the request as failed
+const REQUEST_TIMEOUT: u64 = 30;
+// how much of a response is read at a time, before waiting as long as the throttle asks
+const READ_SIZE: usize = 16384;
+
+/// A byte range of one file on a web seed, with an inclusive end as in the HTTP Range header
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeRequest {
+    pub url: String,
+    pub start: u64,
+    pub end: u64,
+    // where the range's bytes go within the piece
+    pub offset: u64,
+}
+
+/// A server hosting the torrent's files over HTTP (BEP 19), which is used like a peer that has
+/// every piece. Both `http://` and `https://` URLs are supported, while `ftp://` and other seeds
+/// from the `url-list` are logged and skipped
+#[derive(Debug)]
+pub struct WebSeed {
+    url: String,
+    failures: u32,
+    retry_at: Instant,
+}
+
+impl WebSeed {
+    pub fn new(url: &str) -> Self {
+        WebSeed {
+            url: url.to_string(),
+            failures: 0,
+            retry_at: Instant::now(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns whether the seed may be used now, which isn't the case while it backs off after
+    /// a failed request
+    pub fn is_ready(&self) -> bool {
+        self.retry_at <= Instant::now()
+    }
+
+    /// Returns the URL of a file of the torrent, given its path components within the torrent's
+    /// directory. A single-file torrent's URL names the file itself unless it ends with a slash,
+    /// while a multi-file torrent's URL is the directory containing the torrent's directory
+    fn file_url(&self, info: &Info, path: &[String]) -> String {
+        if info.files.is_empty() && !self.url.ends_with('/') {
+            return self.url.clone()
+        }
+
+        let mut url = self.url.clone();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let components: Vec<String> = Some(&info.name).into_iter().chain(path.iter())
+            .map(|c| utf8_percent_encode(c, PATH_SEGMENT_ENCODE_SET).collect())
+            .collect();
+        url.push_str(&components.join("/"));
+        url
+    }
+
+    /// Splits a piece into range requests for the files it spans. Padding files aren't hosted by
+    /// the seed, so their part of the piece is left out
+    pub fn requests(&self, info: &Info, piece: &PieceInfo) -> Vec<RangeRequest> {
+        let start = piece.offset;
+        let end = start + piece.length as u64;
+
+        let files: Vec<(Vec<String>, u64, bool)> = if info.files.is_empty() {
+            vec![(vec![], info.length, false)]
+        } else {
+            info.files.iter().map(|f| (f.path.clone(), f.length, f.attr.padding)).collect()
+        };
+
+        let mut requests = vec![];
+        let mut offset = 0;
+        for (path, length, padding) in files {
+            if !padding && offset < end && start < offset + length {
+                let from = start.max(offset);
+                let to = end.min(offset + length);
+                requests.push(RangeRequest {
+                    url: self.file_url(info, &path),
+                    start: from - offset,
+                    end: to - offset - 1,
+                    offset: from - start,
+                });
+            }
+            offset += length;
+        }
+        requests
+    }
+
+    /// Downloads the piece with the given index no faster than the throttle allows and checks it
+    /// against its hash, counting the bytes received in the torrent's stats. Failures make the
+    /// seed back off before it is used again
+    pub fn fetch_piece(&mut self, info: &Info, index: usize, piece: &PieceInfo, torrent_mutex: &Mutex<Torrent>,
+                       throttle: &Throttle) -> Result<Vec<u8>, Error> {
+        let result = self.download(info, piece, torrent_mutex, throttle).and_then(|data| {
+            if piece.hash_type.matches(&piece.hash, &data) {
+                Ok(data)
+            } else {
+                Err(Error::Protocol(format!("Web seed {} sent corrupt data for piece {}", self.url, index)))
+            }
+        });
+
+        match result {
+            Ok(_) => self.failures = 0,
+            Err(_) => {
+                self.failures += 1;
+                self.retry_at = Instant::now() + backoff(self.failures);
+            }
+        }
+        result
+    }
+
+    fn download(&self, info: &Info, piece: &PieceInfo, torrent_mutex: &Mutex<Torrent>,
+                throttle: &Throttle) -> Result<Vec<u8>, Error> {
+        let mut client = https::client()?;
+        client.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)));
+        client.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)));
+
+        // padding files are all zeros, which is what's left where no request fills the piece
+        let mut data = vec![0; piece.length as usize];
+        for request in self.requests(info, piece) {
+            let mut response = client.get(&request.url)
+                .header(Range::bytes(request.start, request.end))
+                .header(header::Connection::close())
+                .send()
+                .map_err(|e| Error::Io(io::Error::other(e.to_string())))?;
+
+            let length = request.end - request.start + 1;
+            let body = match response.status {
+                StatusCode::PartialContent => read_body(&mut response, 0, length, torrent_mutex, throttle)?,
+                // the server ignored the range and sends the whole file
+                StatusCode::Ok => read_body(&mut response, request.start, length, torrent_mutex, throttle)?,
+                status => return Err(Error::Protocol(format!("Web seed {} answered {}", request.url, status)))
+            };
+
+            if body.len() as u64 != length {
+                return Err(Error::Protocol(format!("Web seed {} sent {} of {} bytes", request.url, body.len(), length)))
+            }
+            let offset = request.offset as usize;
+            data[offset..offset + body.len()].copy_from_slice(&body);
+        }
+        Ok(data)
+    }
+}
+
+/// Reads `length` bytes of a response body after skipping `skip` bytes, counting them in the
+/// torrent's stats and waiting after each read as long as the throttle asks. The skipped bytes
+/// are thrown away, so they count like protocol overhead
+fn read_body<R: Read>(body: &mut R, skip: u64, length: u64, torrent_mutex: &Mutex<Torrent>,
+                      throttle: &Throttle) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    let mut skipped = 0;
+    let mut buffer = vec![0; READ_SIZE];
+    while (data.len() as u64) < length {
+        let wanted = if skipped < skip { skip - skipped } else { length - data.len() as u64 };
+        let n = body.read(&mut buffer[..(wanted.min(READ_SIZE as u64) as usize)])?;
+        if n == 0 {
+            break;
+        }
+        let (payload, overhead) = if skipped < skip {
+            skipped += n as u64;
+            (0, n as u64)
+        } else {
+            data.extend_from_slice(&buffer[..n]);
+            (n as u64, 0)
+        };
+        torrent_mutex.lock().unwrap().record_seed_download(payload, overhead);
+        thread::sleep(throttle.take(Direction::Download, payload, overhead));
+    }
+    Ok(data)
+}
+
+/// Downloads missing pieces from the web seed until the torrent is complete, within the limits
+/// of the given throttle and the torrent's own. Peers request pieces from the start of the
+/// torrent, so the seed works from the end to avoid duplicate downloads
+pub fn run(mut seed: WebSeed, torrent_mutex: Arc<Mutex<Torrent>>, throttle: Throttle) {
+    let (info, pieces, info_hash, throttle) = {
+        let torrent = torrent_mutex.lock().unwrap();
+        (torrent.metainfo.info.clone(), torrent.metainfo.piece_infos(), util::to_hex(&torrent.metainfo.info_hash),
+         throttle.and(torrent.limits()))
+    };
+
+    loop {
+        let next = {
+            let torrent = torrent_mutex.lock().unwrap();
+            if torrent.is_stopped() {
+                break;
+            }
+            torrent.pieces.iter().rev()
+                .find(|p| !p.is_complete && p.priority != Priority::Skip)
+                .map(|p| p.index as usize)
+        };
+        let index = match next {
+            Some(index) => index,
+            None => break
+        };
+
+        if !seed.is_ready() {
+            thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        match seed.fetch_piece(&info, index, &pieces[index], &torrent_mutex, &throttle) {
+            Ok(data) => {
+                if let Err(e) = torrent_mutex.lock().unwrap().store_piece(index as u32, data) {
+                    warn!(info_hash = info_hash.as_str(), peer = seed.url(); "Storing piece {} failed: {}", index, e);
+                    break;
+                }
+            },
+            Err(e) => warn!(info_hash = info_hash.as_str(), peer = seed.url(); "Fetching piece {} failed: {}", index, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod webseed_tests {
+    use super::{run, RangeRequest, WebSeed};
+    use bandwidth::{Limits, Throttle};
+    use metainfo::{test_metainfo, MetaInfo, Info, PieceInfo, PieceHash};
+    use storage::{Storage, MemoryStorage};
+    use torrent::Torrent;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use util::create_peer_id;
+    use hash;
+
+    /// Serves the given files over HTTP on a local port, answering Range requests, as a stand-in
+    /// for a real web seed. Returns the base URL
+    fn serve(files: HashMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or("").to_string();
+
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if line.to_lowercase().starts_with("range: bytes=") {
+                        let spec = line.trim()["range: bytes=".len()..].to_string();
+                        let mut parts = spec.split('-').map(|n| n.parse::<usize>().unwrap());
+                        range = Some((parts.next().unwrap(), parts.next().unwrap()));
+                    }
+                }
+
+                let response = match (files.get(&path), range) {
+                    (Some(data), Some((start, end))) => {
+                        let body = &data[start..(end + 1).min(data.len())];
+                        let mut r = format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                                            body.len()).into_bytes();
+                        r.extend(body);
+                        r
+                    },
+                    _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+                };
+                let _ = stream.write_all(&response);
+            }
+        });
+        format!("http://127.0.0.1:{}/", port)
+    }
+
+    fn multi_file_metainfo(data: &[u8]) -> MetaInfo {
+        test_metainfo("my dir", 16384, data, &[("a.bin", 20000), ("sub/b.bin", 20000)])
+    }
+
+    fn piece(offset: u64, length: u32, data: &[u8]) -> PieceInfo {
+        let hash = hash::sha(&data[offset as usize..offset as usize + length as usize]);
+        PieceInfo { offset, length, hash, hash_type: PieceHash::Sha1 }
+    }
+
+    #[test]
+    fn requests_test() {
+        let data = vec![0; 40000];
+        let info = multi_file_metainfo(&data).info;
+        let seed = WebSeed::new("http://example.com/files");
+        assert_eq!(seed.requests(&info, &piece(16384, 16384, &data)), vec![
+            RangeRequest { url: String::from("http://example.com/files/my%20dir/a.bin"), start: 16384, end: 19999, offset: 0 },
+            RangeRequest { url: String::from("http://example.com/files/my%20dir/sub/b.bin"), start: 0, end: 12767, offset: 3616 },
+        ]);
+
+        let single = Info { files: vec![], name: String::from("file.iso"), ..info };
+        assert_eq!(WebSeed::new("http://example.com/file.iso").requests(&single, &piece(32768, 7232, &data)), vec![
+            RangeRequest { url: String::from("http://example.com/file.iso"), start: 32768, end: 39999, offset: 0 },
+        ]);
+        assert_eq!(WebSeed::new("http://example.com/").requests(&single, &piece(0, 16384, &data))[0].url, "http://example.com/file.iso");
+    }
+
+    #[test]
+    fn padding_files_test() {
+        let mut data: Vec<u8> = (0..36384).map(|i| (i % 253) as u8).collect();
+        for byte in data[10000..16384].iter_mut() {
+            *byte = 0;
+        }
+        let mut files = HashMap::new();
+        files.insert(String::from("/my%20dir/a.bin"), data[..10000].to_vec());
+        files.insert(String::from("/my%20dir/b.bin"), data[16384..].to_vec());
+        let url = serve(files);
+
+        let mut m = test_metainfo("my dir", 16384, &data, &[("a.bin", 10000), (".pad/6384", 6384), ("b.bin", 20000)]);
+        m.info.files[1].attr.padding = true;
+        m.url_list = vec![url.clone()];
+
+        // the padding file is never requested
+        let seed = WebSeed::new(&url);
+        assert_eq!(seed.requests(&m.info, &piece(0, 16384, &data)), vec![
+            RangeRequest { url: format!("{}my%20dir/a.bin", url), start: 0, end: 9999, offset: 0 },
+        ]);
+
+        let mut storage = MemoryStorage::new();
+        storage.set_len(36384).unwrap();
+        let torrent = Arc::new(Mutex::new(Torrent::with_storage(create_peer_id(), m, Box::new(storage))));
+        run(seed, torrent.clone(), Throttle::unlimited());
+        assert!(torrent.lock().unwrap().is_complete());
+    }
+
+    #[test]
+    fn download_from_web_seed_test() {
+        let data: Vec<u8> = (0..40000).map(|i| (i % 253) as u8).collect();
+        let mut files = HashMap::new();
+        files.insert(String::from("/my%20dir/a.bin"), data[..20000].to_vec());
+        files.insert(String::from("/my%20dir/sub/b.bin"), data[20000..].to_vec());
+        let url = serve(files);
+
+        let mut m = multi_file_metainfo(&data);
+        m.url_list = vec![url.clone()];
+
+        let mut storage = MemoryStorage::new();
+        storage.set_len(40000).unwrap();
+        let torrent = Arc::new(Mutex::new(Torrent::with_storage(create_peer_id(), m, Box::new(storage))));
+        run(WebSeed::new(&url), torrent.clone(), Throttle::unlimited());
+
+        // pieces are only marked complete once their hash matched
+        let torrent = torrent.lock().unwrap();
+        assert!(torrent.is_complete());
+        assert_eq!(torrent.stats().downloaded, 40000);
+    }
+
+    #[test]
+    fn throttled_web_seed_test() {
+        let data: Vec<u8> = (0..40000).map(|i| (i % 253) as u8).collect();
+        let mut files = HashMap::new();
+        files.insert(String::from("/my%20dir/a.bin"), data[..20000].to_vec());
+        files.insert(String::from("/my%20dir/sub/b.bin"), data[20000..].to_vec());
+        let url = serve(files);
+
+        let mut storage = MemoryStorage::new();
+        storage.set_len(40000).unwrap();
+        let torrent = Arc::new(Mutex::new(Torrent::with_storage(create_peer_id(), multi_file_metainfo(&data), Box::new(storage))));
+        torrent.lock().unwrap().limits().set_download_rate(20000);
+        let started = Instant::now();
+        run(WebSeed::new(&url), torrent.clone(), Throttle::new(Limits::unlimited(), Arc::new(AtomicBool::new(false))));
+
+        // a second's worth passes at once, and the rest has to wait for the torrent's limit
+        assert!(torrent.lock().unwrap().is_complete());
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn corrupt_web_seed_backs_off_test() {
+        let data: Vec<u8> = (0..40000).map(|i| (i % 253) as u8).collect();
+        let mut files = HashMap::new();
+        files.insert(String::from("/my%20dir/a.bin"), vec![0; 20000]);
+        let url = serve(files);
+
+        let m = multi_file_metainfo(&data);
+        let info = m.info.clone();
+        let torrent = Mutex::new(Torrent::with_storage(create_peer_id(), m, Box::new(MemoryStorage::new())));
+        let mut seed = WebSeed::new(&url);
+        assert!(seed.fetch_piece(&info, 0, &piece(0, 16384, &data), &torrent, &Throttle::unlimited()).is_err());
+        assert!(!seed.is_ready());
+        // corrupt data counts as downloaded all the same
+        assert_eq!(torrent.lock().unwrap().stats().downloaded, 16384);
+        seed.retry_at = ::std::time::Instant::now();
+        // the second file is missing from the server
+        assert!(seed.fetch_piece(&info, 2, &piece(32768, 7232, &data), &torrent, &Throttle::unlimited()).is_err());
+    }
+}